@@ -8,7 +8,7 @@ pub use unix::*;
 
 #[cfg(unix)]
 mod unix {
-    use std::sync::Arc;
+    use std::{path::Path, sync::Arc};
 
     use futures::future::join_all;
     use log::debug;
@@ -16,13 +16,18 @@ mod unix {
     use tokio::{
         io::AsyncReadExt,
         net::{UnixListener, UnixStream},
-        sync::RwLock,
     };
 
-    use crate::{rpc::rpc_client::current_rpc_context, Enforcer};
+    use crate::{
+        dhcp::{event_queue::DhcpEventQueue, event_spool, lease_file, lease_file::KnownLeases},
+        services::abstractions::AbstractionResolver,
+    };
 
-    /// Listens for DHCP events supplied by the dnsmasq hook script and call relevant handle function.
-    pub async fn listen_for_dhcp_events(enforcer: Arc<RwLock<Enforcer>>) {
+    /// Listens for DHCP events supplied by the dnsmasq hook script and queues them for transmission to the controller.
+    /// The MUD URLs of the devices are also passed to the resolver for MUD abstractions.
+    /// At startup, the leases that changed while the enforcer was not running are determined from the lease file of
+    /// dnsmasq and reported as synthetic events.
+    pub async fn listen_for_dhcp_events(dhcp_events: Arc<DhcpEventQueue>, abstractions: Arc<AbstractionResolver>) {
         debug!("Starting DHCP event listener");
         match std::fs::remove_file("/tmp/namib_dhcp.sock") {
             Ok(_) => Ok(()),
@@ -32,45 +37,98 @@ mod unix {
             },
         }
         .expect("Unable to get access to socket file");
+        let known_leases = Arc::new(KnownLeases::load(crate::state_file_path(
+            lease_file::KNOWN_LEASES_STATE_FILE_NAME,
+        )));
+        tokio::spawn({
+            let known_leases = known_leases.clone();
+            async move { known_leases.persister_task().await }
+        });
         let listener =
             UnixListener::bind("/tmp/namib_dhcp.sock").expect("Could not open socket for DHCP event listener.");
+        // The hook passes new events through the socket from now on, so the events spooled while the enforcer was not
+        // running are handled before accepting connections to keep the events in order.
+        handle_spooled_events(&dhcp_events, &abstractions, &known_leases);
+        // The lease file reflects the current leases, so it is reconciled after handling the (older) spooled events.
+        match known_leases.reconcile(lease_file::lease_file_path()) {
+            Ok(lease_events) => {
+                debug!(
+                    "Reporting {} changed DHCP leases from the lease file",
+                    lease_events.len()
+                );
+                for dhcp_event in lease_events {
+                    handle_dhcp_event(&dhcp_events, &abstractions, &known_leases, dhcp_event);
+                }
+            },
+            Err(e) => warn!("Unable to read the DHCP lease file: {:?}", e),
+        }
         let mut active_listeners = Vec::new();
         while let Ok((event_stream, _)) = listener.accept().await {
-            let enforcer = enforcer.clone();
+            let dhcp_events = dhcp_events.clone();
+            let abstractions = abstractions.clone();
+            let known_leases = known_leases.clone();
             active_listeners.push(tokio::spawn(async move {
-                handle_dhcp_script_connection(enforcer, event_stream).await
+                handle_dhcp_script_connection(&dhcp_events, &abstractions, &known_leases, event_stream).await
             }));
         }
         join_all(active_listeners).await;
     }
 
-    async fn handle_dhcp_script_connection(enforcer: Arc<RwLock<Enforcer>>, mut stream: UnixStream) {
+    /// Handles the events in the spool of the dnsmasq hook (if any) and removes them from the spool.
+    fn handle_spooled_events(
+        dhcp_events: &DhcpEventQueue,
+        abstractions: &AbstractionResolver,
+        known_leases: &KnownLeases,
+    ) {
+        match event_spool::take_spooled_events(Path::new(event_spool::SPOOL_PATH)) {
+            Ok(spooled_events) => {
+                debug!("Handling {} spooled DHCP events", spooled_events.len());
+                for dhcp_event in spooled_events {
+                    handle_dhcp_event(dhcp_events, abstractions, known_leases, dhcp_event);
+                }
+            },
+            Err(e) => warn!("Unable to read spooled DHCP events: {:?}", e),
+        }
+    }
+
+    async fn handle_dhcp_script_connection(
+        dhcp_events: &DhcpEventQueue,
+        abstractions: &AbstractionResolver,
+        known_leases: &KnownLeases,
+        mut stream: UnixStream,
+    ) {
         let mut inc_data = Vec::new();
         stream.read_to_end(&mut inc_data).await.unwrap();
         match serde_json::from_slice::<DhcpEvent>(inc_data.as_slice()) {
             Ok(dhcp_event) => {
                 debug!("Received DHCP event: {:?}", &dhcp_event);
-                let enforcer = enforcer.read().await;
-                enforcer
-                    .client
-                    .dhcp_request(current_rpc_context(), dhcp_event)
-                    .await
-                    .unwrap();
+                handle_dhcp_event(dhcp_events, abstractions, known_leases, dhcp_event);
             },
             Err(e) => {
                 warn!("DHCP event was received, but could not be parsed: {}", e);
             },
         }
     }
+
+    /// Passes the given DHCP event to the resolver for MUD abstractions and the known leases and queues it for
+    /// transmission to the controller.
+    fn handle_dhcp_event(
+        dhcp_events: &DhcpEventQueue,
+        abstractions: &AbstractionResolver,
+        known_leases: &KnownLeases,
+        dhcp_event: DhcpEvent,
+    ) {
+        abstractions.observe_dhcp_event(&dhcp_event);
+        known_leases.observe_dhcp_event(&dhcp_event);
+        dhcp_events.push(dhcp_event);
+    }
 }
 
 #[cfg(not(unix))]
 mod mock {
     use std::sync::Arc;
 
-    use tokio::sync::RwLock;
-
-    use crate::Enforcer;
+    use crate::{dhcp::event_queue::DhcpEventQueue, services::abstractions::AbstractionResolver};
 
-    pub async fn listen_for_dhcp_events(_: Arc<RwLock<Enforcer>>) {}
+    pub async fn listen_for_dhcp_events(_: Arc<DhcpEventQueue>, _: Arc<AbstractionResolver>) {}
 }
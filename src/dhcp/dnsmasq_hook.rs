@@ -1,10 +1,12 @@
 // Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-use std::{convert::TryInto, env, net, net::IpAddr, num, os::unix::net::UnixStream, str::FromStr, time::Duration};
+use std::{
+    convert::TryInto, env, net, net::IpAddr, num, os::unix::net::UnixStream, path::Path, str::FromStr, time::Duration,
+};
 
 use chrono::prelude::*;
-use log::{debug, info};
+use log::{debug, info, warn};
 use namib_shared::{
     macaddr,
     macaddr::SerdeMacAddr,
@@ -16,6 +18,9 @@ use namib_shared::{
 use regex::Regex;
 use snafu::Snafu;
 
+#[path = "event_spool.rs"]
+mod event_spool;
+
 enum EventType {
     Add,
     Del,
@@ -51,6 +56,7 @@ impl From<DnsmasqHookError> for DnsmasqHookReturn {
             DnsmasqHookError::UnsupportedEventType { .. } => 8,
             DnsmasqHookError::EnforcerConnectionError { .. } => 63,
             DnsmasqHookError::EventSerializationError { .. } => 64,
+            DnsmasqHookError::EventSpoolError { .. } => 65,
             DnsmasqHookError::RequiredArgumentMissing { .. } => 1,
         }
     }
@@ -110,6 +116,9 @@ enum DnsmasqHookError {
         event: DhcpEvent,
         source: serde_json::Error,
     },
+    /// Error while spooling event for the enforcer.
+    #[snafu(display("Could not spool event: {}", "source"))]
+    EventSpoolError { source: std::io::Error },
 }
 
 type Result<T> = std::result::Result<T, DnsmasqHookError>;
@@ -140,8 +149,22 @@ fn main() {
 fn run_dnsmasq_hook() -> Result<()> {
     let dhcp_event = extract_dhcp_hook_data()?;
     debug!("Constructed DHCP Event: {:?}", &dhcp_event);
-    let socket = UnixStream::connect("/tmp/namib_dhcp.sock")
-        .map_err(|e| DnsmasqHookError::EnforcerConnectionError { source: e })?;
+    let socket = match UnixStream::connect("/tmp/namib_dhcp.sock") {
+        Ok(socket) => socket,
+        Err(e) => {
+            // The enforcer is not running (e.g. because it is restarting), it will read the event from the spool at
+            // startup.
+            warn!("Could not connect to enforcer, spooling DHCP event: {}", e);
+            event_spool::spool_event(
+                Path::new(event_spool::SPOOL_PATH),
+                &dhcp_event,
+                event_spool::MAX_SPOOLED_EVENTS,
+            )
+            .map_err(|e| DnsmasqHookError::EventSpoolError { source: e })?;
+            info!("DHCP Event spooled for enforcer");
+            return Ok(());
+        },
+    };
     serde_json::to_writer(socket, &dhcp_event).map_err(|e| DnsmasqHookError::EventSerializationError {
         event: dhcp_event,
         source: e,
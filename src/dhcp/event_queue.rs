@@ -0,0 +1,284 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    cmp,
+    collections::VecDeque,
+    fs::File,
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use namib_shared::models::{DhcpEvent, DhcpLeaseInformation};
+use tokio::{
+    select,
+    sync::{Notify, RwLock},
+    time::sleep,
+};
+
+use crate::{
+    dhcp::{lease_client, leased_addr},
+    error::Error,
+    rpc::rpc_client::current_rpc_context,
+    Enforcer,
+};
+
+/// Name of the file the queue is persisted to, which is located in the directory of the config state file.
+pub const DHCP_EVENT_QUEUE_STATE_FILE_NAME: &str = "dhcp_event_queue.json";
+
+/// Delay before the first retry of a failed transmission, which is doubled for each further failure.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum delay between retries of a failed transmission.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Returns the information of the lease the given event belongs to.
+fn event_lease_info(event: &DhcpEvent) -> &DhcpLeaseInformation {
+    match event {
+        DhcpEvent::LeaseAdded { lease_info, .. }
+        | DhcpEvent::LeaseDestroyed { lease_info, .. }
+        | DhcpEvent::ExistingLeaseUpdate { lease_info, .. } => lease_info,
+    }
+}
+
+/// Returns the address leased by the lease the given event belongs to.
+fn event_addr(event: &DhcpEvent) -> IpAddr {
+    leased_addr(&event_lease_info(event).version_specific_information)
+}
+
+/// Returns the key identifying the lease the given event belongs to, consisting of the leased address and the client
+/// holding the lease (as an address may be leased to another client in the meantime).
+fn event_lease(event: &DhcpEvent) -> (IpAddr, String) {
+    (event_addr(event), lease_client(event_lease_info(event)))
+}
+
+/// Persistent queue of the DHCP events that have not been transmitted to the controller yet.
+/// Only the latest event of each lease (i.e. of each address and client) is kept, as it reflects the current state of
+/// the lease.
+#[derive(Debug)]
+pub struct DhcpEventQueue {
+    /// File the queue is persisted to.
+    path: PathBuf,
+    /// Queued events with their sequence numbers, in the order they were queued.
+    events: Mutex<VecDeque<(u64, DhcpEvent)>>,
+    /// Sequence number of the next queued event.
+    next_sequence_number: Mutex<u64>,
+    /// Notifies the sender task of newly queued events.
+    queued: Notify,
+    /// Notifies the sender task that the connection to the controller was reestablished.
+    reconnected: Notify,
+    /// Notifies the persister task of changes that were not persisted yet.
+    changed: Notify,
+}
+
+impl DhcpEventQueue {
+    /// Creates a queue persisted to the given file, containing the events persisted by the last run (if any).
+    pub fn load(path: PathBuf) -> DhcpEventQueue {
+        let events: Vec<DhcpEvent> = match File::open(&path)
+            .map_err(Error::from)
+            .and_then(|file| Ok(serde_json::from_reader(file)?))
+        {
+            Ok(events) => events,
+            Err(Error::IoError { source, .. }) if source.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Error while reading persisted DHCP event queue: {:?}", e);
+                Vec::new()
+            },
+        };
+        if !events.is_empty() {
+            info!(
+                "Restored {} queued DHCP events from \"{}\"",
+                events.len(),
+                path.display()
+            );
+        }
+        DhcpEventQueue {
+            path,
+            next_sequence_number: Mutex::new(events.len() as u64),
+            events: Mutex::new((0..).zip(events).collect()),
+            queued: Notify::new(),
+            reconnected: Notify::new(),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Returns the number of queued events.
+    pub fn len(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    /// Returns whether no events are queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues the given event for transmission, replacing the queued event of the same lease (if any).
+    pub fn push(&self, event: DhcpEvent) {
+        let mut events = self.events.lock().unwrap();
+        let lease = event_lease(&event);
+        events.retain(|(_, queued_event)| event_lease(queued_event) != lease);
+        let mut next_sequence_number = self.next_sequence_number.lock().unwrap();
+        events.push_back((*next_sequence_number, event));
+        *next_sequence_number += 1;
+        self.changed.notify_one();
+        self.queued.notify_one();
+    }
+
+    /// Notifies the sender task that the connection to the controller was reestablished, so that the queued events
+    /// are transmitted without waiting for the next retry.
+    pub fn notify_reconnected(&self) {
+        self.reconnected.notify_one();
+    }
+
+    /// Returns the oldest queued event with its sequence number.
+    fn front(&self) -> Option<(u64, DhcpEvent)> {
+        self.events.lock().unwrap().front().cloned()
+    }
+
+    /// Removes the event with the given sequence number after it was transmitted. The event may have been replaced
+    /// by a newer event of its lease in the meantime, which remains queued.
+    fn remove(&self, sequence_number: u64) {
+        let mut events = self.events.lock().unwrap();
+        events.retain(|(queued_sequence_number, _)| *queued_sequence_number != sequence_number);
+        self.changed.notify_one();
+    }
+
+    /// Persists the queued events to the state file.
+    fn persist(&self) {
+        let events = self.events.lock().unwrap();
+        let events: Vec<&DhcpEvent> = events.iter().map(|(_, event)| event).collect();
+        if let Err(e) = crate::write_state_file(&self.path, &events) {
+            warn!("Error while persisting DHCP event queue: {:?}", e);
+        }
+    }
+
+    /// Task which persists the queue once it changed, writing all changes within `STATE_FILE_WRITE_DELAY` at once.
+    pub async fn persister_task(&self) {
+        loop {
+            self.changed.notified().await;
+            sleep(crate::STATE_FILE_WRITE_DELAY).await;
+            self.persist();
+        }
+    }
+
+    /// Task which transmits the queued events to the controller in order. Failed transmissions are retried with
+    /// exponential backoff, or right away once the connection to the controller was reestablished.
+    pub async fn sender_task(&self, enforcer: Arc<RwLock<Enforcer>>) {
+        let mut retry_delay = INITIAL_RETRY_DELAY;
+        loop {
+            let (sequence_number, event) = match self.front() {
+                Some(queued_event) => queued_event,
+                None => {
+                    self.queued.notified().await;
+                    continue;
+                },
+            };
+            let result = enforcer
+                .read()
+                .await
+                .client
+                .dhcp_request(current_rpc_context(), event)
+                .await;
+            match result {
+                Ok(_) => {
+                    self.remove(sequence_number);
+                    retry_delay = INITIAL_RETRY_DELAY;
+                },
+                Err(e) => {
+                    warn!(
+                        "Unable to transmit DHCP event to the controller, retrying in {:?} ({} events queued): {:?}",
+                        retry_delay,
+                        self.len(),
+                        e
+                    );
+                    select! {
+                        _ = sleep(retry_delay) => {},
+                        _ = self.reconnected.notified() => {},
+                    }
+                    retry_delay = cmp::min(retry_delay * 2, MAX_RETRY_DELAY);
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use namib_shared::macaddr;
+
+    use super::*;
+    use crate::dhcp::event_spool::tests::event;
+
+    fn queued_leases(queue: &DhcpEventQueue) -> Vec<(u64, IpAddr, bool)> {
+        queue
+            .events
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(sequence_number, event)| {
+                (
+                    *sequence_number,
+                    event_addr(event),
+                    matches!(event, DhcpEvent::LeaseDestroyed { .. }),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dhcp_event_queue() {
+        let path = env::temp_dir().join(format!("namib_dhcp_event_queue_test_{}.json", std::process::id()));
+        let queue = DhcpEventQueue::load(path.clone());
+        assert!(queue.is_empty());
+
+        // Only the latest event of each lease is kept.
+        queue.push(event("192.168.1.15", 1000, false));
+        queue.push(event("192.168.1.16", 1001, false));
+        queue.push(event("192.168.1.15", 1002, true));
+        assert_eq!(
+            queued_leases(&queue),
+            vec![
+                (1, "192.168.1.16".parse().unwrap(), false),
+                (2, "192.168.1.15".parse().unwrap(), true),
+            ]
+        );
+
+        // A transmitted event that was replaced by a newer event of its lease does not remove the newer event.
+        let (sequence_number, _) = queue.front().unwrap();
+        queue.push(event("192.168.1.16", 1003, true));
+        queue.remove(sequence_number);
+        assert_eq!(queue.len(), 2);
+
+        // The queued events are restored in order.
+        queue.persist();
+        let restored_queue = DhcpEventQueue::load(path.clone());
+        assert_eq!(
+            queued_leases(&restored_queue),
+            vec![
+                (0, "192.168.1.15".parse().unwrap(), true),
+                (1, "192.168.1.16".parse().unwrap(), true),
+            ]
+        );
+
+        // The events of a client which was assigned the address of another client in the meantime are kept as well.
+        let mut reassigned = event("192.168.1.15", 1004, false);
+        if let DhcpEvent::LeaseAdded { lease_info, .. } = &mut reassigned {
+            lease_info.mac_address = Some("aa:bb:cc:dd:ee:03".parse::<macaddr::MacAddr>().unwrap().into());
+        }
+        restored_queue.push(reassigned);
+        assert_eq!(
+            queued_leases(&restored_queue),
+            vec![
+                (0, "192.168.1.15".parse().unwrap(), true),
+                (1, "192.168.1.16".parse().unwrap(), true),
+                (2, "192.168.1.15".parse().unwrap(), false),
+            ]
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+// Spool of DHCP events which could not be passed to the enforcer by the dnsmasq hook (e.g. because the enforcer is
+// restarting). The spool is a file containing one JSON-serialized event per line, appended to by the hook and drained
+// by the enforcer at startup.
+// This module is used by both the enforcer and the dnsmasq hook binary, so it must only depend on crates that are
+// available to both.
+
+use std::{
+    collections::HashSet,
+    fs,
+    fs::File,
+    io,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use log::warn;
+use namib_shared::models::DhcpEvent;
+
+/// Location of the spool, next to the socket of the enforcer.
+pub const SPOOL_PATH: &str = "/tmp/namib_dhcp_spool.jsonl";
+
+/// Maximum number of events in the spool. If the spool is full, the oldest events are discarded.
+pub const MAX_SPOOLED_EVENTS: usize = 1024;
+
+/// Appends the given event to the spool at the given path, discarding the oldest events if the spool contains more than
+/// `max_events` events afterwards.
+/// dnsmasq runs the hook for one event at a time, so the spool is never written concurrently.
+pub fn spool_event(path: &Path, event: &DhcpEvent, max_events: usize) -> io::Result<()> {
+    let mut lines = read_lines(path)?;
+    lines.push(serde_json::to_string(event)?);
+    let excess_events = lines.len().saturating_sub(max_events);
+    // The spool is replaced instead of written in place, so that it is never left incomplete.
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    for line in &lines[excess_events..] {
+        writeln!(file, "{}", line)?;
+    }
+    file.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+/// Removes the spool at the given path and returns its events in the order they were spooled.
+/// Events of the same lease with the same timestamp (e.g. spooled twice) are only returned once, lines that cannot be
+/// parsed are skipped.
+pub fn take_spooled_events(path: &Path) -> io::Result<Vec<DhcpEvent>> {
+    // The spool is moved away before reading it, so that events spooled in the meantime end up in a new spool instead
+    // of being removed together with the events read.
+    let taken_path = path.with_extension("taken");
+    match fs::rename(path, &taken_path) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    }
+    let lines = read_lines(&taken_path)?;
+    fs::remove_file(&taken_path)?;
+    let mut seen_events = HashSet::new();
+    Ok(lines
+        .iter()
+        .filter_map(|line| match serde_json::from_str::<DhcpEvent>(line) {
+            Ok(event) => Some(event),
+            Err(e) => {
+                warn!("Skipping spooled DHCP event that could not be parsed: {}", e);
+                None
+            },
+        })
+        .filter(|event| seen_events.insert(event_key(event)))
+        .collect())
+}
+
+/// Returns the lines of the spool at the given path, or no lines if there is no spool.
+fn read_lines(path: &Path) -> io::Result<Vec<String>> {
+    match File::open(path) {
+        Ok(file) => BufReader::new(file).lines().collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns the key identifying the given event, consisting of the kind of the event, the lease (i.e. its
+/// version-specific information, serialized as this module cannot depend on the rest of the enforcer) and the timestamp
+/// of the event.
+fn event_key(event: &DhcpEvent) -> (&'static str, String, String) {
+    let (kind, event_timestamp, lease_info) = match event {
+        DhcpEvent::LeaseAdded {
+            event_timestamp,
+            lease_info,
+        } => ("add", event_timestamp, lease_info),
+        DhcpEvent::LeaseDestroyed {
+            event_timestamp,
+            lease_info,
+        } => ("del", event_timestamp, lease_info),
+        DhcpEvent::ExistingLeaseUpdate {
+            event_timestamp,
+            lease_info,
+        } => ("old", event_timestamp, lease_info),
+    };
+    let lease = serde_json::to_string(&lease_info.version_specific_information).unwrap_or_default();
+    (kind, lease, event_timestamp.to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{env, time::Duration};
+
+    use chrono::{Local, TimeZone};
+    use namib_shared::models::{
+        DhcpLeaseInformation, DhcpLeaseVersionSpecificInformation, DhcpV4LeaseVersionSpecificInformation,
+        LeaseExpiryTime,
+    };
+
+    use super::*;
+
+    /// Returns an event adding (or destroying) a DHCPv4 lease of the given address, which is also used by the tests of
+    /// the other DHCP modules.
+    pub(crate) fn event(ip_addr: &str, timestamp: i64, destroyed: bool) -> DhcpEvent {
+        let lease_info = DhcpLeaseInformation {
+            version_specific_information: DhcpLeaseVersionSpecificInformation::V4(
+                DhcpV4LeaseVersionSpecificInformation {
+                    ip_addr: ip_addr.parse().unwrap(),
+                },
+            ),
+            domain: None,
+            client_provided_hostname: None,
+            old_hostname: None,
+            user_classes: Vec::new(),
+            lease_expiry: LeaseExpiryTime::LeaseLength(Duration::from_secs(3600)),
+            time_remaining: Duration::from_secs(3600),
+            receiver_interface: None,
+            mac_address: None,
+            mud_url: None,
+            tags: Vec::new(),
+            hostname: None,
+        };
+        let event_timestamp = Local.timestamp_opt(timestamp, 0).unwrap().into();
+        if destroyed {
+            DhcpEvent::LeaseDestroyed {
+                event_timestamp,
+                lease_info,
+            }
+        } else {
+            DhcpEvent::LeaseAdded {
+                event_timestamp,
+                lease_info,
+            }
+        }
+    }
+
+    fn keys(events: &[DhcpEvent]) -> Vec<(&'static str, String, String)> {
+        events.iter().map(event_key).collect()
+    }
+
+    #[test]
+    fn test_spool() {
+        let path = env::temp_dir().join(format!("namib_dhcp_spool_test_{}.jsonl", std::process::id()));
+        let events = vec![
+            event("192.168.1.15", 1000, false),
+            event("192.168.1.16", 1001, false),
+            event("192.168.1.15", 1002, true),
+            event("192.168.1.17", 1003, false),
+        ];
+        assert!(take_spooled_events(&path).unwrap().is_empty());
+
+        // The oldest events are discarded once the spool is full.
+        for event in &events {
+            spool_event(&path, event, 3).unwrap();
+        }
+        assert_eq!(keys(&take_spooled_events(&path).unwrap()), keys(&events[1..]));
+        assert!(!path.exists());
+        assert!(!path.with_extension("taken").exists());
+
+        // Events of the same lease with the same timestamp are only returned once.
+        for event in &[&events[0], &events[1], &events[0], &events[2]] {
+            spool_event(&path, event, MAX_SPOOLED_EVENTS).unwrap();
+        }
+        assert_eq!(keys(&take_spooled_events(&path).unwrap()), keys(&events[..3]));
+    }
+}
@@ -0,0 +1,492 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::File,
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use chrono::{DateTime, Local, TimeZone};
+use namib_shared::{
+    macaddr,
+    models::{
+        DhcpEvent, DhcpLeaseInformation, DhcpLeaseVersionSpecificInformation, DhcpV4LeaseVersionSpecificInformation,
+        DhcpV6LeaseVersionSpecificInformation, Duid, LeaseExpiryTime,
+    },
+};
+use tokio::{sync::Notify, time::sleep};
+
+use crate::{
+    dhcp::{lease_client, leased_addr},
+    error::Error,
+    services,
+};
+
+// Reconciliation of the leases known to the enforcer with the lease file of dnsmasq, which contains one lease per
+// line. DHCPv4 leases are written as
+//     <expiry> <MAC address> <IPv4 address> <hostname or *> <client ID or *>
+// DHCPv6 leases follow a line `duid <server DUID>` and are written as
+//     <expiry> <IAID> <IPv6 address> <hostname or *> <client DUID>
+// The expiry is a UNIX timestamp, or 0 for leases that never expire. dnsmasq builds without a working real-time clock
+// (HAVE_BROKEN_RTC) write the lease length instead, which is also what their DHCP events report.
+
+/// Name of the file the known leases are persisted to, which is located in the directory of the config state file.
+pub const KNOWN_LEASES_STATE_FILE_NAME: &str = "dhcp_leases.json";
+
+/// Lease length reported for leases that never expire (the infinite lease time of DHCP).
+const INFINITE_LEASE_LENGTH: Duration = Duration::from_secs(0xffff_ffff);
+
+/// Expiries in the lease file up to this value are lease lengths, as they would be timestamps before 2001.
+const LEASE_LENGTH_LIMIT: i64 = 1_000_000_000;
+
+/// Returns the location of the lease file of dnsmasq.
+pub fn lease_file_path() -> &'static Path {
+    if services::is_system_mode() {
+        "/tmp/dhcp.leases".as_ref()
+    } else {
+        "dhcp.leases".as_ref()
+    }
+}
+
+/// Lease read from the lease file of dnsmasq.
+#[derive(Debug, Clone)]
+struct LeaseFileEntry {
+    version_specific_information: DhcpLeaseVersionSpecificInformation,
+    lease_expiry: LeaseExpiryTime,
+    mac_address: Option<String>,
+    hostname: Option<String>,
+}
+
+impl LeaseFileEntry {
+    /// Returns the leased address.
+    fn addr(&self) -> IpAddr {
+        leased_addr(&self.version_specific_information)
+    }
+
+    /// Returns the information of this lease, based on the given information of the lease known from DHCP events
+    /// (which includes information missing from the lease file, e.g. the MUD URL).
+    fn lease_info(
+        &self,
+        known_lease_info: Option<&DhcpLeaseInformation>,
+        now: DateTime<Local>,
+    ) -> DhcpLeaseInformation {
+        let lease_expiry = self.lease_expiry.clone();
+        let time_remaining = match &lease_expiry {
+            LeaseExpiryTime::LeaseLength(length) => *length,
+            LeaseExpiryTime::LeaseExpiryTime(expiry) => {
+                Duration::from_secs((expiry.timestamp() - now.timestamp()).try_into().unwrap_or_default())
+            },
+        };
+        let hostname = self
+            .hostname
+            .clone()
+            .or_else(|| known_lease_info.and_then(|lease_info| lease_info.hostname.clone()));
+        match known_lease_info {
+            Some(known_lease_info) => DhcpLeaseInformation {
+                version_specific_information: self.version_specific_information.clone(),
+                lease_expiry,
+                time_remaining,
+                hostname,
+                ..known_lease_info.clone()
+            },
+            None => DhcpLeaseInformation {
+                version_specific_information: self.version_specific_information.clone(),
+                domain: None,
+                client_provided_hostname: None,
+                old_hostname: None,
+                user_classes: Vec::new(),
+                lease_expiry,
+                time_remaining,
+                receiver_interface: None,
+                mac_address: self
+                    .mac_address
+                    .as_ref()
+                    .and_then(|mac_address| mac_address.parse::<macaddr::MacAddr>().ok())
+                    .map(Into::into),
+                mud_url: None,
+                tags: Vec::new(),
+                hostname,
+            },
+        }
+    }
+}
+
+/// Parses the given DUID, written as hexadecimal octets separated by colons.
+fn parse_duid(duid: &str) -> Option<Duid> {
+    let duid = hex::decode(duid.replace(":", "")).ok()?;
+    if duid.len() < 3 {
+        return None;
+    }
+    Some(match &duid[0..2] {
+        [0x0, 0x1] => Duid::Llt(Vec::from(&duid[2..])),
+        [0x0, 0x2] => Duid::En(Vec::from(&duid[2..])),
+        [0x0, 0x3] => Duid::Ll(Vec::from(&duid[2..])),
+        [0x0, 0x4] => Duid::Uuid(Vec::from(&duid[2..])),
+        t => Duid::Other(t.try_into().unwrap(), Vec::from(&duid[2..])),
+    })
+}
+
+/// Parses the given content of a lease file. Lines that cannot be parsed are skipped.
+fn parse_lease_file(content: &str) -> Vec<LeaseFileEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // The line of the server DUID has only two fields.
+            if fields.len() < 5 {
+                return None;
+            }
+            let lease_expiry = match fields[0].parse::<i64>().ok()? {
+                0 => LeaseExpiryTime::LeaseLength(INFINITE_LEASE_LENGTH),
+                length @ 1..=LEASE_LENGTH_LIMIT => LeaseExpiryTime::LeaseLength(Duration::from_secs(length as u64)),
+                expiry => LeaseExpiryTime::LeaseExpiryTime(Local.timestamp_opt(expiry, 0).single()?.into()),
+            };
+            let hostname = Some(fields[3]).filter(|&hostname| hostname != "*").map(String::from);
+            let (version_specific_information, mac_address) = match fields[2].parse().ok()? {
+                IpAddr::V4(ip_addr) => (
+                    DhcpLeaseVersionSpecificInformation::V4(DhcpV4LeaseVersionSpecificInformation { ip_addr }),
+                    Some(fields[1].to_string()),
+                ),
+                IpAddr::V6(ip_addr) => (
+                    DhcpLeaseVersionSpecificInformation::V6(DhcpV6LeaseVersionSpecificInformation {
+                        ip_addr,
+                        duid: parse_duid(fields[4])?,
+                    }),
+                    None,
+                ),
+            };
+            Some(LeaseFileEntry {
+                version_specific_information,
+                lease_expiry,
+                mac_address,
+                hostname,
+            })
+        })
+        .collect()
+}
+
+/// Returns the given lease expiry as it is written to the lease file: the UNIX timestamp of the expiry or the lease
+/// length, 0 if the lease never expires.
+fn lease_file_expiry(lease_expiry: &LeaseExpiryTime) -> i64 {
+    match lease_expiry {
+        LeaseExpiryTime::LeaseExpiryTime(expiry) => expiry.timestamp(),
+        LeaseExpiryTime::LeaseLength(length) if *length == INFINITE_LEASE_LENGTH => 0,
+        LeaseExpiryTime::LeaseLength(length) => length.as_secs().try_into().unwrap_or(i64::MAX),
+    }
+}
+
+/// Leases known from the DHCP events, which are persisted so that the leases that changed while the enforcer was not
+/// running can be determined at startup.
+#[derive(Debug)]
+pub struct KnownLeases {
+    /// File the known leases are persisted to.
+    path: PathBuf,
+    /// Known leases by the leased address.
+    leases: Mutex<HashMap<IpAddr, DhcpLeaseInformation>>,
+    /// Notifies the persister task of changes that were not persisted yet.
+    changed: Notify,
+}
+
+impl KnownLeases {
+    /// Creates the known leases persisted to the given file, containing the leases persisted by the last run (if any).
+    pub fn load(path: PathBuf) -> KnownLeases {
+        let leases: Vec<DhcpLeaseInformation> = match File::open(&path)
+            .map_err(Error::from)
+            .and_then(|file| Ok(serde_json::from_reader(file)?))
+        {
+            Ok(leases) => leases,
+            Err(Error::IoError { source, .. }) if source.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                warn!("Error while reading persisted DHCP leases: {:?}", e);
+                Vec::new()
+            },
+        };
+        KnownLeases {
+            path,
+            leases: Mutex::new(
+                leases
+                    .into_iter()
+                    .map(|lease_info| (leased_addr(&lease_info.version_specific_information), lease_info))
+                    .collect(),
+            ),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Updates the known leases with the given DHCP event.
+    pub fn observe_dhcp_event(&self, event: &DhcpEvent) {
+        let mut leases = self.leases.lock().unwrap();
+        match event {
+            DhcpEvent::LeaseAdded { lease_info, .. } | DhcpEvent::ExistingLeaseUpdate { lease_info, .. } => {
+                leases.insert(
+                    leased_addr(&lease_info.version_specific_information),
+                    lease_info.clone(),
+                );
+            },
+            DhcpEvent::LeaseDestroyed { lease_info, .. } => {
+                leases.remove(&leased_addr(&lease_info.version_specific_information));
+            },
+        }
+        self.changed.notify_one();
+    }
+
+    /// Persists the known leases to the state file.
+    fn persist(&self) {
+        let leases = self.leases.lock().unwrap();
+        let leases: Vec<&DhcpLeaseInformation> = leases.values().collect();
+        if let Err(e) = crate::write_state_file(&self.path, &leases) {
+            warn!("Error while persisting DHCP leases: {:?}", e);
+        }
+    }
+
+    /// Task which persists the known leases once they changed, writing all changes within `STATE_FILE_WRITE_DELAY` at
+    /// once.
+    pub async fn persister_task(&self) {
+        loop {
+            self.changed.notified().await;
+            sleep(crate::STATE_FILE_WRITE_DELAY).await;
+            self.persist();
+        }
+    }
+
+    /// Returns the synthetic DHCP events which reconcile the known leases with the lease file at the given path:
+    /// `ExistingLeaseUpdate` for leases that are unknown or whose expiry changed and `LeaseDestroyed` for known leases
+    /// missing from the lease file. If an address was leased to another client in the meantime, the known lease is
+    /// destroyed before the lease of the new client is updated.
+    pub fn reconcile(&self, lease_file_path: &Path) -> io::Result<Vec<DhcpEvent>> {
+        let content = match std::fs::read_to_string(lease_file_path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(self.reconcile_entries(&parse_lease_file(&content), Local::now()))
+    }
+
+    fn reconcile_entries(&self, entries: &[LeaseFileEntry], now: DateTime<Local>) -> Vec<DhcpEvent> {
+        let leases = self.leases.lock().unwrap();
+        let mut events = Vec::new();
+        for entry in entries {
+            let mut known_lease_info = leases.get(&entry.addr());
+            if let Some(reassigned_lease_info) = known_lease_info
+                .filter(|known_lease_info| lease_client(known_lease_info) != lease_client(&entry.lease_info(None, now)))
+            {
+                events.push(DhcpEvent::LeaseDestroyed {
+                    event_timestamp: now.into(),
+                    lease_info: reassigned_lease_info.clone(),
+                });
+                known_lease_info = None;
+            }
+            if known_lease_info.map_or(true, |known_lease_info| {
+                lease_file_expiry(&known_lease_info.lease_expiry) != lease_file_expiry(&entry.lease_expiry)
+            }) {
+                events.push(DhcpEvent::ExistingLeaseUpdate {
+                    event_timestamp: now.into(),
+                    lease_info: entry.lease_info(known_lease_info, now),
+                });
+            }
+        }
+        let mut destroyed_leases: Vec<(&IpAddr, &DhcpLeaseInformation)> = leases
+            .iter()
+            .filter(|(addr, _)| !entries.iter().any(|entry| entry.addr() == **addr))
+            .collect();
+        destroyed_leases.sort_by_key(|(addr, _)| **addr);
+        events.extend(
+            destroyed_leases
+                .into_iter()
+                .map(|(_, lease_info)| DhcpEvent::LeaseDestroyed {
+                    event_timestamp: now.into(),
+                    lease_info: lease_info.clone(),
+                }),
+        );
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    const LEASE_FILE: &str = "\
+1700003600 aa:bb:cc:dd:ee:01 192.168.1.15 camera *
+0 aa:bb:cc:dd:ee:02 192.168.1.16 * 01:aa:bb:cc:dd:ee:02
+duid 00:01:00:01:2a:3b:4c:5d:aa:bb:cc:dd:ee:ff
+1700007200 12345678 fd00::15 camera 00:03:00:01:aa:bb:cc:dd:ee:01
+invalid line
+99999999999999999 aa:bb:cc:dd:ee:04 192.168.1.18 * *
+";
+
+    fn known_leases(test_name: &str, entries: &[LeaseFileEntry], mud_url: &str) -> KnownLeases {
+        let path = env::temp_dir().join(format!("namib_dhcp_leases_{}_{}.json", test_name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let known_leases = KnownLeases::load(path);
+        let now = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        for entry in entries {
+            let mut lease_info = entry.lease_info(None, now);
+            lease_info.mud_url = Some(String::from(mud_url));
+            known_leases.observe_dhcp_event(&DhcpEvent::LeaseAdded {
+                event_timestamp: now.into(),
+                lease_info,
+            });
+        }
+        known_leases
+    }
+
+    fn summary(events: &[DhcpEvent]) -> Vec<(&'static str, IpAddr, Option<String>)> {
+        events
+            .iter()
+            .map(|event| match event {
+                DhcpEvent::LeaseAdded { lease_info, .. } => (
+                    "add",
+                    leased_addr(&lease_info.version_specific_information),
+                    lease_info.mud_url.clone(),
+                ),
+                DhcpEvent::LeaseDestroyed { lease_info, .. } => (
+                    "del",
+                    leased_addr(&lease_info.version_specific_information),
+                    lease_info.mud_url.clone(),
+                ),
+                DhcpEvent::ExistingLeaseUpdate { lease_info, .. } => (
+                    "old",
+                    leased_addr(&lease_info.version_specific_information),
+                    lease_info.mud_url.clone(),
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_lease_file() {
+        let entries = parse_lease_file(LEASE_FILE);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(lease_file_expiry(&entries[0].lease_expiry), 1_700_003_600);
+        assert_eq!(entries[0].mac_address.as_deref(), Some("aa:bb:cc:dd:ee:01"));
+        assert_eq!(entries[0].hostname.as_deref(), Some("camera"));
+        assert_eq!(lease_file_expiry(&entries[1].lease_expiry), 0);
+        assert_eq!(entries[1].hostname, None);
+        assert_eq!(entries[2].mac_address, None);
+        assert!(matches!(
+            &entries[2].version_specific_information,
+            DhcpLeaseVersionSpecificInformation::V6(v6) if matches!(&v6.duid, Duid::Ll(id) if id.len() == 8)
+        ));
+
+        let now = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        let lease_info = entries[0].lease_info(None, now);
+        assert_eq!(lease_file_expiry(&lease_info.lease_expiry), 1_700_003_600);
+        assert_eq!(lease_info.time_remaining, Duration::from_secs(3600));
+        assert_eq!(lease_file_expiry(&entries[1].lease_info(None, now).lease_expiry), 0);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let entries = parse_lease_file(LEASE_FILE);
+        let now = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+
+        // Unchanged leases do not result in events.
+        let known_leases = known_leases("reconcile", &entries, "https://example.com/camera.json");
+        assert!(known_leases.reconcile_entries(&entries, now).is_empty());
+
+        // Leases missing from the lease file were destroyed, new leases and leases with a changed expiry are updated,
+        // keeping the information of the known leases.
+        let mut current_entries = vec![entries[0].clone(), entries[2].clone()];
+        current_entries[1].lease_expiry =
+            LeaseExpiryTime::LeaseExpiryTime(Local.timestamp_opt(1_700_010_800, 0).unwrap().into());
+        current_entries.push(LeaseFileEntry {
+            version_specific_information: DhcpLeaseVersionSpecificInformation::V4(
+                DhcpV4LeaseVersionSpecificInformation {
+                    ip_addr: "192.168.1.17".parse().unwrap(),
+                },
+            ),
+            lease_expiry: LeaseExpiryTime::LeaseExpiryTime(Local.timestamp_opt(1_700_003_600, 0).unwrap().into()),
+            mac_address: Some(String::from("aa:bb:cc:dd:ee:03")),
+            hostname: None,
+        });
+        let events = known_leases.reconcile_entries(&current_entries, now);
+        let mud_url = Some(String::from("https://example.com/camera.json"));
+        assert_eq!(
+            summary(&events),
+            vec![
+                ("old", "fd00::15".parse().unwrap(), mud_url.clone()),
+                ("old", "192.168.1.17".parse().unwrap(), None),
+                ("del", "192.168.1.16".parse().unwrap(), mud_url),
+            ]
+        );
+
+        // Once the events are applied, the known leases match the lease file.
+        for event in &events {
+            known_leases.observe_dhcp_event(event);
+        }
+        assert!(known_leases.reconcile_entries(&current_entries, now).is_empty());
+    }
+
+    #[test]
+    fn test_reconcile_reassigned_address() {
+        let entries = parse_lease_file(LEASE_FILE);
+        let now = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        let known_leases = known_leases("reassigned", &entries, "https://example.com/camera.json");
+
+        // The address of the first lease was leased to another client, whose lease does not include any information of
+        // the known lease.
+        let mut current_entries = entries.clone();
+        current_entries[0].mac_address = Some(String::from("aa:bb:cc:dd:ee:05"));
+        let events = known_leases.reconcile_entries(&current_entries, now);
+        assert_eq!(
+            summary(&events),
+            vec![
+                (
+                    "del",
+                    "192.168.1.15".parse().unwrap(),
+                    Some(String::from("https://example.com/camera.json"))
+                ),
+                ("old", "192.168.1.15".parse().unwrap(), None),
+            ]
+        );
+        match &events[1] {
+            DhcpEvent::ExistingLeaseUpdate { lease_info, .. } => assert_eq!(
+                lease_client(lease_info),
+                lease_client(&current_entries[0].lease_info(None, now))
+            ),
+            event => panic!("unexpected event: {:?}", event),
+        }
+
+        for event in &events {
+            known_leases.observe_dhcp_event(event);
+        }
+        assert!(known_leases.reconcile_entries(&current_entries, now).is_empty());
+    }
+
+    #[test]
+    fn test_reconcile_lease_lengths() {
+        // dnsmasq builds with HAVE_BROKEN_RTC write and report lease lengths instead of expiry times.
+        let entries = parse_lease_file("43200 aa:bb:cc:dd:ee:01 192.168.1.15 camera *\n");
+        let now = Local.timestamp_opt(1_700_000_000, 0).unwrap();
+        assert!(matches!(
+            entries[0].lease_expiry,
+            LeaseExpiryTime::LeaseLength(length) if length == Duration::from_secs(43200)
+        ));
+        assert_eq!(
+            entries[0].lease_info(None, now).time_remaining,
+            Duration::from_secs(43200)
+        );
+
+        // Unchanged leases do not result in events, leases with a changed length are updated.
+        let known_leases = known_leases("lease_lengths", &entries, "https://example.com/camera.json");
+        assert!(known_leases.reconcile_entries(&entries, now).is_empty());
+        let current_entries = parse_lease_file("86400 aa:bb:cc:dd:ee:01 192.168.1.15 camera *\n");
+        assert_eq!(
+            summary(&known_leases.reconcile_entries(&current_entries, now)),
+            vec![(
+                "old",
+                "192.168.1.15".parse().unwrap(),
+                Some(String::from("https://example.com/camera.json"))
+            )]
+        );
+    }
+}
@@ -1,4 +1,29 @@
 // Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
+use std::net::IpAddr;
+
+use namib_shared::models::{DhcpLeaseInformation, DhcpLeaseVersionSpecificInformation};
+
 pub mod dhcp_event_listener;
+pub mod event_queue;
+pub mod event_spool;
+pub mod lease_file;
+
+/// Returns the address leased by the DHCP lease with the given version-specific information.
+pub fn leased_addr(version_specific_information: &DhcpLeaseVersionSpecificInformation) -> IpAddr {
+    match version_specific_information {
+        DhcpLeaseVersionSpecificInformation::V4(v4) => v4.ip_addr.into(),
+        DhcpLeaseVersionSpecificInformation::V6(v6) => v6.ip_addr.into(),
+    }
+}
+
+/// Returns the identifier of the client holding the given lease, i.e. its MAC address for DHCPv4 leases and its DUID
+/// for DHCPv6 leases. The identifier is serialized, so that the identifiers of different leases can be compared.
+pub fn lease_client(lease_info: &DhcpLeaseInformation) -> String {
+    match &lease_info.version_specific_information {
+        DhcpLeaseVersionSpecificInformation::V4(_) => serde_json::to_string(&lease_info.mac_address),
+        DhcpLeaseVersionSpecificInformation::V6(v6) => serde_json::to_string(&v6.duid),
+    }
+    .unwrap_or_default()
+}
@@ -58,6 +58,107 @@ pub enum Error {
         source: std::ffi::NulError,
         backtrace: Backtrace,
     },
+    #[snafu(display("NetlinkError: {} (errno {})", message, errno), visibility(pub))]
+    NetlinkError {
+        message: &'static str,
+        errno: i32,
+        backtrace: Backtrace,
+    },
+    #[snafu(
+        display(
+            "MissingCapabilityError: Changing the {} was not permitted (errno {}), the enforcer requires the CAP_NET_ADMIN capability (either run as root or use `setcap 'cap_net_admin=+ep'` on the binary)",
+            object,
+            errno
+        ),
+        visibility(pub)
+    )]
+    MissingCapabilityError {
+        object: String,
+        errno: i32,
+        backtrace: Backtrace,
+    },
+    #[snafu(
+        display(
+            "MissingKernelModuleError: The {} requires a kernel module that is not available (errno {}){}",
+            object,
+            errno,
+            kernel_message
+        ),
+        visibility(pub)
+    )]
+    MissingKernelModuleError {
+        object: String,
+        errno: i32,
+        kernel_message: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(
+        display(
+            "UnsupportedExpressionError: The {} uses an expression or option the kernel does not support (errno {}){}",
+            object,
+            errno,
+            kernel_message
+        ),
+        visibility(pub)
+    )]
+    UnsupportedExpressionError {
+        object: String,
+        errno: i32,
+        kernel_message: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(
+        display(
+            "TableBusyError: The {} could not be changed because it is in use, e.g. by a concurrent ruleset change (errno {}){}",
+            object,
+            errno,
+            kernel_message
+        ),
+        visibility(pub)
+    )]
+    TableBusyError {
+        object: String,
+        errno: i32,
+        kernel_message: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(
+        display(
+            "NftablesBatchError: The kernel rejected the {} (errno {}){}",
+            object,
+            errno,
+            kernel_message
+        ),
+        visibility(pub)
+    )]
+    NftablesBatchError {
+        object: String,
+        errno: i32,
+        kernel_message: String,
+        backtrace: Backtrace,
+    },
+    #[snafu(
+        display(
+            "SelfTestError: The kernel or the enforcer's privileges lack capabilities required by the configuration: {}",
+            capabilities
+        ),
+        visibility(pub)
+    )]
+    SelfTestError { capabilities: String, backtrace: Backtrace },
+    #[snafu(
+        display("SettingsError: Unable to parse the enforcer settings at \"{}\": {}", path, source),
+        visibility(pub)
+    )]
+    SettingsError {
+        path: String,
+        source: serde_json::Error,
+        backtrace: Backtrace,
+    },
+    #[snafu(display("CoexistenceError: {}", message), visibility(pub))]
+    CoexistenceError {
+        message: &'static str,
+        backtrace: Backtrace,
+    },
     #[snafu(display("NoneError"), visibility(pub))]
     NoneError { backtrace: Backtrace },
     #[snafu(display("SerdeError {}", source), context(false))]
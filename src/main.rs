@@ -7,25 +7,44 @@
 #[macro_use]
 extern crate log;
 
-use std::{env, fs::File, net::SocketAddr, path::Path, sync::Arc, thread};
+use std::{
+    collections::HashSet,
+    env,
+    fs::File,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
 
 use dotenv::dotenv;
 use error::{Error, Result};
 use namib_shared::{rpc::NamibRpcClient, EnforcerConfig};
-use tokio::{fs, fs::OpenOptions, sync::RwLock};
+use serde::Serialize;
+use tokio::{
+    fs,
+    fs::OpenOptions,
+    sync::{mpsc, RwLock},
+};
 
 use crate::{
+    dhcp::event_queue::DhcpEventQueue,
     rpc::rpc_client::current_rpc_context,
     services::{
+        abstractions::AbstractionResolver,
         controller_name::apply_secure_name_config,
+        events::EventReporter,
         firewall_service::{apply_firewall_config_inner, FirewallService},
     },
+    settings::Settings,
 };
 
 mod dhcp;
 mod error;
 mod rpc;
 mod services;
+mod settings;
 mod uci;
 
 /// Default location for the file containing the last received enforcer configuration.
@@ -56,21 +75,56 @@ async fn persist_config(config: &EnforcerConfig) {
             .await
             .unwrap_or_else(|e| warn!("Error while creating config state parent directory: {:?}", e));
     };
-    if let Err(e) = File::create(config_state_path)
-        .map_err(Error::from)
-        .and_then(|file| Ok(serde_json::to_writer(&file, &config)?))
-    {
+    if let Err(e) = write_state_file(config_state_path, config) {
         warn!("Error while persisting config state: {:?}", e);
         return;
     }
     debug!("Persisted configuration at path \"{}\"", config_state_path.display());
 }
 
+/// Returns the location of the state file with the given name, which is located in the directory of the config state
+/// file (as specified by the `NAMIB_CONFIG_STATE_FILE` environment variable).
+pub(crate) fn state_file_path(name: &str) -> PathBuf {
+    let config_state_path =
+        env::var("NAMIB_CONFIG_STATE_FILE").unwrap_or_else(|_| String::from(DEFAULT_CONFIG_STATE_FILE));
+    Path::new(&config_state_path).with_file_name(name)
+}
+
+/// Delay before the changes of the state files that change with each DHCP event are written, so that all changes within
+/// the delay are written at once instead of writing to the (flash) storage for each change.
+pub(crate) const STATE_FILE_WRITE_DELAY: Duration = Duration::from_secs(10);
+
+/// Writes the given value to the state file at the given path as JSON. The file is replaced instead of written in
+/// place, so that it is never left incomplete if the enforcer is stopped while writing it.
+pub(crate) fn write_state_file<T: Serialize+?Sized>(path: &Path, value: &T) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path)?;
+    serde_json::to_writer(&file, value)?;
+    file.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+    Ok(())
+}
+
 #[tokio::main(flavor = "multi_thread", worker_threads = 2)]
 async fn main() -> Result<()> {
     dotenv().ok();
     env_logger::init();
 
+    // `namib_enforcer self-test` only reports the capabilities of the kernel and the enforcer's privileges.
+    if env::args().nth(1).as_deref() == Some("self-test") {
+        let settings = Settings::load()?;
+        let capabilities = services::self_test::run(&settings);
+        print!("{}", capabilities);
+        return capabilities.check(&settings);
+    }
+
+    // `namib_enforcer status` reports the state persisted by the (running) enforcer.
+    if env::args().nth(1).as_deref() == Some("status") {
+        let dhcp_events = DhcpEventQueue::load(state_file_path(dhcp::event_queue::DHCP_EVENT_QUEUE_STATE_FILE_NAME));
+        println!("Queued DHCP events: {}", dhcp_events.len());
+        return Ok(());
+    }
+
     info!(
         "Starting in {} mode",
         if services::is_system_mode() { "SYSTEM" } else { "USER" }
@@ -81,6 +135,14 @@ async fn main() -> Result<()> {
         OpenOptions::new().write(true).create(true).open("config/dhcp").await?;
     }
 
+    // Test the capabilities of the kernel before contacting the controller, so that missing capabilities are reported
+    // right away instead of failing once the first configuration is applied.
+    let mut settings = Settings::load()?;
+    let capabilities = services::self_test::run(&settings);
+    info!("Kernel capabilities:\n{}", capabilities);
+    capabilities.apply_to(&mut settings)?;
+    let settings = Arc::new(settings);
+
     // Attempt to read last persisted enforcer state.
     info!("Reading last saved enforcer state");
     let config_state_path =
@@ -121,12 +183,31 @@ async fn main() -> Result<()> {
     };
 
     // Instantiate DNS resolver service.
-    let mut dns_service = services::dns::DnsService::new().unwrap();
+    let mut dns_service = services::dns::DnsService::new(&settings.dns).unwrap();
+    // Restore the DNS cache of the last run, so that the rules for hostnames are installed without waiting for their
+    // resolution. The restored entries are revalidated in the background.
+    dns_service
+        .restore_from(state_file_path(services::dns::DNS_CACHE_STATE_FILE_NAME))
+        .await;
 
     // Instantiate firewall service with DNS watcher.
     let watcher = dns_service.create_watcher();
 
-    apply_firewall_config_inner(&config, &watcher).await?;
+    // Resolves the MUD abstractions used by rules (e.g. `local-networks`) from local knowledge.
+    let abstractions = Arc::new(AbstractionResolver::new(settings.clone()));
+
+    // Make sure that the rules are not bypassed by other firewall tables (e.g. the fw4 table of OpenWrt).
+    let coexistence_issues = services::coexistence::check_at_startup(&settings)?;
+
+    // Blocks the domains a device is not allowed to reach using additional dnsmasq instances, which are started before
+    // the DNS queries of the devices are redirected to them.
+    let dns_views = Arc::new(services::dns_blocking::DnsViewInstances::new(&settings.dns.blocking));
+    dns_views
+        .apply(&config)
+        .await
+        .unwrap_or_else(|e| warn!("Unable to start the DNS views blocking domains: {:?}", e));
+
+    apply_firewall_config_inner(&config, &settings, &HashSet::new(), &watcher, &abstractions).await?;
 
     // If the RPC client was not already retrieved while getting the initial config, get it now.
     let enforcer = match connected_enforcer {
@@ -144,16 +225,86 @@ async fn main() -> Result<()> {
     }
 
     // Create the firewall service
-    let fw_service = Arc::new(FirewallService::new(enforcer.clone(), watcher));
+    let fw_service = Arc::new(FirewallService::new(
+        enforcer.clone(),
+        settings.clone(),
+        watcher,
+        abstractions.clone(),
+        dns_views.clone(),
+    ));
+
+    // Start reporting events (e.g. exceeded limits) detected by the firewall to the controller.
+    let (event_reporter, event_receiver) = EventReporter::new();
+    let event_task = tokio::spawn(services::events::event_reporter_task(enforcer.clone(), event_receiver));
+    let (logged_packet_sender, logged_packet_receiver) = mpsc::channel(services::nflog::LOGGED_PACKET_QUEUE_SIZE);
+    let coexistence_task = tokio::spawn(services::coexistence::coexistence_watcher(
+        fw_service.clone(),
+        settings.clone(),
+        event_reporter.clone(),
+        coexistence_issues,
+    ));
+    let nflog_task = tokio::spawn(services::nflog::logged_packet_dispatcher(
+        logged_packet_receiver,
+        event_reporter.clone(),
+        fw_service.clone(),
+        settings.clone(),
+    ));
+    let nflog_group = settings.nflog_group;
+    let _nflog_listener = thread::spawn(move || services::nflog::listen(nflog_group, &logged_packet_sender));
 
-    let heartbeat_task = tokio::spawn(rpc::rpc_client::heartbeat(enforcer.clone(), fw_service.clone()));
-    let dhcp_event_task = tokio::spawn(dhcp::dhcp_event_listener::listen_for_dhcp_events(enforcer.clone()));
-    let dns_task = tokio::spawn(async move { dns_service.auto_refresher_task().await });
+    // DHCP events are queued until they were transmitted to the controller, including the events of the last run.
+    let dhcp_events = Arc::new(DhcpEventQueue::load(state_file_path(
+        dhcp::event_queue::DHCP_EVENT_QUEUE_STATE_FILE_NAME,
+    )));
+    let heartbeat_task = tokio::spawn(rpc::rpc_client::heartbeat(
+        enforcer.clone(),
+        fw_service.clone(),
+        dhcp_events.clone(),
+    ));
+    let dhcp_event_task = tokio::spawn(dhcp::dhcp_event_listener::listen_for_dhcp_events(
+        dhcp_events.clone(),
+        abstractions.clone(),
+    ));
+    let dhcp_persister_task = tokio::spawn({
+        let dhcp_events = dhcp_events.clone();
+        async move { dhcp_events.persister_task().await }
+    });
+    let dhcp_sender_task = tokio::spawn({
+        let enforcer = enforcer.clone();
+        async move { dhcp_events.sender_task(enforcer).await }
+    });
+    let abstraction_set_task = tokio::spawn({
+        let abstractions = abstractions.clone();
+        async move { abstractions.set_update_watcher().await }
+    });
+    let controller_discovery_task = tokio::spawn(async move { abstractions.controller_discovery().await });
+    let dns_view_task = tokio::spawn(async move { dns_views.supervisor_task().await });
+    let device_view = dns_service.device_view();
+    let blocked_query_reporter =
+        services::dns_blocking::BlockedQueryReporter::new(&settings.dns.blocking, event_reporter.clone());
+    let dns_task = tokio::spawn(async move { dns_service.auto_refresher_task(event_reporter).await });
+    let schedule_task = tokio::spawn(services::schedule::schedule_watcher(fw_service.clone(), settings));
     let firewall_task = tokio::spawn(async move { fw_service.firewall_change_watcher().await });
     let np0f_log_task = tokio::spawn(services::log_watcher::watch_np0f(enforcer.clone()));
 
-    let _log_watcher = thread::spawn(move || services::log_watcher::watch(&enforcer));
+    let _log_watcher =
+        thread::spawn(move || services::log_watcher::watch(&enforcer, device_view, blocked_query_reporter));
 
-    tokio::try_join!(heartbeat_task, dhcp_event_task, dns_task, firewall_task, np0f_log_task)?;
+    tokio::try_join!(
+        heartbeat_task,
+        dhcp_event_task,
+        dhcp_sender_task,
+        dhcp_persister_task,
+        dns_task,
+        firewall_task,
+        np0f_log_task,
+        schedule_task,
+        event_task,
+        nflog_task,
+        coexistence_task,
+        abstraction_set_task,
+        controller_discovery_task,
+        dns_view_task
+    )?;
     Ok(())
 }
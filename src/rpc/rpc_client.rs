@@ -26,6 +26,7 @@ use tokio_util::codec::LengthDelimitedCodec;
 
 use super::controller_discovery::discover_controllers;
 use crate::{
+    dhcp::event_queue::DhcpEventQueue,
     error::Result,
     services::{controller_name::apply_secure_name_config, firewall_service::FirewallService},
     Enforcer,
@@ -64,7 +65,11 @@ pub async fn run() -> Result<(NamibRpcClient, SocketAddr)> {
     }
 }
 
-pub async fn heartbeat(enforcer: Arc<RwLock<Enforcer>>, fw_service: Arc<FirewallService>) {
+pub async fn heartbeat(
+    enforcer: Arc<RwLock<Enforcer>>,
+    fw_service: Arc<FirewallService>,
+    dhcp_events: Arc<DhcpEventQueue>,
+) {
     loop {
         {
             let enf = enforcer.read().await;
@@ -79,6 +84,7 @@ pub async fn heartbeat(enforcer: Arc<RwLock<Enforcer>>, fw_service: Arc<Firewall
                             let mut enf = enforcer.write().await;
                             enf.client = new_client;
                             enf.addr = addr;
+                            dhcp_events.notify_reconnected();
                         }
                     },
                     _ => {
@@ -97,7 +103,7 @@ pub async fn heartbeat(enforcer: Arc<RwLock<Enforcer>>, fw_service: Arc<Firewall
                     enforcer.write().await.apply_new_config(config).await;
                     fw_service.notify_firewall_change();
                 },
-                Ok(None) => debug!("Heartbeat OK!"),
+                Ok(None) => debug!("Heartbeat OK! ({} DHCP events queued)", dhcp_events.len()),
             }
         }
 
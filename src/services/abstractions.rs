@@ -0,0 +1,627 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{future, StreamExt};
+use namib_shared::models::DhcpEvent;
+use tokio::{sync::Notify, time::sleep};
+
+#[cfg(feature = "nftables")]
+pub use self::nftables::*;
+use crate::{
+    dhcp::leased_addr,
+    rpc::controller_discovery::discover_controllers,
+    services::zones::{open_uci, ZoneMap},
+    settings::{AbstractionSettings, MudAbstraction, Settings},
+    uci::Uci,
+};
+
+/// Interval in which the controllers announced via DNS-SD are discovered again.
+const CONTROLLER_DISCOVERY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Link-local IPv6 addresses, which are always part of the local networks.
+const IPV6_LINK_LOCAL: IpPrefix = IpPrefix {
+    addr: IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)),
+    len: 10,
+};
+
+/// An IPv4 or IPv6 address prefix, e.g. `192.168.1.0/24`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpPrefix {
+    pub addr: IpAddr,
+    pub len: u8,
+}
+
+impl IpPrefix {
+    /// Returns the prefix which only contains the given address.
+    pub fn host(addr: IpAddr) -> IpPrefix {
+        IpPrefix {
+            addr,
+            len: max_prefix_len(addr),
+        }
+    }
+
+    /// Parses a prefix in CIDR notation (e.g. `fd00::/64`) or a single address.
+    pub fn parse(value: &str) -> Option<IpPrefix> {
+        let mut parts = value.splitn(2, '/');
+        let addr: IpAddr = parts.next()?.parse().ok()?;
+        let len = match parts.next() {
+            Some(len) => len.parse().ok().filter(|&len| len <= max_prefix_len(addr))?,
+            None => max_prefix_len(addr),
+        };
+        Some(IpPrefix { addr, len })
+    }
+
+    /// Returns the first and the last address of the prefix as integers.
+    fn bounds(&self) -> (u128, u128) {
+        let addr = match self.addr {
+            IpAddr::V4(addr) => u128::from(u32::from(addr)),
+            IpAddr::V6(addr) => u128::from(addr),
+        };
+        let host_bits = u32::from(max_prefix_len(self.addr) - self.len);
+        let host_mask = 1u128.checked_shl(host_bits).map_or(u128::MAX, |bit| bit - 1);
+        (addr & !host_mask, addr | host_mask)
+    }
+}
+
+/// Returns the length of a prefix which only contains the given address.
+fn max_prefix_len(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+/// Range of IPv4 or IPv6 addresses from `start` up to (but not including) `end`, or up to the last address if `end` is
+/// `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRange {
+    pub start: IpAddr,
+    pub end: Option<IpAddr>,
+}
+
+/// Merges the prefixes of the given IP version into a sorted list of address ranges which neither overlap nor touch.
+pub fn address_ranges(prefixes: &[IpPrefix], ipv6: bool) -> Vec<AddressRange> {
+    let mut bounds: Vec<(u128, u128)> = prefixes
+        .iter()
+        .filter(|prefix| prefix.addr.is_ipv6() == ipv6)
+        .map(IpPrefix::bounds)
+        .collect();
+    bounds.sort_unstable();
+    let mut merged: Vec<(u128, u128)> = Vec::with_capacity(bounds.len());
+    for (start, last) in bounds {
+        match merged.last_mut() {
+            Some((_, merged_last)) if start <= merged_last.saturating_add(1) => {
+                *merged_last = (*merged_last).max(last);
+            },
+            _ => merged.push((start, last)),
+        }
+    }
+    let to_addr = |value: u128| {
+        if ipv6 {
+            IpAddr::from(Ipv6Addr::from(value))
+        } else {
+            IpAddr::from(Ipv4Addr::from(value as u32))
+        }
+    };
+    let max = if ipv6 { u128::MAX } else { u128::from(u32::MAX) };
+    merged
+        .into_iter()
+        .map(|(start, last)| AddressRange {
+            start: to_addr(start),
+            end: if last < max { Some(to_addr(last + 1)) } else { None },
+        })
+        .collect()
+}
+
+/// Returns the authority (host and optional port) of the given MUD URL in lowercase, which identifies the
+/// manufacturer of a device (RFC 8520, section 8).
+pub fn mud_url_authority(mud_url: &str) -> Option<String> {
+    let without_scheme = mud_url.splitn(2, "://").nth(1)?;
+    let authority = without_scheme.split(|c| c == '/' || c == '?' || c == '#').next()?;
+    // Remove the user information, if any.
+    let authority = authority.rsplit('@').next()?;
+    if authority.is_empty() {
+        None
+    } else {
+        Some(authority.to_ascii_lowercase())
+    }
+}
+
+/// Addresses an abstraction resolves to, identified independently of the rule it is used in, so that rules using
+/// the same abstraction share a set.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AbstractionKey {
+    LocalNetworks,
+    /// Devices whose MUD URL has the given authority.
+    Manufacturer(String),
+    /// Devices with the same manufacturer as the device with the given addresses.
+    SameManufacturer(Vec<IpAddr>),
+    /// Controllers announced under the given DNS-SD service type.
+    Controller(String),
+    /// Controllers for the MUD URL of the device with the given addresses.
+    MyController(Vec<IpAddr>),
+}
+
+impl AbstractionKey {
+    /// Returns the key of the given abstraction used in a rule of the device with the given addresses.
+    pub fn new(abstraction: &MudAbstraction, device_addrs: Vec<IpAddr>) -> AbstractionKey {
+        match abstraction {
+            MudAbstraction::LocalNetworks => AbstractionKey::LocalNetworks,
+            MudAbstraction::SameManufacturer => AbstractionKey::SameManufacturer(device_addrs),
+            MudAbstraction::Manufacturer { authority } => AbstractionKey::Manufacturer(authority.to_ascii_lowercase()),
+            MudAbstraction::Controller { service_type } => AbstractionKey::Controller(service_type.clone()),
+            MudAbstraction::MyController => AbstractionKey::MyController(device_addrs),
+        }
+    }
+}
+
+/// Hosts known to the enforcer which abstractions are resolved to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct KnownHosts {
+    /// MUD URLs of the devices with active DHCP leases, keyed by the leased address.
+    mud_urls: HashMap<IpAddr, String>,
+    /// Addresses of the controllers, keyed by the DNS-SD service type they are announced under.
+    controllers: HashMap<String, HashSet<IpAddr>>,
+    local_networks: Vec<IpPrefix>,
+}
+
+impl KnownHosts {
+    /// Returns the MUD URL of the device with the given addresses, if known.
+    fn mud_url_of(&self, device_addrs: &[IpAddr]) -> Option<&str> {
+        device_addrs
+            .iter()
+            .find_map(|addr| self.mud_urls.get(addr))
+            .map(String::as_str)
+    }
+
+    /// Returns the addresses of all devices whose MUD URL has the given authority.
+    fn devices_of_manufacturer(&self, authority: &str) -> Vec<IpPrefix> {
+        self.mud_urls
+            .iter()
+            .filter(|(_, mud_url)| mud_url_authority(mud_url).as_deref() == Some(authority))
+            .map(|(addr, _)| IpPrefix::host(*addr))
+            .collect()
+    }
+
+    /// Returns the addresses of the controllers announced under the given service type.
+    fn controllers_of(&self, service_type: &str) -> Vec<IpPrefix> {
+        self.controllers
+            .get(service_type)
+            .map(|addrs| addrs.iter().copied().map(IpPrefix::host).collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the prefixes the abstraction with the given key currently resolves to.
+    fn resolve(&self, key: &AbstractionKey, settings: &AbstractionSettings) -> Vec<IpPrefix> {
+        match key {
+            AbstractionKey::LocalNetworks => self.local_networks.clone(),
+            AbstractionKey::Manufacturer(authority) => self.devices_of_manufacturer(authority),
+            AbstractionKey::SameManufacturer(device_addrs) => self
+                .mud_url_of(device_addrs)
+                .and_then(mud_url_authority)
+                .map(|authority| self.devices_of_manufacturer(&authority))
+                .unwrap_or_default(),
+            AbstractionKey::Controller(service_type) => self.controllers_of(service_type),
+            AbstractionKey::MyController(device_addrs) => self
+                .mud_url_of(device_addrs)
+                .and_then(|mud_url| settings.my_controllers.get(mud_url))
+                .map(|service_type| self.controllers_of(service_type))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Reads the prefixes of the local networks, i.e. of the logical networks of the given zones, from the UCI network
+/// configuration.
+fn local_networks_from_uci(uci: &mut Uci, local_zones: &[String]) -> Vec<IpPrefix> {
+    let zones = ZoneMap::from_uci(uci);
+    let mut prefixes = vec![IPV6_LINK_LOCAL];
+    for zone in local_zones {
+        let networks = match zones.networks(zone) {
+            Some(networks) => networks,
+            None => {
+                warn!("Ignoring unknown local firewall zone {}", zone);
+                continue;
+            },
+        };
+        for network in networks {
+            prefixes.extend(network_prefixes(uci, network));
+        }
+    }
+    prefixes
+}
+
+/// Returns the statically configured prefixes of the given logical network.
+fn network_prefixes(uci: &mut Uci, network: &str) -> Vec<IpPrefix> {
+    let mut prefixes = Vec::new();
+    // IPv4 addresses are either given in CIDR notation or together with a netmask (defaulting to a single address).
+    let netmask_len = uci
+        .get(&format!("network.{}.netmask", network))
+        .ok()
+        .and_then(|netmask| netmask.parse::<Ipv4Addr>().ok())
+        .map(|netmask| u32::from(netmask).count_ones() as u8);
+    if let Ok(addrs) = uci.get(&format!("network.{}.ipaddr", network)) {
+        for addr in addrs.split_whitespace() {
+            match (IpPrefix::parse(addr), netmask_len) {
+                (Some(prefix), Some(len)) if !addr.contains('/') => prefixes.push(IpPrefix { len, ..prefix }),
+                (Some(prefix), _) => prefixes.push(prefix),
+                (None, _) => warn!("Ignoring invalid address {} of network {}", addr, network),
+            }
+        }
+    }
+    if let Ok(addrs) = uci.get(&format!("network.{}.ip6addr", network)) {
+        prefixes.extend(addrs.split_whitespace().filter_map(IpPrefix::parse));
+    }
+    // Networks with an IPv6 assignment get a part of the ULA prefix of the router.
+    if uci.get(&format!("network.{}.ip6assign", network)).is_ok() {
+        if let Ok(ula_prefix) = uci.get("network.globals.ula_prefix") {
+            prefixes.extend(IpPrefix::parse(&ula_prefix));
+        }
+    }
+    prefixes
+}
+
+/// Sets created for the abstractions used by the rules of a firewall configuration. For each abstraction, a set of
+/// IPv4 and a set of IPv6 address ranges is created.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AbstractionSets {
+    keys: Vec<AbstractionKey>,
+}
+
+impl AbstractionSets {
+    /// Returns the name of the set of the given IP version for the given abstraction, adding the sets of the
+    /// abstraction if necessary.
+    pub fn set_name(&mut self, key: AbstractionKey, ipv6: bool) -> String {
+        let index = match self.keys.iter().position(|k| *k == key) {
+            Some(index) => index,
+            None => {
+                self.keys.push(key);
+                self.keys.len() - 1
+            },
+        };
+        abstraction_set_name(index, ipv6)
+    }
+
+    /// Returns the abstractions together with the index used in the names of their sets.
+    pub fn iter(&self) -> impl Iterator<Item=(usize, &AbstractionKey)> {
+        self.keys.iter().enumerate()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+/// Returns the name of the set of the given IP version of the abstraction with the given index.
+fn abstraction_set_name(index: usize, ipv6: bool) -> String {
+    format!("abstraction_{}_{}", index, if ipv6 { "v6" } else { "v4" })
+}
+
+/// Resolves the MUD abstractions used by rules from local knowledge and keeps their sets up to date when devices come
+/// and go (according to DHCP events) or controllers appear and disappear (according to DNS-SD).
+pub struct AbstractionResolver {
+    settings: Arc<Settings>,
+    known_hosts: Mutex<KnownHosts>,
+    /// Sets of the firewall configuration that is currently installed.
+    installed_sets: Mutex<AbstractionSets>,
+    change_notify: Notify,
+}
+
+impl AbstractionResolver {
+    pub fn new(settings: Arc<Settings>) -> AbstractionResolver {
+        let resolver = AbstractionResolver {
+            settings,
+            known_hosts: Mutex::new(KnownHosts::default()),
+            installed_sets: Mutex::new(AbstractionSets::default()),
+            change_notify: Notify::new(),
+        };
+        resolver.reload_local_networks();
+        resolver
+    }
+
+    /// Reads the local networks from the UCI configuration if any rule uses the `local-networks` abstraction.
+    pub fn reload_local_networks(&self) {
+        let settings = &self.settings.abstractions;
+        let uses_local_networks = settings
+            .rules
+            .iter()
+            .any(|r| r.src == Some(MudAbstraction::LocalNetworks) || r.dst == Some(MudAbstraction::LocalNetworks));
+        if !uses_local_networks {
+            return;
+        }
+        let local_networks = match open_uci() {
+            Ok(mut uci) => local_networks_from_uci(&mut uci, &settings.local_zones),
+            Err(e) => {
+                warn!("Unable to read the local networks from UCI: {:?}", e);
+                vec![IPV6_LINK_LOCAL]
+            },
+        };
+        debug!("Local networks: {:?}", local_networks);
+        self.update_known_hosts(|known_hosts| known_hosts.local_networks = local_networks);
+    }
+
+    /// Records the MUD URL of the device a DHCP lease was added, updated or removed for.
+    pub fn observe_dhcp_event(&self, event: &DhcpEvent) {
+        match event {
+            DhcpEvent::LeaseAdded { lease_info, .. } | DhcpEvent::ExistingLeaseUpdate { lease_info, .. } => {
+                let addr = leased_addr(&lease_info.version_specific_information);
+                let mud_url = lease_info.mud_url.clone();
+                self.update_known_hosts(|known_hosts| match mud_url {
+                    Some(mud_url) => {
+                        known_hosts.mud_urls.insert(addr, mud_url);
+                    },
+                    None => {
+                        known_hosts.mud_urls.remove(&addr);
+                    },
+                });
+            },
+            DhcpEvent::LeaseDestroyed { lease_info, .. } => {
+                let addr = leased_addr(&lease_info.version_specific_information);
+                self.update_known_hosts(|known_hosts| {
+                    known_hosts.mud_urls.remove(&addr);
+                });
+            },
+        }
+    }
+
+    /// Applies the given update to the known hosts and notifies the set update watcher if they changed.
+    fn update_known_hosts(&self, update: impl FnOnce(&mut KnownHosts)) {
+        let mut known_hosts = self.known_hosts.lock().unwrap();
+        let previous = known_hosts.clone();
+        update(&mut known_hosts);
+        if *known_hosts != previous {
+            self.change_notify.notify_one();
+        }
+    }
+
+    /// Returns the prefixes the abstraction with the given key currently resolves to.
+    pub fn resolve(&self, key: &AbstractionKey) -> Vec<IpPrefix> {
+        self.known_hosts
+            .lock()
+            .unwrap()
+            .resolve(key, &self.settings.abstractions)
+    }
+
+    /// Remembers the sets of the firewall configuration that was installed last, whose elements are updated from now
+    /// on.
+    pub fn set_installed_sets(&self, sets: AbstractionSets) {
+        *self.installed_sets.lock().unwrap() = sets;
+    }
+
+    /// Watcher which updates the elements of the installed sets whenever the known hosts change.
+    pub async fn set_update_watcher(&self) {
+        loop {
+            self.change_notify.notified().await;
+            let sets = self.installed_sets.lock().unwrap().clone();
+            if sets.is_empty() {
+                continue;
+            }
+            if let Err(e) = replace_set_elements(&sets, self) {
+                warn!("Unable to update the sets of MUD abstractions: {:?}", e);
+            }
+        }
+    }
+
+    /// Periodically discovers the controllers referred to by `controller` and `my-controller` abstractions via
+    /// DNS-SD.
+    pub async fn controller_discovery(&self) {
+        let service_types = self.settings.abstractions.controller_service_types();
+        if service_types.is_empty() {
+            return;
+        }
+        loop {
+            for service_type in &service_types {
+                // The discovery ends with an error once no new controllers are found within the search timeout.
+                let addrs: HashSet<IpAddr> = discover_controllers(service_type)
+                    .take_while(|result| future::ready(result.is_ok()))
+                    .filter_map(|result| future::ready(result.ok()))
+                    .map(|addr| SocketAddr::from(addr).ip())
+                    .collect()
+                    .await;
+                debug!("Discovered controllers for {}: {:?}", service_type, addrs);
+                self.update_known_hosts(|known_hosts| {
+                    known_hosts.controllers.insert(service_type.clone(), addrs);
+                });
+            }
+            sleep(CONTROLLER_DISCOVERY_INTERVAL).await;
+        }
+    }
+}
+
+#[cfg(feature = "nftables")]
+mod nftables {
+    use nftnl::{Batch, MsgType};
+
+    use super::{abstraction_set_name, address_ranges, AbstractionResolver, AbstractionSets, IpPrefix};
+    use crate::{
+        error::Result,
+        services::{
+            firewall_service::{send_and_process, TABLE_NAME},
+            nft_exprs::{AddressSet, AddressSetElements},
+        },
+    };
+
+    /// Maximum number of address ranges added to a set in a single message.
+    const MAX_RANGES_PER_MESSAGE: usize = 256;
+
+    /// Adds messages which create the given sets with their current elements to the given batch.
+    pub fn add_abstraction_sets(batch: &mut Batch, sets: &AbstractionSets, resolver: &AbstractionResolver) {
+        for (index, key) in sets.iter() {
+            let prefixes = resolver.resolve(key);
+            for &ipv6 in &[false, true] {
+                let name = abstraction_set_name(index, ipv6);
+                let set = AddressSet {
+                    table: TABLE_NAME.to_string(),
+                    name: name.clone(),
+                    id: index as u32 * 2 + u32::from(ipv6),
+                    ipv6,
+                    timeout: false,
+                };
+                batch.add(&set, MsgType::Add);
+                add_set_elements(batch, name, &prefixes, ipv6);
+            }
+        }
+    }
+
+    /// Replaces the elements of the given (installed) sets with the addresses their abstractions currently resolve
+    /// to, without changing any rules.
+    pub fn replace_set_elements(sets: &AbstractionSets, resolver: &AbstractionResolver) -> Result<()> {
+        let mut batch = Batch::new();
+        for (index, key) in sets.iter() {
+            let prefixes = resolver.resolve(key);
+            for &ipv6 in &[false, true] {
+                let name = abstraction_set_name(index, ipv6);
+                // Deleting an empty list of elements removes all elements of the set.
+                let flush = AddressSetElements {
+                    table: TABLE_NAME.to_string(),
+                    set: name.clone(),
+                    ranges: Vec::new(),
+                };
+                batch.add(&flush, MsgType::Del);
+                add_set_elements(&mut batch, name, &prefixes, ipv6);
+            }
+        }
+        debug!("Updating the sets of MUD abstractions");
+        send_and_process(batch.finalize(), &[])
+    }
+
+    /// Adds messages which add the given prefixes of the given IP version to the set with the given name to the
+    /// given batch.
+    fn add_set_elements(batch: &mut Batch, set: String, prefixes: &[IpPrefix], ipv6: bool) {
+        for ranges in address_ranges(prefixes, ipv6).chunks(MAX_RANGES_PER_MESSAGE) {
+            let elements = AddressSetElements {
+                table: TABLE_NAME.to_string(),
+                set: set.clone(),
+                ranges: ranges.to_vec(),
+            };
+            batch.add(&elements, MsgType::Add);
+        }
+    }
+}
+
+#[cfg(not(feature = "nftables"))]
+fn replace_set_elements(_sets: &AbstractionSets, _resolver: &AbstractionResolver) -> crate::error::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+
+    fn prefix(value: &str) -> IpPrefix {
+        IpPrefix::parse(value).unwrap()
+    }
+
+    fn range(start: &str, end: Option<&str>) -> AddressRange {
+        AddressRange {
+            start: start.parse().unwrap(),
+            end: end.map(|end| end.parse().unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_address_ranges() {
+        let prefixes = [
+            prefix("192.168.1.0/24"),
+            prefix("10.0.0.1"),
+            // Overlapping and adjacent prefixes are merged.
+            prefix("192.168.1.128/25"),
+            prefix("192.168.2.0/24"),
+            prefix("255.255.255.0/24"),
+            prefix("fe80::1/10"),
+        ];
+        assert_eq!(
+            address_ranges(&prefixes, false),
+            vec![
+                range("10.0.0.1", Some("10.0.0.2")),
+                range("192.168.1.0", Some("192.168.3.0")),
+                range("255.255.255.0", None),
+            ]
+        );
+        assert_eq!(address_ranges(&prefixes, true), vec![range("fe80::", Some("fec0::"))]);
+        assert_eq!(IpPrefix::parse("10.0.0.0/33"), None);
+        assert_eq!(prefix("::/0").bounds(), (0, u128::MAX));
+    }
+
+    #[test]
+    fn test_mud_url_authority() {
+        assert_eq!(
+            mud_url_authority("https://Example.com/mud/lightbulb.json"),
+            Some("example.com".to_string())
+        );
+        assert_eq!(
+            mud_url_authority("https://user@mud.example.com:8443?x"),
+            Some("mud.example.com:8443".to_string())
+        );
+        assert_eq!(mud_url_authority("example.com/mud.json"), None);
+    }
+
+    #[test]
+    fn test_resolve_abstractions() {
+        let device: IpAddr = "192.168.1.10".parse().unwrap();
+        let other: IpAddr = "192.168.1.11".parse().unwrap();
+        let foreign: IpAddr = "192.168.1.12".parse().unwrap();
+        let controller: IpAddr = "192.168.1.2".parse().unwrap();
+        let mut settings = AbstractionSettings::default();
+        settings
+            .my_controllers
+            .insert("https://example.com/bulb.json".to_string(), "_bulb._tcp".to_string());
+        let mut known_hosts = KnownHosts::default();
+        known_hosts
+            .mud_urls
+            .insert(device, "https://example.com/bulb.json".to_string());
+        known_hosts
+            .mud_urls
+            .insert(other, "https://EXAMPLE.com/switch.json".to_string());
+        known_hosts
+            .mud_urls
+            .insert(foreign, "https://example.org/bulb.json".to_string());
+        known_hosts
+            .controllers
+            .insert("_bulb._tcp".to_string(), vec![controller].into_iter().collect());
+
+        let mut same_manufacturer = known_hosts.resolve(&AbstractionKey::SameManufacturer(vec![device]), &settings);
+        same_manufacturer.sort_by_key(|prefix| prefix.addr);
+        assert_eq!(same_manufacturer, vec![IpPrefix::host(device), IpPrefix::host(other)]);
+        assert_eq!(
+            known_hosts.resolve(
+                &AbstractionKey::new(
+                    &MudAbstraction::Manufacturer {
+                        authority: "Example.org".to_string()
+                    },
+                    Vec::new()
+                ),
+                &settings
+            ),
+            vec![IpPrefix::host(foreign)]
+        );
+        assert_eq!(
+            known_hosts.resolve(&AbstractionKey::MyController(vec![device]), &settings),
+            vec![IpPrefix::host(controller)]
+        );
+        // Devices without a known MUD URL have neither a manufacturer nor a controller.
+        assert!(known_hosts
+            .resolve(&AbstractionKey::MyController(vec![controller]), &settings)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_local_networks_from_uci() -> Result<()> {
+        let mut uci = Uci::new()?;
+        uci.set_config_dir("tests/config")?;
+        uci.set_save_dir("/tmp/.uci_tests")?;
+        assert_eq!(
+            local_networks_from_uci(&mut uci, &["lan".to_string(), "guest".to_string()]),
+            vec![IPV6_LINK_LOCAL, prefix("2.3.4.5/32")]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::{Duration, Instant},
+};
+
+use crate::{services::events::AnomalyKind, settings::AnomalyDetectionSettings};
+
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const IPPROTO_SCTP: u8 = 132;
+
+const IPV6_HEADER_LEN: usize = 40;
+
+/// A connection attempt of a device as seen by the firewall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionAttempt {
+    pub destination: IpAddr,
+    /// Destination port for TCP, UDP and SCTP connections.
+    pub destination_port: Option<u16>,
+    /// Whether the connection attempt was rejected by the firewall.
+    pub rejected: bool,
+}
+
+/// Parses the destination of a connection attempt from the beginning of a packet (starting with the IP header) of the
+/// given protocol family. IPv6 extension headers are not supported, so no port is returned for such packets.
+pub fn parse_connection_attempt(family: u8, packet: &[u8], rejected: bool) -> Option<ConnectionAttempt> {
+    let (destination, protocol, transport_header) = match family {
+        NFPROTO_IPV4 => {
+            let header_len = usize::from(packet.first()? & 0x0f) * 4;
+            let destination: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv4Addr::from(destination)),
+                *packet.get(9)?,
+                packet.get(header_len..),
+            )
+        },
+        NFPROTO_IPV6 => {
+            let destination: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (
+                IpAddr::from(Ipv6Addr::from(destination)),
+                *packet.get(6)?,
+                packet.get(IPV6_HEADER_LEN..),
+            )
+        },
+        _ => return None,
+    };
+    let destination_port = match protocol {
+        IPPROTO_TCP | IPPROTO_UDP | IPPROTO_SCTP => transport_header
+            .and_then(|h| h.get(2..4))
+            .map(|p| u16::from_be_bytes([p[0], p[1]])),
+        _ => None,
+    };
+    Some(ConnectionAttempt {
+        destination,
+        destination_port,
+        rejected,
+    })
+}
+
+/// Connection attempts of a device within the current time window.
+#[derive(Debug)]
+struct DeviceWindow {
+    start: Instant,
+    ports_per_destination: HashMap<IpAddr, HashSet<u16>>,
+    rejected: usize,
+    /// Anomalies that have already been detected in this window.
+    detected: HashSet<AnomalyKind>,
+}
+
+impl DeviceWindow {
+    fn new(start: Instant) -> DeviceWindow {
+        DeviceWindow {
+            start,
+            ports_per_destination: HashMap::new(),
+            rejected: 0,
+            detected: HashSet::new(),
+        }
+    }
+}
+
+/// Detects anomalous connection attempts of devices by counting them in fixed time windows.
+#[derive(Debug)]
+pub struct AnomalyDetector {
+    settings: AnomalyDetectionSettings,
+    windows: HashMap<i64, DeviceWindow>,
+}
+
+impl AnomalyDetector {
+    pub fn new(settings: AnomalyDetectionSettings) -> AnomalyDetector {
+        AnomalyDetector {
+            settings,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Records a connection attempt of the given device at the given point in time and returns all anomalies that
+    /// were newly detected because of it. Each anomaly is only returned once per time window.
+    pub fn observe(&mut self, device_id: i64, attempt: &ConnectionAttempt, now: Instant) -> Vec<AnomalyKind> {
+        let window_length = Duration::from_secs(self.settings.window_seconds);
+        let window = self.windows.entry(device_id).or_insert_with(|| DeviceWindow::new(now));
+        if now.duration_since(window.start) >= window_length {
+            *window = DeviceWindow::new(now);
+        }
+
+        let mut anomalies = Vec::new();
+        if attempt.rejected {
+            window.rejected += 1;
+            if window.rejected >= self.settings.rejected_threshold {
+                anomalies.push(AnomalyKind::RejectedSpike);
+            }
+        } else {
+            let ports = window.ports_per_destination.entry(attempt.destination).or_default();
+            if let Some(port) = attempt.destination_port {
+                ports.insert(port);
+            }
+            if ports.len() >= self.settings.port_scan_threshold {
+                anomalies.push(AnomalyKind::PortScan);
+            }
+            if window.ports_per_destination.len() >= self.settings.fan_out_threshold {
+                anomalies.push(AnomalyKind::FanOut);
+            }
+        }
+        anomalies.retain(|anomaly| window.detected.insert(*anomaly));
+        anomalies
+    }
+
+    /// Removes the time windows of all devices that have not attempted any connections recently.
+    pub fn remove_expired_windows(&mut self, now: Instant) {
+        let window_length = Duration::from_secs(self.settings.window_seconds);
+        self.windows
+            .retain(|_, window| now.duration_since(window.start) < window_length);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector() -> AnomalyDetector {
+        AnomalyDetector::new(AnomalyDetectionSettings {
+            enabled: true,
+            port_scan_threshold: 3,
+            fan_out_threshold: 3,
+            rejected_threshold: 2,
+            ..AnomalyDetectionSettings::default()
+        })
+    }
+
+    fn attempt(destination: [u8; 4], port: u16, rejected: bool) -> ConnectionAttempt {
+        ConnectionAttempt {
+            destination: IpAddr::from(destination),
+            destination_port: Some(port),
+            rejected,
+        }
+    }
+
+    #[test]
+    fn test_parse_connection_attempt() {
+        // IPv4 header (20 bytes) from 10.0.0.2 to 192.168.1.1, followed by the TCP ports.
+        let mut ipv4_packet = b"\x45\x00\x00\x28\x00\x00\x00\x00\x40\x06\x00\x00".to_vec();
+        ipv4_packet.extend_from_slice(&[10, 0, 0, 2, 192, 168, 1, 1]);
+        ipv4_packet.extend_from_slice(&[0xc0, 0x01, 0x01, 0xbb]);
+        assert_eq!(
+            parse_connection_attempt(NFPROTO_IPV4, &ipv4_packet, false),
+            Some(attempt([192, 168, 1, 1], 443, false))
+        );
+
+        // IPv6 header (40 bytes) from ::1 to 2001:db8::1, followed by the UDP ports.
+        let mut ipv6_packet = vec![0x60, 0, 0, 0, 0, 8, IPPROTO_UDP, 64];
+        ipv6_packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        ipv6_packet.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6_packet.extend_from_slice(&[0xc0, 0x01, 0x00, 0x35]);
+        assert_eq!(
+            parse_connection_attempt(NFPROTO_IPV6, &ipv6_packet, true),
+            Some(ConnectionAttempt {
+                destination: "2001:db8::1".parse().unwrap(),
+                destination_port: Some(53),
+                rejected: true,
+            })
+        );
+
+        assert_eq!(parse_connection_attempt(NFPROTO_IPV4, &ipv4_packet[..10], false), None);
+    }
+
+    #[test]
+    fn test_port_scan_and_fan_out() {
+        let mut detector = detector();
+        let now = Instant::now();
+        assert!(detector.observe(1, &attempt([10, 0, 0, 1], 22, false), now).is_empty());
+        assert!(detector.observe(1, &attempt([10, 0, 0, 1], 23, false), now).is_empty());
+        // Attempts of other devices are counted separately.
+        assert!(detector.observe(2, &attempt([10, 0, 0, 1], 24, false), now).is_empty());
+        assert_eq!(
+            detector.observe(1, &attempt([10, 0, 0, 1], 24, false), now),
+            vec![AnomalyKind::PortScan]
+        );
+        // Anomalies are only reported once per window.
+        assert!(detector.observe(1, &attempt([10, 0, 0, 1], 25, false), now).is_empty());
+        assert!(detector.observe(1, &attempt([10, 0, 0, 2], 80, false), now).is_empty());
+        assert_eq!(
+            detector.observe(1, &attempt([10, 0, 0, 3], 80, false), now),
+            vec![AnomalyKind::FanOut]
+        );
+    }
+
+    #[test]
+    fn test_rejected_spike_and_window_reset() {
+        let mut detector = detector();
+        let now = Instant::now();
+        assert!(detector.observe(1, &attempt([10, 0, 0, 1], 22, true), now).is_empty());
+        let next_window = now + Duration::from_secs(60);
+        assert!(detector
+            .observe(1, &attempt([10, 0, 0, 1], 22, true), next_window)
+            .is_empty());
+        assert_eq!(
+            detector.observe(1, &attempt([10, 0, 0, 1], 22, true), next_window),
+            vec![AnomalyKind::RejectedSpike]
+        );
+        detector.remove_expired_windows(next_window + Duration::from_secs(60));
+        assert!(detector.windows.is_empty());
+    }
+}
@@ -0,0 +1,322 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::fmt;
+
+use crate::{
+    error::{
+        Error, MissingCapabilityError, MissingKernelModuleError, NftablesBatchError, TableBusyError,
+        UnsupportedExpressionError,
+    },
+    services::{
+        netlink::{parse_str, Message, NFNL_SUBSYS_NFTABLES},
+        rule_annotations::{parse_comment_userdata, RuleOrigin},
+    },
+};
+
+// Decoding of the errors the kernel reports for messages of an nftables batch.
+// Every error message contains the original message that caused it (unless capped), which is used to identify the
+// device and rule the failed message was created for.
+
+const EPERM: i32 = 1;
+const ENOENT: i32 = 2;
+const EACCES: i32 = 13;
+const EBUSY: i32 = 16;
+const EOPNOTSUPP: i32 = 95;
+const EAFNOSUPPORT: i32 = 97;
+
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_DELCHAIN: u16 = 5;
+const NFT_MSG_NEWRULE: u16 = 6;
+const NFT_MSG_DELRULE: u16 = 8;
+const NFT_MSG_NEWSET: u16 = 9;
+const NFT_MSG_DELSET: u16 = 11;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFT_MSG_DELSETELEM: u16 = 14;
+const NFT_MSG_NEWFLOWTABLE: u16 = 22;
+const NFT_MSG_DELFLOWTABLE: u16 = 24;
+
+const NFTA_TABLE_NAME: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_RULE_USERDATA: u16 = 7;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+/// Attribute containing the name of a set (for set and set element messages) or a flowtable.
+const NFTA_SET_NAME: u16 = 2;
+
+/// Prefix of the names of the chains containing the rules of a device.
+const DEVICE_CHAIN_PREFIX: &str = "device_";
+
+/// The object a failed message of an nftables batch was meant to add or delete.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailedObject {
+    Table(String),
+    Chain {
+        name: String,
+        device_id: Option<i64>,
+    },
+    Rule {
+        chain: String,
+        origin: Option<RuleOrigin>,
+    },
+    Set(String),
+    SetElements(String),
+    Flowtable(String),
+    /// A message that was not sent by the enforcer or could not be decoded (e.g. because the kernel capped it).
+    Unknown(u16),
+}
+
+impl FailedObject {
+    /// Identifies the object of the given original message of an error message.
+    pub fn from_message(message: &Message<'_>) -> FailedObject {
+        if message.subsystem() != NFNL_SUBSYS_NFTABLES {
+            return FailedObject::Unknown(message.msg_type);
+        }
+        let string_attr = |attr_type| {
+            message
+                .nfnetlink_attributes()
+                .find(attr_type)
+                .and_then(parse_str)
+                .map(String::from)
+        };
+        let object = match message.subsystem_msg_type() {
+            NFT_MSG_NEWTABLE | NFT_MSG_DELTABLE => string_attr(NFTA_TABLE_NAME).map(FailedObject::Table),
+            NFT_MSG_NEWCHAIN | NFT_MSG_DELCHAIN => string_attr(NFTA_CHAIN_NAME).map(|name| FailedObject::Chain {
+                device_id: name.strip_prefix(DEVICE_CHAIN_PREFIX).and_then(|id| id.parse().ok()),
+                name,
+            }),
+            NFT_MSG_NEWRULE | NFT_MSG_DELRULE => string_attr(NFTA_RULE_CHAIN).map(|chain| FailedObject::Rule {
+                chain,
+                origin: message
+                    .nfnetlink_attributes()
+                    .find(NFTA_RULE_USERDATA)
+                    .and_then(parse_comment_userdata)
+                    .and_then(|comment| comment.parse().ok()),
+            }),
+            NFT_MSG_NEWSET | NFT_MSG_DELSET => string_attr(NFTA_SET_NAME).map(FailedObject::Set),
+            NFT_MSG_NEWSETELEM | NFT_MSG_DELSETELEM => string_attr(NFTA_SET_NAME).map(FailedObject::SetElements),
+            NFT_MSG_NEWFLOWTABLE | NFT_MSG_DELFLOWTABLE => string_attr(NFTA_SET_NAME).map(FailedObject::Flowtable),
+            _ => None,
+        };
+        object.unwrap_or(FailedObject::Unknown(message.msg_type))
+    }
+
+    /// Returns the ID of the device the object was created for, if any.
+    pub fn device_id(&self) -> Option<i64> {
+        match self {
+            FailedObject::Chain { device_id, .. } => *device_id,
+            FailedObject::Rule {
+                origin: Some(origin), ..
+            } => Some(origin.device_id),
+            FailedObject::Rule { chain, .. } => chain.strip_prefix(DEVICE_CHAIN_PREFIX).and_then(|id| id.parse().ok()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FailedObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailedObject::Table(name) => write!(f, "table {}", name),
+            FailedObject::Chain { name, .. } => write!(f, "chain {}", name),
+            FailedObject::Rule {
+                chain,
+                origin: Some(origin),
+            } => {
+                match (origin.rule_index, &origin.rule_name) {
+                    (Some(index), Some(name)) => write!(f, "rule {} ({}) of device {}", index, name, origin.device_id)?,
+                    (Some(index), None) => write!(f, "rule {} of device {}", index, origin.device_id)?,
+                    (None, _) => write!(f, "rule for device {}", origin.device_id)?,
+                }
+                if let Some(hostname) = &origin.hostname {
+                    write!(f, " for host {}", hostname)?;
+                }
+                write!(f, " in chain {}", chain)
+            },
+            FailedObject::Rule { chain, origin: None } => write!(f, "rule in chain {}", chain),
+            FailedObject::Set(name) => write!(f, "set {}", name),
+            FailedObject::SetElements(name) => write!(f, "elements of set {}", name),
+            FailedObject::Flowtable(name) => write!(f, "flowtable {}", name),
+            FailedObject::Unknown(msg_type) => write!(f, "message of type {:#06x}", msg_type),
+        }
+    }
+}
+
+/// Converts an error message received in response to an nftables batch into an error describing the cause and the
+/// failed object. Returns `None` for acknowledgements and messages which are not error messages.
+pub fn batch_error(message: &Message<'_>) -> Option<Error> {
+    let errno = -message.error_code().filter(|&code| code < 0)?;
+    let original = message.error_original_header();
+    let object = original
+        .as_ref()
+        .map_or(FailedObject::Unknown(0), FailedObject::from_message);
+    let ack = message.extended_ack();
+    let kernel_message = ack.message.map(|m| format!(": {}", m)).unwrap_or_default();
+    let invalid_attr_path = original
+        .as_ref()
+        .zip(ack.invalid_attr_offset)
+        .map(|(original, offset)| original.nfnetlink_attribute_path_at(offset))
+        .unwrap_or_default();
+    let object = object.to_string();
+
+    let result: Result<(), Error> = match errno {
+        EPERM | EACCES => MissingCapabilityError { object, errno }.fail(),
+        EAFNOSUPPORT => MissingKernelModuleError {
+            object,
+            errno,
+            kernel_message,
+        }
+        .fail(),
+        // A rule refers to an expression type whose module could not be loaded. ENOENT is also returned for missing
+        // chains or sets referenced by a rule, so this is only assumed if the kernel points at the expression name.
+        ENOENT
+            if original.as_ref().map(Message::subsystem_msg_type) == Some(NFT_MSG_NEWRULE)
+                && invalid_attr_path.starts_with(&[NFTA_RULE_EXPRESSIONS, NFTA_LIST_ELEM, NFTA_EXPR_NAME]) =>
+        {
+            MissingKernelModuleError {
+                object,
+                errno,
+                kernel_message,
+            }
+            .fail()
+        },
+        EOPNOTSUPP => UnsupportedExpressionError {
+            object,
+            errno,
+            kernel_message,
+        }
+        .fail(),
+        EBUSY => TableBusyError {
+            object,
+            errno,
+            kernel_message,
+        }
+        .fail(),
+        _ => NftablesBatchError {
+            object,
+            errno,
+            kernel_message,
+        }
+        .fail(),
+    };
+    result.err()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::{
+        netlink::{messages, MessageBuilder, NLMSG_ERROR, NLM_F_ACK_TLVS, NLM_F_REQUEST},
+        rule_annotations::comment_userdata,
+    };
+
+    fn error_message(errno: i32, original: &[u8]) -> Vec<u8> {
+        let mut error = Vec::new();
+        error.extend_from_slice(&(20 + original.len() as u32).to_ne_bytes());
+        error.extend_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        error.extend_from_slice(&0u16.to_ne_bytes());
+        error.extend_from_slice(&1u32.to_ne_bytes());
+        error.extend_from_slice(&0u32.to_ne_bytes());
+        error.extend_from_slice(&(-errno).to_ne_bytes());
+        error.extend_from_slice(original);
+        error
+    }
+
+    /// Returns an error message with an extended acknowledgement pointing at the given offset of the original message.
+    fn error_message_at(errno: i32, original: &[u8], invalid_attr_offset: u32) -> Vec<u8> {
+        let mut error = error_message(errno, original);
+        error[6..8].copy_from_slice(&NLM_F_ACK_TLVS.to_ne_bytes());
+        // NLMSGERR_ATTR_OFFS
+        error.extend_from_slice(&8u16.to_ne_bytes());
+        error.extend_from_slice(&2u16.to_ne_bytes());
+        error.extend_from_slice(&invalid_attr_offset.to_ne_bytes());
+        let len = error.len() as u32;
+        error[0..4].copy_from_slice(&len.to_ne_bytes());
+        error
+    }
+
+    fn rule_message(origin: &RuleOrigin) -> Vec<u8> {
+        let mut rule = MessageBuilder::new_nfnetlink(NFNL_SUBSYS_NFTABLES, NFT_MSG_NEWRULE, NLM_F_REQUEST, 1, 1, 0);
+        rule.attr_str(1, "namib")
+            .attr_str(NFTA_RULE_CHAIN, &format!("device_{}", origin.device_id))
+            .attr(NFTA_RULE_USERDATA, &comment_userdata(&origin.comment()));
+        rule.finish()
+    }
+
+    #[test]
+    fn test_failed_object() {
+        let origin = RuleOrigin {
+            device_id: 5,
+            config_version: "3".to_string(),
+            ..RuleOrigin::default()
+        }
+        .for_rule(2, Some("cl0-frdev".to_string()));
+        let buffer = rule_message(&origin);
+        let object = FailedObject::from_message(&messages(&buffer).next().unwrap());
+        assert_eq!(object.device_id(), Some(5));
+        assert_eq!(object.to_string(), "rule 2 (cl0-frdev) of device 5 in chain device_5");
+
+        let mut chain = MessageBuilder::new_nfnetlink(NFNL_SUBSYS_NFTABLES, NFT_MSG_NEWCHAIN, NLM_F_REQUEST, 1, 1, 0);
+        chain.attr_str(1, "namib").attr_str(NFTA_CHAIN_NAME, "device_12");
+        let buffer = chain.finish();
+        let object = FailedObject::from_message(&messages(&buffer).next().unwrap());
+        assert_eq!(object.device_id(), Some(12));
+        assert_eq!(object.to_string(), "chain device_12");
+    }
+
+    #[test]
+    fn test_batch_error() {
+        let origin = RuleOrigin {
+            device_id: 5,
+            ..RuleOrigin::default()
+        }
+        .for_rule(0, None);
+        let rule = rule_message(&origin);
+
+        let ack = error_message(0, &rule);
+        assert!(batch_error(&messages(&ack).next().unwrap()).is_none());
+
+        let classify = |errno| {
+            let buffer = error_message(errno, &rule);
+            batch_error(&messages(&buffer).next().unwrap()).unwrap()
+        };
+        assert!(matches!(classify(EPERM), Error::MissingCapabilityError { .. }));
+        assert!(matches!(classify(ENOENT), Error::NftablesBatchError { .. }));
+        assert!(matches!(classify(EOPNOTSUPP), Error::UnsupportedExpressionError { .. }));
+        assert!(matches!(classify(EBUSY), Error::TableBusyError { .. }));
+        match classify(22) {
+            Error::NftablesBatchError { object, errno, .. } => {
+                assert_eq!(object, "rule 0 of device 5 in chain device_5");
+                assert_eq!(errno, 22);
+            },
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_missing_expression_error() {
+        let mut rule = MessageBuilder::new_nfnetlink(NFNL_SUBSYS_NFTABLES, NFT_MSG_NEWRULE, NLM_F_REQUEST, 1, 1, 0);
+        rule.attr_str(1, "namib")
+            .attr_str(NFTA_RULE_CHAIN, "device_5")
+            .begin_nested(NFTA_RULE_EXPRESSIONS)
+            .begin_nested(NFTA_LIST_ELEM)
+            .attr_str(NFTA_EXPR_NAME, "lookup");
+        let rule = rule.finish();
+        // The headers are followed by the table (12 bytes), the chain (16 bytes) and the nested expressions.
+        let (chain_offset, expressions_offset) = (32, 48);
+        let name_offset = expressions_offset + 8;
+
+        let classify = |offset| {
+            let buffer = error_message_at(ENOENT, &rule, offset);
+            batch_error(&messages(&buffer).next().unwrap()).unwrap()
+        };
+        assert!(matches!(classify(name_offset), Error::MissingKernelModuleError { .. }));
+        // A missing chain or set referenced by the rule is not caused by a missing module.
+        assert!(matches!(classify(chain_offset), Error::NftablesBatchError { .. }));
+        assert!(matches!(classify(expressions_offset), Error::NftablesBatchError { .. }));
+    }
+}
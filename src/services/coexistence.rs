@@ -0,0 +1,593 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{collections::HashSet, fmt, sync::Arc, thread, time::Duration};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::{
+    error::{CoexistenceError, Error, Result},
+    services::{
+        events::{EventReporter, PolicyEvent},
+        firewall_service::{FirewallService, TABLE_NAME},
+        netlink::{self, Attributes},
+    },
+    settings::{BypassAction, Settings},
+};
+
+// The enforcer shares the netfilter hooks with other firewalls, most notably the fw4 table of OpenWrt. Every packet
+// passes the base chains of all tables registered for a hook in the order of their priorities. An accept verdict only
+// ends the evaluation of the current base chain, so the enforcer's rules still apply to packets accepted by other
+// tables, but packets dropped by another table never reach the enforcer and flows offloaded to a flowtable skip the
+// forward hook (and therefore the enforcer's rules) entirely.
+
+const NFPROTO_INET: u8 = 1;
+const NFPROTO_IPV4: u8 = 2;
+const NFPROTO_IPV6: u8 = 10;
+
+const NF_INET_FORWARD: u32 = 2;
+const NF_DROP: u32 = 0;
+
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+
+/// Time to wait after a change of the ruleset before inspecting it, so that a firewall reload (which consists of
+/// many changes) is only inspected once.
+const RULESET_CHANGE_DELAY: Duration = Duration::from_secs(2);
+
+/// Returns the nft name of the given address family.
+fn family_name(family: u8) -> &'static str {
+    match family {
+        NFPROTO_INET => "inet",
+        NFPROTO_IPV4 => "ip",
+        NFPROTO_IPV6 => "ip6",
+        _ => "unknown",
+    }
+}
+
+/// A base chain (i.e. a chain attached to a netfilter hook) as reported by the kernel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BaseChain {
+    pub family: u8,
+    pub table: String,
+    pub name: String,
+    pub hook: u32,
+    pub priority: i32,
+    pub policy: u32,
+}
+
+impl BaseChain {
+    /// Returns true if the chain sees the forwarded IP packets the enforcer's base chain sees.
+    fn is_forward_chain(&self) -> bool {
+        matches!(self.family, NFPROTO_INET | NFPROTO_IPV4 | NFPROTO_IPV6) && self.hook == NF_INET_FORWARD
+    }
+
+    /// Returns true if the chain belongs to the enforcer's table.
+    fn is_own_chain(&self) -> bool {
+        self.family == NFPROTO_INET && self.table == TABLE_NAME
+    }
+
+    /// Returns the table of the chain as shown by nft, e.g. `inet fw4`.
+    fn qualified_table(&self) -> String {
+        format!("{} {}", family_name(self.family), self.table)
+    }
+}
+
+/// Parses a base chain from the attributes of a chain message sent by the kernel.
+/// Returns `None` for regular chains.
+fn parse_base_chain(family: u8, attributes: Attributes<'_>) -> Option<BaseChain> {
+    let mut table = None;
+    let mut name = None;
+    let mut hook = None;
+    let mut priority = None;
+    let mut policy = None;
+    for (attr_type, data) in attributes {
+        match attr_type {
+            NFTA_CHAIN_TABLE => table = netlink::parse_str(data).map(String::from),
+            NFTA_CHAIN_NAME => name = netlink::parse_str(data).map(String::from),
+            NFTA_CHAIN_HOOK => {
+                let hook_attrs = netlink::attributes(data);
+                hook = hook_attrs
+                    .clone()
+                    .find(NFTA_HOOK_HOOKNUM)
+                    .and_then(netlink::parse_u32_be);
+                priority = hook_attrs.find(NFTA_HOOK_PRIORITY).and_then(netlink::parse_i32_be);
+            },
+            NFTA_CHAIN_POLICY => policy = netlink::parse_u32_be(data),
+            _ => {},
+        }
+    }
+    Some(BaseChain {
+        family,
+        table: table?,
+        name: name?,
+        hook: hook?,
+        priority: priority?,
+        policy: policy?,
+    })
+}
+
+/// Parses the attributes of a rule message sent by the kernel and returns the table and chain of the rule if it
+/// offloads flows to a flowtable (`flow add @<flowtable>`).
+fn parse_offloading_rule(family: u8, attributes: Attributes<'_>) -> Option<(String, String)> {
+    let mut table = None;
+    let mut chain = None;
+    let mut offloads = false;
+    for (attr_type, data) in attributes {
+        match attr_type {
+            NFTA_RULE_TABLE => table = netlink::parse_str(data).map(String::from),
+            NFTA_RULE_CHAIN => chain = netlink::parse_str(data).map(String::from),
+            NFTA_RULE_EXPRESSIONS => {
+                offloads = netlink::attributes(data)
+                    .filter(|(t, _)| *t == NFTA_LIST_ELEM)
+                    .any(|(_, expr)| {
+                        netlink::attributes(expr)
+                            .find(NFTA_EXPR_NAME)
+                            .and_then(netlink::parse_str)
+                            == Some("flow_offload")
+                    });
+            },
+            _ => {},
+        }
+    }
+    if !offloads {
+        return None;
+    }
+    Some((format!("{} {}", family_name(family), table?), chain?))
+}
+
+/// Interaction of another table with the enforcer's rules.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CoexistenceIssue {
+    /// Flows are offloaded to a flowtable by another table. Packets of offloaded flows skip the forward hook, so
+    /// changes of the enforcer's rules (or schedules and limits) do not apply to them.
+    FlowOffload { table: String, chain: String },
+    /// Another forward chain drops all packets it does not explicitly accept, regardless of the enforcer's rules.
+    DropPolicy {
+        table: String,
+        chain: String,
+        priority: i32,
+        /// Whether the chain is evaluated before the enforcer's chain, i.e. dropped packets are never seen by the
+        /// enforcer.
+        before_enforcer: bool,
+    },
+    /// Another forward chain has the same priority as the enforcer's chain, so the order in which they are evaluated
+    /// is undefined.
+    SamePriority {
+        table: String,
+        chain: String,
+        priority: i32,
+    },
+}
+
+impl CoexistenceIssue {
+    /// Returns true if the enforcer's policy is bypassed for some packets because of this issue.
+    pub fn bypasses_policy(&self) -> bool {
+        matches!(self, CoexistenceIssue::FlowOffload { .. })
+    }
+}
+
+impl fmt::Display for CoexistenceIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoexistenceIssue::FlowOffload { table, chain } => write!(
+                f,
+                "Chain \"{}\" of table \"{}\" offloads flows to a flowtable, packets of offloaded flows bypass the \
+                 enforcer's rules",
+                chain, table
+            ),
+            CoexistenceIssue::DropPolicy {
+                table,
+                chain,
+                priority,
+                before_enforcer,
+            } => write!(
+                f,
+                "Chain \"{}\" of table \"{}\" (priority {}) has a drop policy and is evaluated {} the enforcer's \
+                 chain, packets not accepted by it are dropped regardless of the enforcer's rules",
+                chain,
+                table,
+                priority,
+                if *before_enforcer { "before" } else { "after" }
+            ),
+            CoexistenceIssue::SamePriority { table, chain, priority } => write!(
+                f,
+                "Chain \"{}\" of table \"{}\" uses the same forward hook priority ({}) as the enforcer, the order \
+                 of evaluation is undefined",
+                chain, table, priority
+            ),
+        }
+    }
+}
+
+/// Result of an inspection of the installed ruleset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RulesetInspection {
+    /// Whether the enforcer's base chain is installed.
+    pub enforcer_chain_installed: bool,
+    pub issues: Vec<CoexistenceIssue>,
+}
+
+/// Determines how the given base chains and rules offloading flows (given by their table and chain) of the installed
+/// ruleset interact with the enforcer's base chain with the given priority.
+fn inspect(hook_priority: i32, chains: &[BaseChain], offloading_rules: &[(String, String)]) -> RulesetInspection {
+    let mut issues = Vec::new();
+    for chain in chains.iter().filter(|c| c.is_forward_chain() && !c.is_own_chain()) {
+        if chain.priority == hook_priority {
+            issues.push(CoexistenceIssue::SamePriority {
+                table: chain.qualified_table(),
+                chain: chain.name.clone(),
+                priority: chain.priority,
+            });
+        }
+        if chain.policy == NF_DROP {
+            issues.push(CoexistenceIssue::DropPolicy {
+                table: chain.qualified_table(),
+                chain: chain.name.clone(),
+                priority: chain.priority,
+                before_enforcer: chain.priority < hook_priority,
+            });
+        }
+    }
+    let own_table = format!("{} {}", family_name(NFPROTO_INET), TABLE_NAME);
+    for (table, chain) in offloading_rules.iter().filter(|(table, _)| *table != own_table) {
+        let issue = CoexistenceIssue::FlowOffload {
+            table: table.clone(),
+            chain: chain.clone(),
+        };
+        if !issues.contains(&issue) {
+            issues.push(issue);
+        }
+    }
+    RulesetInspection {
+        enforcer_chain_installed: chains.iter().any(BaseChain::is_own_chain),
+        issues,
+    }
+}
+
+#[cfg(feature = "nftables")]
+mod nftables {
+    use std::{io, os::unix::io::AsRawFd, sync::Arc};
+
+    use tokio::sync::Notify;
+
+    use super::{inspect, parse_base_chain, parse_offloading_rule, RulesetInspection};
+    use crate::{
+        error::Result,
+        services::{
+            firewall_service::TABLE_NAME,
+            netlink::{self, NFNL_SUBSYS_NFTABLES},
+        },
+    };
+
+    const NFT_MSG_NEWTABLE: u16 = 0;
+    const NFT_MSG_DELTABLE: u16 = 2;
+    const NFT_MSG_DELRULE: u16 = 8;
+    const NFT_MSG_GETCHAIN: u16 = 4;
+    const NFT_MSG_GETRULE: u16 = 7;
+    const NFT_MSG_NEWFLOWTABLE: u16 = 22;
+    const NFT_MSG_DELFLOWTABLE: u16 = 24;
+
+    /// The table is the first attribute of table, chain, rule and flowtable messages.
+    const NFTA_TABLE: u16 = 1;
+
+    /// Multicast group of nftables ruleset change notifications.
+    const NFNLGRP_NFTABLES: u32 = 7;
+
+    /// Retrieves all base chains and rules from the kernel and inspects them.
+    pub fn inspect_ruleset(hook_priority: i32) -> Result<RulesetInspection> {
+        let chains: Vec<_> = netlink::nftables_dump(
+            NFT_MSG_GETCHAIN,
+            libc::NFPROTO_UNSPEC as u8,
+            None,
+            "Unable to retrieve installed chains",
+        )?
+        .iter()
+        .filter_map(|(family, attrs)| parse_base_chain(*family, netlink::attributes(attrs)))
+        .collect();
+        let offloading_rules: Vec<_> = netlink::nftables_dump(
+            NFT_MSG_GETRULE,
+            libc::NFPROTO_UNSPEC as u8,
+            None,
+            "Unable to retrieve installed rules",
+        )?
+        .iter()
+        .filter_map(|(family, attrs)| parse_offloading_rule(*family, netlink::attributes(attrs)))
+        .collect();
+        Ok(inspect(hook_priority, &chains, &offloading_rules))
+    }
+
+    /// Subscribes to nftables ruleset change notifications and notifies the given `Notify` whenever a table, chain,
+    /// rule or flowtable is changed (except for changes of rules and chains in the enforcer's own table).
+    /// Blocks until an error occurs.
+    pub fn watch_ruleset_changes(changed: &Arc<Notify>) -> Result<()> {
+        let socket = mnl::Socket::new(mnl::Bus::Netfilter)?;
+        let group = NFNLGRP_NFTABLES;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_NETLINK,
+                libc::NETLINK_ADD_MEMBERSHIP,
+                &group as *const u32 as *const libc::c_void,
+                std::mem::size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut buffer = vec![0; nftnl::nft_nlmsg_maxsize() as usize];
+        loop {
+            let len = match socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    // Notifications were lost because too many changes happened at once.
+                    changed.notify_one();
+                    continue;
+                },
+                Err(e) => return Err(e.into()),
+            };
+            let foreign_change = netlink::messages(&buffer[..len])
+                .filter(|m| m.subsystem() == NFNL_SUBSYS_NFTABLES)
+                .filter(|m| {
+                    (NFT_MSG_NEWTABLE..=NFT_MSG_DELRULE).contains(&m.subsystem_msg_type())
+                        || (NFT_MSG_NEWFLOWTABLE..=NFT_MSG_DELFLOWTABLE).contains(&m.subsystem_msg_type())
+                })
+                .any(|m| {
+                    // Changes of the enforcer's table are only relevant if the whole table is affected (e.g. by
+                    // `nft flush ruleset`), all other changes are made by the enforcer itself.
+                    let table = m.nfnetlink_attributes().find(NFTA_TABLE).and_then(netlink::parse_str);
+                    table != Some(TABLE_NAME) || m.subsystem_msg_type() <= NFT_MSG_DELTABLE
+                });
+            if foreign_change {
+                changed.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "nftables")]
+pub use nftables::{inspect_ruleset, watch_ruleset_changes};
+
+/// Without nftables support, there is no ruleset to inspect.
+#[cfg(not(feature = "nftables"))]
+pub fn inspect_ruleset(_hook_priority: i32) -> Result<RulesetInspection> {
+    Ok(RulesetInspection {
+        enforcer_chain_installed: true,
+        issues: Vec::new(),
+    })
+}
+
+/// Without nftables support, the ruleset never changes.
+#[cfg(not(feature = "nftables"))]
+pub fn watch_ruleset_changes(_changed: &Arc<Notify>) -> Result<()> {
+    Ok(())
+}
+
+/// Logs the given issue, as an error if it bypasses the enforcer's policy.
+fn log_issue(issue: &CoexistenceIssue) {
+    if issue.bypasses_policy() {
+        error!("{}", issue);
+    } else {
+        warn!("{}", issue);
+    }
+}
+
+/// Inspects the installed ruleset before the enforcer's rules are applied for the first time and logs all issues.
+/// Returns an error if the enforcer's policy would be bypassed and the enforcer is configured to refuse to run in this
+/// case, otherwise returns the issues.
+pub fn check_at_startup(settings: &Settings) -> Result<Vec<CoexistenceIssue>> {
+    let inspection = match inspect_ruleset(settings.hook_priority) {
+        Ok(inspection) => inspection,
+        Err(e) => {
+            warn!(
+                "Unable to inspect the installed firewall rules of other tables: {:?}",
+                e
+            );
+            return Ok(Vec::new());
+        },
+    };
+    inspection.issues.iter().for_each(log_issue);
+    if settings.on_bypass == BypassAction::Refuse && inspection.issues.iter().any(CoexistenceIssue::bypasses_policy) {
+        return CoexistenceError {
+            message: "The enforcer's rules would be bypassed by other firewall tables",
+        }
+        .fail();
+    }
+    Ok(inspection.issues)
+}
+
+/// Watches for changes of the ruleset made by other programs (e.g. reloads of fw4) and reports new issues to the
+/// controller. If the enforcer's table was removed, the firewall configuration is applied again.
+pub async fn coexistence_watcher(
+    fw_service: Arc<FirewallService>,
+    settings: Arc<Settings>,
+    event_reporter: EventReporter,
+    startup_issues: Vec<CoexistenceIssue>,
+) {
+    for issue in &startup_issues {
+        event_reporter.report(PolicyEvent::CoexistenceIssue { issue: issue.clone() });
+    }
+    let mut known_issues: HashSet<CoexistenceIssue> = startup_issues.into_iter().collect();
+
+    let changed = Arc::new(Notify::new());
+    let watcher_changed = changed.clone();
+    let _ruleset_watcher = thread::spawn(move || {
+        if let Err(e) = watch_ruleset_changes(&watcher_changed) {
+            warn!(
+                "Unable to watch for changes of the firewall rules of other tables: {:?}",
+                e
+            );
+        }
+    });
+
+    loop {
+        changed.notified().await;
+        tokio::time::sleep(RULESET_CHANGE_DELAY).await;
+        let hook_priority = settings.hook_priority;
+        let inspection = match tokio::task::spawn_blocking(move || inspect_ruleset(hook_priority))
+            .await
+            .map_err(Error::from)
+            .and_then(|inspection| inspection)
+        {
+            Ok(inspection) => inspection,
+            Err(e) => {
+                warn!(
+                    "Unable to inspect the installed firewall rules of other tables: {:?}",
+                    e
+                );
+                continue;
+            },
+        };
+        debug!(
+            "Firewall rules changed, found {} coexistence issue(s)",
+            inspection.issues.len()
+        );
+        if !inspection.enforcer_chain_installed {
+            warn!("The enforcer's firewall table was removed by another program, applying it again");
+            fw_service.notify_firewall_change();
+        }
+        for issue in inspection.issues.iter().filter(|i| !known_issues.contains(i)) {
+            log_issue(issue);
+            if issue.bypasses_policy() && settings.on_bypass == BypassAction::Refuse {
+                error!(
+                    "The enforcer's rules are bypassed by other firewall tables, the firewall has to be fixed manually"
+                );
+            }
+            event_reporter.report(PolicyEvent::CoexistenceIssue { issue: issue.clone() });
+        }
+        known_issues = inspection.issues.into_iter().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::netlink::MessageBuilder;
+
+    fn chain(family: u8, table: &str, name: &str, priority: i32, policy: u32) -> BaseChain {
+        BaseChain {
+            family,
+            table: table.to_string(),
+            name: name.to_string(),
+            hook: NF_INET_FORWARD,
+            priority,
+            policy,
+        }
+    }
+
+    #[test]
+    fn test_parse_base_chain() {
+        let mut message = MessageBuilder::new(0x0a03, 0, 1);
+        message
+            .attr_str(NFTA_CHAIN_TABLE, "fw4")
+            .attr_str(NFTA_CHAIN_NAME, "forward");
+        message
+            .begin_nested(NFTA_CHAIN_HOOK)
+            .attr_u32_be(NFTA_HOOK_HOOKNUM, NF_INET_FORWARD)
+            .attr(NFTA_HOOK_PRIORITY, &(-10i32).to_be_bytes())
+            .end_nested();
+        message.attr_u32_be(NFTA_CHAIN_POLICY, NF_DROP);
+        let buffer = message.finish();
+        let attributes = netlink::messages(&buffer).next().unwrap().payload;
+        assert_eq!(
+            parse_base_chain(NFPROTO_INET, netlink::attributes(attributes)),
+            Some(chain(NFPROTO_INET, "fw4", "forward", -10, NF_DROP))
+        );
+
+        let mut message = MessageBuilder::new(0x0a03, 0, 1);
+        message
+            .attr_str(NFTA_CHAIN_TABLE, "fw4")
+            .attr_str(NFTA_CHAIN_NAME, "forward_lan");
+        let buffer = message.finish();
+        let attributes = netlink::messages(&buffer).next().unwrap().payload;
+        assert_eq!(parse_base_chain(NFPROTO_INET, netlink::attributes(attributes)), None);
+    }
+
+    #[test]
+    fn test_parse_offloading_rule() {
+        let mut message = MessageBuilder::new(0x0a06, 0, 1);
+        message
+            .attr_str(NFTA_RULE_TABLE, "fw4")
+            .attr_str(NFTA_RULE_CHAIN, "forward");
+        message
+            .begin_nested(NFTA_RULE_EXPRESSIONS)
+            .begin_nested(NFTA_LIST_ELEM)
+            .attr_str(NFTA_EXPR_NAME, "meta")
+            .end_nested()
+            .begin_nested(NFTA_LIST_ELEM)
+            .attr_str(NFTA_EXPR_NAME, "flow_offload")
+            .end_nested()
+            .end_nested();
+        let buffer = message.finish();
+        let attributes = netlink::messages(&buffer).next().unwrap().payload;
+        assert_eq!(
+            parse_offloading_rule(NFPROTO_INET, netlink::attributes(attributes)),
+            Some(("inet fw4".to_string(), "forward".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_inspect() {
+        let chains = vec![
+            chain(NFPROTO_INET, TABLE_NAME, "base_chain", 0, 1),
+            chain(NFPROTO_INET, "fw4", "forward", 0, NF_DROP),
+            chain(NFPROTO_IPV4, "legacy", "forward", -100, 1),
+            BaseChain {
+                hook: 1,
+                ..chain(NFPROTO_INET, "fw4", "input", 0, NF_DROP)
+            },
+        ];
+        let offloading_rules = vec![
+            ("inet fw4".to_string(), "forward".to_string()),
+            ("inet fw4".to_string(), "forward".to_string()),
+        ];
+        let inspection = inspect(0, &chains, &offloading_rules);
+        assert!(inspection.enforcer_chain_installed);
+        assert_eq!(
+            inspection.issues,
+            vec![
+                CoexistenceIssue::SamePriority {
+                    table: "inet fw4".to_string(),
+                    chain: "forward".to_string(),
+                    priority: 0,
+                },
+                CoexistenceIssue::DropPolicy {
+                    table: "inet fw4".to_string(),
+                    chain: "forward".to_string(),
+                    priority: 0,
+                    before_enforcer: false,
+                },
+                CoexistenceIssue::FlowOffload {
+                    table: "inet fw4".to_string(),
+                    chain: "forward".to_string(),
+                },
+            ]
+        );
+        assert!(inspection.issues[2].bypasses_policy());
+
+        let inspection = inspect(10, &chains[1..], &[]);
+        assert!(!inspection.enforcer_chain_installed);
+        assert_eq!(
+            inspection.issues,
+            vec![CoexistenceIssue::DropPolicy {
+                table: "inet fw4".to_string(),
+                chain: "forward".to_string(),
+                priority: 0,
+                before_enforcer: true,
+            }]
+        );
+    }
+}
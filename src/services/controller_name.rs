@@ -6,8 +6,8 @@ use std::net::SocketAddr;
 use crate::{error::Result, services::is_system_mode, uci::Uci};
 
 /// The folder where the configuration file should be stored.
-const CONFIG_DIR: &str = "config";
-const SAVE_DIR: &str = "/tmp/.uci_namib";
+pub(crate) const CONFIG_DIR: &str = "config";
+pub(crate) const SAVE_DIR: &str = "/tmp/.uci_namib";
 
 pub fn apply_secure_name_config(secure_name: &str, controller_addr: SocketAddr) -> Result<()> {
     let mut uci = Uci::new()?;
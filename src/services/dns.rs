@@ -2,25 +2,66 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use std::{
-    cmp::{max, Ordering},
+    cmp::{max, min, Ordering},
     collections::{BinaryHeap, HashMap, HashSet},
+    fs::File,
     hash::{Hash, Hasher},
+    io,
     net::IpAddr,
     ops::{Add, Deref},
+    path::PathBuf,
     pin::Pin,
-    sync::Arc,
-    time::Instant,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, Instant},
 };
 
-use tokio::sync::{Mutex, Notify, RwLock, RwLockWriteGuard};
+use chrono::{DateTime, Timelike, Utc};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::{Mutex, Notify, RwLock, RwLockWriteGuard},
+};
 use trust_dns_resolver::{
-    config::LookupIpStrategy, error::ResolveError, lookup_ip::LookupIp, AsyncResolver, TokioAsyncResolver,
+    config::{LookupIpStrategy, NameServerConfigGroup, Protocol, ResolverConfig, ResolverOpts},
+    error::ResolveError,
+    lookup::Lookup,
+    lookup_ip::LookupIp,
+    proto::{
+        op::Query,
+        rr::{RData, Record, RecordType},
+    },
+    AsyncResolver, Name, TokioAsyncResolver,
+};
+
+#[cfg(feature = "nftables")]
+pub use self::nftables::*;
+use crate::{
+    error::Error,
+    services::{
+        dnsmasq_log::DeviceDnsView,
+        events::{EventReporter, PolicyEvent},
+    },
+    settings::{DnsIpStrategy, DnsProtocol, DnsServer, DnsSettings},
 };
 
 /// The minimum time that is waited before refreshing the dns cache even though there are entries with a TTL of 0.
 const MIN_TIME_BEFORE_REFRESH: std::time::Duration = std::time::Duration::from_secs(30);
 
-/// Represents an entry in the DNS refresh queue. Entries define a custom ordering based on the TTLs of their corresponding DNS cache entries.
+/// Time after which a name that failed to resolve is retried for the first time. The time is doubled after each
+/// further failure.
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Maximum time between retries of a name that keeps failing to resolve.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// Maximum number of lookups performed concurrently while refreshing the DNS cache.
+const MAX_CONCURRENT_LOOKUPS: usize = 16;
+
+/// Name of the file the DNS cache is persisted to, which is located next to the config state file.
+pub const DNS_CACHE_STATE_FILE_NAME: &str = "dns_cache.json";
+
+/// Represents an entry in the DNS refresh queue. Entries define a custom ordering based on the refresh times of their corresponding DNS cache entries.
 #[derive(Debug, Clone)]
 struct DnsRefreshQueueEntry {
     /// A copy of the DNS cache entry that should be refreshed (with shared references to the lookup result and watchers).
@@ -31,17 +72,13 @@ impl Eq for DnsRefreshQueueEntry {}
 
 impl PartialEq for DnsRefreshQueueEntry {
     fn eq(&self, other: &Self) -> bool {
-        self.cache_entry.lookup_result.valid_until() == other.cache_entry.lookup_result.valid_until()
+        self.cache_entry.refresh_at == other.cache_entry.refresh_at
     }
 }
 
 impl Ord for DnsRefreshQueueEntry {
     fn cmp(&self, other: &Self) -> Ordering {
-        other
-            .cache_entry
-            .lookup_result
-            .valid_until()
-            .cmp(&self.cache_entry.lookup_result.valid_until())
+        other.cache_entry.refresh_at.cmp(&self.cache_entry.refresh_at)
     }
 }
 
@@ -58,10 +95,230 @@ struct DnsCacheEntry {
     name: String,
     /// A reference to the cached lookup result.
     lookup_result: Arc<LookupIp>,
+    /// The records of the lookup result, including the CNAME chain that was followed.
+    records: Arc<DnsRecords>,
+    /// Time at which the entry is refreshed, i.e. when the record with the shortest TTL expires.
+    refresh_at: Instant,
+    /// Expiry of the addresses of this and previous lookup results. Addresses remain valid for the grace period (or
+    /// their TTL, if it is longer) after they were last resolved.
+    addresses: Arc<HashMap<IpAddr, Instant>>,
+    /// Addresses restored from the persisted cache although they expired, which are only kept until the entry is
+    /// refreshed.
+    stale_addresses: Arc<HashSet<IpAddr>>,
+    /// Number of consecutive failed refreshes, which are retried with exponential backoff.
+    refresh_failures: u32,
     /// A shared mutable reference to a set of watcher senders for the watchers that want to be notified of changes to this entry.
     watchers: Arc<RwLock<HashSet<Arc<Pin<Box<DnsWatcherSender>>>>>>,
 }
 
+impl DnsCacheEntry {
+    /// Creates a cache entry for the given lookup result. Addresses of the previous lookup results keep their expiry.
+    fn new(
+        name: &str,
+        lookup: LookupIp,
+        previous_addresses: &HashMap<IpAddr, Instant>,
+        watchers: Arc<RwLock<HashSet<Arc<Pin<Box<DnsWatcherSender>>>>>>,
+        grace_period: Duration,
+    ) -> DnsCacheEntry {
+        let now = Instant::now();
+        let records = DnsRecords::from_lookup(name, &lookup);
+        DnsCacheEntry {
+            name: name.to_string(),
+            addresses: Arc::new(address_expiry(
+                previous_addresses,
+                lookup.iter(),
+                lookup.valid_until(),
+                now,
+                grace_period,
+            )),
+            stale_addresses: Arc::default(),
+            refresh_failures: 0,
+            refresh_at: records.refresh_time(lookup.valid_until(), now),
+            records: Arc::new(records),
+            lookup_result: Arc::new(lookup),
+            watchers,
+        }
+    }
+}
+
+/// A record of a DNS lookup result.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+pub struct DnsRecord {
+    /// Name the record belongs to.
+    pub name: String,
+    /// Target of a CNAME record or address of an A/AAAA record.
+    pub data: String,
+    /// TTL of the record in seconds.
+    pub ttl: u32,
+}
+
+/// Records a hostname resolved to, including the CNAME chain that was followed to get to its addresses.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DnsRecords {
+    /// Canonical names the hostname is an alias for, in the order in which they were followed. Empty if the hostname
+    /// has no CNAME record.
+    pub cname_chain: Vec<String>,
+    /// CNAME, A and AAAA records of the lookup result.
+    pub records: Vec<DnsRecord>,
+}
+
+impl DnsRecords {
+    /// Collects the records of the given lookup result for the given hostname.
+    fn from_lookup(name: &str, lookup: &LookupIp) -> DnsRecords {
+        let mut records: Vec<DnsRecord> = lookup
+            .as_lookup()
+            .record_iter()
+            .filter_map(|record| {
+                let data = match record.rdata() {
+                    RData::CNAME(target) => dns_name(target),
+                    RData::A(addr) => addr.to_string(),
+                    RData::AAAA(addr) => addr.to_string(),
+                    _ => return None,
+                };
+                Some(DnsRecord {
+                    name: dns_name(record.name()),
+                    data,
+                    ttl: record.ttl(),
+                })
+            })
+            .collect();
+        // Lookups of IPv4 and IPv6 addresses both contain the CNAME records.
+        records.sort();
+        records.dedup();
+
+        let mut cname_chain: Vec<String> = Vec::new();
+        let mut current = name.trim_end_matches('.').to_lowercase();
+        while let Some(cname) = records
+            .iter()
+            .find(|r| r.name == current && r.data.parse::<IpAddr>().is_err())
+        {
+            // Stop at CNAME loops, which can't resolve to any address anyway.
+            if cname_chain.contains(&cname.data) || cname_chain.len() >= records.len() {
+                break;
+            }
+            current = cname.data.clone();
+            cname_chain.push(current.clone());
+        }
+        DnsRecords { cname_chain, records }
+    }
+
+    /// Returns the time at which a lookup performed at the given time has to be refreshed: when its record with the
+    /// shortest TTL expires, but no later than the lookup result becomes invalid.
+    fn refresh_time(&self, valid_until: Instant, now: Instant) -> Instant {
+        self.records
+            .iter()
+            .map(|record| now + Duration::from_secs(u64::from(record.ttl)))
+            .fold(valid_until, min)
+    }
+}
+
+/// Formats the given DNS name without the trailing dot of fully qualified names.
+fn dns_name(name: &Name) -> String {
+    name.to_lowercase().to_utf8().trim_end_matches('.').to_string()
+}
+
+/// Represents a name that failed to resolve in the DNS cache (negative caching). Lookups of the name fail without
+/// querying the resolver until the name is retried by the refresher task.
+#[derive(Debug, Clone)]
+struct FailedResolution {
+    /// Description of the last resolution error.
+    error: String,
+    /// Number of consecutive failed resolutions.
+    failures: u32,
+    /// Time at which the name is retried.
+    retry_at: Instant,
+    /// Whether the failure has been reported to the controller.
+    reported: bool,
+    /// Watchers that want to be notified once the name resolves.
+    watchers: Arc<RwLock<HashSet<Arc<Pin<Box<DnsWatcherSender>>>>>>,
+}
+
+impl FailedResolution {
+    /// Records another failed resolution and schedules the next retry.
+    fn record_failure(&mut self, error: &ResolveError, now: Instant) {
+        self.error = error.to_string();
+        self.failures += 1;
+        self.retry_at = now + retry_delay(self.failures);
+    }
+}
+
+/// Returns the time after which a name is retried after the given number of consecutive failed resolutions.
+fn retry_delay(failures: u32) -> Duration {
+    INITIAL_RETRY_DELAY
+        .checked_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+/// Cache entry as persisted across restarts of the enforcer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PersistedDnsEntry {
+    name: String,
+    /// The addresses of the entry together with the time at which they expire.
+    addresses: Vec<(IpAddr, DateTime<Utc>)>,
+}
+
+impl PersistedDnsEntry {
+    /// Converts the given expiry of the addresses of a cache entry to wall clock time.
+    fn new(name: &str, addresses: &HashMap<IpAddr, Instant>, now: Instant, now_utc: DateTime<Utc>) -> Self {
+        let mut addresses: Vec<(IpAddr, DateTime<Utc>)> = addresses
+            .iter()
+            .filter_map(|(&addr, expiry)| {
+                let remaining = chrono::Duration::from_std(expiry.checked_duration_since(now)?).ok()?;
+                // Whole seconds suffice for the expiry, which keeps it stable between update cycles.
+                let expiry = now_utc + remaining;
+                Some((
+                    addr,
+                    expiry - chrono::Duration::nanoseconds(i64::from(expiry.nanosecond())),
+                ))
+            })
+            .collect();
+        addresses.sort();
+        PersistedDnsEntry {
+            name: name.to_string(),
+            addresses,
+        }
+    }
+
+    /// Returns the expiry of the addresses and the stale addresses, i.e. those that expired while the enforcer was not
+    /// running. Stale addresses expire after the given grace period.
+    fn address_expiry(
+        &self,
+        now: Instant,
+        now_utc: DateTime<Utc>,
+        grace_period: Duration,
+    ) -> (HashMap<IpAddr, Instant>, HashSet<IpAddr>) {
+        let mut stale_addresses = HashSet::new();
+        let addresses = self
+            .addresses
+            .iter()
+            .map(|&(addr, expiry)| match (expiry - now_utc).to_std() {
+                Ok(remaining) => (addr, now + remaining),
+                Err(_) => {
+                    stale_addresses.insert(addr);
+                    (addr, now + grace_period)
+                },
+            })
+            .collect();
+        (addresses, stale_addresses)
+    }
+}
+
+/// Returns a lookup result containing the given addresses which is valid until the given time.
+fn restored_lookup(name: &str, addresses: impl Iterator<Item=IpAddr>, valid_until: Instant) -> Option<LookupIp> {
+    let name = Name::from_utf8(name).ok()?;
+    let records: Vec<Record> = addresses
+        .map(|addr| {
+            let rdata = match addr {
+                IpAddr::V4(addr) => RData::A(addr),
+                IpAddr::V6(addr) => RData::AAAA(addr),
+            };
+            Record::from_rdata(name.clone(), 0, rdata)
+        })
+        .collect();
+    let query = Query::query(name, RecordType::A);
+    Some(Lookup::new_with_deadline(query, records.into(), valid_until).into())
+}
+
 /// DNS resolution cache for the DNS service.
 #[derive(Debug, Clone)]
 struct DnsServiceCache {
@@ -71,16 +328,24 @@ struct DnsServiceCache {
     refresh_queue: BinaryHeap<DnsRefreshQueueEntry>,
     /// Cache entries for the DNS cache.
     cache_data: HashMap<String, DnsCacheEntry>,
+    /// Names that failed to resolve, which are retried with exponential backoff.
+    failed_names: HashMap<String, FailedResolution>,
+    /// Notifies the refresher task of new failures, which are reported and retried by it.
+    failure_notify: Arc<Notify>,
+    /// Time an address remains valid after it was last resolved.
+    grace_period: Duration,
 }
 
 impl DnsServiceCache {
-    fn new() -> Result<DnsServiceCache, ResolveError> {
-        let (resolver_conf, mut resolver_opts) = trust_dns_resolver::system_conf::read_system_conf()?;
-        resolver_opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    fn new(settings: &DnsSettings) -> Result<DnsServiceCache, ResolveError> {
+        let (resolver_conf, resolver_opts) = resolver_config(settings)?;
         Ok(DnsServiceCache {
             resolver: AsyncResolver::tokio(resolver_conf, resolver_opts)?,
             refresh_queue: BinaryHeap::default(),
             cache_data: HashMap::default(),
+            failed_names: HashMap::default(),
+            failure_notify: Arc::default(),
+            grace_period: Duration::from_secs(settings.grace_period_seconds),
         })
     }
 
@@ -89,13 +354,45 @@ impl DnsServiceCache {
         self.cache_data.get(name).map(|v| v.deref().clone())
     }
 
-    /// Resolves a supplied name and adds it to the DNS cache.
-    async fn lookup_and_cache(&mut self, name: &str) -> Result<DnsCacheEntry, ResolveError> {
-        let lookup_result = DnsCacheEntry {
-            name: String::from(name),
-            lookup_result: Arc::new(self.resolver.lookup_ip(name).await?),
-            watchers: Arc::new(RwLock::new(HashSet::new())),
+    /// Returns the watchers of the given name, whether it resolved or failed to resolve.
+    fn watchers_of(&self, name: &str) -> Option<Arc<RwLock<HashSet<Arc<Pin<Box<DnsWatcherSender>>>>>>> {
+        self.cache_data
+            .get(name)
+            .map(|entry| entry.watchers.clone())
+            .or_else(|| self.failed_names.get(name).map(|failed| failed.watchers.clone()))
+    }
+
+    /// Adds the given result of resolving the supplied name to the DNS cache. If the name failed to resolve, the
+    /// failure is cached instead.
+    fn cache_lookup(
+        &mut self,
+        name: &str,
+        result: Result<LookupIp, ResolveError>,
+    ) -> Result<DnsCacheEntry, ResolveError> {
+        let lookup = match result {
+            Ok(lookup) => lookup,
+            Err(e) => {
+                warn!("Unable to resolve {:?}, retrying later: {}", name, e);
+                let mut failed = FailedResolution {
+                    error: String::new(),
+                    failures: 0,
+                    retry_at: Instant::now(),
+                    reported: false,
+                    watchers: Arc::new(RwLock::new(HashSet::new())),
+                };
+                failed.record_failure(&e, Instant::now());
+                self.failed_names.insert(name.into(), failed);
+                self.failure_notify.notify_one();
+                return Err(e);
+            },
         };
+        let lookup_result = DnsCacheEntry::new(
+            name,
+            lookup,
+            &HashMap::new(),
+            Arc::new(RwLock::new(HashSet::new())),
+            self.grace_period,
+        );
         self.cache_data.insert(name.into(), lookup_result);
         self.refresh_queue.push(DnsRefreshQueueEntry {
             cache_entry: self.cache_data.get(name).unwrap().clone(),
@@ -103,13 +400,227 @@ impl DnsServiceCache {
         Ok(self.cache_data.get(name).unwrap().clone())
     }
 
-    /// Resolves the supplied DNS name. If the name is already in the DNS cache, returns the cached result instead.
-    async fn resolve(&mut self, name: &str) -> Result<DnsCacheEntry, ResolveError> {
-        match self.cache_data.get(name) {
-            Some(v) => Ok(v.clone()),
-            None => self.lookup_and_cache(name).await,
+    /// Returns the cached result of resolving the supplied DNS name, or None if the name has to be resolved. If the
+    /// name failed to resolve before, fails without querying the resolver until the name is retried.
+    fn resolve_cached(&self, name: &str) -> Option<Result<DnsCacheEntry, ResolveError>> {
+        if let Some(failed) = self.failed_names.get(name) {
+            return Some(Err(ResolveError::from(format!(
+                "{} failed to resolve {} time(s): {}",
+                name, failed.failures, failed.error
+            ))));
+        }
+        self.cache_data.get(name).cloned().map(Ok)
+    }
+
+    /// Removes the entries that have to be refreshed at the given time from the refresh queue and returns them.
+    /// Entries without watchers are removed from the cache instead.
+    async fn take_due_entries(&mut self, now: Instant) -> Vec<DnsRefreshQueueEntry> {
+        let mut due_entries = Vec::new();
+        while let Some(queue_element) = self.refresh_queue.pop() {
+            // remove cache entries without watchers
+            if queue_element.cache_entry.watchers.read().await.is_empty() {
+                self.cache_data.remove(&queue_element.cache_entry.name);
+                continue;
+            }
+            if let Some(duration_until_invalid) = queue_element.cache_entry.refresh_at.checked_duration_since(now) {
+                if duration_until_invalid > MIN_TIME_BEFORE_REFRESH {
+                    // Return last queue element to queue.
+                    self.refresh_queue.push(queue_element);
+                    break;
+                }
+            }
+            due_entries.push(queue_element);
+        }
+        due_entries
+    }
+
+    /// Returns the failed names that have to be retried at the given time. Failed names without watchers are removed
+    /// from the cache instead.
+    async fn due_failed_names(&mut self, now: Instant) -> Vec<String> {
+        let mut due_names = Vec::new();
+        let mut unwatched_names = Vec::new();
+        for (name, failed) in &self.failed_names {
+            if failed.watchers.read().await.is_empty() {
+                unwatched_names.push(name.clone());
+            } else if failed.retry_at <= now {
+                due_names.push(name.clone());
+            }
+        }
+        for name in unwatched_names {
+            self.failed_names.remove(&name);
+        }
+        due_names
+    }
+
+    /// Returns the cache entries in the form in which they are persisted.
+    fn persisted_entries(&self) -> Vec<PersistedDnsEntry> {
+        let (now, now_utc) = (Instant::now(), Utc::now());
+        let mut entries: Vec<PersistedDnsEntry> = self
+            .cache_data
+            .values()
+            .map(|entry| PersistedDnsEntry::new(&entry.name, &entry.addresses, now, now_utc))
+            .filter(|entry| !entry.addresses.is_empty())
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// Adds the addresses of the given persisted entries to the cache, including the stale addresses which expired in
+    /// the meantime. The restored entries are revalidated in the next update cycle.
+    fn restore_entries(&mut self, entries: Vec<PersistedDnsEntry>) -> usize {
+        let (now, now_utc) = (Instant::now(), Utc::now());
+        let mut restored = 0;
+        for persisted in entries {
+            let (addresses, stale_addresses) = persisted.address_expiry(now, now_utc, self.grace_period);
+            if addresses.is_empty() || self.cache_data.contains_key(&persisted.name) {
+                continue;
+            }
+            let lookup = match restored_lookup(&persisted.name, addresses.keys().copied(), now) {
+                Some(lookup) => lookup,
+                None => {
+                    warn!(
+                        "Ignoring persisted DNS cache entry with invalid name {:?}",
+                        persisted.name
+                    );
+                    continue;
+                },
+            };
+            let entry = DnsCacheEntry {
+                name: persisted.name.clone(),
+                lookup_result: Arc::new(lookup),
+                records: Arc::default(),
+                refresh_at: now,
+                addresses: Arc::new(addresses),
+                stale_addresses: Arc::new(stale_addresses),
+                refresh_failures: 0,
+                watchers: Arc::new(RwLock::new(HashSet::new())),
+            };
+            self.refresh_queue.push(DnsRefreshQueueEntry {
+                cache_entry: entry.clone(),
+            });
+            self.cache_data.insert(persisted.name, entry);
+            restored += 1;
+        }
+        restored
+    }
+
+    /// Returns the time at which the next cache entry expires or the next failed name is retried.
+    fn next_refresh_time(&self) -> Option<Instant> {
+        let next_expiry = self.refresh_queue.peek().map(|v| v.cache_entry.refresh_at);
+        let next_retry = self.failed_names.values().map(|failed| failed.retry_at).min();
+        next_expiry.into_iter().chain(next_retry).min()
+    }
+
+    /// Reports the failures that have not been reported yet to the controller.
+    fn report_failures(&mut self, event_reporter: &EventReporter) {
+        for (name, failed) in self.failed_names.iter_mut().filter(|(_, failed)| !failed.reported) {
+            event_reporter.report(PolicyEvent::DnsResolutionFailed {
+                name: name.clone(),
+                error: failed.error.clone(),
+            });
+            failed.reported = true;
+        }
+    }
+}
+
+/// Resolves the given names concurrently, performing at most `MAX_CONCURRENT_LOOKUPS` lookups at a time.
+async fn lookup_all(
+    resolver: &TokioAsyncResolver,
+    names: Vec<String>,
+) -> HashMap<String, Result<LookupIp, ResolveError>> {
+    stream::iter(names)
+        .map(|name| async move {
+            debug!("Refreshing DNS cache entry for {:?} because cache entry expired.", name);
+            let result = resolver.lookup_ip(name.as_str()).await;
+            (name, result)
+        })
+        .buffer_unordered(MAX_CONCURRENT_LOOKUPS)
+        .collect()
+        .await
+}
+
+/// Returns the resolver configuration for the given settings. Without configured name servers, the name servers of the
+/// system configuration are used.
+fn resolver_config(settings: &DnsSettings) -> Result<(ResolverConfig, ResolverOpts), ResolveError> {
+    let (config, mut opts) = if settings.servers.is_empty() {
+        trust_dns_resolver::system_conf::read_system_conf()?
+    } else {
+        let mut name_servers = NameServerConfigGroup::new();
+        for server in &settings.servers {
+            name_servers.merge(name_server_config(server)?);
         }
+        (
+            ResolverConfig::from_parts(None, Vec::new(), name_servers),
+            ResolverOpts::default(),
+        )
+    };
+    opts.timeout = Duration::from_secs(settings.timeout_seconds);
+    opts.attempts = settings.attempts;
+    opts.validate = settings.dnssec;
+    // Keep the CNAME records of lookup results, which are reported along with the addresses.
+    opts.preserve_intermediates = true;
+    opts.ip_strategy = match settings.ip_strategy {
+        DnsIpStrategy::Ipv4Only => LookupIpStrategy::Ipv4Only,
+        DnsIpStrategy::Ipv6Only => LookupIpStrategy::Ipv6Only,
+        DnsIpStrategy::Ipv4AndIpv6 => LookupIpStrategy::Ipv4AndIpv6,
+        DnsIpStrategy::Ipv4ThenIpv6 => LookupIpStrategy::Ipv4thenIpv6,
+        DnsIpStrategy::Ipv6ThenIpv4 => LookupIpStrategy::Ipv6thenIpv4,
+    };
+    Ok((config, opts))
+}
+
+/// Returns the name server configuration for the given name server.
+fn name_server_config(server: &DnsServer) -> Result<NameServerConfigGroup, ResolveError> {
+    let tls_name = || {
+        server
+            .tls_name
+            .clone()
+            .ok_or_else(|| ResolveError::from(format!("Name server {} requires a TLS name", server.address)))
+    };
+    let name_servers = match server.protocol {
+        DnsProtocol::Udp | DnsProtocol::Tcp => {
+            let protocol = if server.protocol == DnsProtocol::Udp {
+                Protocol::Udp
+            } else {
+                Protocol::Tcp
+            };
+            // The configuration contains both a UDP and a TCP connection, only the configured one is kept.
+            let mut name_servers =
+                NameServerConfigGroup::from_ips_clear(&[server.address], server.port.unwrap_or(53), true);
+            name_servers.retain(|name_server| name_server.protocol == protocol);
+            name_servers
+        },
+        DnsProtocol::Tls => {
+            NameServerConfigGroup::from_ips_tls(&[server.address], server.port.unwrap_or(853), tls_name()?, true)
+        },
+        DnsProtocol::Https => {
+            NameServerConfigGroup::from_ips_https(&[server.address], server.port.unwrap_or(443), tls_name()?, true)
+        },
+    };
+    Ok(name_servers)
+}
+
+/// Returns the expiry of the addresses of a cache entry after a lookup: The resolved addresses expire after the grace
+/// period (or when the lookup result becomes invalid, if that is later), previously resolved addresses keep their
+/// expiry unless it has already passed.
+fn address_expiry(
+    previous: &HashMap<IpAddr, Instant>,
+    resolved: impl Iterator<Item=IpAddr>,
+    valid_until: Instant,
+    now: Instant,
+    grace_period: Duration,
+) -> HashMap<IpAddr, Instant> {
+    let expiry = max(valid_until, now + grace_period);
+    let mut addresses: HashMap<IpAddr, Instant> = previous
+        .iter()
+        .filter(|(_, &previous_expiry)| previous_expiry > now)
+        .map(|(&addr, &previous_expiry)| (addr, previous_expiry))
+        .collect();
+    for addr in resolved {
+        let addr_expiry = addresses.entry(addr).or_insert(expiry);
+        *addr_expiry = max(*addr_expiry, expiry);
     }
+    addresses
 }
 
 /// Helper struct used to notify DNS watchers of changes to watched cache entries.
@@ -117,6 +628,8 @@ impl DnsServiceCache {
 struct DnsWatcherSender {
     updated_names: Arc<Mutex<HashSet<String>>>,
     notify: Arc<Notify>,
+    /// Notified if watched cache entries were refreshed without changes, which extends the expiry of their addresses.
+    renew_notify: Arc<Notify>,
 }
 
 impl Eq for DnsWatcherSender {}
@@ -136,95 +649,332 @@ impl Hash for DnsWatcherSender {
 /// DNS Service which provides methods to query a DNS cache for entries and
 pub(crate) struct DnsService {
     cache: Arc<RwLock<DnsServiceCache>>,
+    /// Location of the file the cache is persisted to after each update cycle (if any).
+    state_path: Option<PathBuf>,
+    /// Entries written to the state file by the last update cycle, which are not written again if unchanged.
+    persisted_entries: StdMutex<Vec<PersistedDnsEntry>>,
+    /// Addresses the devices were told by dnsmasq, if hostnames are matched against them instead of the addresses
+    /// resolved by the enforcer.
+    device_view: Option<Arc<DeviceDnsView>>,
 }
 
 impl DnsService {
-    pub fn new() -> Result<DnsService, ResolveError> {
+    pub fn new(settings: &DnsSettings) -> Result<DnsService, ResolveError> {
         Ok(DnsService {
-            cache: Arc::new(RwLock::new(DnsServiceCache::new()?)),
+            cache: Arc::new(RwLock::new(DnsServiceCache::new(settings)?)),
+            state_path: None,
+            persisted_entries: StdMutex::default(),
+            device_view: Some(settings).filter(|settings| settings.device_view).map(|settings| {
+                Arc::new(DeviceDnsView::new(Duration::from_secs(
+                    settings.device_view_lifetime_seconds,
+                )))
+            }),
         })
     }
 
-    /// Asynchronous task to automatically refresh dns cache entries as they expire.
-    pub async fn auto_refresher_task(&mut self) {
+    /// Returns the view of the devices on the addresses of hostnames, which is learned from the dnsmasq query log (if
+    /// enabled).
+    pub fn device_view(&self) -> Option<Arc<DeviceDnsView>> {
+        self.device_view.clone()
+    }
+
+    /// Restores the cache persisted at the given location, so that rules for hostnames can be created without
+    /// resolving them first. The restored entries are revalidated by the refresher task, which also persists the
+    /// cache to the given location from now on.
+    pub async fn restore_from(&mut self, path: PathBuf) {
+        match File::open(&path)
+            .map_err(Error::from)
+            .and_then(|file| Ok(serde_json::from_reader(file)?))
+        {
+            Ok(entries) => {
+                let restored = self.cache.write().await.restore_entries(entries);
+                info!("Restored {} DNS cache entries from \"{}\"", restored, path.display());
+            },
+            Err(Error::IoError { source, .. }) if source.kind() == io::ErrorKind::NotFound => {
+                debug!("No persisted DNS cache at \"{}\"", path.display());
+            },
+            Err(e) => warn!("Error while reading persisted DNS cache: {:?}", e),
+        }
+        self.state_path = Some(path);
+    }
+
+    /// Persists the given cache entries to the state file (if any), unless they were already persisted.
+    fn persist(&self, entries: Vec<PersistedDnsEntry>) -> crate::error::Result<()> {
+        if let Some(path) = &self.state_path {
+            let mut persisted_entries = self.persisted_entries.lock().unwrap();
+            if *persisted_entries == entries {
+                return Ok(());
+            }
+            crate::write_state_file(path, &entries)?;
+            debug!(
+                "Persisted {} DNS cache entries at \"{}\"",
+                entries.len(),
+                path.display()
+            );
+            *persisted_entries = entries;
+        }
+        Ok(())
+    }
+
+    /// Asynchronous task to automatically refresh dns cache entries as they expire and to retry names that failed to
+    /// resolve. Resolution failures are reported to the controller using the given event reporter.
+    pub async fn auto_refresher_task(&mut self, event_reporter: EventReporter) {
+        let failure_notify = self.cache.read().await.failure_notify.clone();
         let mut next_expiry_time = None;
         loop {
-            tokio::time::sleep_until(max(
-                next_expiry_time.unwrap_or_else(Instant::now).into(),
-                Instant::now().add(MIN_TIME_BEFORE_REFRESH).into(),
-            ))
-            .await;
-            debug!("Starting new update cycle of DNS cache.");
-            let mut cache = self.cache.write().await;
-            let refresh_start = Instant::now();
-            let mut watchers_to_notify = HashSet::new();
-            let mut new_entries = Vec::new();
-            while let Some(queue_element) = cache.refresh_queue.pop() {
-                // remove cache entries without watchers
-                if queue_element.cache_entry.watchers.read().await.is_empty() {
-                    cache.cache_data.remove(&queue_element.cache_entry.name);
+            select! {
+                _ = tokio::time::sleep_until(max(
+                    next_expiry_time.unwrap_or_else(Instant::now).into(),
+                    Instant::now().add(MIN_TIME_BEFORE_REFRESH).into(),
+                )) => {}
+                _ = failure_notify.notified() => {
+                    // Report new failures right away, they are retried in a later update cycle.
+                    let mut cache = self.cache.write().await;
+                    cache.report_failures(&event_reporter);
+                    next_expiry_time = cache.next_refresh_time();
                     continue;
                 }
-                if let Some(duration_until_invalid) = queue_element
+            }
+            next_expiry_time = self.refresh_cycle(&event_reporter).await;
+        }
+    }
+
+    /// Refreshes the cache entries that expire and retries the names whose retry is due, then notifies the watchers
+    /// of the changed entries. Returns the time at which the next update cycle is due.
+    async fn refresh_cycle(&self, event_reporter: &EventReporter) -> Option<Instant> {
+        debug!("Starting new update cycle of DNS cache.");
+        let refresh_start = Instant::now();
+        // The lookups are performed without holding the lock, so that resolving other names (and with it updating
+        // the firewall) is not blocked by slow name servers.
+        let (resolver, due_entries, due_names) = {
+            let mut cache = self.cache.write().await;
+            let due_entries = cache.take_due_entries(refresh_start).await;
+            let due_names = cache.due_failed_names(refresh_start).await;
+            (cache.resolver.clone(), due_entries, due_names)
+        };
+        let names = due_entries
+            .iter()
+            .map(|queue_element| queue_element.cache_entry.name.clone())
+            .chain(due_names.iter().cloned())
+            .collect();
+        let mut results = lookup_all(&resolver, names).await;
+
+        let mut cache = self.cache.write().await;
+        let mut watchers_to_notify = HashSet::new();
+        let mut watchers_to_renew = HashSet::new();
+        for queue_element in due_entries {
+            let result = results.remove(&queue_element.cache_entry.name).unwrap();
+            if let Some(new_entry) = DnsService::merge_refreshed_entry(
+                queue_element,
+                result,
+                &mut cache,
+                &mut watchers_to_notify,
+                &mut watchers_to_renew,
+                event_reporter,
+            )
+            .await
+            {
+                cache.refresh_queue.push(new_entry);
+            }
+        }
+        for name in due_names {
+            let result = results.remove(&name).unwrap();
+            DnsService::merge_retried_name(name, result, &mut cache, &mut watchers_to_notify).await;
+        }
+        cache.report_failures(event_reporter);
+        watchers_to_notify.iter().for_each(|w| w.notify.notify_one());
+        // Watchers notified of changes install the renewed addresses anyway.
+        watchers_to_renew
+            .difference(&watchers_to_notify)
+            .for_each(|w| w.renew_notify.notify_one());
+        let next_refresh_time = cache.next_refresh_time();
+        let persisted_entries = cache.persisted_entries();
+        drop(cache);
+        self.persist(persisted_entries)
+            .unwrap_or_else(|e| warn!("Error while persisting DNS cache: {:?}", e));
+        debug!("Finished DNS cache refresh cycle.");
+        next_refresh_time
+    }
+
+    /// Updates the cache entry of the given queue element with the result of its refresh and returns the queue element
+    /// for the updated entry. If the refresh failed, the failure is reported and the cached addresses are kept until
+    /// they expire, while the refresh is retried with exponential backoff. Once all addresses expired, the name is
+    /// handled like a name that failed to resolve and `None` is returned. Changes of the CNAME chain are reported using
+    /// the given event reporter.
+    async fn merge_refreshed_entry(
+        queue_element: DnsRefreshQueueEntry,
+        result: Result<LookupIp, ResolveError>,
+        cache: &mut RwLockWriteGuard<'_, DnsServiceCache>,
+        watchers_to_notify: &mut HashSet<Arc<Pin<Box<DnsWatcherSender>>>>,
+        watchers_to_renew: &mut HashSet<Arc<Pin<Box<DnsWatcherSender>>>>,
+        event_reporter: &EventReporter,
+    ) -> Option<DnsRefreshQueueEntry> {
+        let name = queue_element.cache_entry.name.as_str();
+        let new_entry = match result {
+            Ok(v) => {
+                // Stale addresses that were not resolved again are removed right away.
+                let previous_addresses: HashMap<IpAddr, Instant> = queue_element
                     .cache_entry
-                    .lookup_result
-                    .valid_until()
-                    .checked_duration_since(refresh_start)
-                {
-                    if duration_until_invalid > MIN_TIME_BEFORE_REFRESH {
-                        // Return last queue element to queue.
-                        cache.refresh_queue.push(queue_element);
-                        break;
-                    }
-                }
-                new_entries
-                    .push(DnsService::refresh_dns_entry(queue_element, &mut cache, &mut watchers_to_notify).await);
+                    .addresses
+                    .iter()
+                    .filter(|(addr, _)| !queue_element.cache_entry.stale_addresses.contains(addr))
+                    .map(|(&addr, &expiry)| (addr, expiry))
+                    .collect();
+                DnsCacheEntry::new(
+                    name,
+                    v,
+                    &previous_addresses,
+                    queue_element.cache_entry.watchers.clone(),
+                    cache.grace_period,
+                )
+            },
+            Err(e) => {
+                return DnsService::merge_failed_refresh(queue_element, e, cache, watchers_to_notify, event_reporter)
+                    .await
+            },
+        };
+        let new_set: HashSet<IpAddr> = new_entry.lookup_result.iter().collect();
+        let old_set: HashSet<IpAddr> = queue_element.cache_entry.lookup_result.iter().collect();
+        let new_chain = &new_entry.records.cname_chain;
+        let old_chain = &queue_element.cache_entry.records.cname_chain;
+        // Entries restored from the persisted cache don't know their CNAME chain.
+        let chain_changed = new_chain != old_chain && !queue_element.cache_entry.records.records.is_empty();
+        if chain_changed {
+            info!(
+                "CNAME chain of {:?} has changed from {:?} to {:?}.",
+                name, old_chain, new_chain
+            );
+            event_reporter.report(PolicyEvent::DnsCnameChainChanged {
+                name: name.to_string(),
+                previous: old_chain.clone(),
+                current: new_chain.clone(),
+            });
+        }
+        if new_set != old_set || chain_changed {
+            debug!(
+                "IP address set for {:?} has changed from {:?} to {:?}, notifying watchers of DNS entry change.",
+                name, old_set, new_set
+            );
+            let watchers = new_entry.watchers.read().await;
+            for w in watchers.iter() {
+                watchers_to_notify.insert(w.clone());
+                w.updated_names.lock().await.insert(name.to_string());
             }
-            cache.refresh_queue.append(&mut new_entries.into());
-            watchers_to_notify.iter().for_each(|w| w.notify.notify_one());
-            next_expiry_time = cache
-                .refresh_queue
-                .peek()
-                .map(|v| v.cache_entry.lookup_result.valid_until());
-            debug!("Finished DNS cache refresh cycle.");
+        } else {
+            watchers_to_renew.extend(new_entry.watchers.read().await.iter().cloned());
         }
+        cache.cache_data.insert(name.to_string(), new_entry);
+        Some(DnsRefreshQueueEntry {
+            cache_entry: cache.cache_data.get(name).unwrap().clone(),
+        })
     }
 
-    async fn refresh_dns_entry(
+    /// Updates the cache entry of the given queue element after its refresh failed with the given error, see
+    /// `merge_refreshed_entry`.
+    async fn merge_failed_refresh(
         queue_element: DnsRefreshQueueEntry,
+        error: ResolveError,
         cache: &mut RwLockWriteGuard<'_, DnsServiceCache>,
         watchers_to_notify: &mut HashSet<Arc<Pin<Box<DnsWatcherSender>>>>,
-    ) -> DnsRefreshQueueEntry {
-        debug!(
-            "Refreshing DNS cache entry for {:?} because cache entry expired.",
-            queue_element.cache_entry.name
+        event_reporter: &EventReporter,
+    ) -> Option<DnsRefreshQueueEntry> {
+        let now = Instant::now();
+        let entry = queue_element.cache_entry;
+        let failures = entry.refresh_failures + 1;
+        if failures == 1 {
+            event_reporter.report(PolicyEvent::DnsResolutionFailed {
+                name: entry.name.clone(),
+                error: error.to_string(),
+            });
+        }
+        let addresses: HashMap<IpAddr, Instant> = entry
+            .addresses
+            .iter()
+            .filter(|(_, &expiry)| expiry > now)
+            .map(|(&addr, &expiry)| (addr, expiry))
+            .collect();
+        if addresses.is_empty() {
+            warn!(
+                "Unable to refresh DNS cache entry for {:?} ({} failure(s)), its cached addresses expired: {}",
+                entry.name, failures, error
+            );
+            for w in entry.watchers.read().await.iter() {
+                watchers_to_notify.insert(w.clone());
+                w.updated_names.lock().await.insert(entry.name.clone());
+            }
+            cache.cache_data.remove(&entry.name);
+            cache.failed_names.insert(
+                entry.name,
+                FailedResolution {
+                    error: error.to_string(),
+                    failures,
+                    retry_at: now + retry_delay(failures),
+                    reported: true,
+                    watchers: entry.watchers,
+                },
+            );
+            return None;
+        }
+        warn!(
+            "Unable to refresh DNS cache entry for {:?} ({} failure(s)), keeping the cached addresses until they \
+             expire and retrying in {:?}: {}",
+            entry.name,
+            failures,
+            retry_delay(failures),
+            error
         );
-        let name = queue_element.cache_entry.name.as_str();
-        let new_entry = cache.resolver.lookup_ip(name).await.map(|v| DnsCacheEntry {
-            name: name.to_string(),
-            lookup_result: Arc::new(v),
-            watchers: queue_element.cache_entry.watchers.clone(),
-        });
-        if let Ok(new_entry) = new_entry {
-            let new_set: HashSet<IpAddr> = new_entry.lookup_result.iter().collect();
-            let old_set: HashSet<IpAddr> = queue_element.cache_entry.lookup_result.iter().collect();
-            if new_set != old_set {
-                debug!(
-                    "IP address set for {:?} has changed from {:?} to {:?}, notifying watchers of DNS entry change.",
-                    name, old_set, new_set
+        let entry = DnsCacheEntry {
+            addresses: Arc::new(addresses),
+            refresh_failures: failures,
+            refresh_at: now + retry_delay(failures),
+            ..entry
+        };
+        cache.cache_data.insert(entry.name.clone(), entry.clone());
+        Some(DnsRefreshQueueEntry { cache_entry: entry })
+    }
+
+    /// Updates the cache with the result of retrying a name that failed to resolve. Watchers of names that resolve
+    /// again are notified, so that the rules for these names are created.
+    async fn merge_retried_name(
+        name: String,
+        result: Result<LookupIp, ResolveError>,
+        cache: &mut RwLockWriteGuard<'_, DnsServiceCache>,
+        watchers_to_notify: &mut HashSet<Arc<Pin<Box<DnsWatcherSender>>>>,
+    ) {
+        let mut failed = match cache.failed_names.remove(&name) {
+            Some(failed) => failed,
+            None => return,
+        };
+        match result {
+            Ok(lookup) => {
+                info!("{:?} resolves again after {} failure(s).", name, failed.failures);
+                let entry = DnsCacheEntry::new(
+                    &name,
+                    lookup,
+                    &HashMap::new(),
+                    failed.watchers.clone(),
+                    cache.grace_period,
                 );
-                let watchers = new_entry.watchers.read().await;
-                for w in watchers.iter() {
+                for w in failed.watchers.read().await.iter() {
                     watchers_to_notify.insert(w.clone());
-                    w.updated_names.lock().await.insert(name.to_string());
+                    w.updated_names.lock().await.insert(name.clone());
                 }
-            }
-            cache.cache_data.insert(name.to_string(), new_entry);
-            DnsRefreshQueueEntry {
-                cache_entry: cache.cache_data.get(name).unwrap().clone(),
-            }
-        } else {
-            queue_element
+                cache.refresh_queue.push(DnsRefreshQueueEntry {
+                    cache_entry: entry.clone(),
+                });
+                cache.cache_data.insert(name, entry);
+            },
+            Err(e) => {
+                failed.record_failure(&e, Instant::now());
+                debug!(
+                    "Retrying {:?} failed ({} failure(s)), next retry in {:?}: {}",
+                    name,
+                    failed.failures,
+                    retry_delay(failed.failures),
+                    e
+                );
+                cache.failed_names.insert(name, failed);
+            },
         }
     }
 
@@ -235,8 +985,11 @@ impl DnsService {
             sender: Arc::new(Pin::new(Box::new(DnsWatcherSender {
                 updated_names: Arc::default(),
                 notify: Arc::default(),
+                renew_notify: Arc::default(),
             }))),
             current_watched_entries: Mutex::default(),
+            installed_sets: StdMutex::default(),
+            device_view: self.device_view.clone(),
         }
     }
 }
@@ -249,23 +1002,45 @@ pub struct DnsWatcher {
     sender: Arc<Pin<Box<DnsWatcherSender>>>,
     /// Set of currently watched DNS entries.
     current_watched_entries: Mutex<HashSet<String>>,
+    /// Sets of the firewall configuration that is currently installed.
+    installed_sets: StdMutex<HostnameSets>,
+    /// Addresses the devices were told by dnsmasq (if enabled).
+    device_view: Option<Arc<DeviceDnsView>>,
 }
 
 impl DnsWatcher {
     /// Resolves the given DNS name and adds the name to the list of watched DNS entries.
+    /// Names that fail to resolve are watched as well, the watcher is notified once they resolve.
     pub async fn resolve_and_watch(&self, name: &str) -> Result<LookupIp, ResolveError> {
-        let mut cache = self.cache.write().await;
-        let resolved_value = cache.resolve(name).await?;
-        resolved_value.watchers.write().await.insert(self.sender.clone());
-        self.current_watched_entries.lock().await.insert(name.into());
-        Ok(resolved_value.lookup_result.deref().clone())
+        // Names that are not cached yet are resolved without holding the lock (like in the update cycle), the result
+        // is cached unless the name was cached in the meantime.
+        let mut lookup = None;
+        loop {
+            let mut cache = self.cache.write().await;
+            let resolved_value = match (cache.resolve_cached(name), lookup.take()) {
+                (Some(cached_value), _) => cached_value,
+                (None, Some(result)) => cache.cache_lookup(name, result),
+                (None, None) => {
+                    let resolver = cache.resolver.clone();
+                    drop(cache);
+                    lookup = Some(resolver.lookup_ip(name).await);
+                    continue;
+                },
+            };
+            if let Some(watchers) = cache.watchers_of(name) {
+                watchers.write().await.insert(self.sender.clone());
+                self.current_watched_entries.lock().await.insert(name.into());
+            }
+            return Ok(resolved_value?.lookup_result.deref().clone());
+        }
     }
 
     /// Removes a name from the list of watched DNS entries.
     pub async fn remove_watched_name(&self, name: &str) {
         let cache = self.cache.read().await;
-        let cache_entry = cache.resolve_if_cached(name).unwrap();
-        cache_entry.watchers.write().await.remove(&self.sender.clone());
+        if let Some(watchers) = cache.watchers_of(name) {
+            watchers.write().await.remove(&self.sender.clone());
+        }
         self.current_watched_entries.lock().await.remove(name);
     }
 
@@ -277,36 +1052,621 @@ impl DnsWatcher {
         }
     }
 
-    /// Yield until a change to any of the watched DNS entries of this watcher occurs.
+    /// Yield until a change to any of the watched DNS entries of this watcher occurs and return the names whose
+    /// addresses or CNAME chain changed (or which resolve again after failing to resolve).
     /// Returns immediately in case a change has already happened but was not waited for.
-    pub async fn address_changed(&self) {
-        self.sender.notify.notified().await
+    pub async fn address_changed(&self) -> HashSet<String> {
+        self.sender.notify.notified().await;
+        std::mem::take(&mut *self.sender.updated_names.lock().await)
+    }
+
+    /// Yield until any of the watched DNS entries was refreshed without changes, i.e. the expiry of its addresses was
+    /// extended.
+    pub async fn addresses_renewed(&self) {
+        self.sender.renew_notify.notified().await
+    }
+
+    /// Returns the addresses of the given name that have not expired yet, together with the time until they expire.
+    pub async fn addresses(&self, name: &str) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        let cache = self.cache.read().await;
+        let mut addresses: Vec<(IpAddr, Duration)> = cache
+            .resolve_if_cached(name)
+            .iter()
+            .flat_map(|entry| entry.addresses.iter())
+            .filter_map(|(&addr, expiry)| expiry.checked_duration_since(now).map(|remaining| (addr, remaining)))
+            // Element timeouts are given in milliseconds, a timeout of zero would never expire.
+            .filter(|(_, remaining)| remaining.as_millis() > 0)
+            .collect();
+        addresses.sort();
+        addresses
+    }
+
+    /// Returns the records of the cached lookup result of the given name, including its CNAME chain and the TTLs of
+    /// the records.
+    pub async fn records(&self, name: &str) -> Option<Arc<DnsRecords>> {
+        self.cache
+            .read()
+            .await
+            .resolve_if_cached(name)
+            .map(|entry| entry.records)
+    }
+
+    /// Returns whether hostnames are matched against the addresses the devices were told by dnsmasq.
+    pub fn uses_device_view(&self) -> bool {
+        self.device_view.is_some()
+    }
+
+    /// Returns the addresses the device with the given ID was told for the given name together with the addresses
+    /// resolved by the enforcer that have not expired yet, together with the time until they expire. The resolved
+    /// addresses allow the device to connect before its query was read from the query log.
+    pub async fn device_addresses(&self, device_id: i64, name: &str) -> Vec<(IpAddr, Duration)> {
+        let told_addresses = self
+            .device_view
+            .as_ref()
+            .map(|view| view.addresses(device_id, name, Instant::now()))
+            .unwrap_or_default();
+        merge_addresses(self.addresses(name).await, told_addresses)
     }
+
+    /// Yield until the addresses any device was told changed and return the device IDs and names whose addresses
+    /// changed. Never returns if the device view is disabled.
+    pub async fn device_addresses_changed(&self) -> HashSet<(i64, String)> {
+        match &self.device_view {
+            Some(view) => view.changed().await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Remembers the sets of the firewall configuration that was installed last, whose elements are renewed from now
+    /// on.
+    pub fn set_installed_sets(&self, sets: HostnameSets) {
+        *self.installed_sets.lock().unwrap() = sets;
+    }
+
+    /// Returns the sets of the firewall configuration that is currently installed.
+    pub fn installed_sets(&self) -> HostnameSets {
+        self.installed_sets.lock().unwrap().clone()
+    }
+}
+
+/// Sets created for the hostnames used by the rules of a firewall configuration. For each hostname, a set of its
+/// IPv4 and a set of its IPv6 addresses is created, whose elements expire once the addresses are no longer valid.
+/// If the device view is enabled, the sets are created per device and contain the addresses the device was told.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostnameSets {
+    /// Hostnames together with the ID of the device whose view of the hostname the sets contain (if any).
+    names: Vec<(String, Option<i64>)>,
+}
+
+impl HostnameSets {
+    /// Returns the name of the set of the given IP version for the given hostname (as seen by the given device),
+    /// adding the sets of the hostname if necessary.
+    pub fn set_name(&mut self, hostname: &str, device_id: Option<i64>, ipv6: bool) -> String {
+        let index = match self.names.iter().position(|(n, d)| n == hostname && *d == device_id) {
+            Some(index) => index,
+            None => {
+                self.names.push((hostname.to_string(), device_id));
+                self.names.len() - 1
+            },
+        };
+        hostname_set_name(index, ipv6)
+    }
+
+    /// Returns the hostnames and device IDs together with the index used in the names of their sets.
+    pub fn iter(&self) -> impl Iterator<Item=(usize, &String, Option<i64>)> {
+        self.names
+            .iter()
+            .enumerate()
+            .map(|(index, (hostname, device_id))| (index, hostname, *device_id))
+    }
+
+    /// Returns whether sets have been created for the given hostname (as seen by the given device).
+    pub fn contains(&self, hostname: &str, device_id: Option<i64>) -> bool {
+        self.names.iter().any(|(n, d)| n == hostname && *d == device_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
+
+/// Returns the name of the set of the given IP version of the hostname with the given index.
+fn hostname_set_name(index: usize, ipv6: bool) -> String {
+    format!("dns_{}_{}", index, if ipv6 { "v6" } else { "v4" })
+}
+
+/// Merges the given addresses with the time until they expire, keeping the later expiry of addresses contained in both.
+fn merge_addresses(addresses: Vec<(IpAddr, Duration)>, other: Vec<(IpAddr, Duration)>) -> Vec<(IpAddr, Duration)> {
+    let mut merged: HashMap<IpAddr, Duration> = HashMap::new();
+    for (addr, remaining) in addresses.into_iter().chain(other) {
+        let merged_remaining = merged.entry(addr).or_insert(remaining);
+        *merged_remaining = max(*merged_remaining, remaining);
+    }
+    let mut merged: Vec<(IpAddr, Duration)> = merged.into_iter().collect();
+    merged.sort();
+    merged
+}
+
+#[cfg(feature = "nftables")]
+mod nftables {
+    use std::{collections::HashSet, net::IpAddr, time::Duration};
+
+    use nftnl::{Batch, MsgType};
+
+    use super::{hostname_set_name, DnsWatcher, HostnameSets};
+    use crate::{
+        error::Result,
+        services::{
+            firewall_service::{send_and_process, TABLE_NAME},
+            nft_exprs::{AddressSet, ExpiringSetElements},
+        },
+    };
+
+    /// Maximum number of addresses added to a set in a single message.
+    const MAX_ELEMENTS_PER_MESSAGE: usize = 256;
+    /// Offset of the IDs of the hostname sets within a batch, which keeps them distinct from the IDs of the sets of
+    /// MUD abstractions.
+    const SET_ID_OFFSET: u32 = 0x8000_0000;
+
+    /// Adds messages which create the given sets with the current addresses of their hostnames to the given batch.
+    pub async fn add_hostname_sets(batch: &mut Batch, sets: &HostnameSets, watcher: &DnsWatcher) {
+        for (index, hostname, device_id) in sets.iter() {
+            let addresses = set_addresses(watcher, hostname, device_id).await;
+            for &ipv6 in &[false, true] {
+                let name = hostname_set_name(index, ipv6);
+                let set = AddressSet {
+                    table: TABLE_NAME.to_string(),
+                    name: name.clone(),
+                    id: SET_ID_OFFSET + index as u32 * 2 + u32::from(ipv6),
+                    ipv6,
+                    timeout: true,
+                };
+                batch.add(&set, MsgType::Add);
+                add_set_elements(batch, name, &addresses, ipv6);
+            }
+        }
+    }
+
+    /// Replaces the elements of the installed sets of the given hostnames and device IDs (or of all hostnames if
+    /// `None`) with their current addresses without changing any rules. This also renews the timeouts of the
+    /// addresses that are still valid.
+    pub async fn replace_hostname_set_elements(
+        watcher: &DnsWatcher,
+        hostnames: Option<&HashSet<(String, Option<i64>)>>,
+    ) -> Result<()> {
+        let sets = watcher.installed_sets();
+        let mut batch = Batch::new();
+        let mut updated_sets = 0;
+        for (index, hostname, device_id) in sets.iter() {
+            if hostnames.map_or(false, |hostnames| !hostnames.contains(&(hostname.clone(), device_id))) {
+                continue;
+            }
+            updated_sets += 1;
+            let addresses = set_addresses(watcher, hostname, device_id).await;
+            for &ipv6 in &[false, true] {
+                let name = hostname_set_name(index, ipv6);
+                // Deleting an empty list of elements removes all elements of the set.
+                let flush = ExpiringSetElements {
+                    table: TABLE_NAME.to_string(),
+                    set: name.clone(),
+                    elements: Vec::new(),
+                };
+                batch.add(&flush, MsgType::Del);
+                add_set_elements(&mut batch, name, &addresses, ipv6);
+            }
+        }
+        if updated_sets == 0 {
+            return Ok(());
+        }
+        debug!("Updating the addresses of {} hostname(s)", updated_sets);
+        send_and_process(batch.finalize(), &[])
+    }
+
+    /// Returns the addresses of the given hostname, as resolved by the enforcer or as seen by the given device.
+    async fn set_addresses(watcher: &DnsWatcher, hostname: &str, device_id: Option<i64>) -> Vec<(IpAddr, Duration)> {
+        match device_id {
+            Some(device_id) => watcher.device_addresses(device_id, hostname).await,
+            None => watcher.addresses(hostname).await,
+        }
+    }
+
+    /// Adds messages which add the given addresses of the given IP version (with their remaining validity as timeout)
+    /// to the set with the given name to the given batch.
+    fn add_set_elements(batch: &mut Batch, set: String, addresses: &[(IpAddr, Duration)], ipv6: bool) {
+        let addresses: Vec<(IpAddr, Duration)> = addresses
+            .iter()
+            .filter(|(addr, _)| addr.is_ipv6() == ipv6)
+            .copied()
+            .collect();
+        for elements in addresses.chunks(MAX_ELEMENTS_PER_MESSAGE) {
+            let elements = ExpiringSetElements {
+                table: TABLE_NAME.to_string(),
+                set: set.clone(),
+                elements: elements.to_vec(),
+            };
+            batch.add(&elements, MsgType::Add);
+        }
+    }
+}
+
+#[cfg(not(feature = "nftables"))]
+pub async fn replace_hostname_set_elements(
+    _watcher: &DnsWatcher,
+    _hostnames: Option<&HashSet<(String, Option<i64>)>>,
+) -> crate::error::Result<()> {
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use crate::{error::Result, services::dns::DnsService};
+    use std::{
+        collections::{BinaryHeap, HashMap, HashSet},
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::{Arc, Mutex as StdMutex},
+        time::{Duration, Instant},
+    };
+
+    use chrono::{Timelike, Utc};
+    use tokio::{net::UdpSocket, sync::RwLock, time::timeout};
+    use trust_dns_resolver::{
+        config::Protocol,
+        lookup::Lookup,
+        lookup_ip::LookupIp,
+        proto::{
+            op::{Message, MessageType, Query, ResponseCode},
+            rr::{RData, Record, RecordType},
+        },
+        Name,
+    };
+
+    use crate::{
+        error::Result,
+        services::{
+            dns::{
+                address_expiry, dns_name, merge_addresses, name_server_config, restored_lookup, retry_delay,
+                DnsCacheEntry, DnsRecord, DnsRecords, DnsRefreshQueueEntry, DnsService, HostnameSets,
+                PersistedDnsEntry, INITIAL_RETRY_DELAY, MAX_RETRY_DELAY,
+            },
+            events::{EventReporter, PolicyEvent},
+        },
+        settings::{DnsIpStrategy, DnsProtocol, DnsServer, DnsSettings},
+    };
+
+    /// Time waited for notifications of watchers, which are sent by the refresh cycle before it returns.
+    const NOTIFY_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// Name server on the loopback interface that answers A queries for the configured names with a TTL of 0 (so
+    /// that the resolver does not cache them) and with NXDOMAIN for all other names.
+    struct StubDnsServer {
+        addr: SocketAddr,
+        answers: Arc<StdMutex<HashMap<String, Vec<Ipv4Addr>>>>,
+    }
+
+    impl StubDnsServer {
+        async fn start() -> StubDnsServer {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let answers: Arc<StdMutex<HashMap<String, Vec<Ipv4Addr>>>> = Arc::default();
+            let server_answers = answers.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0; 512];
+                loop {
+                    let (len, peer) = socket.recv_from(&mut buffer).await.unwrap();
+                    let request = match Message::from_vec(&buffer[..len]) {
+                        Ok(request) => request,
+                        Err(_) => continue,
+                    };
+                    let response = stub_response(&request, &server_answers.lock().unwrap());
+                    socket.send_to(&response.to_vec().unwrap(), peer).await.unwrap();
+                }
+            });
+            StubDnsServer { addr, answers }
+        }
+
+        /// Sets the addresses the given name resolves to, a name without addresses does not resolve.
+        fn set_addresses(&self, name: &str, addresses: &[&str]) {
+            let mut answers = self.answers.lock().unwrap();
+            if addresses.is_empty() {
+                answers.remove(name);
+            } else {
+                answers.insert(name.to_string(), addresses.iter().map(|a| a.parse().unwrap()).collect());
+            }
+        }
+
+        /// Returns DNS settings which only use this name server.
+        fn settings(&self) -> DnsSettings {
+            DnsSettings {
+                servers: vec![DnsServer {
+                    address: self.addr.ip(),
+                    port: Some(self.addr.port()),
+                    protocol: DnsProtocol::Udp,
+                    tls_name: None,
+                }],
+                ip_strategy: DnsIpStrategy::Ipv4Only,
+                timeout_seconds: 1,
+                ..DnsSettings::default()
+            }
+        }
+    }
+
+    fn stub_response(request: &Message, answers: &HashMap<String, Vec<Ipv4Addr>>) -> Message {
+        let mut response = Message::new();
+        response
+            .set_id(request.id())
+            .set_message_type(MessageType::Response)
+            .set_op_code(request.op_code())
+            .set_recursion_desired(request.recursion_desired())
+            .set_recursion_available(true)
+            .add_queries(request.queries().to_vec());
+        let query = match request.queries().first() {
+            Some(query) => query,
+            None => return response,
+        };
+        match answers.get(&dns_name(query.name())) {
+            Some(addresses) if query.query_type() == RecordType::A => {
+                for &addr in addresses {
+                    response.add_answer(Record::from_rdata(query.name().clone(), 0, RData::A(addr)));
+                }
+            },
+            Some(_) => {},
+            None => {
+                response.set_response_code(ResponseCode::NXDomain);
+            },
+        }
+        response
+    }
+
+    /// Returns a lookup result of the given name with a single address whose record has the given TTL.
+    fn lookup(name: &str, addr: &str, ttl: u32) -> LookupIp {
+        let name = Name::from_utf8(name).unwrap();
+        let record = Record::from_rdata(name.clone(), ttl, RData::A(addr.parse().unwrap()));
+        let valid_until = Instant::now() + Duration::from_secs(u64::from(ttl));
+        Lookup::new_with_deadline(Query::query(name, RecordType::A), vec![record].into(), valid_until).into()
+    }
+
+    #[test]
+    fn test_refresh_queue_order() {
+        let entry = |name: &str, ttl| DnsRefreshQueueEntry {
+            cache_entry: DnsCacheEntry::new(
+                name,
+                lookup(name, "192.0.2.1", ttl),
+                &HashMap::new(),
+                Arc::new(RwLock::new(HashSet::new())),
+                Duration::from_secs(300),
+            ),
+        };
+        let mut queue: BinaryHeap<DnsRefreshQueueEntry> = vec![
+            entry("b.example.test", 300),
+            entry("a.example.test", 60),
+            entry("d.example.test", 0),
+            entry("c.example.test", 3600),
+        ]
+        .into_iter()
+        .collect();
+        // The entry that expires first is refreshed first.
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop())
+            .map(|queue_element| queue_element.cache_entry.name)
+            .collect();
+        assert_eq!(
+            order,
+            vec!["d.example.test", "a.example.test", "b.example.test", "c.example.test"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watcher_notification() {
+        let server = StubDnsServer::start().await;
+        server.set_addresses("www.example.test", &["192.0.2.1"]);
+        let service = DnsService::new(&server.settings()).unwrap();
+        let watcher = service.create_watcher();
+        let (event_reporter, _events) = EventReporter::new();
+        let lookup = watcher.resolve_and_watch("www.example.test").await.unwrap();
+        assert_eq!(
+            lookup.iter().collect::<Vec<_>>(),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+
+        // Refreshing an entry without changes only renews its addresses.
+        service.refresh_cycle(&event_reporter).await;
+        assert!(timeout(NOTIFY_TIMEOUT, watcher.addresses_renewed()).await.is_ok());
+        assert!(timeout(NOTIFY_TIMEOUT, watcher.address_changed()).await.is_err());
+
+        server.set_addresses("www.example.test", &["192.0.2.2"]);
+        service.refresh_cycle(&event_reporter).await;
+        let changed_names = timeout(NOTIFY_TIMEOUT, watcher.address_changed()).await.unwrap();
+        assert_eq!(
+            changed_names,
+            vec!["www.example.test".to_string()].into_iter().collect()
+        );
+        // The previous address remains valid for the grace period.
+        let addresses: Vec<IpAddr> = watcher
+            .addresses("www.example.test")
+            .await
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .collect();
+        assert_eq!(
+            addresses,
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap(), "192.0.2.2".parse().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restored_stale_addresses() {
+        let server = StubDnsServer::start().await;
+        server.set_addresses("www.example.test", &["192.0.2.2"]);
+        let service = DnsService::new(&server.settings()).unwrap();
+        let watcher = service.create_watcher();
+        let (event_reporter, _events) = EventReporter::new();
+        let expired = Utc::now() - chrono::Duration::seconds(60);
+        service.cache.write().await.restore_entries(vec![PersistedDnsEntry {
+            name: String::from("www.example.test"),
+            addresses: vec![
+                ("192.0.2.1".parse().unwrap(), expired),
+                ("192.0.2.2".parse().unwrap(), expired),
+            ],
+        }]);
+        let addresses = |addresses: Vec<(IpAddr, Duration)>| -> Vec<IpAddr> {
+            addresses.into_iter().map(|(addr, _)| addr).collect()
+        };
+
+        // Stale addresses are used until the entry is revalidated.
+        watcher.resolve_and_watch("www.example.test").await.unwrap();
+        assert_eq!(
+            addresses(watcher.addresses("www.example.test").await),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap(), "192.0.2.2".parse().unwrap()]
+        );
+
+        // Stale addresses that do not resolve anymore are removed right away.
+        service.refresh_cycle(&event_reporter).await;
+        assert!(timeout(NOTIFY_TIMEOUT, watcher.address_changed()).await.is_ok());
+        assert_eq!(
+            addresses(watcher.addresses("www.example.test").await),
+            vec!["192.0.2.2".parse::<IpAddr>().unwrap()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_refresh_failure() {
+        let server = StubDnsServer::start().await;
+        server.set_addresses("www.example.test", &["192.0.2.1"]);
+        let service = DnsService::new(&server.settings()).unwrap();
+        let watcher = service.create_watcher();
+        let (event_reporter, mut events) = EventReporter::new();
+        watcher.resolve_and_watch("www.example.test").await.unwrap();
+
+        // A failed refresh is reported and retried later, the cached addresses are kept until they expire.
+        server.set_addresses("www.example.test", &[]);
+        let refresh_start = Instant::now();
+        service.refresh_cycle(&event_reporter).await;
+        assert!(matches!(
+            events.recv().await,
+            Some(PolicyEvent::DnsResolutionFailed { name, .. }) if name == "www.example.test"
+        ));
+        let entry = service
+            .cache
+            .read()
+            .await
+            .resolve_if_cached("www.example.test")
+            .unwrap();
+        assert_eq!(entry.refresh_failures, 1);
+        assert!(entry.refresh_at >= refresh_start + INITIAL_RETRY_DELAY);
+        assert_eq!(watcher.addresses("www.example.test").await.len(), 1);
+
+        // Once the cached addresses expired, the name is handled like a name that failed to resolve.
+        {
+            let mut cache = service.cache.write().await;
+            let mut entry = cache.refresh_queue.pop().unwrap().cache_entry;
+            entry.addresses = Arc::new(entry.addresses.keys().map(|&addr| (addr, Instant::now())).collect());
+            cache.refresh_queue.push(DnsRefreshQueueEntry { cache_entry: entry });
+        }
+        service.refresh_cycle(&event_reporter).await;
+        let changed_names = timeout(NOTIFY_TIMEOUT, watcher.address_changed()).await.unwrap();
+        assert_eq!(
+            changed_names,
+            vec!["www.example.test".to_string()].into_iter().collect()
+        );
+        assert!(watcher.addresses("www.example.test").await.is_empty());
+        let cache = service.cache.read().await;
+        assert_eq!(cache.failed_names["www.example.test"].failures, 2);
+        assert!(cache.refresh_queue.is_empty());
+        // The failure is only reported once.
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_eviction_without_watchers() {
+        let server = StubDnsServer::start().await;
+        server.set_addresses("www.example.test", &["192.0.2.1"]);
+        server.set_addresses("video.example.test", &["192.0.2.2"]);
+        let service = DnsService::new(&server.settings()).unwrap();
+        let watcher = service.create_watcher();
+        let (event_reporter, _events) = EventReporter::new();
+        watcher.resolve_and_watch("www.example.test").await.unwrap();
+        watcher.resolve_and_watch("video.example.test").await.unwrap();
+        watcher.remove_watched_name("www.example.test").await;
+
+        service.refresh_cycle(&event_reporter).await;
+        let cache = service.cache.read().await;
+        assert!(cache.resolve_if_cached("www.example.test").is_none());
+        assert!(cache.resolve_if_cached("video.example.test").is_some());
+        let queued_names: Vec<&str> = cache
+            .refresh_queue
+            .iter()
+            .map(|queue_element| queue_element.cache_entry.name.as_str())
+            .collect();
+        assert_eq!(queued_names, vec!["video.example.test"]);
+    }
+
+    #[tokio::test]
+    async fn test_resolution_failure() {
+        let server = StubDnsServer::start().await;
+        let service = DnsService::new(&server.settings()).unwrap();
+        let watcher = service.create_watcher();
+        let (event_reporter, mut events) = EventReporter::new();
+        assert!(watcher.resolve_and_watch("www.example.test").await.is_err());
+        // Until the name is retried, it fails without querying the name server.
+        server.set_addresses("www.example.test", &["192.0.2.1"]);
+        assert!(watcher.resolve_and_watch("www.example.test").await.is_err());
+
+        // The retry is not due yet, but the failure is reported.
+        service.refresh_cycle(&event_reporter).await;
+        assert!(matches!(
+            events.recv().await,
+            Some(PolicyEvent::DnsResolutionFailed { name, .. }) if name == "www.example.test"
+        ));
+        assert!(service.cache.read().await.failed_names.contains_key("www.example.test"));
+
+        service
+            .cache
+            .write()
+            .await
+            .failed_names
+            .get_mut("www.example.test")
+            .unwrap()
+            .retry_at = Instant::now();
+        service.refresh_cycle(&event_reporter).await;
+        let changed_names = timeout(NOTIFY_TIMEOUT, watcher.address_changed()).await.unwrap();
+        assert_eq!(
+            changed_names,
+            vec!["www.example.test".to_string()].into_iter().collect()
+        );
+        assert!(service.cache.read().await.failed_names.is_empty());
+        let lookup = watcher.resolve_and_watch("www.example.test").await.unwrap();
+        assert_eq!(
+            lookup.iter().collect::<Vec<_>>(),
+            vec!["192.0.2.1".parse::<IpAddr>().unwrap()]
+        );
+    }
 
     #[tokio::test]
     async fn test() -> Result<()> {
-        let service = DnsService::new().unwrap();
+        let server = StubDnsServer::start().await;
+        server.set_addresses("www.example.test", &["192.0.2.1"]);
+        server.set_addresses("video.example.test", &["192.0.2.2"]);
+        let service = DnsService::new(&server.settings()).unwrap();
         let watcher = service.create_watcher();
-        let lookup = watcher.resolve_and_watch("www.google.com").await?;
-        let cache_entry = watcher.cache.read().await.resolve_if_cached("www.google.com").unwrap();
-        assert_eq!(cache_entry.name, "www.google.com");
+        let lookup = watcher.resolve_and_watch("www.example.test").await?;
+        let cache_entry = watcher
+            .cache
+            .read()
+            .await
+            .resolve_if_cached("www.example.test")
+            .unwrap();
+        assert_eq!(cache_entry.name, "www.example.test");
         assert!(cache_entry.watchers.read().await.contains(watcher.sender.as_ref()));
         assert_eq!(cache_entry.lookup_result.as_lookup(), lookup.as_lookup());
         assert!(watcher.cache.read().await.refresh_queue.peek().is_some());
 
-        watcher.resolve_and_watch("www.youtube.com").await?;
-        watcher.resolve_and_watch("www.google.com").await?;
+        watcher.resolve_and_watch("video.example.test").await?;
+        watcher.resolve_and_watch("www.example.test").await?;
 
         assert!(watcher
             .current_watched_entries
             .lock()
             .await
-            .eq(&["www.google.com", "www.youtube.com"]
+            .eq(&["www.example.test", "video.example.test"]
                 .iter()
                 .map(ToString::to_string)
                 .collect()));
@@ -316,7 +1676,7 @@ mod test {
                 .cache
                 .read()
                 .await
-                .resolve_if_cached("www.google.com")
+                .resolve_if_cached("www.example.test")
                 .unwrap()
                 .watchers
                 .read()
@@ -324,18 +1684,218 @@ mod test {
                 .len(),
             1
         );
-        assert!(watcher.cache.read().await.resolve_if_cached("www.blabla.com").is_none());
+        assert!(watcher
+            .cache
+            .read()
+            .await
+            .resolve_if_cached("unknown.example.test")
+            .is_none());
 
-        watcher.remove_watched_name("www.google.com").await;
+        watcher.remove_watched_name("www.example.test").await;
 
-        let cache_entry = watcher.cache.read().await.resolve_if_cached("www.google.com").unwrap();
+        let cache_entry = watcher
+            .cache
+            .read()
+            .await
+            .resolve_if_cached("www.example.test")
+            .unwrap();
         assert!(cache_entry.watchers.read().await.is_empty());
         assert!(watcher
             .current_watched_entries
             .lock()
             .await
-            .eq(&["www.youtube.com"].iter().map(ToString::to_string).collect()));
+            .eq(&["video.example.test"].iter().map(ToString::to_string).collect()));
 
         Ok(())
     }
+
+    #[test]
+    fn test_address_expiry() {
+        let addr = |value: &str| -> IpAddr { value.parse().unwrap() };
+        let now = Instant::now();
+        let grace_period = Duration::from_secs(300);
+        let previous: HashMap<IpAddr, Instant> = vec![
+            (addr("192.0.2.1"), now + Duration::from_secs(100)),
+            (addr("192.0.2.2"), now + Duration::from_secs(100)),
+            (addr("192.0.2.3"), now - Duration::from_secs(1)),
+        ]
+        .into_iter()
+        .collect();
+
+        // Resolved addresses are renewed, addresses no longer resolved are kept until they expire.
+        let addresses = address_expiry(
+            &previous,
+            vec![addr("192.0.2.1"), addr("2001:db8::1")].into_iter(),
+            now + Duration::from_secs(60),
+            now,
+            grace_period,
+        );
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses[&addr("192.0.2.1")], now + grace_period);
+        assert_eq!(addresses[&addr("2001:db8::1")], now + grace_period);
+        assert_eq!(addresses[&addr("192.0.2.2")], now + Duration::from_secs(100));
+
+        // A TTL longer than the grace period determines the expiry.
+        let valid_until = now + Duration::from_secs(3600);
+        let addresses = address_expiry(
+            &HashMap::new(),
+            vec![addr("192.0.2.1")].into_iter(),
+            valid_until,
+            now,
+            grace_period,
+        );
+        assert_eq!(addresses[&addr("192.0.2.1")], valid_until);
+    }
+
+    #[test]
+    fn test_name_server_config() {
+        let server = |protocol, tls_name: Option<&str>| DnsServer {
+            address: "192.0.2.53".parse().unwrap(),
+            port: None,
+            protocol,
+            tls_name: tls_name.map(String::from),
+        };
+        let udp = name_server_config(&server(DnsProtocol::Udp, None)).unwrap();
+        assert_eq!(udp.len(), 1);
+        assert_eq!(udp[0].protocol, Protocol::Udp);
+        assert_eq!(udp[0].socket_addr, "192.0.2.53:53".parse().unwrap());
+        let tls = name_server_config(&server(DnsProtocol::Tls, Some("dns.example.com"))).unwrap();
+        assert_eq!(tls[0].protocol, Protocol::Tls);
+        assert_eq!(tls[0].socket_addr, "192.0.2.53:853".parse().unwrap());
+        assert_eq!(tls[0].tls_dns_name.as_deref(), Some("dns.example.com"));
+        assert!(name_server_config(&server(DnsProtocol::Https, None)).is_err());
+    }
+
+    #[test]
+    fn test_dns_records() {
+        let name = |value: &str| Name::from_utf8(value).unwrap();
+        let record = |owner: &str, ttl, rdata| Record::from_rdata(name(owner), ttl, rdata);
+        let records = vec![
+            record("api.vendor.com.", 3600, RData::CNAME(name("api.vendor.cdn.example."))),
+            record(
+                "api.vendor.cdn.example.",
+                300,
+                RData::CNAME(name("edge-1.cdn.example.")),
+            ),
+            record("edge-1.cdn.example.", 60, RData::A("192.0.2.1".parse().unwrap())),
+            record("edge-1.cdn.example.", 60, RData::A("192.0.2.2".parse().unwrap())),
+            // The CNAME records are contained in the results of both the IPv4 and the IPv6 lookup.
+            record("api.vendor.com.", 3600, RData::CNAME(name("api.vendor.cdn.example."))),
+        ];
+        let now = Instant::now();
+        let valid_until = now + Duration::from_secs(600);
+        let query = Query::query(name("api.vendor.com."), RecordType::A);
+        let lookup = Lookup::new_with_deadline(query, records.into(), valid_until).into();
+
+        let records = DnsRecords::from_lookup("api.vendor.com", &lookup);
+        assert_eq!(
+            records.cname_chain,
+            vec!["api.vendor.cdn.example".to_string(), "edge-1.cdn.example".to_string()]
+        );
+        assert_eq!(records.records.len(), 4);
+        assert_eq!(
+            records.records[0],
+            DnsRecord {
+                name: "api.vendor.cdn.example".to_string(),
+                data: "edge-1.cdn.example".to_string(),
+                ttl: 300,
+            }
+        );
+        // The record with the shortest TTL determines when the entry is refreshed.
+        assert_eq!(records.refresh_time(valid_until, now), now + Duration::from_secs(60));
+        assert_eq!(
+            records.refresh_time(now + Duration::from_secs(30), now),
+            now + Duration::from_secs(30)
+        );
+        assert_eq!(DnsRecords::default().refresh_time(valid_until, now), valid_until);
+    }
+
+    #[test]
+    fn test_persisted_entry() {
+        let addr = |value: &str| -> IpAddr { value.parse().unwrap() };
+        let (now, now_utc) = (Instant::now(), Utc::now().with_nanosecond(0).unwrap());
+        let addresses: HashMap<IpAddr, Instant> = vec![
+            (addr("192.0.2.1"), now + Duration::from_secs(300)),
+            (addr("2001:db8::1"), now + Duration::from_secs(60)),
+        ]
+        .into_iter()
+        .collect();
+        let persisted = PersistedDnsEntry::new("www.example.com", &addresses, now, now_utc);
+        assert_eq!(
+            persisted.addresses,
+            vec![
+                (addr("192.0.2.1"), now_utc + chrono::Duration::seconds(300)),
+                (addr("2001:db8::1"), now_utc + chrono::Duration::seconds(60)),
+            ]
+        );
+        // The entry persisted in the next update cycle is unchanged.
+        let next_cycle = Duration::from_millis(300);
+        assert_eq!(
+            PersistedDnsEntry::new(
+                "www.example.com",
+                &addresses,
+                now + next_cycle,
+                now_utc + chrono::Duration::from_std(next_cycle).unwrap()
+            ),
+            persisted
+        );
+        let json = serde_json::to_string(&persisted).unwrap();
+        assert_eq!(serde_json::from_str::<PersistedDnsEntry>(&json).unwrap(), persisted);
+
+        // After a restart two minutes later, the address which expired in the meantime is restored as stale address,
+        // which expires after the grace period.
+        let later = now + Duration::from_secs(120);
+        let grace_period = Duration::from_secs(30);
+        let (restored, stale) = persisted.address_expiry(later, now_utc + chrono::Duration::seconds(120), grace_period);
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[&addr("192.0.2.1")], later + Duration::from_secs(180));
+        assert_eq!(restored[&addr("2001:db8::1")], later + grace_period);
+        assert_eq!(stale, vec![addr("2001:db8::1")].into_iter().collect());
+
+        let lookup = restored_lookup("www.example.com", vec![addr("192.0.2.1")].into_iter(), later).unwrap();
+        assert_eq!(lookup.iter().collect::<Vec<_>>(), vec![addr("192.0.2.1")]);
+        assert_eq!(lookup.valid_until(), later);
+    }
+
+    #[test]
+    fn test_merge_addresses() {
+        let addr = |value: &str| -> IpAddr { value.parse().unwrap() };
+        let secs = Duration::from_secs;
+        assert_eq!(
+            merge_addresses(
+                vec![(addr("192.0.2.2"), secs(60)), (addr("192.0.2.1"), secs(300))],
+                vec![(addr("192.0.2.2"), secs(3600)), (addr("192.0.2.3"), secs(30))],
+            ),
+            vec![
+                (addr("192.0.2.1"), secs(300)),
+                (addr("192.0.2.2"), secs(3600)),
+                (addr("192.0.2.3"), secs(30)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), INITIAL_RETRY_DELAY);
+        assert_eq!(retry_delay(2), INITIAL_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(4), INITIAL_RETRY_DELAY * 8);
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_hostname_sets() {
+        let mut sets = HostnameSets::default();
+        assert_eq!(sets.set_name("www.example.com", None, false), "dns_0_v4");
+        assert_eq!(sets.set_name("example.org", None, true), "dns_1_v6");
+        assert_eq!(sets.set_name("www.example.com", None, true), "dns_0_v6");
+        // Sets containing the view of a device are separate from the sets of the addresses resolved by the enforcer.
+        assert_eq!(sets.set_name("www.example.com", Some(5), true), "dns_2_v6");
+        assert_eq!(sets.set_name("www.example.com", Some(6), true), "dns_3_v6");
+        assert_eq!(sets.iter().count(), 4);
+        assert!(sets.contains("example.org", None));
+        assert!(!sets.contains("example.org", Some(5)));
+        assert!(sets.contains("www.example.com", Some(5)));
+        assert!(!sets.contains("example.net", None));
+    }
 }
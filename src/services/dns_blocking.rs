@@ -0,0 +1,403 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::Mutex,
+    time::Duration,
+};
+
+use namib_shared::{
+    firewall_config::{FirewallDevice, RuleTargetHost, Verdict},
+    EnforcerConfig,
+};
+
+use crate::{
+    error::Result,
+    services,
+    services::{
+        dnsmasq_log::DnsmasqLogParser,
+        events::{EventReporter, PolicyEvent},
+        log_watcher,
+    },
+    settings::DnsBlockingSettings,
+};
+
+// Blocking of domains per device, which answers the queries of a device for domains it is not allowed to reach with
+// NXDOMAIN, so that it does not even learn their addresses. dnsmasq cannot answer queries differently depending on
+// the client: tags (e.g. set by `dhcp-host=<mac>,set:<tag>`) only apply to DHCP, while `address=/<domain>/` applies
+// to all clients. Therefore the devices are grouped by the domains blocked for them and each group gets a view, an
+// additional dnsmasq instance which blocks these domains and forwards all other queries to the local dnsmasq
+// instance. The firewall redirects the DNS queries of the devices to the port of their view.
+
+/// Interval in which the dnsmasq instances of the views are checked and restarted if they exited.
+const VIEW_SUPERVISION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Additional dnsmasq instance answering the DNS queries of the devices for which the same domains are blocked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsView {
+    /// Port the instance listens on, to which the DNS queries of the devices are redirected.
+    pub port: u16,
+    /// IDs of the devices whose queries are answered by this view.
+    pub device_ids: Vec<i64>,
+    pub blocked_domains: BTreeSet<String>,
+}
+
+/// Returns the views for the given config, i.e. one view for each set of domains that is blocked for a device.
+/// The ports are assigned in the order of the blocked domains.
+pub fn dns_views(settings: &DnsBlockingSettings, config: &EnforcerConfig) -> Vec<DnsView> {
+    if !settings.enabled {
+        return Vec::new();
+    }
+    let mut device_ids: BTreeMap<BTreeSet<String>, Vec<i64>> = BTreeMap::new();
+    for device in config.devices() {
+        // The queries of devices without an address cannot be redirected.
+        if device.ipv4_addr.is_none() && device.ipv6_addr.is_none() {
+            continue;
+        }
+        let blocked_domains = blocked_domains(settings, device);
+        if !blocked_domains.is_empty() {
+            device_ids.entry(blocked_domains).or_default().push(device.id);
+        }
+    }
+    device_ids
+        .into_iter()
+        .zip(settings.first_port..=u16::MAX)
+        .map(|((blocked_domains, device_ids), port)| DnsView {
+            port,
+            device_ids,
+            blocked_domains,
+        })
+        .collect()
+}
+
+/// Returns the domains that are blocked for the given device, i.e. the configured domains that are not used by any of
+/// its rules accepting traffic.
+fn blocked_domains(settings: &DnsBlockingSettings, device: &FirewallDevice) -> BTreeSet<String> {
+    let allowed_hostnames: Vec<String> = device
+        .rules
+        .iter()
+        .filter(|rule| matches!(rule.verdict, Verdict::Accept))
+        .flat_map(|rule| Iterator::chain(rule.src.host.iter(), rule.dst.host.iter()))
+        .filter_map(|host| match host {
+            RuleTargetHost::Hostname(name) => Some(domain_name(name)),
+            _ => None,
+        })
+        .collect();
+    settings
+        .domains
+        .iter()
+        .map(|domain| domain_name(domain))
+        .filter(|domain| {
+            !allowed_hostnames
+                .iter()
+                .any(|hostname| blocked_domain(&[domain.as_str()], hostname).is_some())
+        })
+        .collect()
+}
+
+/// Normalizes the given domain name, which is case-insensitive and may be written with a trailing dot.
+fn domain_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Returns the domain of the given blocked domains the given name belongs to, i.e. which is the name itself or one
+/// of its parent domains.
+fn blocked_domain<'a, D: AsRef<str>>(domains: &'a [D], name: &str) -> Option<&'a str> {
+    domains
+        .iter()
+        .map(AsRef::as_ref)
+        .find(|&domain| name == domain || (name.ends_with(domain) && name[..name.len() - domain.len()].ends_with('.')))
+}
+
+/// Returns the configuration of the dnsmasq instance of the given view, which writes its process ID to the given PID
+/// file and logs its queries to the given query log.
+fn dnsmasq_config(settings: &DnsBlockingSettings, view: &DnsView, pid_file: &Path, query_log: &Path) -> String {
+    let mut config = String::from("# Generated by the NAMIB enforcer, changes are overwritten.\n");
+    config.push_str(&format!("port={}\npid-file={}\n", view.port, pid_file.display()));
+    // The local dnsmasq instance answers from the hosts files, all other queries are forwarded to it without caching,
+    // so that each answer is logged with the client it was sent to.
+    config.push_str("no-hosts\nno-resolv\ncache-size=0\n");
+    config.push_str(&format!("server={}\n", settings.upstream_server));
+    config.push_str(&format!("user={}\n", settings.user));
+    config.push_str(&format!("log-queries=extra\nlog-facility={}\n", query_log.display()));
+    for domain in &view.blocked_domains {
+        // An `address` without an IP address answers queries for the domain and its subdomains with NXDOMAIN.
+        config.push_str(&format!("address=/{}/\n", domain));
+    }
+    config
+}
+
+/// Running dnsmasq instance of a view.
+#[derive(Debug)]
+struct ViewInstance {
+    /// The configuration the instance was started with.
+    config: String,
+    process: Child,
+}
+
+/// Starts, stops and supervises the dnsmasq instances of the views.
+#[derive(Debug)]
+pub struct DnsViewInstances {
+    settings: DnsBlockingSettings,
+    /// Running instances by their port.
+    instances: Mutex<HashMap<u16, ViewInstance>>,
+}
+
+impl DnsViewInstances {
+    pub fn new(settings: &DnsBlockingSettings) -> DnsViewInstances {
+        let instances = DnsViewInstances {
+            settings: settings.clone(),
+            instances: Mutex::default(),
+        };
+        if services::is_system_mode() {
+            instances.stop_leftover_instances();
+        }
+        instances
+    }
+
+    /// Returns the path of the configuration file of the instance listening on the given port.
+    fn config_path(&self, port: u16) -> PathBuf {
+        self.settings.conf_dir.join(format!("view_{}.conf", port))
+    }
+
+    /// Returns the path of the PID file of the instance listening on the given port.
+    fn pid_path(&self, port: u16) -> PathBuf {
+        self.settings.conf_dir.join(format!("view_{}.pid", port))
+    }
+
+    /// Stops the instances left running by a previous run of the enforcer (as identified by their PID files), which
+    /// would prevent the new instances from listening on their ports.
+    fn stop_leftover_instances(&self) {
+        let entries = match fs::read_dir(&self.settings.conf_dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            if path.extension() != Some("pid".as_ref()) {
+                continue;
+            }
+            if let Ok(pid) = fs::read_to_string(&path) {
+                debug!("Stopping the leftover dnsmasq instance with PID {}", pid.trim());
+                Command::new("kill")
+                    .arg(pid.trim())
+                    .status()
+                    .map(|_| ())
+                    .unwrap_or_else(|e| warn!("Unable to stop the leftover dnsmasq instance: {:?}", e));
+            }
+            fs::remove_file(&path).ok();
+        }
+    }
+
+    /// Starts the dnsmasq instance listening on the given port.
+    fn spawn(&self, port: u16) -> io::Result<Child> {
+        Command::new(&self.settings.dnsmasq_path)
+            .arg("--keep-in-foreground")
+            .arg(format!("--conf-file={}", self.config_path(port).display()))
+            .stdin(Stdio::null())
+            .spawn()
+    }
+
+    /// Starts, restarts and stops the dnsmasq instances so that they match the views of the given config. Instances
+    /// whose configuration did not change keep running, so only the devices of changed views are unable to resolve
+    /// names while their instance restarts.
+    pub async fn apply(&self, config: &EnforcerConfig) -> Result<()> {
+        let query_log = log_watcher::dnsmasq_log_path();
+        let mut configs: HashMap<u16, String> = dns_views(&self.settings, config)
+            .iter()
+            .map(|view| {
+                let config = dnsmasq_config(&self.settings, view, &self.pid_path(view.port), query_log);
+                (view.port, config)
+            })
+            .collect();
+        let stopped: Vec<(u16, ViewInstance)> = {
+            let mut instances = self.instances.lock().unwrap();
+            let stopped_ports: Vec<u16> = instances
+                .iter()
+                .filter(|(port, instance)| configs.get(port) != Some(&instance.config))
+                .map(|(&port, _)| port)
+                .collect();
+            let stopped = stopped_ports
+                .into_iter()
+                .filter_map(|port| instances.remove(&port).map(|instance| (port, instance)))
+                .collect();
+            // The remaining instances keep running.
+            configs.retain(|port, _| !instances.contains_key(port));
+            stopped
+        };
+        for (port, mut instance) in stopped {
+            debug!("Stopping the dnsmasq instance of the DNS view on port {}", port);
+            instance
+                .process
+                .kill()
+                .and_then(|_| instance.process.wait())
+                .map(|_| ())
+                .unwrap_or_else(|e| debug!("Unable to stop the dnsmasq instance: {:?}", e));
+            tokio::fs::remove_file(self.pid_path(port)).await.ok();
+            if !configs.contains_key(&port) {
+                tokio::fs::remove_file(self.config_path(port))
+                    .await
+                    .unwrap_or_else(|e| debug!("Unable to remove the configuration of the DNS view: {:?}", e));
+            }
+        }
+        if configs.is_empty() {
+            return Ok(());
+        }
+        tokio::fs::create_dir_all(&self.settings.conf_dir).await?;
+        for (port, config) in configs {
+            tokio::fs::write(self.config_path(port), &config).await?;
+            if !services::is_system_mode() {
+                continue;
+            }
+            debug!("Starting the dnsmasq instance of the DNS view on port {}", port);
+            match self.spawn(port) {
+                Ok(process) => {
+                    self.instances
+                        .lock()
+                        .unwrap()
+                        .insert(port, ViewInstance { config, process });
+                },
+                Err(e) => warn!(
+                    "Unable to start the dnsmasq instance of the DNS view on port {}: {:?}",
+                    port, e
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// Restarts the dnsmasq instances that exited, as the devices of their views are unable to resolve names otherwise.
+    pub async fn supervisor_task(&self) {
+        loop {
+            tokio::time::sleep(VIEW_SUPERVISION_INTERVAL).await;
+            let mut instances = self.instances.lock().unwrap();
+            for (&port, instance) in instances.iter_mut() {
+                let status = match instance.process.try_wait() {
+                    Ok(Some(status)) => status,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!(
+                            "Unable to check the dnsmasq instance of the DNS view on port {}: {:?}",
+                            port, e
+                        );
+                        continue;
+                    },
+                };
+                warn!(
+                    "The dnsmasq instance of the DNS view on port {} exited ({}), restarting it",
+                    port, status
+                );
+                match self.spawn(port) {
+                    Ok(process) => instance.process = process,
+                    Err(e) => warn!("Unable to restart the dnsmasq instance of the DNS view: {:?}", e),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for DnsViewInstances {
+    fn drop(&mut self) {
+        let instances = std::mem::take(self.instances.get_mut().unwrap());
+        for (port, mut instance) in instances {
+            instance.process.kill().ok();
+            instance.process.wait().ok();
+            fs::remove_file(self.pid_path(port)).ok();
+        }
+    }
+}
+
+/// Reports the queries of devices for blocked domains logged by dnsmasq as policy events.
+#[derive(Debug, Clone)]
+pub struct BlockedQueryReporter {
+    settings: DnsBlockingSettings,
+    event_reporter: EventReporter,
+}
+
+impl BlockedQueryReporter {
+    /// Creates a reporter for the given settings, or `None` if blocking is disabled.
+    pub fn new(settings: &DnsBlockingSettings, event_reporter: EventReporter) -> Option<BlockedQueryReporter> {
+        if !settings.enabled {
+            return None;
+        }
+        Some(BlockedQueryReporter {
+            settings: settings.clone(),
+            event_reporter,
+        })
+    }
+
+    /// Reports the queries for domains blocked for the querying device in the given lines of the query log.
+    pub fn report_blocked_queries(&self, config: &EnforcerConfig, parser: &DnsmasqLogParser, lines: &[String]) {
+        let device_domains: HashMap<IpAddr, (i64, Vec<String>)> = config
+            .devices()
+            .iter()
+            .flat_map(|d| {
+                let domains: Vec<String> = blocked_domains(&self.settings, d).into_iter().collect();
+                Iterator::chain(
+                    d.ipv4_addr.iter().map(|&addr| IpAddr::from(addr)),
+                    d.ipv6_addr.iter().map(|&addr| IpAddr::from(addr)),
+                )
+                .map(move |addr| (addr, (d.id, domains.clone())))
+            })
+            .filter(|(_, (_, domains))| !domains.is_empty())
+            .collect();
+        if device_domains.is_empty() {
+            return;
+        }
+        for query in lines.iter().filter_map(|line| parser.parse_query(line)) {
+            if let Some((device_id, domains)) = device_domains.get(&query.client) {
+                if blocked_domain(domains, &query.name).is_some() {
+                    self.event_reporter.report(PolicyEvent::DnsQueryBlocked {
+                        device_id: *device_id,
+                        name: query.name,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocked_domain() {
+        let domains = ["tracker.example", "ads.vendor.com"];
+        assert_eq!(blocked_domain(&domains, "tracker.example"), Some("tracker.example"));
+        assert_eq!(blocked_domain(&domains, "eu.tracker.example"), Some("tracker.example"));
+        assert_eq!(blocked_domain(&domains, "x.ads.vendor.com"), Some("ads.vendor.com"));
+        assert_eq!(blocked_domain(&domains, "notracker.example"), None);
+        assert_eq!(blocked_domain(&domains, "vendor.com"), None);
+        assert_eq!(blocked_domain(&domains, "example"), None);
+    }
+
+    #[test]
+    fn test_dnsmasq_config() {
+        let settings = DnsBlockingSettings::default();
+        let view = DnsView {
+            port: 5401,
+            device_ids: vec![3, 4],
+            blocked_domains: vec![String::from("tracker.example"), String::from("ads.vendor.com")]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(
+            dnsmasq_config(
+                &settings,
+                &view,
+                "/tmp/namib/dnsmasq/view_5401.pid".as_ref(),
+                "/tmp/dnsmasq.log".as_ref()
+            ),
+            "# Generated by the NAMIB enforcer, changes are overwritten.\nport=5401\n\
+             pid-file=/tmp/namib/dnsmasq/view_5401.pid\nno-hosts\nno-resolv\ncache-size=0\nserver=127.0.0.1#53\n\
+             user=dnsmasq\nlog-queries=extra\nlog-facility=/tmp/dnsmasq.log\naddress=/ads.vendor.com/\n\
+             address=/tracker.example/\n"
+        );
+        assert_eq!(domain_name("Tracker.Example."), "tracker.example");
+    }
+}
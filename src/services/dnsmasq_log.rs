@@ -0,0 +1,465 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+use tokio::sync::Notify;
+
+// Parsing of the query log of dnsmasq (`log-queries`), which is used to learn the addresses each device was told for
+// the names it queried. Queries are logged as
+//     query[A] api.vendor.com from 192.168.1.15
+// followed by the records of the answer, starting with the CNAME chain (if any):
+//     reply api.vendor.com is <CNAME>
+//     reply edge.cdn.example is 192.0.2.1
+// With `log-queries=extra`, each line is prefixed with the serial number and the client of the request, which allows
+// attributing answers to concurrent requests for the same name. The dnsmasq instances used for blocking domains write
+// to the same log, so the serial numbers are only unique together with the process ID.
+
+/// Maximum number of requests that are waiting for an answer. Requests that are never answered (e.g. because the
+/// upstream server timed out) are discarded once the limit is exceeded.
+const MAX_PENDING_REQUESTS: usize = 1024;
+
+/// Pattern of the log lines of queries and answers, optionally prefixed with the serial number and client of the
+/// request.
+const LOG_LINE_PATTERN: &str = r"^(?:.*dnsmasq\[(?P<pid>\d+)\]: )?(?:(?P<serial>\d+) (?P<client>[0-9A-Fa-f.:]+)/\d+ )?(?P<kind>\S+) (?P<name>\S+) (?P<verb>from|to|is) (?P<value>\S+)$";
+
+/// Value of an answer record logged by dnsmasq.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnswerValue {
+    Address(IpAddr),
+    /// The name is an alias, the next logged record belongs to its canonical name.
+    Cname,
+    /// Negative answers (e.g. `NXDOMAIN` or `NODATA`) and records without an address.
+    Other,
+}
+
+/// Entry of the dnsmasq query log which is relevant for learning the addresses of names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LogEntry {
+    Query { name: String, client: IpAddr },
+    Answer { name: String, value: AnswerValue },
+}
+
+/// Parses a line of the dnsmasq query log using the regex of `LOG_LINE_PATTERN`. Returns the process ID of dnsmasq
+/// (0 if it is not logged) and the serial number of the request (only logged with `log-queries=extra`) and the entry,
+/// or `None` for lines that are neither a query nor an answer.
+fn parse_line(regex: &Regex, line: &str) -> Option<(Option<(u32, u32)>, LogEntry)> {
+    let captures = regex.captures(line.trim_end())?;
+    let pid = captures
+        .name("pid")
+        .and_then(|pid| pid.as_str().parse().ok())
+        .unwrap_or(0);
+    let serial = captures
+        .name("serial")
+        .and_then(|serial| serial.as_str().parse().ok())
+        .map(|serial| (pid, serial));
+    let name = captures["name"].trim_end_matches('.').to_lowercase();
+    let value = &captures["value"];
+    let entry = match (&captures["kind"], &captures["verb"]) {
+        (kind, "from") if kind.starts_with("query[") => LogEntry::Query {
+            name,
+            client: value.parse().ok()?,
+        },
+        // Answers are logged as `reply` (from the upstream server), `cached`, `config` (e.g. `address=`) or with the
+        // path of the hosts file they were read from.
+        (kind, "is") if kind == "reply" || kind == "cached" || kind == "config" || kind.starts_with('/') => {
+            let value = match value {
+                "<CNAME>" => AnswerValue::Cname,
+                value => value.parse().map_or(AnswerValue::Other, AnswerValue::Address),
+            };
+            LogEntry::Answer { name, value }
+        },
+        _ => return None,
+    };
+    Some((serial, entry))
+}
+
+/// Address a client was told for a name it queried.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LearnedAddress {
+    pub client: IpAddr,
+    /// The name queried by the client (not the canonical name the address belongs to).
+    pub name: String,
+    pub addr: IpAddr,
+}
+
+/// Query of a client logged by dnsmasq.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedQuery {
+    pub client: IpAddr,
+    pub name: String,
+}
+
+/// Answer that is currently being logged.
+#[derive(Debug, Clone)]
+struct AnswerContext {
+    /// The queried name.
+    name: String,
+    /// Clients which queried the name.
+    clients: Vec<IpAddr>,
+    /// Name of the last record of the answer.
+    last_name: String,
+    /// Whether the last record was a CNAME, i.e. the next record belongs to the canonical name.
+    follows_cname: bool,
+}
+
+impl AnswerContext {
+    fn new(name: String, clients: Vec<IpAddr>) -> AnswerContext {
+        AnswerContext {
+            last_name: name.clone(),
+            name,
+            clients,
+            follows_cname: false,
+        }
+    }
+
+    /// Returns whether a record with the given name is part of this answer.
+    fn contains(&self, name: &str) -> bool {
+        self.follows_cname || self.last_name == name
+    }
+
+    /// Adds the given record to the answer and returns the addresses learned from it.
+    fn add_record(&mut self, name: String, value: AnswerValue) -> Vec<LearnedAddress> {
+        self.last_name = name;
+        self.follows_cname = value == AnswerValue::Cname;
+        match value {
+            AnswerValue::Address(addr) => self
+                .clients
+                .iter()
+                .map(|&client| LearnedAddress {
+                    client,
+                    name: self.name.clone(),
+                    addr,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Parser of the dnsmasq query log, which attributes the logged answers to the clients that queried them.
+#[derive(Debug)]
+pub struct DnsmasqLogParser {
+    line_regex: Regex,
+    /// Clients waiting for an answer, by the queried name (without `log-queries=extra`).
+    pending_names: HashMap<String, Vec<IpAddr>>,
+    /// The answer that is currently being logged (without `log-queries=extra`).
+    current_answer: Option<AnswerContext>,
+    /// Answers of the requests by the process ID of dnsmasq and their serial number (with `log-queries=extra`).
+    requests: HashMap<(u32, u32), AnswerContext>,
+}
+
+impl Default for DnsmasqLogParser {
+    fn default() -> Self {
+        DnsmasqLogParser::new()
+    }
+}
+
+impl DnsmasqLogParser {
+    pub fn new() -> DnsmasqLogParser {
+        DnsmasqLogParser {
+            line_regex: Regex::new(LOG_LINE_PATTERN).unwrap(),
+            pending_names: HashMap::new(),
+            current_answer: None,
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Processes the given line of the query log and returns the addresses learned from it.
+    pub fn process_line(&mut self, line: &str) -> Vec<LearnedAddress> {
+        match parse_line(&self.line_regex, line) {
+            Some((Some(request), entry)) => self.process_request_entry(request, entry),
+            Some((None, entry)) => self.process_entry(entry),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the query of the given line of the query log, if it logs a query. Does not change the state of the
+    /// parser.
+    pub fn parse_query(&self, line: &str) -> Option<LoggedQuery> {
+        match parse_line(&self.line_regex, line)? {
+            (_, LogEntry::Query { name, client }) => Some(LoggedQuery { client, name }),
+            _ => None,
+        }
+    }
+
+    /// Processes an entry logged with the process ID of dnsmasq and the serial number of its request.
+    fn process_request_entry(&mut self, request: (u32, u32), entry: LogEntry) -> Vec<LearnedAddress> {
+        match entry {
+            LogEntry::Query { name, client } => {
+                if self.requests.len() >= MAX_PENDING_REQUESTS {
+                    // Serial numbers are increasing, so the oldest request (of any process) is discarded.
+                    if let Some(&oldest) = self.requests.keys().min_by_key(|(_, serial)| *serial) {
+                        self.requests.remove(&oldest);
+                    }
+                }
+                self.requests.insert(request, AnswerContext::new(name, vec![client]));
+                Vec::new()
+            },
+            LogEntry::Answer { name, value } => match self.requests.get_mut(&request) {
+                Some(answer) => answer.add_record(name, value),
+                None => Vec::new(),
+            },
+        }
+    }
+
+    /// Processes an entry logged without the serial number of its request. Answers are attributed to the clients
+    /// that queried the name since its last answer.
+    fn process_entry(&mut self, entry: LogEntry) -> Vec<LearnedAddress> {
+        match entry {
+            LogEntry::Query { name, client } => {
+                if self.pending_names.len() >= MAX_PENDING_REQUESTS {
+                    self.pending_names.clear();
+                }
+                let clients = self.pending_names.entry(name).or_default();
+                if !clients.contains(&client) {
+                    clients.push(client);
+                }
+                Vec::new()
+            },
+            LogEntry::Answer { name, value } => {
+                if !self
+                    .current_answer
+                    .as_ref()
+                    .map_or(false, |answer| answer.contains(&name))
+                {
+                    self.current_answer = self
+                        .pending_names
+                        .remove(&name)
+                        .map(|clients| AnswerContext::new(name.clone(), clients));
+                }
+                match &mut self.current_answer {
+                    Some(answer) => answer.add_record(name, value),
+                    None => Vec::new(),
+                }
+            },
+        }
+    }
+}
+
+/// Addresses each device was told for the names it queried, as learned from the dnsmasq query log.
+#[derive(Debug)]
+pub struct DeviceDnsView {
+    /// Time an address remains valid for a device after it was last told the address.
+    lifetime: Duration,
+    /// Expiry of the addresses by device ID and queried name.
+    addresses: Mutex<HashMap<(i64, String), HashMap<IpAddr, Instant>>>,
+    /// Device IDs and names whose addresses changed since the last notification.
+    changed: Mutex<HashSet<(i64, String)>>,
+    notify: Notify,
+}
+
+impl DeviceDnsView {
+    pub fn new(lifetime: Duration) -> DeviceDnsView {
+        DeviceDnsView {
+            lifetime,
+            addresses: Mutex::default(),
+            changed: Mutex::default(),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Records that the device with the given ID was told the given address for the given name at the given time.
+    /// Expired addresses of all devices are removed.
+    pub fn learn(&self, device_id: i64, name: &str, addr: IpAddr, now: Instant) {
+        let mut addresses = self.addresses.lock().unwrap();
+        addresses
+            .entry((device_id, name.to_string()))
+            .or_default()
+            .insert(addr, now + self.lifetime);
+        addresses.retain(|_, name_addresses| {
+            name_addresses.retain(|_, expiry| *expiry > now);
+            !name_addresses.is_empty()
+        });
+        // The expiry of known addresses is renewed as well.
+        self.changed.lock().unwrap().insert((device_id, name.to_string()));
+    }
+
+    /// Notifies the waiting task of the addresses learned since the last notification, if any.
+    pub fn notify_changes(&self) {
+        if !self.changed.lock().unwrap().is_empty() {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Yield until the addresses of any device changed and return the device IDs and names whose addresses changed.
+    pub async fn changed(&self) -> HashSet<(i64, String)> {
+        self.notify.notified().await;
+        std::mem::take(&mut *self.changed.lock().unwrap())
+    }
+
+    /// Returns the addresses the device with the given ID was told for the given name that have not expired yet,
+    /// together with the time until they expire.
+    pub fn addresses(&self, device_id: i64, name: &str, now: Instant) -> Vec<(IpAddr, Duration)> {
+        let addresses = self.addresses.lock().unwrap();
+        let mut addresses: Vec<(IpAddr, Duration)> = addresses
+            .get(&(device_id, name.to_string()))
+            .iter()
+            .flat_map(|name_addresses| name_addresses.iter())
+            .filter_map(|(&addr, expiry)| expiry.checked_duration_since(now).map(|remaining| (addr, remaining)))
+            .filter(|(_, remaining)| remaining.as_millis() > 0)
+            .collect();
+        addresses.sort();
+        addresses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn learned(client: &str, name: &str, addr: &str) -> LearnedAddress {
+        LearnedAddress {
+            client: client.parse().unwrap(),
+            name: name.to_string(),
+            addr: addr.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let regex = Regex::new(LOG_LINE_PATTERN).unwrap();
+        let parse_line = |line| parse_line(&regex, line);
+        assert_eq!(
+            parse_line("Jun 13 12:00:00 dnsmasq[1234]: query[A] API.vendor.com from 192.168.1.15"),
+            Some((
+                None,
+                LogEntry::Query {
+                    name: "api.vendor.com".to_string(),
+                    client: "192.168.1.15".parse().unwrap(),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_line("Jun 13 12:00:00 dnsmasq[1234]: 12 fd00::15/53412 reply edge.cdn.example is 2001:db8::1"),
+            Some((
+                Some((1234, 12)),
+                LogEntry::Answer {
+                    name: "edge.cdn.example".to_string(),
+                    value: AnswerValue::Address("2001:db8::1".parse().unwrap()),
+                }
+            ))
+        );
+        assert_eq!(
+            parse_line("dnsmasq[1234]: /etc/hosts router.lan is <CNAME>"),
+            Some((
+                None,
+                LogEntry::Answer {
+                    name: "router.lan".to_string(),
+                    value: AnswerValue::Cname,
+                }
+            ))
+        );
+        assert_eq!(
+            parse_line("dnsmasq[1234]: cached api.vendor.com is NXDOMAIN").map(|(_, entry)| entry),
+            Some(LogEntry::Answer {
+                name: "api.vendor.com".to_string(),
+                value: AnswerValue::Other,
+            })
+        );
+        assert_eq!(
+            parse_line("dnsmasq[1234]: forwarded api.vendor.com to 192.0.2.53"),
+            None
+        );
+        assert_eq!(parse_line("dnsmasq[1234]: read /etc/hosts - 4 addresses"), None);
+    }
+
+    #[test]
+    fn test_parser() {
+        let mut parser = DnsmasqLogParser::new();
+        let log = "\
+dnsmasq[1]: query[A] api.vendor.com from 192.168.1.15
+dnsmasq[1]: forwarded api.vendor.com to 192.0.2.53
+dnsmasq[1]: query[A] api.vendor.com from 192.168.1.16
+dnsmasq[1]: query[AAAA] www.example.com from 192.168.1.16
+dnsmasq[1]: reply api.vendor.com is <CNAME>
+dnsmasq[1]: reply edge.cdn.example is 192.0.2.1
+dnsmasq[1]: reply edge.cdn.example is 192.0.2.2
+dnsmasq[1]: reply www.example.com is NODATA-IPv6
+dnsmasq[1]: cached api.vendor.com is 192.0.2.3";
+        let learned_addresses: Vec<LearnedAddress> = log.lines().flat_map(|line| parser.process_line(line)).collect();
+        assert_eq!(
+            learned_addresses,
+            vec![
+                learned("192.168.1.15", "api.vendor.com", "192.0.2.1"),
+                learned("192.168.1.16", "api.vendor.com", "192.0.2.1"),
+                learned("192.168.1.15", "api.vendor.com", "192.0.2.2"),
+                learned("192.168.1.16", "api.vendor.com", "192.0.2.2"),
+            ]
+        );
+        assert_eq!(
+            parser.parse_query("dnsmasq[1]: query[A] API.vendor.com. from 192.168.1.15"),
+            Some(LoggedQuery {
+                client: "192.168.1.15".parse().unwrap(),
+                name: String::from("api.vendor.com"),
+            })
+        );
+        assert_eq!(
+            parser.parse_query("dnsmasq[1]: reply edge.cdn.example is 192.0.2.1"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parser_extra() {
+        let mut parser = DnsmasqLogParser::new();
+        let log = "\
+dnsmasq[1]: 7 192.168.1.15/4711 query[A] api.vendor.com from 192.168.1.15
+dnsmasq[1]: 8 192.168.1.16/4712 query[A] api.vendor.com from 192.168.1.16
+dnsmasq[2]: 7 192.168.1.17/4713 query[A] api.vendor.com from 192.168.1.17
+dnsmasq[1]: 8 192.168.1.16/4712 reply api.vendor.com is <CNAME>
+dnsmasq[1]: 8 192.168.1.16/4712 reply edge-2.cdn.example is 192.0.2.2
+dnsmasq[2]: 7 192.168.1.17/4713 reply api.vendor.com is 192.0.2.3
+dnsmasq[1]: 7 192.168.1.15/4711 reply api.vendor.com is <CNAME>
+dnsmasq[1]: 7 192.168.1.15/4711 reply edge-1.cdn.example is 192.0.2.1";
+        let learned_addresses: Vec<LearnedAddress> = log.lines().flat_map(|line| parser.process_line(line)).collect();
+        assert_eq!(
+            learned_addresses,
+            vec![
+                learned("192.168.1.16", "api.vendor.com", "192.0.2.2"),
+                learned("192.168.1.17", "api.vendor.com", "192.0.2.3"),
+                learned("192.168.1.15", "api.vendor.com", "192.0.2.1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_device_dns_view() {
+        let view = DeviceDnsView::new(Duration::from_secs(3600));
+        let now = Instant::now();
+        let addr = |value: &str| -> IpAddr { value.parse().unwrap() };
+        view.learn(5, "api.vendor.com", addr("192.0.2.1"), now);
+        view.learn(5, "api.vendor.com", addr("192.0.2.2"), now + Duration::from_secs(1800));
+        view.learn(6, "api.vendor.com", addr("192.0.2.3"), now + Duration::from_secs(1800));
+
+        let later = now + Duration::from_secs(3000);
+        assert_eq!(
+            view.addresses(5, "api.vendor.com", later),
+            vec![
+                (addr("192.0.2.1"), Duration::from_secs(600)),
+                (addr("192.0.2.2"), Duration::from_secs(2400)),
+            ]
+        );
+        assert_eq!(
+            view.addresses(6, "api.vendor.com", later),
+            vec![(addr("192.0.2.3"), Duration::from_secs(2400))]
+        );
+        assert!(view.addresses(5, "www.example.com", later).is_empty());
+        assert_eq!(
+            view.addresses(5, "api.vendor.com", now + Duration::from_secs(3600)),
+            vec![(addr("192.0.2.2"), Duration::from_secs(1800))]
+        );
+        assert_eq!(
+            view.changed.lock().unwrap().clone(),
+            vec![(5, "api.vendor.com".to_string()), (6, "api.vendor.com".to_string())]
+                .into_iter()
+                .collect()
+        );
+    }
+}
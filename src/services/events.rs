@@ -0,0 +1,166 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{mpsc, RwLock};
+
+use crate::{rpc::rpc_client, services::coexistence::CoexistenceIssue, Enforcer};
+
+/// Prefix of log lines sent to the controller that contain an event (serialized as JSON) instead of a log message.
+pub const EVENT_LOG_PREFIX: &str = "namib_enforcer event: ";
+
+/// Time span in which identical events are only reported once.
+const EVENT_DEDUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Number of events that may be queued for reporting before new events are discarded.
+const EVENT_QUEUE_SIZE: usize = 256;
+
+/// Kind of a traffic limit configured in the enforcer settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKind {
+    /// Bytes per second.
+    Rate,
+    /// New connections per second.
+    ConnectionRate,
+    /// Total number of bytes.
+    Quota,
+    /// Number of concurrent connections.
+    Connections,
+}
+
+/// Kind of an anomaly in the connection attempts of a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnomalyKind {
+    /// Connection attempts to many ports of a single host.
+    PortScan,
+    /// Connection attempts to many different hosts.
+    FanOut,
+    /// Many connection attempts rejected by the firewall.
+    RejectedSpike,
+}
+
+/// Event detected by the enforcer which is reported to the controller.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PolicyEvent {
+    /// Traffic of a device (or of a single rule of a device) exceeded a configured limit and was dropped.
+    LimitExceeded {
+        device_id: i64,
+        rule_index: Option<usize>,
+        limit: LimitKind,
+    },
+    /// Anomalous connection attempts of a device were detected.
+    AnomalyDetected {
+        device_id: i64,
+        anomaly: AnomalyKind,
+        /// Whether the device has been quarantined because of the anomaly.
+        quarantined: bool,
+    },
+    /// Another firewall table interferes with the enforcer's rules.
+    CoexistenceIssue { issue: CoexistenceIssue },
+    /// Traffic of a device was not matched by any of its rules and accepted by its `accept_and_log` fallback verdict.
+    UnmatchedTraffic { device_id: i64 },
+    /// A hostname used by rules failed to resolve (or to be refreshed), the rules for it are missing (once its cached
+    /// addresses expired) until it is resolved by a retry.
+    DnsResolutionFailed { name: String, error: String },
+    /// The CNAME chain a hostname used by rules resolves through has changed, e.g. because it moved to another CDN.
+    DnsCnameChainChanged {
+        name: String,
+        previous: Vec<String>,
+        current: Vec<String>,
+    },
+    /// A device queried a name of a domain blocked for it, which its DNS view answered with `NXDOMAIN`.
+    DnsQueryBlocked { device_id: i64, name: String },
+}
+
+/// Handle used to queue events for reporting to the controller.
+#[derive(Debug, Clone)]
+pub struct EventReporter {
+    sender: mpsc::Sender<PolicyEvent>,
+}
+
+impl EventReporter {
+    /// Creates a new event reporter and the receiver that has to be passed to `event_reporter_task`.
+    pub fn new() -> (EventReporter, mpsc::Receiver<PolicyEvent>) {
+        let (sender, receiver) = mpsc::channel(EVENT_QUEUE_SIZE);
+        (EventReporter { sender }, receiver)
+    }
+
+    /// Queues the given event for reporting. Events are discarded if the queue is full.
+    pub fn report(&self, event: PolicyEvent) {
+        if let Err(e) = self.sender.try_send(event) {
+            warn!("Unable to queue event for reporting: {:?}", e);
+        }
+    }
+}
+
+/// Formats the given event as a log line for the controller.
+fn event_log_line(event: &PolicyEvent) -> serde_json::Result<String> {
+    Ok(format!("{}{}", EVENT_LOG_PREFIX, serde_json::to_string(event)?))
+}
+
+/// Task which reports queued events to the controller.
+/// Identical events are only reported once per `EVENT_DEDUP_INTERVAL`.
+pub async fn event_reporter_task(enforcer: Arc<RwLock<Enforcer>>, mut receiver: mpsc::Receiver<PolicyEvent>) {
+    let mut last_reported: HashMap<PolicyEvent, Instant> = HashMap::new();
+    while let Some(event) = receiver.recv().await {
+        let now = Instant::now();
+        last_reported.retain(|_, reported| now.duration_since(*reported) < EVENT_DEDUP_INTERVAL);
+        if last_reported.contains_key(&event) {
+            continue;
+        }
+        let line = match event_log_line(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Unable to serialize event {:?}: {:?}", event, e);
+                continue;
+            },
+        };
+        info!("Reporting event to controller: {:?}", event);
+        last_reported.insert(event, now);
+        if let Err(e) = enforcer
+            .read()
+            .await
+            .client
+            .send_logs(rpc_client::current_rpc_context(), vec![line])
+            .await
+        {
+            warn!("Failed to report event to controller: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log_line() {
+        let event = PolicyEvent::LimitExceeded {
+            device_id: 3,
+            rule_index: None,
+            limit: LimitKind::ConnectionRate,
+        };
+        assert_eq!(
+            event_log_line(&event).unwrap(),
+            r#"namib_enforcer event: {"event":"limit_exceeded","device_id":3,"rule_index":null,"limit":"connection_rate"}"#
+        );
+        let event = PolicyEvent::AnomalyDetected {
+            device_id: 3,
+            anomaly: AnomalyKind::PortScan,
+            quarantined: true,
+        };
+        assert_eq!(
+            event_log_line(&event).unwrap(),
+            r#"namib_enforcer event: {"event":"anomaly_detected","device_id":3,"anomaly":"port_scan","quarantined":true}"#
+        );
+    }
+}
@@ -1,13 +1,16 @@
 // Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
-#[cfg(feature = "nftables")]
-use std::ffi::CString;
 use std::{
+    collections::{HashMap, HashSet},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
+#[cfg(feature = "nftables")]
+use std::{convert::TryFrom, ffi::CString};
 
+#[cfg(feature = "nftables")]
+use chrono::Utc;
 use namib_shared::firewall_config::{FirewallDevice, FirewallRule};
 #[cfg(feature = "nftables")]
 use namib_shared::{
@@ -16,7 +19,7 @@ use namib_shared::{
 };
 #[cfg(feature = "nftables")]
 use nftnl::{
-    expr::{IcmpCode, RejectionType, Verdict as VerdictExpr},
+    expr::{ct::States, Cmp, CmpOp, IcmpCode, RejectionType, Verdict as VerdictExpr},
     nft_expr, Batch, Chain, FinalizedBatch, ProtoFamily, Rule, Table,
 };
 use tokio::{
@@ -24,7 +27,39 @@ use tokio::{
     sync::{Notify, RwLock},
 };
 
-use crate::{error::Result, services::dns::DnsWatcher, Enforcer};
+use crate::{
+    error::Result,
+    services::{
+        abstractions::AbstractionResolver,
+        dns::{replace_hostname_set_elements, DnsWatcher},
+        dns_blocking::DnsViewInstances,
+    },
+    settings::Settings,
+    Enforcer,
+};
+#[cfg(feature = "nftables")]
+use crate::{
+    services::{
+        abstractions::{add_abstraction_sets, AbstractionKey, AbstractionSets},
+        batch_errors,
+        dns::{add_hostname_sets, HostnameSets},
+        dns_blocking,
+        events::LimitKind,
+        netlink,
+        nflog::{LogKind, LogPrefix, LOGGED_PACKET_SNAPLEN},
+        nft_exprs::{
+            ConnLimit, FlowOffload, Flowtable, HostToNetwork, Immediate32, ImmediatePort, Limit, LimitType, Lookup,
+            MetaTime, NetworkHeaderLoad, NetworkHeaderWrite, NfLog, Quota, Redirect, SetMark,
+        },
+        rule_annotations,
+        rule_annotations::{RuleAnnotator, RuleOrigin},
+        schedule,
+        schedule::UtcTimeSlot,
+        self_test::Capability,
+        zones::ZoneMap,
+    },
+    settings::{FallbackVerdict, MudAbstraction, TrafficLimits, TrafficMarking},
+};
 
 /// This file represent the service for firewall on openwrt.
 ///
@@ -32,8 +67,46 @@ use crate::{error::Result, services::dns::DnsWatcher, Enforcer};
 ///
 /// @author Namib Group 3.
 
-const TABLE_NAME: &str = "namib";
+pub const TABLE_NAME: &str = "namib";
 const BASE_CHAIN_NAME: &str = "base_chain";
+#[cfg(feature = "nftables")]
+const FLOWTABLE_NAME: &str = "offload";
+/// Chain redirecting the DNS queries of devices to the dnsmasq instances of their DNS views.
+#[cfg(feature = "nftables")]
+const DNS_VIEW_CHAIN_NAME: &str = "dns_views";
+/// Priority of the DNS view chain, which has to be lower than the priority of the `dstnat` chain of fw4 (-100), as only
+/// the first NAT rule matching a connection applies.
+#[cfg(feature = "nftables")]
+const DNS_VIEW_CHAIN_PRIORITY: i32 = -101;
+/// Port DNS queries are sent to.
+#[cfg(feature = "nftables")]
+const DNS_PORT: u16 = 53;
+/// Chain rejecting packets with ICMP administratively prohibited at a limited rate.
+#[cfg(feature = "nftables")]
+const REJECT_CHAIN_NAME: &str = "reject";
+/// Chain rejecting TCP connections with a TCP reset and other packets with ICMP at a limited rate.
+#[cfg(feature = "nftables")]
+const REJECT_TCP_RESET_CHAIN_NAME: &str = "reject_tcp_reset";
+/// Comment of the rules of the reject chains, which do not belong to a device.
+#[cfg(feature = "nftables")]
+const REJECT_CHAIN_COMMENT: &str = "namib reject";
+
+/// Limit kinds that are enforced for packets accepted by a rule and for packets sent by a device.
+#[cfg(feature = "nftables")]
+const ALL_LIMIT_KINDS: [LimitKind; 4] = [
+    LimitKind::Rate,
+    LimitKind::ConnectionRate,
+    LimitKind::Quota,
+    LimitKind::Connections,
+];
+
+/// Maximum number of connection attempts per second and rule which are sent to the enforcer for anomaly detection.
+#[cfg(feature = "nftables")]
+const CONNECTION_LOG_RATE: u32 = 100;
+
+/// Largest valid DSCP value (six bits).
+#[cfg(feature = "nftables")]
+const MAX_DSCP: u8 = 63;
 
 /// Service which provides firewall configuration functionality by integrating into the linux system
 /// firewall (nftables).
@@ -42,7 +115,12 @@ const BASE_CHAIN_NAME: &str = "base_chain";
 /// To send commands to the netlink interface, the [mnl-rs](https://github.com/mullvad/mnl-rs) library is used.
 pub struct FirewallService {
     dns_watcher: Arc<DnsWatcher>,
+    abstractions: Arc<AbstractionResolver>,
+    dns_views: Arc<DnsViewInstances>,
     enforcer_state: Arc<RwLock<Enforcer>>,
+    settings: Arc<Settings>,
+    /// IDs of the devices whose traffic is currently rejected because of detected anomalies.
+    quarantined_devices: Mutex<HashSet<i64>>,
     change_notify: Notify,
 }
 
@@ -51,6 +129,23 @@ pub struct FirewallService {
 enum RuleAddrEntry {
     AnyAddr,
     AddrEntry(IpAddr),
+    /// Named set of IPv4 or IPv6 addresses (e.g. the addresses of a MUD abstraction or a hostname).
+    AddrSet {
+        name: String,
+        ipv6: bool,
+    },
+}
+
+impl RuleAddrEntry {
+    /// Returns an address of the IP version this entry is restricted to, if any.
+    fn reference_ip(&self) -> Option<IpAddr> {
+        match self {
+            RuleAddrEntry::AnyAddr => None,
+            RuleAddrEntry::AddrEntry(addr) => Some(*addr),
+            RuleAddrEntry::AddrSet { ipv6: false, .. } => Some(Ipv4Addr::UNSPECIFIED.into()),
+            RuleAddrEntry::AddrSet { ipv6: true, .. } => Some(Ipv6Addr::UNSPECIFIED.into()),
+        }
+    }
 }
 
 impl From<IpAddr> for RuleAddrEntry {
@@ -72,15 +167,42 @@ impl From<Ipv6Addr> for RuleAddrEntry {
 }
 
 impl FirewallService {
-    /// Creates a new `FirewallService` instance with the given enforcer state and dns watcher (generated from the dns service).
-    pub(crate) fn new(enforcer_state: Arc<RwLock<Enforcer>>, watcher: DnsWatcher) -> FirewallService {
+    /// Creates a new `FirewallService` instance with the given enforcer state, enforcer settings, dns watcher
+    /// (generated from the dns service) and resolver for MUD abstractions.
+    pub(crate) fn new(
+        enforcer_state: Arc<RwLock<Enforcer>>,
+        settings: Arc<Settings>,
+        watcher: DnsWatcher,
+        abstractions: Arc<AbstractionResolver>,
+        dns_views: Arc<DnsViewInstances>,
+    ) -> FirewallService {
         FirewallService {
             enforcer_state,
             dns_watcher: Arc::new(watcher),
+            abstractions,
+            dns_views,
+            settings,
+            quarantined_devices: Mutex::new(HashSet::new()),
             change_notify: Notify::new(),
         }
     }
 
+    /// Rejects all traffic of the device with the given ID until it is released using `release_device`.
+    pub fn quarantine_device(&self, device_id: i64) {
+        if self.quarantined_devices.lock().unwrap().insert(device_id) {
+            warn!("Quarantining device {}", device_id);
+            self.notify_firewall_change();
+        }
+    }
+
+    /// Releases the device with the given ID from quarantine.
+    pub fn release_device(&self, device_id: i64) {
+        if self.quarantined_devices.lock().unwrap().remove(&device_id) {
+            info!("Releasing device {} from quarantine", device_id);
+            self.notify_firewall_change();
+        }
+    }
+
     /// Updates the current firewall config with a new value and notifies the firewall change watcher to update the firewall config.
     pub fn notify_firewall_change(&self) {
         self.change_notify.notify_one();
@@ -91,7 +213,59 @@ impl FirewallService {
         loop {
             select! {
                 _ = self.change_notify.notified() => {}
-                _ = self.dns_watcher.address_changed() => {}
+                changed_names = self.dns_watcher.address_changed() => {
+                    for name in &changed_names {
+                        if let Some(records) = self.dns_watcher.records(name).await {
+                            debug!(
+                                "{:?} changed, CNAME chain: {:?}, records: {:?}",
+                                name, records.cname_chain, records.records
+                            );
+                        }
+                    }
+                    // The rules refer to the sets of hostnames (including the sets of the devices, which contain the
+                    // resolved addresses as well), so only the elements of their sets have to be replaced. Hostnames
+                    // without sets (e.g. because they did not resolve before) require new rules.
+                    let installed_sets = self.dns_watcher.installed_sets();
+                    if changed_names
+                        .iter()
+                        .all(|name| installed_sets.iter().any(|(_, hostname, _)| hostname == name))
+                    {
+                        let changed_sets: HashSet<(String, Option<i64>)> = installed_sets
+                            .iter()
+                            .filter(|(_, hostname, _)| changed_names.contains(*hostname))
+                            .map(|(_, hostname, device_id)| (hostname.clone(), device_id))
+                            .collect();
+                        match replace_hostname_set_elements(&self.dns_watcher, Some(&changed_sets)).await {
+                            Ok(()) => continue,
+                            Err(e) => warn!(
+                                "Unable to update the addresses of {:?}, rebuilding the rules: {:?}",
+                                changed_sets, e
+                            ),
+                        }
+                    }
+                }
+                changed_names = self.dns_watcher.device_addresses_changed() => {
+                    // Addresses learned for hostnames without sets of the device are not used by any rule.
+                    let installed_sets = self.dns_watcher.installed_sets();
+                    let changed_sets: HashSet<(String, Option<i64>)> = changed_names
+                        .into_iter()
+                        .map(|(device_id, name)| (name, Some(device_id)))
+                        .filter(|(name, device_id)| installed_sets.contains(name, *device_id))
+                        .collect();
+                    if !changed_sets.is_empty() {
+                        replace_hostname_set_elements(&self.dns_watcher, Some(&changed_sets))
+                            .await
+                            .unwrap_or_else(|e| warn!("Unable to update the addresses learned by devices: {:?}", e));
+                    }
+                    continue;
+                }
+                _ = self.dns_watcher.addresses_renewed() => {
+                    // Only the timeouts of the addresses change, which does not require rebuilding the rules.
+                    replace_hostname_set_elements(&self.dns_watcher, None)
+                        .await
+                        .unwrap_or_else(|e| warn!("Unable to renew the addresses of hostnames: {:?}", e));
+                    continue;
+                }
             }
             self.apply_current_config()
                 .await
@@ -105,29 +279,106 @@ impl FirewallService {
         let config = &self.enforcer_state.read().await.config;
         debug!("{:?}", config);
         self.dns_watcher.clear_watched_names().await;
-        apply_firewall_config_inner(&config, &self.dns_watcher).await
+        let quarantined_devices = self.quarantined_devices.lock().unwrap().clone();
+        // The DNS views are started before the DNS queries of their devices are redirected to them.
+        self.dns_views
+            .apply(config)
+            .await
+            .unwrap_or_else(|e| warn!("Unable to update the DNS views blocking domains: {:?}", e));
+        apply_firewall_config_inner(
+            &config,
+            &self.settings,
+            &quarantined_devices,
+            &self.dns_watcher,
+            &self.abstractions,
+        )
+        .await
     }
 }
 
 #[cfg(feature = "nftables")]
-pub(crate) async fn apply_firewall_config_inner(config: &EnforcerConfig, dns_watcher: &DnsWatcher) -> Result<()> {
+pub(crate) async fn apply_firewall_config_inner(
+    config: &EnforcerConfig,
+    settings: &Settings,
+    quarantined_devices: &HashSet<i64>,
+    dns_watcher: &DnsWatcher,
+    abstractions: &AbstractionResolver,
+) -> Result<()> {
     let mut batch = Batch::new();
     add_old_config_deletion_instructions(&mut batch)?;
     let mut device_batches = Vec::new();
-    convert_config_to_nftnl_commands(&mut batch, &config, dns_watcher, &mut device_batches).await?;
+    let mut annotator = RuleAnnotator::new();
+    // The quotas of the rules start over once the rules are replaced, unless their consumed bytes are carried over.
+    match rule_annotations::installed_rules(TABLE_NAME) {
+        Ok(installed) => annotator.carry_over_quotas(&installed),
+        Err(e) => warn!("Unable to retrieve the consumed quotas of the installed rules: {:?}", e),
+    }
+    let mut abstraction_sets = AbstractionSets::default();
+    let mut hostname_sets = HostnameSets::default();
+    convert_config_to_nftnl_commands(
+        &mut batch,
+        &config,
+        settings,
+        quarantined_devices,
+        dns_watcher,
+        &mut annotator,
+        &mut abstraction_sets,
+        &mut hostname_sets,
+        &mut device_batches,
+    )
+    .await?;
+    // The sets are created in the table batch, which is sent before the device batches containing the rules that
+    // refer to them.
+    if !abstraction_sets.is_empty() {
+        abstractions.reload_local_networks();
+        add_abstraction_sets(&mut batch, &abstraction_sets, abstractions);
+    }
+    add_hostname_sets(&mut batch, &hostname_sets, dns_watcher).await;
     let batch = batch.finalize();
     if let Err(e) = send_and_process(batch, &device_batches) {
         error!("Error sending firewall configuration to netfilter: {:?}", e);
         Err(e)
     } else {
+        abstractions.set_installed_sets(abstraction_sets);
+        dns_watcher.set_installed_sets(hostname_sets);
+        verify_installed_rules(&annotator);
         Ok(())
     }
 }
 
+/// Compares the rules installed in the kernel with the rules created for the current configuration and logs any
+/// differences, identifying the affected rules by their comments.
+#[cfg(feature = "nftables")]
+fn verify_installed_rules(annotator: &RuleAnnotator) {
+    let installed = match rule_annotations::installed_rules(TABLE_NAME) {
+        Ok(installed) => installed,
+        Err(e) => {
+            warn!("Unable to verify the installed firewall rules: {:?}", e);
+            return;
+        },
+    };
+    let (missing, unexpected) = annotator.verify(&installed);
+    for (comment, count) in &missing {
+        warn!(
+            "{} firewall rule(s) missing after applying configuration: {}",
+            count, comment
+        );
+    }
+    for (comment, count) in &unexpected {
+        warn!("{} unexpected firewall rule(s) installed: {}", count, comment);
+    }
+    if missing.is_empty() && unexpected.is_empty() {
+        debug!("Verified {} installed firewall rules", annotator.expected_rules());
+    }
+}
+
 #[cfg(not(feature = "nftables"))]
 pub async fn apply_firewall_config_inner(
     _config: &namib_shared::EnforcerConfig,
+    _settings: &Settings,
+    _quarantined_devices: &HashSet<i64>,
     _dns_watcher: &DnsWatcher,
+    _abstractions: &AbstractionResolver,
 ) -> Result<()> {
     Ok(())
 }
@@ -150,9 +401,23 @@ fn add_old_config_deletion_instructions(batch: &mut Batch) -> Result<()> {
 async fn convert_config_to_nftnl_commands(
     batch: &mut Batch,
     config: &EnforcerConfig,
+    settings: &Settings,
+    quarantined_devices: &HashSet<i64>,
     dns_watcher: &DnsWatcher,
+    annotator: &mut RuleAnnotator,
+    abstraction_sets: &mut AbstractionSets,
+    hostname_sets: &mut HostnameSets,
     device_batches: &mut Vec<FinalizedBatch>,
 ) -> Result<()> {
+    let time_matching_supported = !settings.schedules.is_empty() && schedule::time_matching_supported(settings);
+    // Without support for logging packets, exceeded limits and unmatched traffic are not reported.
+    let nflog_group = Some(settings.nflog_group).filter(|_| settings.capabilities.supports(Capability::Log));
+    let zones = if settings.uses_zones() {
+        ZoneMap::load()
+    } else {
+        ZoneMap::default()
+    };
+
     // Create new firewall table.
     let table = Table::new(&CString::new(TABLE_NAME).unwrap(), ProtoFamily::Inet);
     batch.add(&table, nftnl::MsgType::Add);
@@ -160,105 +425,506 @@ async fn convert_config_to_nftnl_commands(
     // Create base chain. This base chain is the entry point for the firewall table and will redirect all
     // packets corresponding to a configured device in the firewall config to its separate chain.
     let mut base_chain = Chain::new(&CString::new(BASE_CHAIN_NAME).unwrap(), &table);
-    base_chain.set_hook(nftnl::Hook::Forward, 0);
+    base_chain.set_hook(nftnl::Hook::Forward, settings.hook_priority);
     // If a device is not one of the configured devices, accept packets by default.
     base_chain.set_policy(nftnl::Policy::Accept);
     batch.add(&base_chain, nftnl::MsgType::Add);
 
+    // All rejected packets are passed to the reject chains, which limit the rate of rejections.
+    add_reject_chains(&table, batch, settings, annotator);
+
+    // Create the flowtable to which flows accepted by the device rules are offloaded.
+    let flow_offload = &settings.flow_offload;
+    if flow_offload.is_enabled() {
+        let flowtable = Flowtable {
+            table: TABLE_NAME.to_string(),
+            name: FLOWTABLE_NAME.to_string(),
+            interfaces: flow_offload.interfaces.clone(),
+            hardware: flow_offload.hardware,
+        };
+        batch.add(&flowtable, nftnl::MsgType::Add);
+    } else if flow_offload.enabled {
+        warn!("Flow offloading is enabled, but no interfaces are configured");
+    }
+
+    // The DNS queries of devices for which domains are blocked are redirected to the dnsmasq instances of their views.
+    let dns_view_ports: HashMap<i64, u16> = dns_blocking::dns_views(&settings.dns.blocking, config)
+        .iter()
+        .flat_map(|view| view.device_ids.iter().map(move |&device_id| (device_id, view.port)))
+        .collect();
+    let mut dns_view_chain = Chain::new(&CString::new(DNS_VIEW_CHAIN_NAME).unwrap(), &table);
+    dns_view_chain.set_hook(nftnl::Hook::PreRouting, DNS_VIEW_CHAIN_PRIORITY);
+    dns_view_chain.set_type(nftnl::ChainType::Nat);
+    if !dns_view_ports.is_empty() {
+        batch.add(&dns_view_chain, nftnl::MsgType::Add);
+    }
+
     // Iterate over all devices.
     for device in config.devices() {
+        // All rules created for this device are annotated with the device and the configuration version.
+        let device_origin = RuleOrigin {
+            device_id: device.id,
+            config_version: config.version().to_string(),
+            ..RuleOrigin::default()
+        };
+
         // Create chain which is responsible for deciding how packets for/from this device will be treated.
-        let device_chain = Chain::new(&CString::new(format!("device_{}", device.id)).unwrap(), &table);
+        let mut device_chain = Chain::new(&CString::new(format!("device_{}", device.id)).unwrap(), &table);
+        if settings.annotate_chains {
+            rule_annotations::set_chain_comment(&mut device_chain, &device_origin.comment());
+        }
         batch.add(&device_chain, nftnl::MsgType::Add);
 
         let mut device_batch = Batch::new();
 
         // Create fallback rules for when applying the device batch fails: Reject all packets.
         let mut device_fallback_rule = Rule::new(&device_chain);
-        device_fallback_rule.add_expr(&VerdictExpr::Reject(RejectionType::Icmp(IcmpCode::AdminProhibited)));
+        device_fallback_rule.add_expr(&reject_verdict(REJECT_CHAIN_NAME));
+        // The fallback rule is not expected to remain installed, so it is annotated without being recorded.
+        rule_annotations::set_rule_comment(&mut device_fallback_rule, &device_origin.comment());
         batch.add(&device_fallback_rule, nftnl::MsgType::Add);
         // If the device batch is successfully applied, delete the fallback rule.
         device_batch.add(&device_fallback_rule, nftnl::MsgType::Del);
 
-        if let Some(v4addr) = device.ipv4_addr {
-            // Create two rules in the base chain, one for packets coming from the device and one for packets going to the device.
-            let mut device_jump_rule_src = Rule::new(&base_chain);
-            device_jump_rule_src.add_expr(&nft_expr!(meta nfproto));
-            let mut device_jump_rule_dst = Rule::new(&base_chain);
-            device_jump_rule_dst.add_expr(&nft_expr!(meta nfproto));
-            // Match rule if source or target address is configured device.
-            device_jump_rule_src.add_expr(&nft_expr!(meta nfproto));
-            device_jump_rule_src.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
-            device_jump_rule_src.add_expr(&nft_expr!(payload ipv4 saddr));
-            device_jump_rule_src.add_expr(&nft_expr!(cmp == v4addr));
-            device_jump_rule_dst.add_expr(&nft_expr!(meta nfproto));
-            device_jump_rule_dst.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
-            device_jump_rule_dst.add_expr(&nft_expr!(payload ipv4 daddr));
-            device_jump_rule_dst.add_expr(&nft_expr!(cmp == v4addr));
-            // If these rules apply, jump to the chain responsible for handling this device.
-            device_jump_rule_src
-                .add_expr(&nft_expr!(verdict jump CString::new(format!("device_{}", device.id)).unwrap()));
-            device_jump_rule_dst
-                .add_expr(&nft_expr!(verdict jump CString::new(format!("device_{}", device.id)).unwrap()));
-            batch.add(&device_jump_rule_src, nftnl::MsgType::Add);
-            batch.add(&device_jump_rule_dst, nftnl::MsgType::Add);
+        // Create two rules in the base chain for each address of the device (and each interface of its network
+        // segment), one for packets coming from the device and one for packets going to the device.
+        let segment_interfaces = match settings.segment_for(device.id) {
+            Some(segment) => {
+                let interfaces = zones.resolve(&segment.interfaces, &segment.zones);
+                if interfaces.is_empty() {
+                    warn!(
+                        "The network segment of device {} has no interfaces, no traffic is matched",
+                        device.id
+                    );
+                }
+                interfaces.into_iter().map(Some).collect()
+            },
+            None => vec![None],
+        };
+        for device_addr in device_addrs(device) {
+            for interface in &segment_interfaces {
+                add_device_jump_rules(
+                    &base_chain,
+                    batch,
+                    device.id,
+                    device_addr,
+                    interface.as_deref(),
+                    &device_origin,
+                    annotator,
+                );
+            }
+        }
+        if let Some(&port) = dns_view_ports.get(&device.id) {
+            add_dns_redirect_rules(&dns_view_chain, batch, device, port, &device_origin, annotator);
+        }
+
+        // Reject all traffic of quarantined devices instead of applying their rules.
+        if quarantined_devices.contains(&device.id) {
+            let mut quarantine_rule = Rule::new(&device_chain);
+            quarantine_rule.add_expr(&reject_verdict(REJECT_CHAIN_NAME));
+            annotator.annotate(&mut quarantine_rule, &device_origin);
+            device_batch.add(&quarantine_rule, nftnl::MsgType::Add);
+            device_batches.push(device_batch.finalize());
+            continue;
+        }
+
+        // Notify the enforcer about new connections initiated by the device for anomaly detection.
+        let detect_anomalies = settings.anomaly_detection.is_enabled_for(device.id);
+        if detect_anomalies {
+            for device_addr in device_addrs(device) {
+                let mut new_connection_log_rule = Rule::new(&device_chain);
+                add_addr_match_expressions(
+                    &mut new_connection_log_rule,
+                    &RuleAddrEntry::from(device_addr),
+                    &RuleAddrEntry::AnyAddr,
+                );
+                add_connection_log_expressions(
+                    &mut new_connection_log_rule,
+                    settings.nflog_group,
+                    &LogPrefix {
+                        kind: LogKind::NewConnection,
+                        device_id: device.id,
+                        rule_index: None,
+                    },
+                );
+                annotator.annotate(&mut new_connection_log_rule, &device_origin);
+                device_batch.add(&new_connection_log_rule, nftnl::MsgType::Add);
+            }
         }
-        if let Some(v6addr) = device.ipv6_addr {
-            // Create two rules in the base chain, one for packets coming from the device and one for packets going to the device.
-            let mut device_jump_rule_src = Rule::new(&base_chain);
-            device_jump_rule_src.add_expr(&nft_expr!(meta nfproto));
-            let mut device_jump_rule_dst = Rule::new(&base_chain);
-            device_jump_rule_dst.add_expr(&nft_expr!(meta nfproto));
-            // Match rule if source or target address is configured device.
-            device_jump_rule_src.add_expr(&nft_expr!(meta nfproto));
-            device_jump_rule_src.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV6 as u8));
-            device_jump_rule_src.add_expr(&nft_expr!(payload ipv6 saddr));
-            device_jump_rule_src.add_expr(&nft_expr!(cmp == v6addr));
-            device_jump_rule_dst.add_expr(&nft_expr!(meta nfproto));
-            device_jump_rule_dst.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV6 as u8));
-            device_jump_rule_dst.add_expr(&nft_expr!(payload ipv6 daddr));
-            device_jump_rule_dst.add_expr(&nft_expr!(cmp == v6addr));
-            // If these rules apply, jump to the chain responsible for handling this device.
-            device_jump_rule_src
-                .add_expr(&nft_expr!(verdict jump CString::new(format!("device_{}", device.id)).unwrap()));
-            device_jump_rule_dst
-                .add_expr(&nft_expr!(verdict jump CString::new(format!("device_{}", device.id)).unwrap()));
-            batch.add(&device_jump_rule_src, nftnl::MsgType::Add);
-            batch.add(&device_jump_rule_dst, nftnl::MsgType::Add);
+
+        // Traffic exceeding the limits for the whole device is dropped before any of the device rules are evaluated.
+        let device_limits = settings.limits_for(device.id, None);
+        if let Some(limits) = device_limits {
+            add_device_limit_rules(
+                &device_chain,
+                &mut device_batch,
+                device,
+                limits,
+                nflog_group,
+                &device_origin,
+                annotator,
+            );
+        }
+
+        // Settings for single rules refer to the rules by their name, which might change with the device's MUD profile.
+        let rule_names: Vec<String> = device.rules.iter().filter_map(rule_name).collect();
+        for (setting, name) in settings.unmatched_rule_names(device.id, &rule_names) {
+            warn!(
+                "Ignoring {} for rule \"{}\" of device {}, the device has no rule with this name",
+                setting, name, device.id
+            );
         }
 
         // Iterate over device rules.
-        for rule_spec in &device.rules {
-            add_rule_to_batch(&device_chain, &mut device_batch, &device, &rule_spec, dns_watcher).await?;
+        for (rule_index, rule_spec) in device.rules.iter().enumerate() {
+            let ace_name = rule_name(rule_spec);
+            // Rules with a schedule are either restricted to the time slots of the schedule using nftables time
+            // matching or, if the kernel does not support this, only added while the schedule is active.
+            let time_slots = match settings.schedule_for(device.id, ace_name.as_deref()) {
+                None => vec![None],
+                Some(rule_schedule) if time_matching_supported => {
+                    schedule::utc_time_slots(rule_schedule).into_iter().map(Some).collect()
+                },
+                Some(rule_schedule) => {
+                    if !schedule::is_active_at(rule_schedule, &Utc::now()) {
+                        debug!(
+                            "Skipping rule {} of device {} because its schedule is not active",
+                            rule_index, device.id
+                        );
+                        continue;
+                    }
+                    vec![None]
+                },
+            };
+            // Rules restricted to interfaces are created for each combination of input and output interface.
+            let rule_interfaces = ace_name
+                .as_deref()
+                .and_then(|name| settings.rule_interfaces_for(device.id, name));
+            let (input_interfaces, output_interfaces) = match rule_interfaces {
+                Some(rule_interfaces) => (
+                    interface_restriction(&zones, &rule_interfaces.input_interfaces, &rule_interfaces.input_zones),
+                    interface_restriction(
+                        &zones,
+                        &rule_interfaces.output_interfaces,
+                        &rule_interfaces.output_zones,
+                    ),
+                ),
+                None => (vec![None], vec![None]),
+            };
+            let mut scopes = Vec::new();
+            for time_slot in &time_slots {
+                for input_interface in &input_interfaces {
+                    for output_interface in &output_interfaces {
+                        scopes.push(RuleScope {
+                            time_slot: *time_slot,
+                            input_interface: input_interface.clone(),
+                            output_interface: output_interface.clone(),
+                        });
+                    }
+                }
+            }
+            // MUD abstractions used as source or destination are matched using the sets of their addresses.
+            let rule_abstraction = ace_name
+                .as_deref()
+                .and_then(|name| settings.abstractions.abstraction_for(device.id, name));
+            let mut abstraction_entries = |abstraction: Option<&MudAbstraction>| {
+                abstraction.map(|abstraction| {
+                    let key = AbstractionKey::new(abstraction, device_addrs(device).collect());
+                    [false, true]
+                        .iter()
+                        .map(|&ipv6| RuleAddrEntry::AddrSet {
+                            name: abstraction_sets.set_name(key.clone(), ipv6),
+                            ipv6,
+                        })
+                        .collect()
+                })
+            };
+            let source_sets = abstraction_entries(rule_abstraction.and_then(|a| a.src.as_ref()));
+            let dest_sets = abstraction_entries(rule_abstraction.and_then(|a| a.dst.as_ref()));
+            let limits = ace_name
+                .as_deref()
+                .and_then(|name| settings.limits_for(device.id, Some(name)));
+            let marking = settings.marking_for(device.id, ace_name.as_deref());
+            let options = RuleOptions {
+                origin: device_origin.for_rule(rule_index, ace_name.clone()),
+                scopes,
+                source_sets,
+                dest_sets,
+                limits,
+                detect_anomalies,
+                nflog_group: settings.nflog_group,
+                limit_nflog_group: nflog_group,
+                marking,
+                // Offloaded packets bypass the rules, so flows are only offloaded if no schedule, limit or marking
+                // applies.
+                offload_flows: flow_offload.is_enabled()
+                    && device_limits.is_none()
+                    && limits.is_none()
+                    && marking.is_none()
+                    && settings.schedule_for(device.id, ace_name.as_deref()).is_none(),
+            };
+            add_rule_to_batch(
+                &device_chain,
+                &mut device_batch,
+                &device,
+                &rule_spec,
+                &options,
+                dns_watcher,
+                hostname_sets,
+                annotator,
+            )
+            .await?;
         }
+
+        // Traffic not matched by any rule of the device receives the device's fallback verdict.
+        add_fallback_rules(
+            &device_chain,
+            &mut device_batch,
+            settings.fallback.verdict_for(device.id),
+            nflog_group,
+            Some(settings.nflog_group).filter(|_| detect_anomalies),
+            &device_origin,
+            annotator,
+        );
         device_batches.push(device_batch.finalize());
     }
 
     Ok(())
 }
 
-/// Adds a rule based on the given rule_spec to the given device_batch as part of the given device_chain
+/// Returns the interfaces a rule is restricted to by the given interfaces and zones, with `None` matching any
+/// interface if neither interfaces nor zones are given.
+#[cfg(feature = "nftables")]
+fn interface_restriction(zones: &ZoneMap, interfaces: &[String], zone_names: &[String]) -> Vec<Option<String>> {
+    if interfaces.is_empty() && zone_names.is_empty() {
+        return vec![None];
+    }
+    // If none of the zones could be resolved, no rules are created.
+    zones.resolve(interfaces, zone_names).into_iter().map(Some).collect()
+}
+
+/// Adds two rules to the given base chain which jump to the chain of the given device, one for packets coming from
+/// the given address of the device and one for packets going to it. If an interface is given, only packets received
+/// from (or sent to) this interface are matched.
+#[cfg(feature = "nftables")]
+fn add_device_jump_rules(
+    base_chain: &Chain<'_>,
+    batch: &mut Batch,
+    device_id: i64,
+    device_addr: IpAddr,
+    interface: Option<&str>,
+    device_origin: &RuleOrigin,
+    annotator: &mut RuleAnnotator,
+) {
+    for &from_device in &[true, false] {
+        let mut device_jump_rule = Rule::new(base_chain);
+        // Match rule if source or target address is configured device.
+        if from_device {
+            add_addr_match_expressions(
+                &mut device_jump_rule,
+                &RuleAddrEntry::from(device_addr),
+                &RuleAddrEntry::AnyAddr,
+            );
+        } else {
+            add_addr_match_expressions(
+                &mut device_jump_rule,
+                &RuleAddrEntry::AnyAddr,
+                &RuleAddrEntry::from(device_addr),
+            );
+        }
+        if let Some(interface) = interface {
+            add_interface_match(&mut device_jump_rule, from_device, interface);
+        }
+        // If this rule applies, jump to the chain responsible for handling this device.
+        device_jump_rule.add_expr(&nft_expr!(verdict jump CString::new(format!("device_{}", device_id)).unwrap()));
+        annotator.annotate(&mut device_jump_rule, device_origin);
+        batch.add(&device_jump_rule, nftnl::MsgType::Add);
+    }
+}
+
+/// Adds expressions to the given rule which match packets received from (if `input` is true) or sent to the
+/// interface with the given name.
+#[cfg(feature = "nftables")]
+fn add_interface_match(rule: &mut Rule, input: bool, interface: &str) {
+    if input {
+        rule.add_expr(&nft_expr!(meta iifname));
+    } else {
+        rule.add_expr(&nft_expr!(meta oifname));
+    }
+    // Include the terminating null byte, otherwise all interfaces starting with the name would match.
+    let mut name = interface.as_bytes().to_vec();
+    name.push(0);
+    rule.add_expr(&nft_expr!(cmp == &name[..]));
+}
+
+/// Returns the name of the given rule (the MUD ACE name).
+#[cfg(feature = "nftables")]
+fn rule_name(rule_spec: &FirewallRule) -> Option<String> {
+    serde_json::to_value(&rule_spec.rule_name)
+        .ok()
+        .and_then(|name| name.as_str().map(String::from))
+}
+
+/// Adds rules which redirect the DNS queries of the given device to the given port, on which the dnsmasq instance of
+/// its DNS view listens, to the given batch.
+#[cfg(feature = "nftables")]
+fn add_dns_redirect_rules(
+    chain: &Chain,
+    batch: &mut Batch,
+    device: &FirewallDevice,
+    port: u16,
+    origin: &RuleOrigin,
+    annotator: &mut RuleAnnotator,
+) {
+    for device_addr in device_addrs(device) {
+        for &tcp in &[false, true] {
+            let mut redirect_rule = Rule::new(chain);
+            add_addr_match_expressions(
+                &mut redirect_rule,
+                &RuleAddrEntry::from(device_addr),
+                &RuleAddrEntry::AnyAddr,
+            );
+            redirect_rule.add_expr(&nft_expr!(meta l4proto));
+            if tcp {
+                redirect_rule.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+                redirect_rule.add_expr(&nft_expr!(payload tcp dport));
+            } else {
+                redirect_rule.add_expr(&nft_expr!(cmp == libc::IPPROTO_UDP as u8));
+                redirect_rule.add_expr(&nft_expr!(payload udp dport));
+            }
+            redirect_rule.add_expr(&nft_expr!(cmp == DNS_PORT.to_be()));
+            redirect_rule.add_expr(&ImmediatePort { port });
+            redirect_rule.add_expr(&Redirect);
+            annotator.annotate(&mut redirect_rule, origin);
+            batch.add(&redirect_rule, nftnl::MsgType::Add);
+        }
+    }
+}
+
+/// Returns the IPv4 and IPv6 address of the given device (if known).
+#[cfg(feature = "nftables")]
+fn device_addrs(device: &FirewallDevice) -> impl Iterator<Item=IpAddr> {
+    device
+        .ipv4_addr
+        .map(IpAddr::from)
+        .into_iter()
+        .chain(device.ipv6_addr.map(IpAddr::from))
+}
+
+/// Adds rules which drop traffic of the given device exceeding the given limits to the given batch.
+/// Rate and quota limits are enforced separately for traffic sent and received by the device, connection limits only
+/// apply to connections initiated by the device.
+#[cfg(feature = "nftables")]
+fn add_device_limit_rules(
+    device_chain: &Chain<'_>,
+    device_batch: &mut Batch,
+    device: &FirewallDevice,
+    limits: &TrafficLimits,
+    nflog_group: Option<u16>,
+    device_origin: &RuleOrigin,
+    annotator: &mut RuleAnnotator,
+) {
+    for device_addr in device_addrs(device) {
+        let directions = [
+            (
+                RuleAddrEntry::from(device_addr),
+                RuleAddrEntry::AnyAddr,
+                &ALL_LIMIT_KINDS[..],
+            ),
+            (
+                RuleAddrEntry::AnyAddr,
+                RuleAddrEntry::from(device_addr),
+                &[LimitKind::Rate, LimitKind::Quota][..],
+            ),
+        ];
+        for (source_ip, dest_ip, limit_kinds) in &directions {
+            add_limit_rules(
+                device_chain,
+                device_batch,
+                limits,
+                limit_kinds,
+                nflog_group,
+                device_origin,
+                annotator,
+                |rule| add_addr_match_expressions(rule, source_ip, dest_ip),
+            );
+        }
+    }
+}
+
+/// Enforcer-local options for the conversion of a single rule.
+#[cfg(feature = "nftables")]
+struct RuleOptions<'a> {
+    /// Origin of the rule, used to annotate the created nftables rules.
+    origin: RuleOrigin,
+    /// For each scope, a separate rule is created which only matches within this scope.
+    scopes: Vec<RuleScope>,
+    /// Sets replacing the source host of the rule (the sets of a MUD abstraction).
+    source_sets: Option<Vec<RuleAddrEntry>>,
+    /// Sets replacing the destination host of the rule.
+    dest_sets: Option<Vec<RuleAddrEntry>>,
+    /// Limits for packets accepted by this rule.
+    limits: Option<&'a TrafficLimits>,
+    /// Whether the enforcer should be notified about connection attempts rejected by this rule.
+    detect_anomalies: bool,
+    /// NFLOG group used to notify the enforcer about rejected connection attempts.
+    nflog_group: u16,
+    /// NFLOG group used to notify the enforcer about exceeded limits (`None` if the kernel cannot log packets).
+    limit_nflog_group: Option<u16>,
+    /// DSCP value and packet mark set for packets accepted by this rule.
+    marking: Option<&'a TrafficMarking>,
+    /// Whether flows accepted by this rule are offloaded to the flowtable.
+    offload_flows: bool,
+}
+
+/// Restrictions of a single nftables rule created for a rule specification, in addition to its addresses.
+#[cfg(feature = "nftables")]
+#[derive(Debug, Clone, Default)]
+struct RuleScope {
+    /// Time slot in which the rule matches (`None` matches at any time).
+    time_slot: Option<UtcTimeSlot>,
+    /// Interface the packets have to be received from (`None` matches any interface).
+    input_interface: Option<String>,
+    /// Interface the packets have to be sent to (`None` matches any interface).
+    output_interface: Option<String>,
+}
+
+/// Adds a rule based on the given rule_spec to the given device_batch as part of the given device_chain.
 #[cfg(feature = "nftables")]
 async fn add_rule_to_batch(
     device_chain: &Chain<'_>,
     device_batch: &mut Batch,
     device: &FirewallDevice,
     rule_spec: &FirewallRule,
+    options: &RuleOptions<'_>,
     dns_watcher: &DnsWatcher,
+    hostname_sets: &mut HostnameSets,
+    annotator: &mut RuleAnnotator,
 ) -> Result<()> {
-    // Depending on the type of host identifier (hostname, IP address or placeholder for device IP)
+    let hostname = [
+        (&rule_spec.src.host, &options.source_sets),
+        (&rule_spec.dst.host, &options.dest_sets),
+    ]
+    .iter()
+    .find_map(|host| match host {
+        (Some(RuleTargetHost::Hostname(dns_name)), None) => Some(dns_name.as_str()),
+        _ => None,
+    });
+    let origin = options.origin.with_hostname(hostname);
+
+    // Depending on the type of host identifier (hostname, IP address, placeholder for device IP or MUD abstraction)
     // for the packet source or destination, create a vector of ip addresses for this identifier.
     let source_ips: Vec<RuleAddrEntry> = match &rule_spec.src.host {
+        _ if options.source_sets.is_some() => options.source_sets.clone().unwrap_or_default(),
         Some(RuleTargetHost::Ip(ipaddr)) => {
             vec![RuleAddrEntry::AddrEntry(ipaddr.clone())]
         },
         // Error handling: If host resolution fails, return an empty Vec. This will cause no rules
         // to be generated for the supplied host (which will then default to being rejected if no other rule matches).
-        Some(RuleTargetHost::Hostname(dns_name)) => dns_watcher
-            .resolve_and_watch(dns_name.as_str())
-            .await
-            .map(|v| v.iter().map(|v| RuleAddrEntry::from(v)).collect())
-            .unwrap_or(Vec::new()),
+        // The host is retried by the DNS service, which triggers an update of the rules once it resolves.
+        Some(RuleTargetHost::Hostname(dns_name)) => {
+            hostname_entries(dns_name, device.id, dns_watcher, hostname_sets).await
+        },
         Some(RuleTargetHost::FirewallDevice) => device
             .ipv4_addr
             .map(RuleAddrEntry::from)
@@ -268,16 +934,16 @@ async fn add_rule_to_batch(
         _ => vec![RuleAddrEntry::AnyAddr],
     };
     let dest_ips: Vec<RuleAddrEntry> = match &rule_spec.dst.host {
+        _ if options.dest_sets.is_some() => options.dest_sets.clone().unwrap_or_default(),
         Some(RuleTargetHost::Ip(ipaddr)) => {
             vec![RuleAddrEntry::AddrEntry(ipaddr.clone())]
         },
         // Error handling: If host resolution fails, return an empty Vec. This will cause no rules
         // to be generated for the supplied host (which will then default to being rejected if no other rule matches).
-        Some(RuleTargetHost::Hostname(dns_name)) => dns_watcher
-            .resolve_and_watch(dns_name.as_str())
-            .await
-            .map(|v| v.iter().map(|v| RuleAddrEntry::from(v)).collect())
-            .unwrap_or(Vec::new()),
+        // The host is retried by the DNS service, which triggers an update of the rules once it resolves.
+        Some(RuleTargetHost::Hostname(dns_name)) => {
+            hostname_entries(dns_name, device.id, dns_watcher, hostname_sets).await
+        },
         Some(RuleTargetHost::FirewallDevice) => device
             .ipv4_addr
             .map(RuleAddrEntry::from)
@@ -293,122 +959,610 @@ async fn add_rule_to_batch(
     // sets yet.
     for source_ip in &source_ips {
         for dest_ip in &dest_ips {
-            let protocol_reference_ip;
+            let (source_reference_ip, dest_reference_ip) = (source_ip.reference_ip(), dest_ip.reference_ip());
             // Do not create rules which mix IPv4 and IPv6 addresses. Also, save at least one specified IP to match for protocol later on.
-            if let &RuleAddrEntry::AddrEntry(saddr) = source_ip {
-                if let RuleAddrEntry::AddrEntry(daddr) = dest_ip {
-                    if (saddr.is_ipv4() && daddr.is_ipv6()) || (daddr.is_ipv4() && saddr.is_ipv6()) {
-                        continue;
-                    }
+            if let (Some(saddr), Some(daddr)) = (source_reference_ip, dest_reference_ip) {
+                if saddr.is_ipv4() != daddr.is_ipv4() {
+                    continue;
                 }
-                protocol_reference_ip = Some(saddr);
-            } else if let &RuleAddrEntry::AddrEntry(daddr) = dest_ip {
-                protocol_reference_ip = Some(daddr);
-            } else {
-                protocol_reference_ip = None;
             }
-            // Create rule for current address combination.
-            let mut current_rule = Rule::new(&device_chain);
-            // Match for protocol. To do this, we need to differentiate between IPv4 and IPv6.
-            match protocol_reference_ip {
-                Some(IpAddr::V4(_v4addr)) => {
-                    // Match for protocol.
-                    match rule_spec.protocol {
-                        Protocol::Tcp => {
-                            current_rule.add_expr(&nft_expr!(payload ipv4 protocol));
-                            current_rule.add_expr(&nft_expr!(cmp == "tcp"));
+            let protocol_reference_ip = source_reference_ip.or(dest_reference_ip);
+            for scope in &options.scopes {
+                // Packets which match the rule but exceed its limits are dropped before reaching the rule itself.
+                if let (Verdict::Accept, Some(limits)) = (&rule_spec.verdict, options.limits) {
+                    add_limit_rules(
+                        device_chain,
+                        device_batch,
+                        limits,
+                        &ALL_LIMIT_KINDS,
+                        options.limit_nflog_group,
+                        &origin,
+                        annotator,
+                        |rule| {
+                            add_rule_match_expressions(
+                                rule,
+                                rule_spec,
+                                source_ip,
+                                dest_ip,
+                                protocol_reference_ip,
+                                scope,
+                            )
                         },
-                        Protocol::Udp => {
-                            current_rule.add_expr(&nft_expr!(payload ipv4 protocol));
-                            current_rule.add_expr(&nft_expr!(cmp == "udp"));
-                        },
-                        _ => {}, // TODO expand with further options (icmp, sctp)
-                    }
-                },
-                Some(IpAddr::V6(_v6addr)) => {
-                    match rule_spec.protocol {
-                        Protocol::Tcp => {
-                            current_rule.add_expr(&nft_expr!(payload ipv6 nextheader));
-                            current_rule.add_expr(&nft_expr!(cmp == "tcp"));
+                    );
+                }
+
+                // Packets accepted by the rule are marked before reaching the rule itself.
+                if let (Verdict::Accept, Some(marking)) = (&rule_spec.verdict, options.marking) {
+                    add_marking_rules(
+                        device_chain,
+                        device_batch,
+                        marking,
+                        protocol_reference_ip,
+                        &origin,
+                        annotator,
+                        |rule| {
+                            add_rule_match_expressions(
+                                rule,
+                                rule_spec,
+                                source_ip,
+                                dest_ip,
+                                protocol_reference_ip,
+                                scope,
+                            )
                         },
-                        Protocol::Udp => {
-                            current_rule.add_expr(&nft_expr!(payload ipv6 nextheader));
-                            current_rule.add_expr(&nft_expr!(cmp == "udp"));
+                    );
+                }
+
+                // Notify the enforcer about rejected connection attempts for anomaly detection.
+                if options.detect_anomalies && !matches!(rule_spec.verdict, Verdict::Accept) {
+                    let mut rejected_log_rule = Rule::new(&device_chain);
+                    add_rule_match_expressions(
+                        &mut rejected_log_rule,
+                        rule_spec,
+                        source_ip,
+                        dest_ip,
+                        protocol_reference_ip,
+                        scope,
+                    );
+                    add_connection_log_expressions(
+                        &mut rejected_log_rule,
+                        options.nflog_group,
+                        &LogPrefix {
+                            kind: LogKind::Rejected,
+                            device_id: device.id,
+                            rule_index: origin.rule_index,
                         },
-                        _ => {}, // TODO expand with further options (icmp, sctp)
-                    }
-                },
-                _ => {},
-            }
-            // Create expressions to match source IP.
-            match source_ip {
-                RuleAddrEntry::AddrEntry(IpAddr::V4(v4addr)) => {
-                    current_rule.add_expr(&nft_expr!(meta nfproto));
-                    current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
-                    current_rule.add_expr(&nft_expr!(payload ipv4 saddr));
-                    current_rule.add_expr(&nft_expr!(cmp == v4addr.clone()));
-                },
-                RuleAddrEntry::AddrEntry(IpAddr::V6(v6addr)) => {
-                    current_rule.add_expr(&nft_expr!(meta nfproto));
-                    current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV6 as u8));
-                    current_rule.add_expr(&nft_expr!(payload ipv6 saddr));
-                    current_rule.add_expr(&nft_expr!(cmp == v6addr.clone()));
-                },
-                RuleAddrEntry::AnyAddr => {},
+                    );
+                    annotator.annotate(&mut rejected_log_rule, &origin);
+                    device_batch.add(&rejected_log_rule, nftnl::MsgType::Add);
+                }
+
+                // Create rule for current address combination.
+                let mut current_rule = Rule::new(&device_chain);
+                add_rule_match_expressions(
+                    &mut current_rule,
+                    rule_spec,
+                    source_ip,
+                    dest_ip,
+                    protocol_reference_ip,
+                    scope,
+                );
+                // Count the packets matching the rule, the counters can be retrieved together with the rule comments.
+                current_rule.add_expr(&nft_expr!(counter));
+                // Offload accepted flows once they are established, new connections are still evaluated by the rules.
+                if options.offload_flows && matches!(rule_spec.verdict, Verdict::Accept) {
+                    current_rule.add_expr(&FlowOffload {
+                        flowtable: CString::new(FLOWTABLE_NAME).unwrap(),
+                    });
+                }
+                // Set verdict if current rule matches.
+                match rule_spec.verdict {
+                    Verdict::Accept => current_rule.add_expr(&nft_expr!(verdict accept)),
+                    Verdict::Reject => current_rule.add_expr(&reject_verdict(REJECT_CHAIN_NAME)),
+                    Verdict::Drop => current_rule.add_expr(&nft_expr!(verdict drop)),
+                }
+                annotator.annotate(&mut current_rule, &origin);
+                device_batch.add(&current_rule, nftnl::MsgType::Add);
             }
-            // Create expressions to match destination IP.
-            match dest_ip {
-                RuleAddrEntry::AddrEntry(IpAddr::V4(v4addr)) => {
-                    current_rule.add_expr(&nft_expr!(meta nfproto));
-                    current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
-                    current_rule.add_expr(&nft_expr!(payload ipv4 daddr));
-                    current_rule.add_expr(&nft_expr!(cmp == v4addr.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the given hostname and returns the entries matching the sets of its IPv4 and IPv6 addresses, or no entries
+/// if the hostname cannot be resolved. If the device view is enabled, the entries match the sets of the addresses the
+/// device with the given ID was told by dnsmasq in addition to the resolved addresses instead, which are created even
+/// if the hostname cannot be resolved.
+#[cfg(feature = "nftables")]
+async fn hostname_entries(
+    dns_name: &str,
+    device_id: i64,
+    dns_watcher: &DnsWatcher,
+    hostname_sets: &mut HostnameSets,
+) -> Vec<RuleAddrEntry> {
+    let resolved = dns_watcher.resolve_and_watch(dns_name).await.is_ok();
+    let device_id = Some(device_id).filter(|_| dns_watcher.uses_device_view());
+    if device_id.is_none() && !resolved {
+        return Vec::new();
+    }
+    [false, true]
+        .iter()
+        .map(|&ipv6| RuleAddrEntry::AddrSet {
+            name: hostname_sets.set_name(dns_name, device_id, ipv6),
+            ipv6,
+        })
+        .collect()
+}
+
+/// Adds the expressions that match the packets described by the given rule specification for the given address
+/// combination (and scope) to the given rule.
+#[cfg(feature = "nftables")]
+fn add_rule_match_expressions(
+    current_rule: &mut Rule,
+    rule_spec: &FirewallRule,
+    source_ip: &RuleAddrEntry,
+    dest_ip: &RuleAddrEntry,
+    protocol_reference_ip: Option<IpAddr>,
+    scope: &RuleScope,
+) {
+    if let Some(time_slot) = &scope.time_slot {
+        add_time_slot_match(current_rule, time_slot);
+    }
+    if let Some(input_interface) = &scope.input_interface {
+        add_interface_match(current_rule, true, input_interface);
+    }
+    if let Some(output_interface) = &scope.output_interface {
+        add_interface_match(current_rule, false, output_interface);
+    }
+    // Match for protocol. To do this, we need to differentiate between IPv4 and IPv6.
+    match protocol_reference_ip {
+        Some(IpAddr::V4(_v4addr)) => {
+            // Match for protocol.
+            match rule_spec.protocol {
+                Protocol::Tcp => {
+                    current_rule.add_expr(&nft_expr!(payload ipv4 protocol));
+                    current_rule.add_expr(&nft_expr!(cmp == "tcp"));
                 },
-                RuleAddrEntry::AddrEntry(IpAddr::V6(v6addr)) => {
-                    current_rule.add_expr(&nft_expr!(meta nfproto));
-                    current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV6 as u8));
-                    current_rule.add_expr(&nft_expr!(payload ipv6 daddr));
-                    current_rule.add_expr(&nft_expr!(cmp == v6addr.clone()));
+                Protocol::Udp => {
+                    current_rule.add_expr(&nft_expr!(payload ipv4 protocol));
+                    current_rule.add_expr(&nft_expr!(cmp == "udp"));
                 },
-                RuleAddrEntry::AnyAddr => {},
+                _ => {}, // TODO expand with further options (icmp, sctp)
             }
-            // Create expressions to match for port numbers.
+        },
+        Some(IpAddr::V6(_v6addr)) => {
             match rule_spec.protocol {
                 Protocol::Tcp => {
-                    if let Some(port) = &rule_spec.dst.port {
-                        current_rule.add_expr(&nft_expr!(payload tcp dport));
-                        current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
-                    }
-                    if let Some(port) = &rule_spec.src.port {
-                        current_rule.add_expr(&nft_expr!(payload tcp dport));
-                        current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
-                    }
+                    current_rule.add_expr(&nft_expr!(payload ipv6 nextheader));
+                    current_rule.add_expr(&nft_expr!(cmp == "tcp"));
                 },
                 Protocol::Udp => {
-                    if let Some(port) = &rule_spec.dst.port {
-                        current_rule.add_expr(&nft_expr!(payload udp dport));
-                        current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
-                    }
-                    if let Some(port) = &rule_spec.src.port {
-                        current_rule.add_expr(&nft_expr!(payload udp dport));
-                        current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
-                    }
+                    current_rule.add_expr(&nft_expr!(payload ipv6 nextheader));
+                    current_rule.add_expr(&nft_expr!(cmp == "udp"));
                 },
-                _ => {},
+                _ => {}, // TODO expand with further options (icmp, sctp)
+            }
+        },
+        _ => {},
+    }
+    add_addr_match_expressions(current_rule, source_ip, dest_ip);
+    // Create expressions to match for port numbers.
+    match rule_spec.protocol {
+        Protocol::Tcp => {
+            if let Some(port) = &rule_spec.dst.port {
+                current_rule.add_expr(&nft_expr!(payload tcp dport));
+                current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
+            }
+            if let Some(port) = &rule_spec.src.port {
+                current_rule.add_expr(&nft_expr!(payload tcp dport));
+                current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
+            }
+        },
+        Protocol::Udp => {
+            if let Some(port) = &rule_spec.dst.port {
+                current_rule.add_expr(&nft_expr!(payload udp dport));
+                current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
             }
+            if let Some(port) = &rule_spec.src.port {
+                current_rule.add_expr(&nft_expr!(payload udp dport));
+                current_rule.add_expr(&nft_expr!(cmp == port.as_str()));
+            }
+        },
+        _ => {},
+    }
+}
 
-            // Set verdict if current rule matches.
-            match rule_spec.verdict {
-                Verdict::Accept => current_rule.add_expr(&nft_expr!(verdict accept)),
-                Verdict::Reject => {
-                    current_rule.add_expr(&VerdictExpr::Reject(RejectionType::Icmp(IcmpCode::AdminProhibited)))
-                },
-                Verdict::Drop => current_rule.add_expr(&nft_expr!(verdict drop)),
+/// Adds expressions to the given rule which match the given source and destination addresses.
+#[cfg(feature = "nftables")]
+fn add_addr_match_expressions(current_rule: &mut Rule, source_ip: &RuleAddrEntry, dest_ip: &RuleAddrEntry) {
+    // Create expressions to match source IP.
+    match source_ip {
+        RuleAddrEntry::AddrEntry(IpAddr::V4(v4addr)) => {
+            current_rule.add_expr(&nft_expr!(meta nfproto));
+            current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
+            current_rule.add_expr(&nft_expr!(payload ipv4 saddr));
+            current_rule.add_expr(&nft_expr!(cmp == v4addr.clone()));
+        },
+        RuleAddrEntry::AddrEntry(IpAddr::V6(v6addr)) => {
+            current_rule.add_expr(&nft_expr!(meta nfproto));
+            current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV6 as u8));
+            current_rule.add_expr(&nft_expr!(payload ipv6 saddr));
+            current_rule.add_expr(&nft_expr!(cmp == v6addr.clone()));
+        },
+        RuleAddrEntry::AddrSet { name, ipv6 } => {
+            add_nfproto_match(current_rule, *ipv6);
+            if *ipv6 {
+                current_rule.add_expr(&nft_expr!(payload ipv6 saddr));
+            } else {
+                current_rule.add_expr(&nft_expr!(payload ipv4 saddr));
+            }
+            current_rule.add_expr(&Lookup {
+                set: CString::new(name.as_str()).unwrap(),
+            });
+        },
+        RuleAddrEntry::AnyAddr => {},
+    }
+    // Create expressions to match destination IP.
+    match dest_ip {
+        RuleAddrEntry::AddrEntry(IpAddr::V4(v4addr)) => {
+            current_rule.add_expr(&nft_expr!(meta nfproto));
+            current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
+            current_rule.add_expr(&nft_expr!(payload ipv4 daddr));
+            current_rule.add_expr(&nft_expr!(cmp == v4addr.clone()));
+        },
+        RuleAddrEntry::AddrEntry(IpAddr::V6(v6addr)) => {
+            current_rule.add_expr(&nft_expr!(meta nfproto));
+            current_rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV6 as u8));
+            current_rule.add_expr(&nft_expr!(payload ipv6 daddr));
+            current_rule.add_expr(&nft_expr!(cmp == v6addr.clone()));
+        },
+        RuleAddrEntry::AddrSet { name, ipv6 } => {
+            add_nfproto_match(current_rule, *ipv6);
+            if *ipv6 {
+                current_rule.add_expr(&nft_expr!(payload ipv6 daddr));
+            } else {
+                current_rule.add_expr(&nft_expr!(payload ipv4 daddr));
             }
-            device_batch.add(&current_rule, nftnl::MsgType::Add);
+            current_rule.add_expr(&Lookup {
+                set: CString::new(name.as_str()).unwrap(),
+            });
+        },
+        RuleAddrEntry::AnyAddr => {},
+    }
+}
+
+/// Adds expressions to the given rule which only match IPv6 (if `ipv6` is true) or IPv4 packets.
+#[cfg(feature = "nftables")]
+fn add_nfproto_match(rule: &mut Rule, ipv6: bool) {
+    rule.add_expr(&nft_expr!(meta nfproto));
+    if ipv6 {
+        rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV6 as u8));
+    } else {
+        rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
+    }
+}
+
+/// Adds rules which drop packets exceeding the given limits to the given batch.
+/// For each configured limit of the given kinds, two rules are created: The first one sends a notification about
+/// the exceeded limit to the enforcer using NFLOG (at most once per second, omitted if `nflog_group` is `None`), the
+/// second one drops the packet.
+/// `add_match` has to add the expressions that match the packets the limits apply to.
+#[cfg(feature = "nftables")]
+#[allow(clippy::too_many_arguments)]
+fn add_limit_rules(
+    chain: &Chain<'_>,
+    batch: &mut Batch,
+    limits: &TrafficLimits,
+    limit_kinds: &[LimitKind],
+    nflog_group: Option<u16>,
+    origin: &RuleOrigin,
+    annotator: &mut RuleAnnotator,
+    add_match: impl Fn(&mut Rule),
+) {
+    for &limit_kind in limit_kinds {
+        for log_rule in &[true, false] {
+            if *log_rule && nflog_group.is_none() {
+                continue;
+            }
+            let mut rule = Rule::new(chain);
+            add_match(&mut rule);
+            let consumed_quota = if limit_kind == LimitKind::Quota {
+                annotator.consumed_quota(&chain.get_name().to_string_lossy(), origin)
+            } else {
+                0
+            };
+            if !add_limit_exceeded_expressions(&mut rule, limits, limit_kind, consumed_quota) {
+                break;
+            }
+            if let (true, Some(nflog_group)) = (*log_rule, nflog_group) {
+                rule.add_expr(&Limit {
+                    rate: 1,
+                    burst: 1,
+                    limit_type: LimitType::Packets,
+                    over: false,
+                });
+                let prefix = LogPrefix {
+                    kind: LogKind::LimitExceeded(limit_kind),
+                    device_id: origin.device_id,
+                    rule_index: origin.rule_index,
+                };
+                rule.add_expr(&NfLog {
+                    group: nflog_group,
+                    prefix: CString::new(prefix.to_string()).unwrap(),
+                    snaplen: 0,
+                });
+            } else {
+                rule.add_expr(&nft_expr!(verdict drop));
+            }
+            annotator.annotate(&mut rule, origin);
+            batch.add(&rule, nftnl::MsgType::Add);
+        }
+    }
+}
+
+/// Adds rules which set the DSCP value and packet mark of the given marking for the packets matched by `add_match` to
+/// the given batch. As the DSCP value is stored at different positions in the IPv4 and IPv6 header, a separate rule is
+/// created for each IP version the packets may have (according to `protocol_reference_ip`).
+#[cfg(feature = "nftables")]
+fn add_marking_rules(
+    chain: &Chain<'_>,
+    batch: &mut Batch,
+    marking: &TrafficMarking,
+    protocol_reference_ip: Option<IpAddr>,
+    origin: &RuleOrigin,
+    annotator: &mut RuleAnnotator,
+    add_match: impl Fn(&mut Rule),
+) {
+    let dscp = marking.dscp.filter(|&dscp| {
+        if dscp > MAX_DSCP {
+            warn!("Ignoring invalid DSCP value {} for {}", dscp, origin);
+        }
+        dscp <= MAX_DSCP
+    });
+    let families = match protocol_reference_ip {
+        Some(IpAddr::V4(_)) => vec![libc::NFPROTO_IPV4 as u8],
+        Some(IpAddr::V6(_)) => vec![libc::NFPROTO_IPV6 as u8],
+        None => vec![libc::NFPROTO_IPV4 as u8, libc::NFPROTO_IPV6 as u8],
+    };
+    for family in families {
+        let mut rule = Rule::new(chain);
+        add_match(&mut rule);
+        rule.add_expr(&nft_expr!(meta nfproto));
+        rule.add_expr(&nft_expr!(cmp == family));
+        if let Some(mark) = marking.mark {
+            rule.add_expr(&Immediate32 { value: mark });
+            rule.add_expr(&SetMark);
+        }
+        if let Some(dscp) = dscp {
+            if family == libc::NFPROTO_IPV4 as u8 {
+                // The DSCP value is stored in the upper six bits of the second byte of the IPv4 header, which is
+                // covered by the header checksum.
+                rule.add_expr(&NetworkHeaderLoad { offset: 1, len: 1 });
+                rule.add_expr(&nft_expr!(bitwise mask 0x03u8, xor dscp << 2));
+                rule.add_expr(&NetworkHeaderWrite {
+                    offset: 1,
+                    len: 1,
+                    checksum_offset: Some(10),
+                });
+            } else {
+                // The DSCP value is stored in the lower four bits of the first and the upper two bits of the second
+                // byte of the IPv6 header. Both bytes are loaded into the register in network byte order.
+                rule.add_expr(&NetworkHeaderLoad { offset: 0, len: 2 });
+                let mask = u16::from_ne_bytes([0xf0, 0x3f]);
+                let value = u16::from_ne_bytes([dscp >> 2, (dscp & 0x03) << 6]);
+                rule.add_expr(&nft_expr!(bitwise mask mask, xor value));
+                rule.add_expr(&NetworkHeaderWrite {
+                    offset: 0,
+                    len: 2,
+                    checksum_offset: None,
+                });
+            }
+        }
+        annotator.annotate(&mut rule, origin);
+        batch.add(&rule, nftnl::MsgType::Add);
+    }
+}
+
+/// Adds expressions to the given rule which match packets exceeding the limit of the given kind, starting with the
+/// given number of bytes consumed by a quota. Returns false if the limit of the given kind is not set.
+#[cfg(feature = "nftables")]
+fn add_limit_exceeded_expressions(
+    rule: &mut Rule,
+    limits: &TrafficLimits,
+    limit_kind: LimitKind,
+    consumed_quota: u64,
+) -> bool {
+    match limit_kind {
+        LimitKind::Rate => {
+            if let Some(rate) = limits.bytes_per_second {
+                rule.add_expr(&Limit {
+                    rate,
+                    burst: limits
+                        .burst_bytes
+                        .unwrap_or_else(|| u32::try_from(rate).unwrap_or(u32::MAX)),
+                    limit_type: LimitType::Bytes,
+                    over: true,
+                });
+                return true;
+            }
+        },
+        LimitKind::ConnectionRate => {
+            if let Some(rate) = limits.new_connections_per_second {
+                add_new_connection_match(rule);
+                rule.add_expr(&Limit {
+                    rate,
+                    burst: u32::try_from(rate).unwrap_or(u32::MAX),
+                    limit_type: LimitType::Packets,
+                    over: true,
+                });
+                return true;
+            }
+        },
+        LimitKind::Quota => {
+            if let Some(bytes) = limits.quota_bytes {
+                rule.add_expr(&Quota {
+                    bytes,
+                    consumed: consumed_quota,
+                    over: true,
+                });
+                return true;
+            }
+        },
+        LimitKind::Connections => {
+            if let Some(count) = limits.max_connections {
+                add_new_connection_match(rule);
+                rule.add_expr(&ConnLimit { count, over: true });
+                return true;
+            }
+        },
+    }
+    false
+}
+
+/// Adds expressions to the given rule which send packets starting a new connection to the enforcer using NFLOG
+/// (at most `CONNECTION_LOG_RATE` packets per second).
+#[cfg(feature = "nftables")]
+fn add_connection_log_expressions(rule: &mut Rule, nflog_group: u16, prefix: &LogPrefix) {
+    add_new_connection_match(rule);
+    rule.add_expr(&Limit {
+        rate: u64::from(CONNECTION_LOG_RATE),
+        burst: CONNECTION_LOG_RATE,
+        limit_type: LimitType::Packets,
+        over: false,
+    });
+    rule.add_expr(&NfLog {
+        group: nflog_group,
+        prefix: CString::new(prefix.to_string()).unwrap(),
+        snaplen: LOGGED_PACKET_SNAPLEN,
+    });
+}
+
+/// Adds expressions to the given rule which only match packets that start a new connection.
+#[cfg(feature = "nftables")]
+fn add_new_connection_match(rule: &mut Rule) {
+    rule.add_expr(&nft_expr!(ct state));
+    rule.add_expr(&nft_expr!(bitwise mask States::NEW.bits(), xor 0u32));
+    rule.add_expr(&nft_expr!(cmp != 0u32));
+}
+
+/// Returns the verdict passing packets to the reject chain with the given name.
+#[cfg(feature = "nftables")]
+fn reject_verdict(chain_name: &str) -> VerdictExpr {
+    VerdictExpr::Jump {
+        chain: CString::new(chain_name).unwrap(),
+    }
+}
+
+/// Creates the reject chains, which drop rejected packets exceeding the configured rate of rejections (to avoid
+/// amplification) and reject all others. If the kernel does not support rejecting packets in tables of the inet
+/// family, all packets are dropped.
+#[cfg(feature = "nftables")]
+fn add_reject_chains(table: &Table, batch: &mut Batch, settings: &Settings, annotator: &mut RuleAnnotator) {
+    let reject_supported = settings.capabilities.supports(Capability::Reject);
+    for &(chain_name, tcp_reset) in &[(REJECT_CHAIN_NAME, false), (REJECT_TCP_RESET_CHAIN_NAME, true)] {
+        let chain = Chain::new(&CString::new(chain_name).unwrap(), table);
+        batch.add(&chain, nftnl::MsgType::Add);
+
+        let mut rules = Vec::new();
+        let mut limit_rule = Rule::new(&chain);
+        limit_rule.add_expr(&Limit {
+            rate: u64::from(settings.fallback.reject_rate_per_second),
+            burst: settings.fallback.reject_burst,
+            limit_type: LimitType::Packets,
+            over: true,
+        });
+        limit_rule.add_expr(&nft_expr!(verdict drop));
+        rules.push(limit_rule);
+        if tcp_reset && reject_supported {
+            let mut tcp_reset_rule = Rule::new(&chain);
+            tcp_reset_rule.add_expr(&nft_expr!(meta l4proto));
+            tcp_reset_rule.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+            tcp_reset_rule.add_expr(&VerdictExpr::Reject(RejectionType::TcpRst));
+            rules.push(tcp_reset_rule);
+        }
+        let mut reject_rule = Rule::new(&chain);
+        if reject_supported {
+            reject_rule.add_expr(&VerdictExpr::Reject(RejectionType::Icmp(IcmpCode::AdminProhibited)));
+        } else {
+            reject_rule.add_expr(&nft_expr!(verdict drop));
+        }
+        rules.push(reject_rule);
+
+        for mut rule in rules {
+            annotator.annotate_with_comment(&mut rule, REJECT_CHAIN_COMMENT);
+            batch.add(&rule, nftnl::MsgType::Add);
         }
     }
-    Ok(())
+}
+
+/// Adds the rules applying the given fallback verdict to the traffic of a device not matched by any of its rules to
+/// the end of the device chain.
+/// If `anomaly_nflog_group` is given (i.e. anomaly detection is enabled for the device), connection attempts rejected
+/// by the fallback verdict are sent to the enforcer like those rejected by the device rules.
+#[cfg(feature = "nftables")]
+fn add_fallback_rules(
+    device_chain: &Chain<'_>,
+    device_batch: &mut Batch,
+    verdict: FallbackVerdict,
+    nflog_group: Option<u16>,
+    anomaly_nflog_group: Option<u16>,
+    device_origin: &RuleOrigin,
+    annotator: &mut RuleAnnotator,
+) {
+    if let (FallbackVerdict::AcceptAndLog, Some(nflog_group)) = (verdict, nflog_group) {
+        let mut log_rule = Rule::new(device_chain);
+        add_connection_log_expressions(
+            &mut log_rule,
+            nflog_group,
+            &LogPrefix {
+                kind: LogKind::Unmatched,
+                device_id: device_origin.device_id,
+                rule_index: None,
+            },
+        );
+        annotator.annotate(&mut log_rule, device_origin);
+        device_batch.add(&log_rule, nftnl::MsgType::Add);
+    }
+    let rejects = !matches!(verdict, FallbackVerdict::Accept | FallbackVerdict::AcceptAndLog);
+    if let (true, Some(anomaly_nflog_group)) = (rejects, anomaly_nflog_group) {
+        let mut rejected_log_rule = Rule::new(device_chain);
+        add_connection_log_expressions(
+            &mut rejected_log_rule,
+            anomaly_nflog_group,
+            &LogPrefix {
+                kind: LogKind::Rejected,
+                device_id: device_origin.device_id,
+                rule_index: None,
+            },
+        );
+        annotator.annotate(&mut rejected_log_rule, device_origin);
+        device_batch.add(&rejected_log_rule, nftnl::MsgType::Add);
+    }
+    let mut verdict_rule = Rule::new(device_chain);
+    verdict_rule.add_expr(&nft_expr!(counter));
+    match verdict {
+        FallbackVerdict::Accept | FallbackVerdict::AcceptAndLog => verdict_rule.add_expr(&nft_expr!(verdict return)),
+        FallbackVerdict::Drop => verdict_rule.add_expr(&nft_expr!(verdict drop)),
+        FallbackVerdict::Reject => verdict_rule.add_expr(&reject_verdict(REJECT_CHAIN_NAME)),
+        FallbackVerdict::RejectWithTcpReset => verdict_rule.add_expr(&reject_verdict(REJECT_TCP_RESET_CHAIN_NAME)),
+    }
+    annotator.annotate(&mut verdict_rule, device_origin);
+    device_batch.add(&verdict_rule, nftnl::MsgType::Add);
+}
+
+/// Adds expressions to the given rule which only match packets during the given time slot.
+/// The current time is converted to network byte order before comparing it, as the kernel compares register contents
+/// byte by byte.
+#[cfg(feature = "nftables")]
+fn add_time_slot_match(rule: &mut Rule, time_slot: &UtcTimeSlot) {
+    rule.add_expr(&MetaTime::Day);
+    rule.add_expr(&nft_expr!(cmp == time_slot.day));
+    rule.add_expr(&MetaTime::Hour);
+    rule.add_expr(&HostToNetwork { size: 4 });
+    rule.add_expr(&Cmp::new(CmpOp::Gte, time_slot.start.to_be()));
+    if !time_slot.ends_at_midnight() {
+        rule.add_expr(&Cmp::new(CmpOp::Lt, time_slot.end.to_be()));
+    }
 }
 
 /// Sends the supplied nftables batches to the kernel for execution.
@@ -417,13 +1571,17 @@ async fn add_rule_to_batch(
 /// as well as the empty device chains (except for the default rejection rule), the device batches should
 /// contain a command to delete the default rejection rule and insert the actual device rules.
 /// Taken and adapted from https://github.com/mullvad/nftnl-rs/blob/master/nftnl/examples/add-rules.rs
-/// Note: An error of type IoError due to an OS error with code 71 might not indicate a protocol
-/// error but a permission error instead (either run as root or use `setcap 'cap_net_admin=+ep' /path/to/program` on the built binary.
+/// Errors reported by the kernel are decoded into specific errors (e.g. a missing capability or kernel module) which
+/// identify the failed message, see `batch_errors::batch_error`.
 /// For information on how to debug, see http://0x90.at/post/netlink-debugging
 #[cfg(feature = "nftables")]
-fn send_and_process(table_batch: FinalizedBatch, device_batches: &[FinalizedBatch]) -> Result<()> {
+pub(crate) fn send_and_process(table_batch: FinalizedBatch, device_batches: &[FinalizedBatch]) -> Result<()> {
     // Create a netlink socket to netfilter.
     let socket = mnl::Socket::new(mnl::Bus::Netfilter)?;
+    // Extended acknowledgements only add detail to errors, so older kernels without support are fine.
+    if let Err(e) = netlink::enable_extended_ack(&socket) {
+        debug!("Could not enable netlink extended acknowledgements: {}", e);
+    }
 
     let portid = socket.portid();
     let mut buffer = vec![0; nftnl::nft_nlmsg_maxsize() as usize];
@@ -465,17 +1623,19 @@ fn send_and_process_batch(
         // https://github.com/acassen/keepalived/issues/392#issuecomment-239609235
         loop {
             match socket_recv(&socket, buffer) {
-                Ok(Some(message)) => {
-                    match mnl::cb_run(message, *seq_num, portid) {
-                        Ok(mnl::CbResult::Stop) => {
-                            break;
-                        },
-                        Ok(mnl::CbResult::Ok) => (),
-                        Err(e) => {
-                            return Err(e.into());
-                        },
+                Ok(Some(received)) => {
+                    let mut stop = false;
+                    for message in netlink::messages(received).filter(|m| m.port_id == portid) {
+                        if let Some(error) = batch_errors::batch_error(&message) {
+                            return Err(error);
+                        }
+                        // Acknowledgements (errors with code 0) and the end of a multipart message complete the part.
+                        stop |= message.error_code() == Some(0) || message.msg_type == netlink::NLMSG_DONE;
                     }
                     *seq_num += 1;
+                    if stop {
+                        break;
+                    }
                 },
                 Err(e) => {
                     return Err(e.into());
@@ -2,50 +2,88 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 
 use std::{
+    collections::HashMap,
     fs,
     fs::File,
     io,
     io::BufRead,
+    net::IpAddr,
     path::Path,
     sync::{mpsc::channel, Arc},
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use notify::{op::Op, RawEvent, RecursiveMode, Watcher};
 use tokio::{runtime::Builder, sync::RwLock};
 
-use crate::{rpc::rpc_client, services, Enforcer};
+use crate::{
+    rpc::rpc_client,
+    services,
+    services::{
+        dns_blocking::BlockedQueryReporter,
+        dnsmasq_log::{DeviceDnsView, DnsmasqLogParser},
+    },
+    Enforcer,
+};
 
-pub fn watch(enforcer: &Arc<RwLock<Enforcer>>) {
+/// Returns the path of the dnsmasq query log.
+pub fn dnsmasq_log_path() -> &'static Path {
+    if services::is_system_mode() {
+        "/tmp/dnsmasq.log".as_ref()
+    } else {
+        "dnsmasq.log".as_ref()
+    }
+}
+
+/// Watches the dnsmasq query log, forwards the lines of devices which collect data to the controller and learns the
+/// addresses the devices were told for the names they queried (if the given device view is enabled) and reports their
+/// queries for blocked domains (if blocking is enabled).
+pub fn watch(
+    enforcer: &Arc<RwLock<Enforcer>>,
+    device_view: Option<Arc<DeviceDnsView>>,
+    blocked_query_reporter: Option<BlockedQueryReporter>,
+) {
     debug!("Starting dnsmasq.log watcher");
+    let mut parser = DnsmasqLogParser::new();
     let (tx, rx) = channel();
-    let mut watcher = notify::watcher(tx, Duration::from_secs(10)).unwrap();
+    // The log is read right after it was written to (instead of debouncing the writes), so that the addresses the
+    // devices were told are learned before they connect.
+    let mut watcher = notify::raw_watcher(tx).unwrap();
 
-    let path: &Path;
-    let tmp_path: &Path;
-    if services::is_system_mode() {
-        path = "/tmp/dnsmasq.log".as_ref();
-        tmp_path = "/tmp/dnsmasq.log.tmp".as_ref();
-    } else {
-        path = "dnsmasq.log".as_ref();
-        tmp_path = "dnsmasq.log.tmp".as_ref();
-    };
+    let path = dnsmasq_log_path();
+    let tmp_path = path.with_extension("log.tmp");
     loop {
         if let Err(e) = watcher.watch(path, RecursiveMode::NonRecursive) {
             debug!("Failed to watch dnsmasq.log! {:?}", e);
             sleep(Duration::from_secs(10));
             continue;
         }
-        if let Err(e) = read_log_file(&enforcer, path, tmp_path) {
+        if let Err(e) = read_log_file(
+            &enforcer,
+            path,
+            &tmp_path,
+            &mut parser,
+            device_view.as_deref(),
+            blocked_query_reporter.as_ref(),
+        ) {
             warn!("failed to process file {:?}", e);
         }
 
         loop {
             match rx.recv() {
-                Ok(DebouncedEvent::Write(_)) | Ok(DebouncedEvent::NoticeWrite(_)) => {
+                Ok(RawEvent { op: Ok(op), .. }) if op.contains(Op::WRITE) => {
+                    // Writes that happened until the log is read are covered by reading it.
+                    while rx.try_recv().is_ok() {}
                     // inner function to make use of Result
-                    if let Err(e) = read_log_file(&enforcer, path, tmp_path) {
+                    if let Err(e) = read_log_file(
+                        &enforcer,
+                        path,
+                        &tmp_path,
+                        &mut parser,
+                        device_view.as_deref(),
+                        blocked_query_reporter.as_ref(),
+                    ) {
                         debug!("failed to process file {:?}", e);
                     }
                 },
@@ -56,20 +94,68 @@ pub fn watch(enforcer: &Arc<RwLock<Enforcer>>) {
     }
 }
 
-fn read_log_file(enforcer: &Arc<RwLock<Enforcer>>, path: &Path, tmp_path: &Path) -> io::Result<()> {
+fn read_log_file(
+    enforcer: &Arc<RwLock<Enforcer>>,
+    path: &Path,
+    tmp_path: &Path,
+    parser: &mut DnsmasqLogParser,
+    device_view: Option<&DeviceDnsView>,
+    blocked_query_reporter: Option<&BlockedQueryReporter>,
+) -> io::Result<()> {
+    // Truncating the log below is a write as well, which does not need to be handled.
+    if fs::metadata(path)?.len() == 0 {
+        return Ok(());
+    }
     debug!("reading dnsmasq log file");
     // it is possible to lose a logline here, but we cannot lock the file either
     fs::copy(path, tmp_path)?;
     fs::File::create(path)?;
-    let lines = io::BufReader::new(File::open(tmp_path)?).lines();
+    let lines = io::BufReader::new(File::open(tmp_path)?)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
     // create async runtime to run rpc client
-    Builder::new_current_thread()
-        .enable_all()
-        .build()?
-        .block_on(handle_log_lines(&enforcer, lines))?;
+    Builder::new_current_thread().enable_all().build()?.block_on(async {
+        if let Some(device_view) = device_view {
+            learn_device_addresses(enforcer, parser, device_view, &lines).await;
+        }
+        if let Some(blocked_query_reporter) = blocked_query_reporter {
+            blocked_query_reporter.report_blocked_queries(&enforcer.read().await.config, parser, &lines);
+        }
+        handle_log_lines(&enforcer, lines.into_iter().map(Ok)).await
+    })?;
     Ok(())
 }
 
+/// Learns the addresses the devices were told for the names they queried from the given lines of the query log.
+async fn learn_device_addresses(
+    enforcer: &RwLock<Enforcer>,
+    parser: &mut DnsmasqLogParser,
+    device_view: &DeviceDnsView,
+    lines: &[String],
+) {
+    let device_ids: HashMap<IpAddr, i64> = enforcer
+        .read()
+        .await
+        .config
+        .devices()
+        .iter()
+        .flat_map(|d| {
+            Iterator::chain(
+                d.ipv4_addr.iter().map(|&addr| IpAddr::from(addr)),
+                d.ipv6_addr.iter().map(|&addr| IpAddr::from(addr)),
+            )
+            .map(move |addr| (addr, d.id))
+        })
+        .collect();
+    let now = Instant::now();
+    for learned in lines.iter().flat_map(|line| parser.process_line(line)) {
+        if let Some(&device_id) = device_ids.get(&learned.client) {
+            device_view.learn(device_id, &learned.name, learned.addr, now);
+        }
+    }
+    device_view.notify_changes();
+}
+
 async fn handle_log_lines(
     enforcer: &RwLock<Enforcer>,
     lines: impl Iterator<Item=io::Result<String>>,
@@ -3,10 +3,25 @@
 
 use std::env;
 
+pub mod abstractions;
+pub mod anomaly;
+pub mod batch_errors;
+pub mod coexistence;
 pub mod controller_name;
 pub mod dns;
+pub mod dns_blocking;
+pub mod dnsmasq_log;
+pub mod events;
 pub mod firewall_service;
 pub mod log_watcher;
+pub mod netlink;
+pub mod nflog;
+#[cfg(feature = "nftables")]
+pub mod nft_exprs;
+pub mod rule_annotations;
+pub mod schedule;
+pub mod self_test;
+pub mod zones;
 
 pub fn is_system_mode() -> bool {
     env::var("NAMIB_SYSTEM").as_deref() == Ok("1")
@@ -0,0 +1,554 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{convert::TryInto, mem::size_of};
+
+// Minimal helpers to construct and parse netlink messages for the netfilter subsystems that are not covered by
+// nftnl-rs and mnl-rs. See netlink(7) and include/uapi/linux/netlink.h for the message format.
+
+/// Message type of netlink error and acknowledgement messages.
+pub const NLMSG_ERROR: u16 = 2;
+/// Message type of the message which terminates a multipart message (e.g. a dump).
+pub const NLMSG_DONE: u16 = 3;
+
+pub const NLM_F_REQUEST: u16 = 0x1;
+pub const NLM_F_MULTI: u16 = 0x2;
+pub const NLM_F_ACK: u16 = 0x4;
+pub const NLM_F_DUMP: u16 = 0x300;
+/// Flag of error messages whose original message has been truncated to its header.
+pub const NLM_F_CAPPED: u16 = 0x100;
+/// Flag of error messages which contain extended acknowledgement attributes.
+pub const NLM_F_ACK_TLVS: u16 = 0x200;
+
+/// Extended acknowledgement attribute containing an error message.
+const NLMSGERR_ATTR_MSG: u16 = 1;
+/// Extended acknowledgement attribute containing the offset of the invalid attribute in the original message.
+const NLMSGERR_ATTR_OFFS: u16 = 2;
+
+/// Flag that has to be set for the type of nested attributes.
+pub const NLA_F_NESTED: u16 = 0x8000;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+
+/// nfnetlink subsystem of nftables.
+pub const NFNL_SUBSYS_NFTABLES: u16 = 10;
+
+/// Version of the nfnetlink protocol.
+const NFNETLINK_V0: u8 = 0;
+
+const NLMSG_HDR_LEN: usize = 16;
+const NLA_HDR_LEN: usize = 4;
+const NFGENMSG_LEN: usize = 4;
+
+/// Rounds the given length up to the netlink alignment of 4 bytes.
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Builder for a single netlink message.
+#[derive(Debug, Clone)]
+pub struct MessageBuilder {
+    buffer: Vec<u8>,
+    open_nests: Vec<usize>,
+}
+
+impl MessageBuilder {
+    /// Creates a message with the given type, flags and sequence number.
+    pub fn new(msg_type: u16, flags: u16, seq: u32) -> MessageBuilder {
+        let mut buffer = Vec::with_capacity(256);
+        // The message length is set once the message is finished.
+        buffer.extend_from_slice(&0u32.to_ne_bytes());
+        buffer.extend_from_slice(&msg_type.to_ne_bytes());
+        buffer.extend_from_slice(&flags.to_ne_bytes());
+        buffer.extend_from_slice(&seq.to_ne_bytes());
+        buffer.extend_from_slice(&0u32.to_ne_bytes());
+        MessageBuilder {
+            buffer,
+            open_nests: Vec::new(),
+        }
+    }
+
+    /// Creates an nfnetlink message for the given subsystem and message type with the given address family and
+    /// resource id.
+    pub fn new_nfnetlink(
+        subsystem: u16,
+        msg_type: u16,
+        flags: u16,
+        seq: u32,
+        family: u8,
+        resource_id: u16,
+    ) -> MessageBuilder {
+        let mut builder = MessageBuilder::new((subsystem << 8) | msg_type, flags, seq);
+        builder.buffer.push(family);
+        builder.buffer.push(NFNETLINK_V0);
+        builder.buffer.extend_from_slice(&resource_id.to_be_bytes());
+        builder
+    }
+
+    /// Adds an attribute with the given type and raw data.
+    pub fn attr(&mut self, attr_type: u16, data: &[u8]) -> &mut Self {
+        let len = NLA_HDR_LEN + data.len();
+        self.buffer.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buffer.extend_from_slice(&attr_type.to_ne_bytes());
+        self.buffer.extend_from_slice(data);
+        self.pad();
+        self
+    }
+
+    /// Adds a null-terminated string attribute.
+    pub fn attr_str(&mut self, attr_type: u16, value: &str) -> &mut Self {
+        let mut data = Vec::with_capacity(value.len() + 1);
+        data.extend_from_slice(value.as_bytes());
+        data.push(0);
+        self.attr(attr_type, &data)
+    }
+
+    /// Adds a 32 bit attribute in network byte order.
+    pub fn attr_u32_be(&mut self, attr_type: u16, value: u32) -> &mut Self {
+        self.attr(attr_type, &value.to_be_bytes())
+    }
+
+    /// Adds a 64 bit attribute in network byte order.
+    pub fn attr_u64_be(&mut self, attr_type: u16, value: u64) -> &mut Self {
+        self.attr(attr_type, &value.to_be_bytes())
+    }
+
+    /// Starts a nested attribute. All attributes added until the matching call to `end_nested` are part of it.
+    pub fn begin_nested(&mut self, attr_type: u16) -> &mut Self {
+        self.open_nests.push(self.buffer.len());
+        self.buffer.extend_from_slice(&0u16.to_ne_bytes());
+        self.buffer.extend_from_slice(&(attr_type | NLA_F_NESTED).to_ne_bytes());
+        self
+    }
+
+    /// Ends the most recently started nested attribute.
+    pub fn end_nested(&mut self) -> &mut Self {
+        if let Some(start) = self.open_nests.pop() {
+            let len = (self.buffer.len() - start) as u16;
+            self.buffer[start..start + 2].copy_from_slice(&len.to_ne_bytes());
+        }
+        self
+    }
+
+    /// Finishes the message and returns its binary representation.
+    pub fn finish(mut self) -> Vec<u8> {
+        while !self.open_nests.is_empty() {
+            self.end_nested();
+        }
+        let len = self.buffer.len() as u32;
+        self.buffer[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buffer
+    }
+
+    fn pad(&mut self) {
+        let padded_len = align(self.buffer.len());
+        self.buffer.resize(padded_len, 0);
+    }
+}
+
+/// A received netlink message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Message<'a> {
+    pub msg_type: u16,
+    pub flags: u16,
+    pub seq: u32,
+    pub port_id: u32,
+    /// The message payload following the netlink message header.
+    pub payload: &'a [u8],
+}
+
+impl<'a> Message<'a> {
+    /// Returns the nfnetlink subsystem of this message.
+    pub fn subsystem(&self) -> u16 {
+        self.msg_type >> 8
+    }
+
+    /// Returns the subsystem specific message type of this message.
+    pub fn subsystem_msg_type(&self) -> u16 {
+        self.msg_type & 0xff
+    }
+
+    /// Returns the attributes of an nfnetlink message (which follow the nfgenmsg header).
+    pub fn nfnetlink_attributes(&self) -> Attributes<'a> {
+        attributes(self.payload.get(NFGENMSG_LEN..).unwrap_or_default())
+    }
+
+    /// Returns the error code if this message is an error message.
+    /// An error code of zero indicates an acknowledgement, all other values are negative errno values.
+    pub fn error_code(&self) -> Option<i32> {
+        if self.msg_type != NLMSG_ERROR {
+            return None;
+        }
+        self.payload
+            .get(0..4)
+            .map(|code| i32::from_ne_bytes(code.try_into().unwrap()))
+    }
+
+    /// For error messages, returns the header of the original message that caused the error.
+    pub fn error_original_header(&self) -> Option<Message<'a>> {
+        if self.msg_type != NLMSG_ERROR {
+            return None;
+        }
+        messages(self.payload.get(4..)?).next()
+    }
+
+    /// For error messages, returns the extended acknowledgement (see `enable_extended_ack`) sent by the kernel.
+    pub fn extended_ack(&self) -> ExtendedAck<'a> {
+        let mut ack = ExtendedAck::default();
+        if self.msg_type != NLMSG_ERROR || self.flags & NLM_F_ACK_TLVS == 0 {
+            return ack;
+        }
+        // The attributes follow the error code and the original message (or only its header if it was capped).
+        let original_len = if self.flags & NLM_F_CAPPED == 0 {
+            self.payload.get(4..8).map_or(NLMSG_HDR_LEN, |len| {
+                u32::from_ne_bytes(len.try_into().unwrap()) as usize
+            })
+        } else {
+            NLMSG_HDR_LEN
+        };
+        for (attr_type, data) in attributes(self.payload.get(4 + align(original_len)..).unwrap_or_default()) {
+            match attr_type {
+                NLMSGERR_ATTR_MSG => ack.message = parse_str(data).filter(|m| !m.is_empty()),
+                NLMSGERR_ATTR_OFFS => {
+                    ack.invalid_attr_offset = data.get(0..4).map(|o| u32::from_ne_bytes(o.try_into().unwrap()))
+                },
+                _ => {},
+            }
+        }
+        ack
+    }
+
+    /// Returns the type of the (top-level) nfnetlink attribute of this message which contains the given offset
+    /// (counted from the beginning of the message header).
+    pub fn nfnetlink_attribute_at(&self, offset: u32) -> Option<u16> {
+        let mut start = NLMSG_HDR_LEN + NFGENMSG_LEN;
+        for (attr_type, data) in self.nfnetlink_attributes() {
+            let end = align(start + NLA_HDR_LEN + data.len());
+            if (start..end).contains(&(offset as usize)) {
+                return Some(attr_type);
+            }
+            start = end;
+        }
+        None
+    }
+
+    /// Returns the types of the nfnetlink attributes of this message which contain the given offset (counted from the
+    /// beginning of the message header), starting with the top-level attribute and descending into nested attributes.
+    pub fn nfnetlink_attribute_path_at(&self, offset: u32) -> Vec<u16> {
+        let mut path = Vec::new();
+        let mut start = NLMSG_HDR_LEN + NFGENMSG_LEN;
+        let mut attrs = self.nfnetlink_attributes();
+        while let Some((attr_type, data)) = attrs.next() {
+            let end = align(start + NLA_HDR_LEN + data.len());
+            if (start..end).contains(&(offset as usize)) {
+                path.push(attr_type);
+                // Descend into the attribute, which only yields further attributes if it is nested.
+                start += NLA_HDR_LEN;
+                attrs = attributes(data);
+                continue;
+            }
+            start = end;
+        }
+        path
+    }
+}
+
+/// Additional information on an error provided by the kernel if extended acknowledgements are enabled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtendedAck<'a> {
+    /// Error message describing the cause of the error.
+    pub message: Option<&'a str>,
+    /// Offset of the attribute which caused the error, counted from the beginning of the original message.
+    pub invalid_attr_offset: Option<u32>,
+}
+
+/// Iterator over the netlink messages in a buffer.
+#[derive(Debug, Clone)]
+pub struct Messages<'a> {
+    buffer: &'a [u8],
+}
+
+/// Returns an iterator over all (complete) netlink messages in the given buffer.
+pub fn messages(buffer: &[u8]) -> Messages<'_> {
+    Messages { buffer }
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Message<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.len() < NLMSG_HDR_LEN {
+            return None;
+        }
+        let len = u32::from_ne_bytes(self.buffer[0..4].try_into().unwrap()) as usize;
+        let header_field = |range: std::ops::Range<usize>| &self.buffer[range];
+        let msg_type = u16::from_ne_bytes(header_field(4..6).try_into().unwrap());
+        let flags = u16::from_ne_bytes(header_field(6..8).try_into().unwrap());
+        let seq = u32::from_ne_bytes(header_field(8..12).try_into().unwrap());
+        let port_id = u32::from_ne_bytes(header_field(12..16).try_into().unwrap());
+        // Error messages may only contain the header of the original message, so the length may exceed the buffer.
+        let payload_end = len.max(NLMSG_HDR_LEN).min(self.buffer.len());
+        let message = Message {
+            msg_type,
+            flags,
+            seq,
+            port_id,
+            payload: &self.buffer[NLMSG_HDR_LEN..payload_end],
+        };
+        self.buffer = self.buffer.get(align(len.max(NLMSG_HDR_LEN))..).unwrap_or_default();
+        Some(message)
+    }
+}
+
+/// Iterator over the netlink attributes in a buffer, yielding the attribute type and the attribute data.
+#[derive(Debug, Clone)]
+pub struct Attributes<'a> {
+    buffer: &'a [u8],
+}
+
+/// Returns an iterator over all netlink attributes in the given buffer.
+pub fn attributes(buffer: &[u8]) -> Attributes<'_> {
+    Attributes { buffer }
+}
+
+impl<'a> Attributes<'a> {
+    /// Returns the data of the first attribute with the given type.
+    pub fn find(mut self, attr_type: u16) -> Option<&'a [u8]> {
+        self.find_map(|(t, data)| if t == attr_type { Some(data) } else { None })
+    }
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer.len() < NLA_HDR_LEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes(self.buffer[0..2].try_into().unwrap()) as usize;
+        let attr_type = u16::from_ne_bytes(self.buffer[2..4].try_into().unwrap()) & NLA_TYPE_MASK;
+        if len < NLA_HDR_LEN || len > self.buffer.len() {
+            return None;
+        }
+        let data = &self.buffer[NLA_HDR_LEN..len];
+        self.buffer = self.buffer.get(align(len)..).unwrap_or_default();
+        Some((attr_type, data))
+    }
+}
+
+/// Parses a netlink attribute containing a null-terminated string.
+pub fn parse_str(data: &[u8]) -> Option<&str> {
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    std::str::from_utf8(&data[..end]).ok()
+}
+
+/// Parses a netlink attribute containing a 32 bit value in network byte order.
+pub fn parse_u32_be(data: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(0..size_of::<u32>())?.try_into().ok()?))
+}
+
+/// Parses a netlink attribute containing a signed 32 bit value in network byte order.
+pub fn parse_i32_be(data: &[u8]) -> Option<i32> {
+    Some(i32::from_be_bytes(data.get(0..size_of::<i32>())?.try_into().ok()?))
+}
+
+/// Parses a netlink attribute containing a 64 bit value in network byte order.
+pub fn parse_u64_be(data: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(0..size_of::<u64>())?.try_into().ok()?))
+}
+
+#[cfg(feature = "nftables")]
+mod nftables {
+    use std::{io, mem::size_of, os::unix::io::AsRawFd};
+
+    use super::{messages, MessageBuilder, NFGENMSG_LEN, NFNL_SUBSYS_NFTABLES, NLMSG_DONE, NLM_F_DUMP, NLM_F_REQUEST};
+    use crate::error::{NetlinkError, Result};
+
+    const NFTA_TABLE_ATTR: u16 = 1;
+
+    const SOL_NETLINK: libc::c_int = 270;
+    const NETLINK_EXT_ACK: libc::c_int = 11;
+
+    /// Requests extended acknowledgements from the kernel, which add an error message and the offset of the invalid
+    /// attribute to error messages. Requires Linux 4.12 or newer.
+    pub fn enable_extended_ack(socket: &mnl::Socket) -> io::Result<()> {
+        let enabled: libc::c_int = 1;
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                SOL_NETLINK,
+                NETLINK_EXT_ACK,
+                &enabled as *const libc::c_int as *const libc::c_void,
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Requests a dump of all nftables objects of the given message type (e.g. `NFT_MSG_GETRULE`) and address family
+    /// (`NFPROTO_UNSPEC` for all families), optionally restricted to a single table.
+    /// Returns the address family and the attributes of each dumped object.
+    pub fn nftables_dump(
+        msg_type: u16,
+        family: u8,
+        table: Option<&str>,
+        error_message: &'static str,
+    ) -> Result<Vec<(u8, Vec<u8>)>> {
+        let socket = mnl::Socket::new(mnl::Bus::Netfilter)?;
+        let seq = 1;
+        let mut request = MessageBuilder::new_nfnetlink(
+            NFNL_SUBSYS_NFTABLES,
+            msg_type,
+            NLM_F_REQUEST | NLM_F_DUMP,
+            seq,
+            family,
+            0,
+        );
+        if let Some(table) = table {
+            // The table is the first attribute of all nftables objects that belong to a table.
+            request.attr_str(NFTA_TABLE_ATTR, table);
+        }
+        socket.send(&request.finish())?;
+
+        let mut buffer = vec![0; nftnl::nft_nlmsg_maxsize() as usize];
+        let mut objects = Vec::new();
+        loop {
+            let len = socket.recv(&mut buffer)?;
+            if len == 0 {
+                return Ok(objects);
+            }
+            for message in messages(&buffer[..len]).filter(|m| m.seq == seq) {
+                if message.msg_type == NLMSG_DONE {
+                    return Ok(objects);
+                }
+                if let Some(errno) = message.error_code() {
+                    if errno == 0 {
+                        continue;
+                    }
+                    return NetlinkError {
+                        message: error_message,
+                        errno: -errno,
+                    }
+                    .fail();
+                }
+                if message.subsystem() == NFNL_SUBSYS_NFTABLES && message.payload.len() >= NFGENMSG_LEN {
+                    objects.push((message.payload[0], message.payload[NFGENMSG_LEN..].to_vec()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "nftables")]
+pub use nftables::{enable_extended_ack, nftables_dump};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let mut builder = MessageBuilder::new_nfnetlink(4, 1, NLM_F_REQUEST | NLM_F_ACK, 42, 2, 100);
+        builder.attr(1, &[1]);
+        builder
+            .begin_nested(3)
+            .attr_str(1, "eth0")
+            .attr_u32_be(2, 7)
+            .end_nested();
+        builder.attr_u64_be(4, 1 << 40);
+        let mut buffer = builder.finish();
+        assert_eq!(buffer.len() % 4, 0);
+        // Append a second message to check that multiple messages in a buffer are parsed correctly.
+        buffer.extend(MessageBuilder::new(NLMSG_DONE, 0, 43).finish());
+
+        let parsed: Vec<Message> = messages(&buffer).collect();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].subsystem(), 4);
+        assert_eq!(parsed[0].subsystem_msg_type(), 1);
+        assert_eq!(parsed[0].seq, 42);
+        assert_eq!(parsed[0].payload[3], 100);
+        assert_eq!(parsed[1].msg_type, NLMSG_DONE);
+        assert_eq!(parsed[1].seq, 43);
+
+        let attrs: Vec<(u16, &[u8])> = parsed[0].nfnetlink_attributes().collect();
+        assert_eq!(attrs.len(), 3);
+        assert_eq!(attrs[0], (1, &[1u8][..]));
+        assert_eq!(attrs[1].0, 3);
+        let nested = attributes(attrs[1].1);
+        assert_eq!(nested.clone().find(1).and_then(parse_str), Some("eth0"));
+        assert_eq!(nested.find(2).and_then(parse_u32_be), Some(7));
+        assert_eq!(parse_u64_be(attrs[2].1), Some(1 << 40));
+    }
+
+    #[test]
+    fn test_error_message() {
+        let original = MessageBuilder::new(0x0a06, NLM_F_REQUEST, 7).finish();
+        let mut error = MessageBuilder::new(NLMSG_ERROR, 0, 7);
+        // EPERM
+        let mut payload = (-1i32).to_ne_bytes().to_vec();
+        payload.extend_from_slice(&original);
+        error.buffer.extend_from_slice(&payload);
+        let buffer = error.finish();
+
+        let message = messages(&buffer).next().unwrap();
+        assert_eq!(message.error_code(), Some(-1));
+        assert_eq!(message.error_original_header().map(|m| m.msg_type), Some(0x0a06));
+        assert_eq!(message.error_original_header().map(|m| m.seq), Some(7));
+        assert_eq!(message.extended_ack(), ExtendedAck::default());
+    }
+
+    #[test]
+    fn test_extended_ack() {
+        let mut original = MessageBuilder::new_nfnetlink(NFNL_SUBSYS_NFTABLES, 6, NLM_F_REQUEST, 7, 1, 0);
+        original.attr_str(1, "namib").attr_str(2, "device_3");
+        let original = original.finish();
+        let mut error = MessageBuilder::new(NLMSG_ERROR, NLM_F_ACK_TLVS, 7);
+        // ENOENT
+        error.buffer.extend_from_slice(&(-2i32).to_ne_bytes());
+        error.buffer.extend_from_slice(&original);
+        // The second attribute (the chain) starts after the headers and the first attribute.
+        let chain_offset = (NLMSG_HDR_LEN + NFGENMSG_LEN + align(NLA_HDR_LEN + 6)) as u32;
+        error
+            .attr_str(NLMSGERR_ATTR_MSG, "chain not found")
+            .attr(NLMSGERR_ATTR_OFFS, &chain_offset.to_ne_bytes());
+        let buffer = error.finish();
+
+        let message = messages(&buffer).next().unwrap();
+        let ack = message.extended_ack();
+        assert_eq!(ack.message, Some("chain not found"));
+        assert_eq!(ack.invalid_attr_offset, Some(chain_offset));
+        let original = message.error_original_header().unwrap();
+        assert_eq!(original.nfnetlink_attribute_at(chain_offset), Some(2));
+        assert_eq!(original.nfnetlink_attribute_at(chain_offset - 1), Some(1));
+        assert_eq!(original.nfnetlink_attribute_at(1000), None);
+        assert_eq!(original.nfnetlink_attribute_path_at(chain_offset), vec![2]);
+    }
+
+    #[test]
+    fn test_nested_attribute_path() {
+        let mut message = MessageBuilder::new_nfnetlink(NFNL_SUBSYS_NFTABLES, 6, NLM_F_REQUEST, 7, 1, 0);
+        message.attr_str(1, "namib").begin_nested(4).begin_nested(1);
+        message
+            .attr_str(1, "lookup")
+            .attr_u32_be(2, 1)
+            .end_nested()
+            .end_nested();
+        let buffer = message.finish();
+        let message = messages(&buffer).next().unwrap();
+
+        let nested_offset = (NLMSG_HDR_LEN + NFGENMSG_LEN + align(NLA_HDR_LEN + 6)) as u32;
+        let name_offset = nested_offset + 2 * NLA_HDR_LEN as u32;
+        let data_offset = name_offset + align(NLA_HDR_LEN + 7) as u32;
+        assert_eq!(message.nfnetlink_attribute_path_at(nested_offset), vec![4]);
+        assert_eq!(
+            message.nfnetlink_attribute_path_at(nested_offset + NLA_HDR_LEN as u32),
+            vec![4, 1]
+        );
+        assert_eq!(message.nfnetlink_attribute_path_at(name_offset), vec![4, 1, 1]);
+        assert_eq!(message.nfnetlink_attribute_path_at(data_offset), vec![4, 1, 2]);
+        assert_eq!(message.nfnetlink_attribute_path_at(nested_offset - 1), vec![1]);
+    }
+}
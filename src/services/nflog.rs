@@ -0,0 +1,413 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    services::{
+        anomaly,
+        anomaly::AnomalyDetector,
+        events::{EventReporter, LimitKind, PolicyEvent},
+        firewall_service::FirewallService,
+    },
+    settings::Settings,
+};
+
+/// Prefix of all NFLOG prefixes used by the enforcer.
+const LOG_PREFIX_START: &str = "namib";
+
+/// Number of bytes of a packet (starting with the network layer header) that are copied to the enforcer if the
+/// contents of the packet are required. This covers the IP header and the beginning of the transport layer header.
+pub const LOGGED_PACKET_SNAPLEN: u32 = 128;
+
+/// Interval in which the time windows of devices without recent connection attempts are removed.
+const ANOMALY_WINDOW_CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Number of logged packets that may be queued for processing before new packets are discarded.
+pub const LOGGED_PACKET_QUEUE_SIZE: usize = 1024;
+
+/// Reason for which a packet was sent to the enforcer by the firewall.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogKind {
+    /// The packet exceeded the given limit and was dropped.
+    LimitExceeded(LimitKind),
+    /// The packet starts a new connection initiated by the device.
+    NewConnection,
+    /// The packet starts a new connection and is rejected (or dropped) by the firewall.
+    Rejected,
+    /// The packet was not matched by any rule of the device and accepted by its fallback verdict.
+    Unmatched,
+}
+
+impl LogKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            LogKind::LimitExceeded(LimitKind::Rate) => "limit-rate",
+            LogKind::LimitExceeded(LimitKind::ConnectionRate) => "limit-connection-rate",
+            LogKind::LimitExceeded(LimitKind::Quota) => "limit-quota",
+            LogKind::LimitExceeded(LimitKind::Connections) => "limit-connections",
+            LogKind::NewConnection => "new-connection",
+            LogKind::Rejected => "rejected",
+            LogKind::Unmatched => "unmatched",
+        }
+    }
+}
+
+impl FromStr for LogKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "limit-rate" => Ok(LogKind::LimitExceeded(LimitKind::Rate)),
+            "limit-connection-rate" => Ok(LogKind::LimitExceeded(LimitKind::ConnectionRate)),
+            "limit-quota" => Ok(LogKind::LimitExceeded(LimitKind::Quota)),
+            "limit-connections" => Ok(LogKind::LimitExceeded(LimitKind::Connections)),
+            "new-connection" => Ok(LogKind::NewConnection),
+            "rejected" => Ok(LogKind::Rejected),
+            "unmatched" => Ok(LogKind::Unmatched),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Prefix of packets sent to the enforcer using NFLOG, which identifies the device, rule and reason for logging the
+/// packet. Formatted as `namib:<kind>:<device id>[:<rule index>]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LogPrefix {
+    pub kind: LogKind,
+    pub device_id: i64,
+    pub rule_index: Option<usize>,
+}
+
+impl fmt::Display for LogPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", LOG_PREFIX_START, self.kind.as_str(), self.device_id)?;
+        if let Some(rule_index) = self.rule_index {
+            write!(f, ":{}", rule_index)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for LogPrefix {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        if parts.next() != Some(LOG_PREFIX_START) {
+            return Err(());
+        }
+        let kind = parts.next().ok_or(())?.parse()?;
+        let device_id = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let rule_index = match parts.next() {
+            Some(rule_index) => Some(rule_index.parse().map_err(|_| ())?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(LogPrefix {
+            kind,
+            device_id,
+            rule_index,
+        })
+    }
+}
+
+/// A packet sent to the enforcer by the firewall.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggedPacket {
+    pub prefix: LogPrefix,
+    /// Protocol family of the packet (`NFPROTO_IPV4` or `NFPROTO_IPV6`).
+    pub family: u8,
+    /// The beginning of the packet, starting with the network layer header.
+    pub payload: Vec<u8>,
+}
+
+#[cfg(feature = "nftables")]
+mod nftables {
+    use std::{
+        cmp, thread,
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::mpsc;
+
+    use super::{LoggedPacket, LOGGED_PACKET_SNAPLEN};
+    use crate::{
+        error::{NetlinkError, Result},
+        services::netlink::{self, MessageBuilder, NLM_F_ACK, NLM_F_REQUEST},
+    };
+
+    const NFNL_SUBSYS_ULOG: u16 = 4;
+    const NFULNL_MSG_PACKET: u16 = 0;
+    const NFULNL_MSG_CONFIG: u16 = 1;
+
+    const NFULA_CFG_CMD: u16 = 1;
+    const NFULA_CFG_MODE: u16 = 2;
+    const NFULNL_CFG_CMD_BIND: u8 = 1;
+    const NFULNL_COPY_PACKET: u8 = 2;
+
+    const NFULA_PAYLOAD: u16 = 9;
+    const NFULA_PREFIX: u16 = 10;
+
+    /// Delay before the socket is re-created after the first error, which is doubled for each further error.
+    const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+    /// Maximum delay before the socket is re-created.
+    const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+    /// Sends a configuration message for the given NFLOG group and waits for its acknowledgement.
+    fn configure(
+        socket: &mnl::Socket,
+        buffer: &mut [u8],
+        group: u16,
+        seq: u32,
+        attr_type: u16,
+        data: &[u8],
+    ) -> Result<()> {
+        let mut message = MessageBuilder::new_nfnetlink(
+            NFNL_SUBSYS_ULOG,
+            NFULNL_MSG_CONFIG,
+            NLM_F_REQUEST | NLM_F_ACK,
+            seq,
+            libc::AF_UNSPEC as u8,
+            group,
+        );
+        message.attr(attr_type, data);
+        socket.send(&message.finish())?;
+        loop {
+            let len = socket.recv(buffer)?;
+            for message in netlink::messages(&buffer[..len]) {
+                match message.error_code() {
+                    Some(0) if message.seq == seq => return Ok(()),
+                    Some(errno) if message.seq == seq => {
+                        return NetlinkError {
+                            message: "Unable to configure NFLOG group",
+                            errno: -errno,
+                        }
+                        .fail()
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+
+    /// Binds to the given NFLOG group and forwards all logged packets with a valid enforcer prefix to the given
+    /// sender. If the socket fails, it is re-created with exponential backoff. Blocks until the receiving end of the
+    /// sender is closed.
+    pub fn listen(group: u16, sender: &mpsc::Sender<LoggedPacket>) {
+        let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
+        loop {
+            let started = Instant::now();
+            let error = match receive(group, sender) {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            // The backoff starts over if the socket worked for a while.
+            if started.elapsed() > MAX_RECONNECT_DELAY {
+                reconnect_delay = INITIAL_RECONNECT_DELAY;
+            }
+            warn!(
+                "Unable to receive packets logged to NFLOG group {}, retrying in {:?}: {:?}",
+                group, reconnect_delay, error
+            );
+            thread::sleep(reconnect_delay);
+            reconnect_delay = cmp::min(reconnect_delay * 2, MAX_RECONNECT_DELAY);
+        }
+    }
+
+    /// Binds a new socket to the given NFLOG group and forwards the logged packets to the given sender, see `listen`.
+    /// Blocks until the receiving end of the sender is closed or the socket fails.
+    fn receive(group: u16, sender: &mpsc::Sender<LoggedPacket>) -> Result<()> {
+        let socket = mnl::Socket::new(mnl::Bus::Netfilter)?;
+        let mut buffer = vec![0; 65536];
+
+        configure(&socket, &mut buffer, group, 1, NFULA_CFG_CMD, &[NFULNL_CFG_CMD_BIND])?;
+        let mut mode = LOGGED_PACKET_SNAPLEN.to_be_bytes().to_vec();
+        mode.extend_from_slice(&[NFULNL_COPY_PACKET, 0]);
+        configure(&socket, &mut buffer, group, 2, NFULA_CFG_MODE, &mode)?;
+        info!("Listening for packets logged to NFLOG group {}", group);
+
+        loop {
+            let len = match socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    // Packets were lost because too many packets were logged at once.
+                    debug!(
+                        "Discarded packets logged to NFLOG group {} because the socket buffer is full",
+                        group
+                    );
+                    continue;
+                },
+                Err(e) => return Err(e.into()),
+            };
+            for message in netlink::messages(&buffer[..len]) {
+                if message.subsystem() != NFNL_SUBSYS_ULOG || message.subsystem_msg_type() != NFULNL_MSG_PACKET {
+                    continue;
+                }
+                let prefix = match message
+                    .nfnetlink_attributes()
+                    .find(NFULA_PREFIX)
+                    .and_then(netlink::parse_str)
+                    .and_then(|p| p.parse().ok())
+                {
+                    Some(prefix) => prefix,
+                    None => continue,
+                };
+                let packet = LoggedPacket {
+                    prefix,
+                    family: message.payload.first().copied().unwrap_or_default(),
+                    payload: message
+                        .nfnetlink_attributes()
+                        .find(NFULA_PAYLOAD)
+                        .map(<[u8]>::to_vec)
+                        .unwrap_or_default(),
+                };
+                match sender.try_send(packet) {
+                    Ok(()) => {},
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        debug!("Discarding logged packet because the queue is full")
+                    },
+                    Err(mpsc::error::TrySendError::Closed(_)) => return Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "nftables")]
+pub use nftables::listen;
+
+/// Without nftables support, no packets are ever logged.
+#[cfg(not(feature = "nftables"))]
+pub fn listen(_group: u16, _sender: &mpsc::Sender<LoggedPacket>) {}
+
+/// Turns logged packets into events and reports them to the controller.
+/// Logged connection attempts are passed to the anomaly detector, detected anomalies are reported and, if configured,
+/// cause the device to be quarantined.
+pub async fn logged_packet_dispatcher(
+    mut receiver: mpsc::Receiver<LoggedPacket>,
+    event_reporter: EventReporter,
+    fw_service: Arc<FirewallService>,
+    settings: Arc<Settings>,
+) {
+    let anomaly_settings = &settings.anomaly_detection;
+    let mut anomaly_detector = AnomalyDetector::new(anomaly_settings.clone());
+    let mut last_cleanup = Instant::now();
+    // Time at which each quarantined device is released, which is postponed if the device is quarantined again.
+    let release_deadlines: Arc<Mutex<HashMap<i64, Instant>>> = Arc::default();
+    while let Some(packet) = receiver.recv().await {
+        let device_id = packet.prefix.device_id;
+        let rejected = match packet.prefix.kind {
+            LogKind::LimitExceeded(limit) => {
+                event_reporter.report(PolicyEvent::LimitExceeded {
+                    device_id,
+                    rule_index: packet.prefix.rule_index,
+                    limit,
+                });
+                continue;
+            },
+            LogKind::Unmatched => {
+                event_reporter.report(PolicyEvent::UnmatchedTraffic { device_id });
+                continue;
+            },
+            LogKind::NewConnection => false,
+            LogKind::Rejected => true,
+        };
+        let attempt = match anomaly::parse_connection_attempt(packet.family, &packet.payload, rejected) {
+            Some(attempt) => attempt,
+            None => continue,
+        };
+        let now = Instant::now();
+        for anomaly in anomaly_detector.observe(device_id, &attempt, now) {
+            warn!("Detected anomaly {:?} for device {}", anomaly, device_id);
+            if anomaly_settings.quarantine {
+                fw_service.quarantine_device(device_id);
+                if let Some(quarantine_seconds) = anomaly_settings.quarantine_seconds {
+                    let deadline = now + Duration::from_secs(quarantine_seconds);
+                    release_deadlines.lock().unwrap().insert(device_id, deadline);
+                    let fw_service = fw_service.clone();
+                    let release_deadlines = release_deadlines.clone();
+                    tokio::spawn(async move {
+                        tokio::time::sleep(deadline.saturating_duration_since(Instant::now())).await;
+                        // The device is only released by the timer of its latest quarantine.
+                        let mut release_deadlines = release_deadlines.lock().unwrap();
+                        if release_deadlines.get(&device_id) == Some(&deadline) {
+                            release_deadlines.remove(&device_id);
+                            fw_service.release_device(device_id);
+                        }
+                    });
+                }
+            }
+            event_reporter.report(PolicyEvent::AnomalyDetected {
+                device_id,
+                anomaly,
+                quarantined: anomaly_settings.quarantine,
+            });
+        }
+        if now.duration_since(last_cleanup) >= ANOMALY_WINDOW_CLEANUP_INTERVAL {
+            anomaly_detector.remove_expired_windows(now);
+            last_cleanup = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_prefix_roundtrip() {
+        let prefixes = vec![
+            LogPrefix {
+                kind: LogKind::LimitExceeded(LimitKind::ConnectionRate),
+                device_id: 12,
+                rule_index: None,
+            },
+            LogPrefix {
+                kind: LogKind::LimitExceeded(LimitKind::Quota),
+                device_id: 3,
+                rule_index: Some(7),
+            },
+            LogPrefix {
+                kind: LogKind::NewConnection,
+                device_id: 4,
+                rule_index: None,
+            },
+            LogPrefix {
+                kind: LogKind::Rejected,
+                device_id: 4,
+                rule_index: Some(1),
+            },
+            LogPrefix {
+                kind: LogKind::Unmatched,
+                device_id: 5,
+                rule_index: None,
+            },
+        ];
+        for prefix in prefixes {
+            assert_eq!(prefix.to_string().parse(), Ok(prefix));
+        }
+        assert_eq!(
+            LogPrefix {
+                kind: LogKind::LimitExceeded(LimitKind::Rate),
+                device_id: 1,
+                rule_index: Some(0),
+            }
+            .to_string(),
+            "namib:limit-rate:1:0"
+        );
+        assert_eq!("namib:unknown:1".parse::<LogPrefix>(), Err(()));
+        assert_eq!("other:limit-rate:1".parse::<LogPrefix>(), Err(()));
+        assert_eq!("namib:limit-rate:1:2:3".parse::<LogPrefix>(), Err(()));
+    }
+}
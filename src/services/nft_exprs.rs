@@ -0,0 +1,648 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    ffi::CString,
+    net::IpAddr,
+    os::raw::{c_char, c_void},
+    time::Duration,
+};
+
+use nftnl::{expr::Expression, nftnl_sys as sys, MsgType, NlMsg, Rule};
+
+use crate::services::{
+    abstractions::AddressRange,
+    netlink::{MessageBuilder, NFNL_SUBSYS_NFTABLES, NLM_F_ACK, NLM_F_REQUEST},
+};
+
+// Additional nftables expressions and objects which are not provided by nftnl-rs.
+// Attribute numbers are taken from libnftnl (include/libnftnl/expr.h), key and operation values as well as the
+// netlink attributes of objects from the kernel uapi headers (include/uapi/linux/netfilter/nf_tables.h).
+
+const NFTNL_EXPR_META_KEY: u16 = 1;
+const NFTNL_EXPR_META_DREG: u16 = 2;
+const NFTNL_EXPR_META_SREG: u16 = 3;
+
+const NFTNL_EXPR_IMM_DREG: u16 = 1;
+const NFTNL_EXPR_IMM_DATA: u16 = 2;
+
+const NFTNL_EXPR_PAYLOAD_DREG: u16 = 1;
+const NFTNL_EXPR_PAYLOAD_BASE: u16 = 2;
+const NFTNL_EXPR_PAYLOAD_OFFSET: u16 = 3;
+const NFTNL_EXPR_PAYLOAD_LEN: u16 = 4;
+const NFTNL_EXPR_PAYLOAD_SREG: u16 = 5;
+const NFTNL_EXPR_PAYLOAD_CSUM_TYPE: u16 = 6;
+const NFTNL_EXPR_PAYLOAD_CSUM_OFFSET: u16 = 7;
+
+const NFTNL_EXPR_BYTEORDER_SREG: u16 = 1;
+const NFTNL_EXPR_BYTEORDER_DREG: u16 = 2;
+const NFTNL_EXPR_BYTEORDER_OP: u16 = 3;
+const NFTNL_EXPR_BYTEORDER_LEN: u16 = 4;
+const NFTNL_EXPR_BYTEORDER_SIZE: u16 = 5;
+
+const NFTNL_EXPR_LIMIT_RATE: u16 = 1;
+const NFTNL_EXPR_LIMIT_UNIT: u16 = 2;
+const NFTNL_EXPR_LIMIT_BURST: u16 = 3;
+const NFTNL_EXPR_LIMIT_TYPE: u16 = 4;
+const NFTNL_EXPR_LIMIT_FLAGS: u16 = 5;
+
+const NFTNL_EXPR_QUOTA_BYTES: u16 = 1;
+const NFTNL_EXPR_QUOTA_FLAGS: u16 = 2;
+const NFTNL_EXPR_QUOTA_CONSUMED: u16 = 3;
+
+const NFTNL_EXPR_CONNLIMIT_COUNT: u16 = 1;
+const NFTNL_EXPR_CONNLIMIT_FLAGS: u16 = 2;
+
+const NFTNL_EXPR_LOG_PREFIX: u16 = 1;
+const NFTNL_EXPR_LOG_GROUP: u16 = 2;
+const NFTNL_EXPR_LOG_SNAPLEN: u16 = 3;
+
+const NFTNL_EXPR_FLOW_TABLE_NAME: u16 = 1;
+
+const NFTNL_EXPR_LOOKUP_SREG: u16 = 1;
+const NFTNL_EXPR_LOOKUP_SET: u16 = 3;
+
+const NFTNL_EXPR_REDIR_REG_PROTO_MIN: u16 = 1;
+
+const NFT_MSG_NEWFLOWTABLE: u16 = 22;
+const NFT_MSG_DELFLOWTABLE: u16 = 24;
+const NFTA_FLOWTABLE_TABLE: u16 = 1;
+const NFTA_FLOWTABLE_NAME: u16 = 2;
+const NFTA_FLOWTABLE_HOOK: u16 = 3;
+const NFTA_FLOWTABLE_FLAGS: u16 = 7;
+const NFTA_FLOWTABLE_HOOK_NUM: u16 = 1;
+const NFTA_FLOWTABLE_HOOK_PRIORITY: u16 = 2;
+const NFTA_FLOWTABLE_HOOK_DEVS: u16 = 3;
+const NFTA_DEVICE_NAME: u16 = 1;
+const NF_NETDEV_INGRESS: u32 = 0;
+const NFT_FLOWTABLE_HW_OFFLOAD: u32 = 1;
+
+const NFT_MSG_NEWSET: u16 = 9;
+const NFT_MSG_DELSET: u16 = 11;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFT_MSG_DELSETELEM: u16 = 14;
+const NFTA_SET_TABLE: u16 = 1;
+const NFTA_SET_NAME: u16 = 2;
+const NFTA_SET_FLAGS: u16 = 3;
+const NFTA_SET_KEY_TYPE: u16 = 4;
+const NFTA_SET_KEY_LEN: u16 = 5;
+const NFTA_SET_ID: u16 = 10;
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_FLAGS: u16 = 3;
+const NFTA_SET_ELEM_TIMEOUT: u16 = 4;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFT_SET_INTERVAL: u32 = 0x4;
+const NFT_SET_TIMEOUT: u32 = 0x10;
+const NFT_SET_ELEM_INTERVAL_END: u32 = 0x1;
+// Key types of the nft userspace tool, which are only used to display the set elements.
+const NFT_TYPE_IPADDR: u32 = 7;
+const NFT_TYPE_IP6ADDR: u32 = 8;
+
+const NFT_META_MARK: u32 = 3;
+const NFT_META_TIME_DAY: u32 = 31;
+const NFT_META_TIME_HOUR: u32 = 32;
+
+const NFT_BYTEORDER_HTON: u32 = 1;
+
+const NFT_PAYLOAD_NETWORK_HEADER: u32 = 1;
+const NFT_PAYLOAD_CSUM_NONE: u32 = 0;
+const NFT_PAYLOAD_CSUM_INET: u32 = 1;
+
+const NFT_LIMIT_PKTS: u32 = 0;
+const NFT_LIMIT_PKT_BYTES: u32 = 1;
+const NFT_LIMIT_F_INV: u32 = 1;
+const NFT_QUOTA_F_INV: u32 = 1;
+const NFT_CONNLIMIT_F_INV: u32 = 1;
+
+/// Allocates a new expression with the given (null-terminated) name.
+///
+/// # Panics
+/// Panics if libnftnl was unable to allocate the expression, which is also how nftnl-rs handles this case.
+unsafe fn alloc_expr(name: &[u8]) -> *mut sys::nftnl_expr {
+    let expr = sys::nftnl_expr_alloc(name.as_ptr() as *const c_char);
+    assert!(!expr.is_null(), "Unable to allocate nftnl expression");
+    expr
+}
+
+/// Loads the current time into the first register. Requires kernel version 5.4 or newer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetaTime {
+    /// The current day of the week in UTC (one byte, 0 = Sunday).
+    Day,
+    /// The seconds since midnight UTC (four bytes, host byte order).
+    Hour,
+}
+
+impl Expression for MetaTime {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        let key = match self {
+            MetaTime::Day => NFT_META_TIME_DAY,
+            MetaTime::Hour => NFT_META_TIME_HOUR,
+        };
+        unsafe {
+            let expr = alloc_expr(b"meta\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_META_KEY, key);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_META_DREG, libc::NFT_REG_1 as u32);
+            expr
+        }
+    }
+}
+
+/// Loads the given 32 bit value (in host byte order) into the first register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Immediate32 {
+    pub value: u32,
+}
+
+impl Expression for Immediate32 {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"immediate\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_IMM_DREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_IMM_DATA, self.value);
+            expr
+        }
+    }
+}
+
+/// Loads the given port (in network byte order) into the first register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImmediatePort {
+    pub port: u16,
+}
+
+impl Expression for ImmediatePort {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        let port = self.port.to_be_bytes();
+        unsafe {
+            let expr = alloc_expr(b"immediate\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_IMM_DREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set(
+                expr,
+                NFTNL_EXPR_IMM_DATA,
+                port.as_ptr() as *const c_void,
+                port.len() as u32,
+            );
+            expr
+        }
+    }
+}
+
+/// Redirects the packet to the local machine at the port in the first register (`redirect to :<port>`).
+/// Only valid in chains of the `nat` type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redirect;
+
+impl Expression for Redirect {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"redir\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_REDIR_REG_PROTO_MIN, libc::NFT_REG_1 as u32);
+            expr
+        }
+    }
+}
+
+/// Sets the packet mark to the value in the first register (`meta mark set`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetMark;
+
+impl Expression for SetMark {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"meta\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_META_KEY, NFT_META_MARK);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_META_SREG, libc::NFT_REG_1 as u32);
+            expr
+        }
+    }
+}
+
+/// Loads `len` bytes at the given offset of the network header into the first register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkHeaderLoad {
+    pub offset: u32,
+    pub len: u32,
+}
+
+impl Expression for NetworkHeaderLoad {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"payload\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_DREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_BASE, NFT_PAYLOAD_NETWORK_HEADER);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_OFFSET, self.offset);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_LEN, self.len);
+            expr
+        }
+    }
+}
+
+/// Writes the first `len` bytes of the first register to the given offset of the network header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkHeaderWrite {
+    pub offset: u32,
+    pub len: u32,
+    /// Offset of the internet checksum (RFC 791) covering the written bytes, which is updated by the kernel.
+    pub checksum_offset: Option<u32>,
+}
+
+impl Expression for NetworkHeaderWrite {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"payload\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_SREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_BASE, NFT_PAYLOAD_NETWORK_HEADER);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_OFFSET, self.offset);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_LEN, self.len);
+            match self.checksum_offset {
+                Some(checksum_offset) => {
+                    sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_CSUM_TYPE, NFT_PAYLOAD_CSUM_INET);
+                    sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_CSUM_OFFSET, checksum_offset);
+                },
+                None => sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_PAYLOAD_CSUM_TYPE, NFT_PAYLOAD_CSUM_NONE),
+            }
+            expr
+        }
+    }
+}
+
+/// Converts the value in the first register from host to network byte order.
+/// This is required for greater/less than comparisons of host byte order values, as the kernel compares register
+/// contents byte by byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostToNetwork {
+    /// Size of the converted value in bytes (2, 4 or 8).
+    pub size: u32,
+}
+
+impl Expression for HostToNetwork {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"byteorder\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_BYTEORDER_SREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_BYTEORDER_DREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_BYTEORDER_OP, NFT_BYTEORDER_HTON);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_BYTEORDER_LEN, self.size);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_BYTEORDER_SIZE, self.size);
+            expr
+        }
+    }
+}
+
+/// Unit of a rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitType {
+    /// Packets per second.
+    Packets,
+    /// Bytes per second.
+    Bytes,
+}
+
+/// Token bucket rate limit (`limit rate [over] <rate>/second burst <burst>`).
+/// Matches packets within the limit or, if `over` is set, packets exceeding the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    /// Number of packets or bytes per second.
+    pub rate: u64,
+    /// Number of packets or bytes that may exceed the rate at once.
+    pub burst: u32,
+    pub limit_type: LimitType,
+    pub over: bool,
+}
+
+impl Expression for Limit {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        let limit_type = match self.limit_type {
+            LimitType::Packets => NFT_LIMIT_PKTS,
+            LimitType::Bytes => NFT_LIMIT_PKT_BYTES,
+        };
+        unsafe {
+            let expr = alloc_expr(b"limit\0");
+            sys::nftnl_expr_set_u64(expr, NFTNL_EXPR_LIMIT_RATE, self.rate);
+            sys::nftnl_expr_set_u64(expr, NFTNL_EXPR_LIMIT_UNIT, 1);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_LIMIT_BURST, self.burst);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_LIMIT_TYPE, limit_type);
+            sys::nftnl_expr_set_u32(
+                expr,
+                NFTNL_EXPR_LIMIT_FLAGS,
+                if self.over { NFT_LIMIT_F_INV } else { 0 },
+            );
+            expr
+        }
+    }
+}
+
+/// Byte quota (`quota [over] <bytes> bytes [used <consumed> bytes]`).
+/// Matches packets until the quota is used up or, if `over` is set, packets after the quota has been used up.
+/// The quota is bound to the rule, so the bytes consumed by the quota of a replaced rule have to be carried over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub bytes: u64,
+    /// Number of bytes of the quota that have already been consumed.
+    pub consumed: u64,
+    pub over: bool,
+}
+
+impl Expression for Quota {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"quota\0");
+            sys::nftnl_expr_set_u64(expr, NFTNL_EXPR_QUOTA_BYTES, self.bytes);
+            sys::nftnl_expr_set_u64(expr, NFTNL_EXPR_QUOTA_CONSUMED, self.consumed);
+            sys::nftnl_expr_set_u32(
+                expr,
+                NFTNL_EXPR_QUOTA_FLAGS,
+                if self.over { NFT_QUOTA_F_INV } else { 0 },
+            );
+            expr
+        }
+    }
+}
+
+/// Limit on the number of tracked connections that passed through this rule (`ct count [over] <count>`).
+/// Requires kernel version 5.1 or newer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnLimit {
+    pub count: u32,
+    pub over: bool,
+}
+
+impl Expression for ConnLimit {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"connlimit\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_CONNLIMIT_COUNT, self.count);
+            sys::nftnl_expr_set_u32(
+                expr,
+                NFTNL_EXPR_CONNLIMIT_FLAGS,
+                if self.over { NFT_CONNLIMIT_F_INV } else { 0 },
+            );
+            expr
+        }
+    }
+}
+
+/// Sends matching packets to the given NFLOG group (`log prefix <prefix> group <group>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NfLog {
+    pub group: u16,
+    pub prefix: CString,
+    /// Number of bytes of the packet that are copied to userspace.
+    pub snaplen: u32,
+}
+
+impl Expression for NfLog {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"log\0");
+            sys::nftnl_expr_set_str(expr, NFTNL_EXPR_LOG_PREFIX, self.prefix.as_ptr());
+            sys::nftnl_expr_set_u16(expr, NFTNL_EXPR_LOG_GROUP, self.group);
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_LOG_SNAPLEN, self.snaplen);
+            expr
+        }
+    }
+}
+
+/// Offloads the flow of the packet to the given flowtable (`flow add @<flowtable>`).
+/// The kernel only offloads flows once they are established, so new connections are still evaluated by the rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowOffload {
+    pub flowtable: CString,
+}
+
+impl Expression for FlowOffload {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"flow_offload\0");
+            sys::nftnl_expr_set_str(expr, NFTNL_EXPR_FLOW_TABLE_NAME, self.flowtable.as_ptr());
+            expr
+        }
+    }
+}
+
+/// Flowtable of the inet family on the given interfaces. Packets of offloaded flows bypass the forward hook (and, with
+/// hardware offloading, the CPU). Requires Linux 5.3 or newer for tables of the inet family.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flowtable {
+    pub table: String,
+    pub name: String,
+    pub interfaces: Vec<String>,
+    /// Offload flows to the network hardware (if supported by the drivers of all interfaces).
+    pub hardware: bool,
+}
+
+unsafe impl NlMsg for Flowtable {
+    unsafe fn write(&self, buf: *mut c_void, seq: u32, msg_type: MsgType) {
+        let raw_msg_type = match msg_type {
+            MsgType::Add => NFT_MSG_NEWFLOWTABLE,
+            MsgType::Del => NFT_MSG_DELFLOWTABLE,
+        };
+        let mut message = MessageBuilder::new_nfnetlink(
+            NFNL_SUBSYS_NFTABLES,
+            raw_msg_type,
+            NLM_F_REQUEST | NLM_F_ACK,
+            seq,
+            libc::NFPROTO_INET as u8,
+            0,
+        );
+        message
+            .attr_str(NFTA_FLOWTABLE_TABLE, &self.table)
+            .attr_str(NFTA_FLOWTABLE_NAME, &self.name);
+        if let MsgType::Add = msg_type {
+            message
+                .begin_nested(NFTA_FLOWTABLE_HOOK)
+                .attr_u32_be(NFTA_FLOWTABLE_HOOK_NUM, NF_NETDEV_INGRESS)
+                .attr_u32_be(NFTA_FLOWTABLE_HOOK_PRIORITY, 0)
+                .begin_nested(NFTA_FLOWTABLE_HOOK_DEVS);
+            for interface in &self.interfaces {
+                message.attr_str(NFTA_DEVICE_NAME, interface);
+            }
+            message.end_nested().end_nested();
+            if self.hardware {
+                message.attr_u32_be(NFTA_FLOWTABLE_FLAGS, NFT_FLOWTABLE_HW_OFFLOAD);
+            }
+        }
+        let message = message.finish();
+        // The batch buffer always has room for a message of the maximum netlink message size.
+        std::ptr::copy_nonoverlapping(message.as_ptr(), buf as *mut u8, message.len());
+    }
+}
+
+/// Matches if the value in the first register is contained in the set with the given name (`@<set>`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lookup {
+    pub set: CString,
+}
+
+impl Expression for Lookup {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = alloc_expr(b"lookup\0");
+            sys::nftnl_expr_set_u32(expr, NFTNL_EXPR_LOOKUP_SREG, libc::NFT_REG_1 as u32);
+            sys::nftnl_expr_set_str(expr, NFTNL_EXPR_LOOKUP_SET, self.set.as_ptr());
+            expr
+        }
+    }
+}
+
+/// Named set of IPv4 or IPv6 address ranges in a table of the inet family, whose elements can be changed without
+/// recreating the rules that refer to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressSet {
+    pub table: String,
+    pub name: String,
+    /// Identifies the set within a batch, has to be unique among the sets added in the same batch.
+    pub id: u32,
+    pub ipv6: bool,
+    /// Whether the set contains single addresses which expire (`ExpiringSetElements`) instead of address ranges.
+    pub timeout: bool,
+}
+
+unsafe impl NlMsg for AddressSet {
+    unsafe fn write(&self, buf: *mut c_void, seq: u32, msg_type: MsgType) {
+        let raw_msg_type = match msg_type {
+            MsgType::Add => NFT_MSG_NEWSET,
+            MsgType::Del => NFT_MSG_DELSET,
+        };
+        let mut message = MessageBuilder::new_nfnetlink(
+            NFNL_SUBSYS_NFTABLES,
+            raw_msg_type,
+            NLM_F_REQUEST | NLM_F_ACK,
+            seq,
+            libc::NFPROTO_INET as u8,
+            0,
+        );
+        message
+            .attr_str(NFTA_SET_TABLE, &self.table)
+            .attr_str(NFTA_SET_NAME, &self.name);
+        if let MsgType::Add = msg_type {
+            let (key_type, key_len) = if self.ipv6 {
+                (NFT_TYPE_IP6ADDR, 16)
+            } else {
+                (NFT_TYPE_IPADDR, 4)
+            };
+            let flags = if self.timeout {
+                NFT_SET_TIMEOUT
+            } else {
+                NFT_SET_INTERVAL
+            };
+            message
+                .attr_u32_be(NFTA_SET_FLAGS, flags)
+                .attr_u32_be(NFTA_SET_KEY_TYPE, key_type)
+                .attr_u32_be(NFTA_SET_KEY_LEN, key_len)
+                .attr_u32_be(NFTA_SET_ID, self.id);
+        }
+        let message = message.finish();
+        std::ptr::copy_nonoverlapping(message.as_ptr(), buf as *mut u8, message.len());
+    }
+}
+
+/// Elements of an `AddressSet`. Adding them requires the ranges to be sorted and not to overlap with each other or
+/// with the elements already in the set. Deleting an empty list of ranges removes all elements of the set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressSetElements {
+    pub table: String,
+    pub set: String,
+    pub ranges: Vec<AddressRange>,
+}
+
+/// Returns the bytes of the given address in network byte order.
+fn address_bytes(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// Adds a set element with the given key (and flags or timeout) to the given message.
+fn add_set_element(message: &mut MessageBuilder, key: IpAddr, flags: u32, timeout: Option<Duration>) {
+    message
+        .begin_nested(NFTA_LIST_ELEM)
+        .begin_nested(NFTA_SET_ELEM_KEY)
+        .attr(NFTA_DATA_VALUE, &address_bytes(key))
+        .end_nested();
+    if flags != 0 {
+        message.attr_u32_be(NFTA_SET_ELEM_FLAGS, flags);
+    }
+    if let Some(timeout) = timeout {
+        message.attr_u64_be(NFTA_SET_ELEM_TIMEOUT, timeout.as_millis() as u64);
+    }
+    message.end_nested();
+}
+
+unsafe impl NlMsg for AddressSetElements {
+    unsafe fn write(&self, buf: *mut c_void, seq: u32, msg_type: MsgType) {
+        let raw_msg_type = match msg_type {
+            MsgType::Add => NFT_MSG_NEWSETELEM,
+            MsgType::Del => NFT_MSG_DELSETELEM,
+        };
+        let mut message = MessageBuilder::new_nfnetlink(
+            NFNL_SUBSYS_NFTABLES,
+            raw_msg_type,
+            NLM_F_REQUEST | NLM_F_ACK,
+            seq,
+            libc::NFPROTO_INET as u8,
+            0,
+        );
+        message
+            .attr_str(NFTA_SET_ELEM_LIST_TABLE, &self.table)
+            .attr_str(NFTA_SET_ELEM_LIST_SET, &self.set);
+        if !self.ranges.is_empty() {
+            message.begin_nested(NFTA_SET_ELEM_LIST_ELEMENTS);
+            // Each range consists of an element for its start and one for the first address after it.
+            for range in &self.ranges {
+                add_set_element(&mut message, range.start, 0, None);
+                if let Some(end) = range.end {
+                    add_set_element(&mut message, end, NFT_SET_ELEM_INTERVAL_END, None);
+                }
+            }
+            message.end_nested();
+        }
+        let message = message.finish();
+        std::ptr::copy_nonoverlapping(message.as_ptr(), buf as *mut u8, message.len());
+    }
+}
+
+/// Elements of an `AddressSet` with timeouts, each of which is removed from the set by the kernel once its timeout has
+/// passed. Adding an address that is already in the set does not change its timeout, so the set has to be flushed
+/// (by deleting an empty list of elements) to renew them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpiringSetElements {
+    pub table: String,
+    pub set: String,
+    pub elements: Vec<(IpAddr, Duration)>,
+}
+
+unsafe impl NlMsg for ExpiringSetElements {
+    unsafe fn write(&self, buf: *mut c_void, seq: u32, msg_type: MsgType) {
+        let raw_msg_type = match msg_type {
+            MsgType::Add => NFT_MSG_NEWSETELEM,
+            MsgType::Del => NFT_MSG_DELSETELEM,
+        };
+        let mut message = MessageBuilder::new_nfnetlink(
+            NFNL_SUBSYS_NFTABLES,
+            raw_msg_type,
+            NLM_F_REQUEST | NLM_F_ACK,
+            seq,
+            libc::NFPROTO_INET as u8,
+            0,
+        );
+        message
+            .attr_str(NFTA_SET_ELEM_LIST_TABLE, &self.table)
+            .attr_str(NFTA_SET_ELEM_LIST_SET, &self.set);
+        if !self.elements.is_empty() {
+            message.begin_nested(NFTA_SET_ELEM_LIST_ELEMENTS);
+            for &(addr, timeout) in &self.elements {
+                add_set_element(&mut message, addr, 0, Some(timeout));
+            }
+            message.end_nested();
+        }
+        let message = message.finish();
+        std::ptr::copy_nonoverlapping(message.as_ptr(), buf as *mut u8, message.len());
+    }
+}
@@ -0,0 +1,550 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    str::FromStr,
+};
+
+use crate::services::netlink::{self, Attributes};
+
+// Rules (and optionally chains) created by the enforcer are annotated with a comment describing their origin, which is
+// shown by `nft list ruleset` and `nft monitor trace`. The comment is stored in the userdata of the rule using the
+// same format as the nft command line tool: A type-length-value encoded list of attributes, in which the comment is
+// a null-terminated string.
+
+/// Prefix of all rule comments created by the enforcer.
+const COMMENT_PREFIX: &str = "namib";
+
+/// Maximum length of a comment (excluding the terminating null byte) as enforced by the nft command line tool.
+const MAX_COMMENT_LEN: usize = 127;
+
+/// Type of the userdata attribute containing the comment of a rule or chain.
+const NFTNL_UDATA_COMMENT: u8 = 0;
+
+/// Origin of a rule (or chain) created by the enforcer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RuleOrigin {
+    /// ID of the device (as assigned by the NAMIB controller) the rule was created for.
+    pub device_id: i64,
+    /// Index of the rule in the device's rule list. Not set for rules which apply to the whole device.
+    pub rule_index: Option<usize>,
+    /// Name of the rule (the MUD ACE name).
+    pub rule_name: Option<String>,
+    /// Hostname the addresses of the rule were resolved from.
+    pub hostname: Option<String>,
+    /// Version of the enforcer configuration the rule was created from.
+    pub config_version: String,
+}
+
+/// Replaces characters that would break the key-value format of the comment.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_whitespace() || c == '=' { '_' } else { c })
+        .collect()
+}
+
+impl RuleOrigin {
+    /// Returns the origin of a rule for the given rule of this device.
+    pub fn for_rule(&self, rule_index: usize, rule_name: Option<String>) -> RuleOrigin {
+        RuleOrigin {
+            rule_index: Some(rule_index),
+            rule_name,
+            ..self.clone()
+        }
+    }
+
+    /// Returns this origin with the given hostname.
+    pub fn with_hostname(&self, hostname: Option<&str>) -> RuleOrigin {
+        RuleOrigin {
+            hostname: hostname.map(String::from),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the comment describing this origin, truncated to the maximum comment length.
+    pub fn comment(&self) -> String {
+        let mut comment = self.to_string();
+        if comment.len() > MAX_COMMENT_LEN {
+            let mut end = MAX_COMMENT_LEN;
+            while !comment.is_char_boundary(end) {
+                end -= 1;
+            }
+            comment.truncate(end);
+        }
+        comment
+    }
+}
+
+impl fmt::Display for RuleOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} device={}", COMMENT_PREFIX, self.device_id)?;
+        if let Some(rule_index) = self.rule_index {
+            write!(f, " rule={}", rule_index)?;
+        }
+        if let Some(rule_name) = &self.rule_name {
+            write!(f, " ace={}", sanitize(rule_name))?;
+        }
+        if let Some(hostname) = &self.hostname {
+            write!(f, " host={}", sanitize(hostname))?;
+        }
+        write!(f, " version={}", sanitize(&self.config_version))
+    }
+}
+
+impl FromStr for RuleOrigin {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(' ');
+        if parts.next() != Some(COMMENT_PREFIX) {
+            return Err(());
+        }
+        let mut device_id = None;
+        let mut origin = RuleOrigin::default();
+        for part in parts {
+            let mut key_value = part.splitn(2, '=');
+            let key = key_value.next().ok_or(())?;
+            let value = key_value.next().ok_or(())?;
+            match key {
+                "device" => device_id = Some(value.parse().map_err(|_| ())?),
+                "rule" => origin.rule_index = Some(value.parse().map_err(|_| ())?),
+                "ace" => origin.rule_name = Some(value.to_string()),
+                "host" => origin.hostname = Some(value.to_string()),
+                "version" => origin.config_version = value.to_string(),
+                // Ignore unknown keys to stay compatible with comments created by newer versions.
+                _ => {},
+            }
+        }
+        origin.device_id = device_id.ok_or(())?;
+        Ok(origin)
+    }
+}
+
+/// Encodes the given comment as userdata of a rule or chain.
+pub fn comment_userdata(comment: &str) -> Vec<u8> {
+    let comment = &comment.as_bytes()[..comment.len().min(MAX_COMMENT_LEN)];
+    let mut userdata = Vec::with_capacity(comment.len() + 3);
+    userdata.push(NFTNL_UDATA_COMMENT);
+    userdata.push((comment.len() + 1) as u8);
+    userdata.extend_from_slice(comment);
+    userdata.push(0);
+    userdata
+}
+
+/// Extracts the comment from the userdata of a rule or chain.
+pub fn parse_comment_userdata(mut userdata: &[u8]) -> Option<&str> {
+    while userdata.len() >= 2 {
+        let (attr_type, len) = (userdata[0], usize::from(userdata[1]));
+        let value = userdata.get(2..2 + len)?;
+        if attr_type == NFTNL_UDATA_COMMENT {
+            let end = value.iter().position(|&b| b == 0).unwrap_or(value.len());
+            return std::str::from_utf8(&value[..end]).ok();
+        }
+        userdata = &userdata[2 + len..];
+    }
+    None
+}
+
+/// A rule installed in the enforcer's nftables table.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstalledRule {
+    pub chain: String,
+    pub comment: Option<String>,
+    /// Number of packets matched by the rule (if the rule contains a counter).
+    pub packets: Option<u64>,
+    /// Number of bytes matched by the rule (if the rule contains a counter).
+    pub bytes: Option<u64>,
+    /// Number of bytes consumed by the quota of the rule (if the rule contains a quota).
+    pub quota_consumed: Option<u64>,
+}
+
+impl InstalledRule {
+    /// Returns the origin of the rule as stored in its comment, if the rule was created by the enforcer.
+    pub fn origin(&self) -> Option<RuleOrigin> {
+        self.comment.as_ref()?.parse().ok()
+    }
+}
+
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_RULE_USERDATA: u16 = 7;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_COUNTER_BYTES: u16 = 1;
+const NFTA_COUNTER_PACKETS: u16 = 2;
+const NFTA_QUOTA_CONSUMED: u16 = 3;
+
+/// Parses a rule from the attributes of a rule message sent by the kernel.
+fn parse_rule(attributes: Attributes<'_>) -> Option<(String, InstalledRule)> {
+    let mut table = None;
+    let mut rule = InstalledRule {
+        chain: String::new(),
+        comment: None,
+        packets: None,
+        bytes: None,
+        quota_consumed: None,
+    };
+    for (attr_type, data) in attributes {
+        match attr_type {
+            NFTA_RULE_TABLE => table = netlink::parse_str(data).map(String::from),
+            NFTA_RULE_CHAIN => rule.chain = netlink::parse_str(data)?.to_string(),
+            NFTA_RULE_USERDATA => {
+                rule.comment = parse_comment_userdata(data).map(String::from);
+            },
+            NFTA_RULE_EXPRESSIONS => {
+                for (_, expr) in netlink::attributes(data).filter(|(t, _)| *t == NFTA_LIST_ELEM) {
+                    let expr_attrs = netlink::attributes(expr);
+                    let expr_data = match expr_attrs.clone().find(NFTA_EXPR_DATA).map(netlink::attributes) {
+                        Some(expr_data) => expr_data,
+                        None => continue,
+                    };
+                    match expr_attrs.find(NFTA_EXPR_NAME).and_then(netlink::parse_str) {
+                        Some("counter") => {
+                            rule.bytes = expr_data
+                                .clone()
+                                .find(NFTA_COUNTER_BYTES)
+                                .and_then(netlink::parse_u64_be);
+                            rule.packets = expr_data.find(NFTA_COUNTER_PACKETS).and_then(netlink::parse_u64_be);
+                        },
+                        Some("quota") => {
+                            rule.quota_consumed = expr_data.find(NFTA_QUOTA_CONSUMED).and_then(netlink::parse_u64_be);
+                        },
+                        _ => {},
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    Some((table?, rule))
+}
+
+/// Returns the key identifying the quotas of the rules with the given origin in the given chain across configuration
+/// versions.
+fn quota_key(chain: &str, origin: &RuleOrigin) -> (String, String) {
+    let origin = RuleOrigin {
+        config_version: String::new(),
+        ..origin.clone()
+    };
+    (chain.to_string(), origin.comment())
+}
+
+/// Keeps track of the origins of all rules created for a firewall configuration, which is used to verify that the
+/// rules have been installed as expected.
+#[derive(Debug, Default)]
+pub struct RuleAnnotator {
+    /// Number of expected rules per comment.
+    expected: HashMap<String, usize>,
+    /// Bytes consumed by the quotas of the previously installed rules, in the order of the rules per chain and origin.
+    consumed_quotas: HashMap<(String, String), VecDeque<u64>>,
+}
+
+impl RuleAnnotator {
+    pub fn new() -> RuleAnnotator {
+        RuleAnnotator::default()
+    }
+
+    /// Records that a rule with the given origin is expected to be installed.
+    pub fn record(&mut self, origin: &RuleOrigin) {
+        self.record_comment(origin.comment());
+    }
+
+    /// Records that a rule with the given comment, which does not belong to a device, is expected to be installed.
+    pub fn record_comment(&mut self, comment: String) {
+        *self.expected.entry(comment).or_default() += 1;
+    }
+
+    /// Remembers the bytes consumed by the quotas of the given installed rules, so that they are carried over to the
+    /// rules created for the new configuration (see `consumed_quota`).
+    pub fn carry_over_quotas(&mut self, installed: &[InstalledRule]) {
+        for rule in installed {
+            if let (Some(consumed), Some(origin)) = (rule.quota_consumed, rule.origin()) {
+                self.consumed_quotas
+                    .entry(quota_key(&rule.chain, &origin))
+                    .or_default()
+                    .push_back(consumed);
+            }
+        }
+    }
+
+    /// Returns the bytes consumed by the quota of the next rule with the given origin in the given chain, i.e. by the
+    /// quota of the installed rule at the same position (or 0 if there is no such rule).
+    pub fn consumed_quota(&mut self, chain: &str, origin: &RuleOrigin) -> u64 {
+        self.consumed_quotas
+            .get_mut(&quota_key(chain, origin))
+            .and_then(VecDeque::pop_front)
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of rules that are expected to be installed.
+    pub fn expected_rules(&self) -> usize {
+        self.expected.values().sum()
+    }
+
+    /// Compares the expected rules with the given installed rules.
+    /// Returns the comments of missing rules and the comments of unexpected rules (with the number of rules each).
+    pub fn verify(&self, installed: &[InstalledRule]) -> (Vec<(String, usize)>, Vec<(String, usize)>) {
+        let mut remaining = self.expected.clone();
+        let mut unexpected: HashMap<String, usize> = HashMap::new();
+        for rule in installed {
+            let comment = rule.comment.clone().unwrap_or_default();
+            match remaining.get_mut(&comment) {
+                Some(count) if *count > 0 => *count -= 1,
+                _ => *unexpected.entry(comment).or_default() += 1,
+            }
+        }
+        let missing = remaining.into_iter().filter(|(_, count)| *count > 0).collect();
+        (missing, unexpected.into_iter().collect())
+    }
+}
+
+#[cfg(feature = "nftables")]
+mod nftables {
+    use std::os::raw::c_void;
+
+    use nftnl::{nftnl_sys as sys, Chain, Rule};
+
+    use super::{comment_userdata, parse_rule, InstalledRule, RuleAnnotator, RuleOrigin};
+    use crate::{error::Result, services::netlink};
+
+    const NFTNL_RULE_USERDATA: u16 = 7;
+    const NFTNL_CHAIN_USERDATA: u16 = 15;
+
+    const NFT_MSG_GETRULE: u16 = 7;
+
+    impl RuleAnnotator {
+        /// Stores the comment for the given origin in the given rule and records that the rule is expected to be
+        /// installed.
+        pub fn annotate(&mut self, rule: &mut Rule, origin: &RuleOrigin) {
+            trace!("Adding rule: {}", origin);
+            set_rule_comment(rule, &origin.comment());
+            self.record(origin);
+        }
+
+        /// Stores the given comment in the given rule and records that the rule is expected to be installed.
+        /// Used for rules which do not belong to a device.
+        pub fn annotate_with_comment(&mut self, rule: &mut Rule, comment: &str) {
+            trace!("Adding rule: {}", comment);
+            set_rule_comment(rule, comment);
+            self.record_comment(comment.to_string());
+        }
+    }
+
+    /// Stores the given comment in the userdata of the given rule.
+    pub fn set_rule_comment(rule: &mut Rule, comment: &str) {
+        let userdata = comment_userdata(comment);
+        unsafe {
+            sys::nftnl_rule_set_data(
+                rule.as_mut_ptr(),
+                NFTNL_RULE_USERDATA,
+                userdata.as_ptr() as *const c_void,
+                userdata.len() as u32,
+            );
+        }
+    }
+
+    /// Stores the given comment in the userdata of the given chain.
+    /// Requires libnftnl 1.1.9 (older versions abort the process) and Linux 5.10 or newer.
+    pub fn set_chain_comment(chain: &mut Chain, comment: &str) {
+        let userdata = comment_userdata(comment);
+        unsafe {
+            sys::nftnl_chain_set_data(
+                chain.as_mut_ptr(),
+                NFTNL_CHAIN_USERDATA,
+                userdata.as_ptr() as *const c_void,
+                userdata.len() as u32,
+            );
+        }
+    }
+
+    /// Retrieves all rules of the given nftables table (of the inet family) from the kernel.
+    pub fn installed_rules(table_name: &str) -> Result<Vec<InstalledRule>> {
+        let rules = netlink::nftables_dump(
+            NFT_MSG_GETRULE,
+            libc::NFPROTO_INET as u8,
+            Some(table_name),
+            "Unable to retrieve installed rules",
+        )?;
+        // Older kernels ignore the table attribute of dump requests and return the rules of all tables.
+        Ok(rules
+            .iter()
+            .filter_map(|(_, attrs)| parse_rule(netlink::attributes(attrs)))
+            .filter(|(table, _)| table == table_name)
+            .map(|(_, rule)| rule)
+            .collect())
+    }
+}
+
+#[cfg(feature = "nftables")]
+pub use nftables::{installed_rules, set_chain_comment, set_rule_comment};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::netlink::MessageBuilder;
+
+    fn origin() -> RuleOrigin {
+        RuleOrigin {
+            device_id: 4,
+            rule_index: None,
+            rule_name: None,
+            hostname: None,
+            config_version: "42".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_origin_comment_roundtrip() {
+        let device_origin = origin();
+        assert_eq!(device_origin.comment(), "namib device=4 version=42");
+        let rule_origin = device_origin
+            .for_rule(2, Some("acl_0 ace=1".to_string()))
+            .with_hostname(Some("example.com"));
+        assert_eq!(
+            rule_origin.comment(),
+            "namib device=4 rule=2 ace=acl_0_ace_1 host=example.com version=42"
+        );
+        for origin in &[device_origin, rule_origin] {
+            let parsed: RuleOrigin = origin.comment().parse().unwrap();
+            assert_eq!(parsed.device_id, origin.device_id);
+            assert_eq!(parsed.rule_index, origin.rule_index);
+            assert_eq!(parsed.hostname, origin.hostname);
+            assert_eq!(parsed.config_version, origin.config_version);
+        }
+        assert_eq!("other device=1".parse::<RuleOrigin>(), Err(()));
+
+        let long_origin = origin().with_hostname(Some(&"a".repeat(200)));
+        assert_eq!(long_origin.comment().len(), MAX_COMMENT_LEN);
+    }
+
+    #[test]
+    fn test_comment_userdata() {
+        let userdata = comment_userdata("namib device=1 version=1");
+        assert_eq!(userdata[0], NFTNL_UDATA_COMMENT);
+        assert_eq!(usize::from(userdata[1]), userdata.len() - 2);
+        assert_eq!(parse_comment_userdata(&userdata), Some("namib device=1 version=1"));
+        // Other attributes before the comment are skipped.
+        let mut userdata_with_other_attr = vec![5, 2, 1, 2];
+        userdata_with_other_attr.extend_from_slice(&userdata);
+        assert_eq!(
+            parse_comment_userdata(&userdata_with_other_attr),
+            Some("namib device=1 version=1")
+        );
+        assert_eq!(parse_comment_userdata(&[0, 10, 1]), None);
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let comment = origin().for_rule(1, None).comment();
+        let mut message = MessageBuilder::new_nfnetlink(10, 6, 0, 1, 1, 0);
+        message.attr_str(NFTA_RULE_TABLE, "namib");
+        message.attr_str(NFTA_RULE_CHAIN, "device_4");
+        message.begin_nested(NFTA_RULE_EXPRESSIONS);
+        message
+            .begin_nested(NFTA_LIST_ELEM)
+            .attr_str(NFTA_EXPR_NAME, "immediate")
+            .end_nested();
+        message
+            .begin_nested(NFTA_LIST_ELEM)
+            .attr_str(NFTA_EXPR_NAME, "counter")
+            .begin_nested(NFTA_EXPR_DATA)
+            .attr_u64_be(NFTA_COUNTER_BYTES, 1500)
+            .attr_u64_be(NFTA_COUNTER_PACKETS, 3)
+            .end_nested()
+            .end_nested();
+        message
+            .begin_nested(NFTA_LIST_ELEM)
+            .attr_str(NFTA_EXPR_NAME, "quota")
+            .begin_nested(NFTA_EXPR_DATA)
+            .attr_u64_be(1, 1_000_000)
+            .attr_u64_be(NFTA_QUOTA_CONSUMED, 4000)
+            .end_nested()
+            .end_nested();
+        message.end_nested();
+        message.attr(NFTA_RULE_USERDATA, &comment_userdata(&comment));
+        let buffer = message.finish();
+
+        let message = netlink::messages(&buffer).next().unwrap();
+        let (table, rule) = parse_rule(message.nfnetlink_attributes()).unwrap();
+        assert_eq!(table, "namib");
+        assert_eq!(
+            rule,
+            InstalledRule {
+                chain: "device_4".to_string(),
+                comment: Some(comment),
+                packets: Some(3),
+                bytes: Some(1500),
+                quota_consumed: Some(4000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_carry_over_quotas() {
+        let quota_rule = |origin: &RuleOrigin, consumed: u64| InstalledRule {
+            chain: "device_4".to_string(),
+            comment: Some(origin.comment()),
+            packets: None,
+            bytes: None,
+            quota_consumed: Some(consumed),
+        };
+        let device_origin = origin();
+        let rule_origin = device_origin.for_rule(0, None);
+        let mut annotator = RuleAnnotator::new();
+        annotator.carry_over_quotas(&[
+            quota_rule(&device_origin, 100),
+            quota_rule(&rule_origin, 200),
+            quota_rule(&device_origin, 300),
+        ]);
+
+        // The quotas are carried over to the rules with the same origin in a new configuration version.
+        let new_device_origin = RuleOrigin {
+            config_version: "43".to_string(),
+            ..device_origin
+        };
+        assert_eq!(annotator.consumed_quota("device_4", &new_device_origin), 100);
+        assert_eq!(annotator.consumed_quota("device_4", &new_device_origin), 300);
+        assert_eq!(annotator.consumed_quota("device_4", &new_device_origin), 0);
+        assert_eq!(annotator.consumed_quota("device_5", &rule_origin), 0);
+        assert_eq!(annotator.consumed_quota("device_4", &rule_origin), 200);
+    }
+
+    #[test]
+    fn test_verify() {
+        let installed_rule = |origin: &RuleOrigin| InstalledRule {
+            chain: "device_4".to_string(),
+            comment: Some(origin.comment()),
+            packets: None,
+            bytes: None,
+            quota_consumed: None,
+        };
+        let device_origin = origin();
+        let rule_origin = device_origin.for_rule(0, None);
+        let mut annotator = RuleAnnotator::new();
+        annotator.record(&device_origin);
+        annotator.record(&rule_origin);
+        annotator.record(&rule_origin);
+        assert_eq!(annotator.expected_rules(), 3);
+
+        let installed = vec![installed_rule(&rule_origin), installed_rule(&device_origin)];
+        assert_eq!(annotator.verify(&installed), (vec![(rule_origin.comment(), 1)], vec![]));
+        assert_eq!(installed[0].origin(), Some(rule_origin.clone()));
+
+        let other_origin = device_origin.for_rule(1, None);
+        let installed = vec![
+            installed_rule(&rule_origin),
+            installed_rule(&rule_origin),
+            installed_rule(&device_origin),
+            installed_rule(&other_origin),
+        ];
+        assert_eq!(
+            annotator.verify(&installed),
+            (vec![], vec![(other_origin.comment(), 1)])
+        );
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{cmp::min, fs, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Datelike, Local, Timelike, Utc, Weekday};
+
+use crate::{
+    services::{
+        firewall_service::FirewallService,
+        self_test::{Capability, ProbeResult},
+    },
+    settings::{DeviceSchedule, Settings},
+};
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+const SECONDS_PER_WEEK: i64 = 7 * SECONDS_PER_DAY;
+
+/// Maximum time between two checks of the schedules, used to detect changes of the local UTC offset (e.g. because of
+/// daylight saving time).
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// A time slot in UTC which can be matched using the nftables `meta day` and `meta hour` expressions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtcTimeSlot {
+    /// Day of the week as used by nftables (0 = Sunday).
+    pub day: u8,
+    /// Start of the time slot in seconds since midnight (inclusive).
+    pub start: u32,
+    /// End of the time slot in seconds since midnight (exclusive, at most one full day).
+    pub end: u32,
+}
+
+impl UtcTimeSlot {
+    /// Returns true if the time slot lasts until the end of the day.
+    pub fn ends_at_midnight(&self) -> bool {
+        i64::from(self.end) >= SECONDS_PER_DAY
+    }
+}
+
+/// Returns true if the running kernel supports matching on the current time (`meta day` and `meta hour`), which was
+/// introduced in Linux 5.4.
+pub fn kernel_supports_time_matching() -> bool {
+    let release = match fs::read_to_string("/proc/sys/kernel/osrelease") {
+        Ok(r) => r,
+        Err(e) => {
+            warn!(
+                "Unable to determine kernel version, assuming no support for time matching: {:?}",
+                e
+            );
+            return false;
+        },
+    };
+    let mut version = release
+        .trim()
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|v| v.parse::<u32>().unwrap_or(0));
+    let major = version.next().unwrap_or(0);
+    let minor = version.next().unwrap_or(0);
+    (major, minor) >= (5, 4)
+}
+
+/// Returns true if the rules can be restricted to the time slots of their schedules using time matching, preferring
+/// the results of the startup self-test over the kernel version.
+pub fn time_matching_supported(settings: &Settings) -> bool {
+    let capabilities = &settings.capabilities;
+    match (
+        capabilities.result(Capability::Time),
+        capabilities.result(Capability::ByteOrder),
+    ) {
+        (Some(ProbeResult::Supported), Some(ProbeResult::Supported)) => true,
+        (Some(ProbeResult::Unsupported(_)), _) | (_, Some(ProbeResult::Unsupported(_))) => false,
+        _ => kernel_supports_time_matching(),
+    }
+}
+
+/// Returns the offset of the schedule's time zone from UTC in seconds.
+fn utc_offset_seconds(schedule: &DeviceSchedule) -> i64 {
+    match schedule.utc_offset_minutes {
+        Some(offset) => i64::from(offset) * 60,
+        None => i64::from(Local::now().offset().local_minus_utc()),
+    }
+}
+
+/// Returns the intervals during which the schedule is active in seconds since the start of the week
+/// (Sunday 00:00 UTC). Interval starts are always within the week, interval ends may be past the end of the week.
+fn utc_week_intervals(schedule: &DeviceSchedule) -> Vec<(i64, i64)> {
+    let offset = utc_offset_seconds(schedule);
+    let mut intervals = Vec::new();
+    for window in &schedule.windows {
+        let start = i64::from(window.start.num_seconds_from_midnight());
+        let mut end = i64::from(window.end.num_seconds_from_midnight());
+        if end <= start {
+            end += SECONDS_PER_DAY;
+        }
+        let days: Vec<u32> = if window.days.is_empty() {
+            (0..7).collect()
+        } else {
+            window.days.iter().map(Weekday::num_days_from_sunday).collect()
+        };
+        for day in days {
+            let interval_start = (i64::from(day) * SECONDS_PER_DAY + start - offset).rem_euclid(SECONDS_PER_WEEK);
+            intervals.push((interval_start, interval_start + end - start));
+        }
+    }
+    intervals
+}
+
+/// Converts the time windows of a schedule into UTC time slots that do not cross midnight.
+pub fn utc_time_slots(schedule: &DeviceSchedule) -> Vec<UtcTimeSlot> {
+    let mut slots = Vec::new();
+    for (start, end) in utc_week_intervals(schedule) {
+        let mut position = start;
+        while position < end {
+            let day_start = position - position.rem_euclid(SECONDS_PER_DAY);
+            let slot_end = min(end, day_start + SECONDS_PER_DAY);
+            slots.push(UtcTimeSlot {
+                day: (day_start / SECONDS_PER_DAY).rem_euclid(7) as u8,
+                start: (position - day_start) as u32,
+                end: (slot_end - day_start) as u32,
+            });
+            position = slot_end;
+        }
+    }
+    slots
+}
+
+/// Returns the seconds since the start of the week (Sunday 00:00 UTC) for the given point in time.
+fn seconds_since_week_start(time: &DateTime<Utc>) -> i64 {
+    i64::from(time.weekday().num_days_from_sunday()) * SECONDS_PER_DAY + i64::from(time.num_seconds_from_midnight())
+}
+
+/// Returns true if the schedule is active at the given point in time.
+pub fn is_active_at(schedule: &DeviceSchedule, time: &DateTime<Utc>) -> bool {
+    let now = seconds_since_week_start(time);
+    utc_week_intervals(schedule).iter().any(|&(start, end)| {
+        (start <= now && now < end) || (start <= now + SECONDS_PER_WEEK && now + SECONDS_PER_WEEK < end)
+    })
+}
+
+/// Returns the time until the schedule is next activated or deactivated after the given point in time.
+pub fn time_until_next_transition(schedule: &DeviceSchedule, time: &DateTime<Utc>) -> Option<Duration> {
+    let now = seconds_since_week_start(time);
+    utc_week_intervals(schedule)
+        .iter()
+        .flat_map(|&(start, end)| vec![start, end])
+        .map(|boundary| match (boundary - now).rem_euclid(SECONDS_PER_WEEK) {
+            0 => SECONDS_PER_WEEK,
+            d => d,
+        })
+        .min()
+        .map(|d| Duration::from_secs(d as u64))
+}
+
+/// Watcher which updates the firewall configuration whenever the schedules require it.
+///
+/// If the kernel supports time matching, schedules are enforced by nftables itself and the firewall only needs to be
+/// updated if the UTC offset of a schedule changes. Otherwise, scheduled rules are only added to the firewall while
+/// their schedule is active, which requires an update of the firewall whenever a schedule starts or ends.
+pub async fn schedule_watcher(fw_service: Arc<FirewallService>, settings: Arc<Settings>) {
+    if settings.schedules.is_empty() {
+        return;
+    }
+    let time_matching_supported = time_matching_supported(&settings);
+    let schedule_state = |now: &DateTime<Utc>| -> Vec<(i64, bool)> {
+        settings
+            .schedules
+            .iter()
+            .map(|s| (utc_offset_seconds(s), !time_matching_supported && is_active_at(s, now)))
+            .collect()
+    };
+    let mut last_state = schedule_state(&Utc::now());
+    loop {
+        let now = Utc::now();
+        let mut sleep_duration = SCHEDULE_CHECK_INTERVAL;
+        if !time_matching_supported {
+            if let Some(next_transition) = settings
+                .schedules
+                .iter()
+                .filter_map(|s| time_until_next_transition(s, &now))
+                .min()
+            {
+                sleep_duration = min(sleep_duration, next_transition);
+            }
+        }
+        tokio::time::sleep(sleep_duration).await;
+
+        let current_state = schedule_state(&Utc::now());
+        if current_state != last_state {
+            debug!("Rule schedules have changed, updating firewall configuration");
+            fw_service.notify_firewall_change();
+            last_state = current_state;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use super::*;
+    use crate::settings::TimeWindow;
+
+    fn schedule(days: Vec<Weekday>, start: (u32, u32), end: (u32, u32), utc_offset_minutes: i32) -> DeviceSchedule {
+        DeviceSchedule {
+            device_id: 1,
+            rule_name: None,
+            utc_offset_minutes: Some(utc_offset_minutes),
+            windows: vec![TimeWindow {
+                days,
+                start: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+                end: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_time_slots_with_offset() {
+        let slots = utc_time_slots(&schedule(vec![Weekday::Mon], (7, 0), (21, 0), 60));
+        assert_eq!(
+            slots,
+            vec![UtcTimeSlot {
+                day: 1,
+                start: 6 * 3600,
+                end: 20 * 3600
+            }]
+        );
+
+        // 00:30 local time at UTC+1 is still the previous day in UTC.
+        let slots = utc_time_slots(&schedule(vec![Weekday::Mon], (0, 30), (2, 0), 60));
+        assert_eq!(
+            slots,
+            vec![
+                UtcTimeSlot {
+                    day: 0,
+                    start: 23 * 3600 + 1800,
+                    end: 24 * 3600
+                },
+                UtcTimeSlot {
+                    day: 1,
+                    start: 0,
+                    end: 3600
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_time_slots_across_end_of_week() {
+        let slots = utc_time_slots(&schedule(vec![Weekday::Sat], (23, 0), (1, 0), 0));
+        assert_eq!(
+            slots,
+            vec![
+                UtcTimeSlot {
+                    day: 6,
+                    start: 23 * 3600,
+                    end: 24 * 3600
+                },
+                UtcTimeSlot {
+                    day: 0,
+                    start: 0,
+                    end: 3600
+                },
+            ]
+        );
+        assert!(slots[0].ends_at_midnight());
+        assert!(!slots[1].ends_at_midnight());
+        assert_eq!(utc_time_slots(&schedule(vec![], (7, 0), (8, 0), 0)).len(), 7);
+    }
+
+    #[test]
+    fn test_active_and_transitions() {
+        let time = |t: &str| t.parse::<DateTime<Utc>>().unwrap();
+        // 2021-05-01 is a Saturday.
+        let saturday_night = time("2021-05-01T23:30:00Z");
+        let sunday_morning = time("2021-05-02T00:30:00Z");
+        let night_schedule = schedule(vec![Weekday::Sat], (23, 0), (1, 0), 0);
+        assert!(is_active_at(&night_schedule, &saturday_night));
+        assert!(is_active_at(&night_schedule, &sunday_morning));
+        assert!(!is_active_at(&night_schedule, &time("2021-05-02T01:00:00Z")));
+        assert_eq!(
+            time_until_next_transition(&night_schedule, &sunday_morning),
+            Some(Duration::from_secs(1800))
+        );
+        assert_eq!(
+            time_until_next_transition(&night_schedule, &time("2021-05-01T22:00:00Z")),
+            Some(Duration::from_secs(3600))
+        );
+    }
+}
@@ -0,0 +1,524 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::fmt;
+
+use crate::{
+    error::{Result, SelfTestError},
+    settings::Settings,
+};
+
+// Startup self-test of the kernel features and privileges the firewall rules depend on.
+// A scratch table with a regular (non-base) chain is created, so the probe rules never see any traffic, and a rule
+// using each expression type of the rule generator is added to it in a separate batch.
+
+/// Name of the table created by the self-test.
+pub const SELF_TEST_TABLE_NAME: &str = "namib_self_test";
+
+/// Kernel feature or privilege the firewall rules depend on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Changing the ruleset (`CAP_NET_ADMIN`).
+    NetAdmin,
+    /// Tables of the inet family.
+    InetTables,
+    Meta,
+    Payload,
+    Cmp,
+    Verdict,
+    Counter,
+    /// Reject verdicts in tables of the inet family.
+    Reject,
+    ConnTrack,
+    Bitwise,
+    Limit,
+    Quota,
+    ConnLimit,
+    Log,
+    /// Sets with expiring elements and lookups in them (used by rules for hostnames and MUD abstractions).
+    Lookup,
+    /// Matching on the current time (used by schedules).
+    Time,
+    ByteOrder,
+    /// Setting the packet mark.
+    Mark,
+    /// Rewriting the network header (used to set DSCP values).
+    PayloadWrite,
+    FlowOffload,
+    /// Redirecting packets to the local machine in NAT chains (used by the DNS views blocking domains).
+    Redirect,
+}
+
+impl Capability {
+    /// All capabilities, in the order in which they are tested.
+    pub const ALL: [Capability; 21] = [
+        Capability::NetAdmin,
+        Capability::InetTables,
+        Capability::Meta,
+        Capability::Payload,
+        Capability::Cmp,
+        Capability::Verdict,
+        Capability::Counter,
+        Capability::Reject,
+        Capability::ConnTrack,
+        Capability::Bitwise,
+        Capability::Limit,
+        Capability::Quota,
+        Capability::ConnLimit,
+        Capability::Log,
+        Capability::Lookup,
+        Capability::Time,
+        Capability::ByteOrder,
+        Capability::Mark,
+        Capability::PayloadWrite,
+        Capability::FlowOffload,
+        Capability::Redirect,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::NetAdmin => "CAP_NET_ADMIN",
+            Capability::InetTables => "inet tables",
+            Capability::Meta => "meta",
+            Capability::Payload => "payload",
+            Capability::Cmp => "cmp",
+            Capability::Verdict => "verdict",
+            Capability::Counter => "counter",
+            Capability::Reject => "reject",
+            Capability::ConnTrack => "ct",
+            Capability::Bitwise => "bitwise",
+            Capability::Limit => "limit",
+            Capability::Quota => "quota",
+            Capability::ConnLimit => "connlimit",
+            Capability::Log => "log",
+            Capability::Lookup => "lookup",
+            Capability::Time => "meta time",
+            Capability::ByteOrder => "byteorder",
+            Capability::Mark => "meta mark set",
+            Capability::PayloadWrite => "payload set",
+            Capability::FlowOffload => "flow offload",
+            Capability::Redirect => "redirect",
+        }
+    }
+
+    /// Returns the kernel module providing this capability, if it is not part of the nf_tables core.
+    pub fn kernel_module(self) -> Option<&'static str> {
+        match self {
+            Capability::InetTables => Some("nf_tables_inet"),
+            Capability::Counter => Some("nft_counter"),
+            Capability::Reject => Some("nft_reject_inet"),
+            Capability::ConnTrack => Some("nft_ct"),
+            Capability::Limit => Some("nft_limit"),
+            Capability::Quota => Some("nft_quota"),
+            Capability::ConnLimit => Some("nft_connlimit"),
+            Capability::Log => Some("nft_log"),
+            Capability::Lookup => Some("nft_set_hash"),
+            Capability::FlowOffload => Some("nft_flow_offload"),
+            Capability::Redirect => Some("nft_redir"),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the enforcer has to refuse to start without this capability, as the rules for the given
+    /// settings could not be created otherwise. Missing optional capabilities disable the features depending on them.
+    pub fn is_required(self, settings: &Settings) -> bool {
+        match self {
+            Capability::NetAdmin
+            | Capability::InetTables
+            | Capability::Meta
+            | Capability::Payload
+            | Capability::Cmp
+            | Capability::Verdict
+            | Capability::Counter
+            // Rules for hostnames may be part of any config received from the controller.
+            | Capability::Lookup => true,
+            // Dropping the traffic exceeding a limit is part of the enforced policy, only the notifications about
+            // exceeded limits are optional.
+            Capability::Limit => settings
+                .limits
+                .iter()
+                .any(|l| l.bytes_per_second.is_some() || l.new_connections_per_second.is_some()),
+            Capability::Quota => settings.limits.iter().any(|l| l.quota_bytes.is_some()),
+            Capability::ConnTrack | Capability::Bitwise | Capability::ConnLimit => {
+                settings.limits.iter().any(|l| l.max_connections.is_some())
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Result of testing a single capability.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProbeResult {
+    Supported,
+    Unsupported(String),
+    /// The capability could not be tested because a prerequisite is missing.
+    Untested,
+}
+
+/// Capabilities of the kernel and the enforcer's privileges as determined by the self-test.
+/// Capabilities that were not tested (e.g. without the nftables feature) are assumed to be supported.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CapabilityMatrix {
+    results: Vec<(Capability, ProbeResult)>,
+}
+
+impl CapabilityMatrix {
+    pub fn record(&mut self, capability: Capability, result: ProbeResult) {
+        self.results.retain(|(c, _)| *c != capability);
+        self.results.push((capability, result));
+    }
+
+    pub fn result(&self, capability: Capability) -> Option<&ProbeResult> {
+        self.results.iter().find(|(c, _)| *c == capability).map(|(_, r)| r)
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        !matches!(self.result(capability), Some(ProbeResult::Unsupported(_)))
+    }
+
+    /// Returns the capabilities that are required for the given settings but not supported.
+    pub fn missing_required(&self, settings: &Settings) -> Vec<Capability> {
+        Capability::ALL
+            .iter()
+            .copied()
+            .filter(|c| !self.supports(*c) && c.is_required(settings))
+            .collect()
+    }
+
+    /// Returns an error if capabilities required for the given settings are not supported.
+    pub fn check(&self, settings: &Settings) -> Result<()> {
+        let missing = self.missing_required(settings);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        SelfTestError {
+            capabilities: missing.iter().map(|c| c.name()).collect::<Vec<_>>().join(", "),
+        }
+        .fail()
+    }
+
+    /// Checks the given settings against this matrix, disables the features depending on unsupported optional
+    /// capabilities and stores the matrix in the settings, so the rule generator can avoid unsupported expressions.
+    pub fn apply_to(self, settings: &mut Settings) -> Result<()> {
+        self.check(settings)?;
+        let anomaly_detection_supported = [
+            Capability::ConnTrack,
+            Capability::Bitwise,
+            Capability::Limit,
+            Capability::Log,
+        ]
+        .iter()
+        .all(|c| self.supports(*c));
+        if settings.anomaly_detection.enabled && !anomaly_detection_supported {
+            warn!("Anomaly detection is disabled, as the kernel does not support the required expressions");
+            settings.anomaly_detection.enabled = false;
+        }
+        if !settings.markings.is_empty()
+            && !(self.supports(Capability::Mark) && self.supports(Capability::PayloadWrite))
+        {
+            warn!("Traffic markings are disabled, as the kernel does not support setting marks or DSCP values");
+            settings.markings.clear();
+        }
+        if settings.flow_offload.enabled && !self.supports(Capability::FlowOffload) {
+            warn!("Flow offloading is disabled, as the kernel does not support flowtables");
+            settings.flow_offload.enabled = false;
+        }
+        if settings.dns.blocking.enabled && !self.supports(Capability::Redirect) {
+            warn!("Blocking domains is disabled, as the kernel does not support redirecting DNS queries");
+            settings.dns.blocking.enabled = false;
+        }
+        if !self.supports(Capability::Reject) {
+            warn!("The kernel does not support rejecting packets in inet tables, rejected packets are dropped instead");
+        }
+        if !settings.limits.is_empty() && !self.supports(Capability::Log) {
+            warn!("The kernel does not support logging packets, exceeded limits are not reported");
+        }
+        settings.capabilities = self;
+        Ok(())
+    }
+}
+
+impl fmt::Display for CapabilityMatrix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (capability, result) in &self.results {
+            let module = capability
+                .kernel_module()
+                .map(|m| format!(" ({})", m))
+                .unwrap_or_default();
+            match result {
+                ProbeResult::Supported => writeln!(f, "{:<16} supported{}", capability.name(), module)?,
+                ProbeResult::Unsupported(e) => writeln!(f, "{:<16} UNSUPPORTED{}: {}", capability.name(), module, e)?,
+                ProbeResult::Untested => writeln!(f, "{:<16} untested{}", capability.name(), module)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "nftables")]
+mod nftables {
+    use std::{ffi::CString, net::Ipv4Addr, time::Duration};
+
+    use nftnl::{
+        expr::{ct::States, IcmpCode, RejectionType, Verdict as VerdictExpr},
+        nft_expr, Batch, Chain, ChainType, Hook, MsgType, ProtoFamily, Rule, Table,
+    };
+
+    use super::{Capability, CapabilityMatrix, ProbeResult, SELF_TEST_TABLE_NAME};
+    use crate::{
+        error::Error,
+        services::{
+            firewall_service::send_and_process,
+            nft_exprs::{
+                AddressSet, ConnLimit, ExpiringSetElements, FlowOffload, Flowtable, HostToNetwork, Immediate32,
+                ImmediatePort, Limit, LimitType, Lookup, MetaTime, NetworkHeaderLoad, NetworkHeaderWrite, NfLog, Quota,
+                Redirect, SetMark,
+            },
+        },
+    };
+
+    const PROBE_CHAIN_NAME: &str = "probe";
+    const PROBE_SET_NAME: &str = "probe_set";
+    const PROBE_FLOWTABLE_NAME: &str = "probe_flowtable";
+    const PROBE_NAT_CHAIN_NAME: &str = "probe_nat";
+
+    /// Creates the scratch table, adds a rule for each expression type and deletes the table again.
+    pub fn run(nflog_group: u16) -> CapabilityMatrix {
+        let mut matrix = CapabilityMatrix::default();
+        let table = Table::new(&CString::new(SELF_TEST_TABLE_NAME).unwrap(), ProtoFamily::Inet);
+        let chain = Chain::new(&CString::new(PROBE_CHAIN_NAME).unwrap(), &table);
+        // Redirecting is only valid in NAT chains.
+        let mut nat_chain = Chain::new(&CString::new(PROBE_NAT_CHAIN_NAME).unwrap(), &table);
+        nat_chain.set_hook(Hook::PreRouting, -100);
+        nat_chain.set_type(ChainType::Nat);
+
+        // Remove leftovers of an interrupted self-test, the table is created first so the deletion cannot fail.
+        let mut batch = Batch::new();
+        batch.add(&table, MsgType::Add);
+        batch.add(&table, MsgType::Del);
+        batch.add(&table, MsgType::Add);
+        batch.add(&chain, MsgType::Add);
+        if let Err(e) = send_and_process(batch.finalize(), &[]) {
+            let netadmin = match &e {
+                Error::MissingCapabilityError { .. } => ProbeResult::Unsupported(e.to_string()),
+                _ => ProbeResult::Supported,
+            };
+            let inet = if netadmin == ProbeResult::Supported {
+                ProbeResult::Unsupported(e.to_string())
+            } else {
+                ProbeResult::Untested
+            };
+            matrix.record(Capability::NetAdmin, netadmin);
+            matrix.record(Capability::InetTables, inet);
+            for capability in &Capability::ALL[2..] {
+                matrix.record(*capability, ProbeResult::Untested);
+            }
+            return matrix;
+        }
+        matrix.record(Capability::NetAdmin, ProbeResult::Supported);
+        matrix.record(Capability::InetTables, ProbeResult::Supported);
+
+        for capability in &Capability::ALL[2..] {
+            let mut batch = Batch::new();
+            let mut rule = match capability {
+                Capability::Redirect => {
+                    batch.add(&nat_chain, MsgType::Add);
+                    Rule::new(&nat_chain)
+                },
+                _ => Rule::new(&chain),
+            };
+            match capability {
+                Capability::Lookup => {
+                    batch.add(
+                        &AddressSet {
+                            table: SELF_TEST_TABLE_NAME.to_string(),
+                            name: PROBE_SET_NAME.to_string(),
+                            id: 0,
+                            ipv6: false,
+                            timeout: true,
+                        },
+                        MsgType::Add,
+                    );
+                    batch.add(
+                        &ExpiringSetElements {
+                            table: SELF_TEST_TABLE_NAME.to_string(),
+                            set: PROBE_SET_NAME.to_string(),
+                            elements: vec![(Ipv4Addr::LOCALHOST.into(), Duration::from_secs(60))],
+                        },
+                        MsgType::Add,
+                    );
+                },
+                Capability::FlowOffload => {
+                    batch.add(
+                        &Flowtable {
+                            table: SELF_TEST_TABLE_NAME.to_string(),
+                            name: PROBE_FLOWTABLE_NAME.to_string(),
+                            interfaces: vec!["lo".to_string()],
+                            hardware: false,
+                        },
+                        MsgType::Add,
+                    );
+                },
+                _ => {},
+            }
+            add_probe_expressions(&mut rule, *capability, nflog_group);
+            batch.add(&rule, MsgType::Add);
+            let result = match send_and_process(batch.finalize(), &[]) {
+                Ok(()) => ProbeResult::Supported,
+                Err(e) => ProbeResult::Unsupported(e.to_string()),
+            };
+            matrix.record(*capability, result);
+        }
+
+        let mut batch = Batch::new();
+        batch.add(&table, MsgType::Del);
+        if let Err(e) = send_and_process(batch.finalize(), &[]) {
+            warn!("Unable to delete the self-test table {}: {}", SELF_TEST_TABLE_NAME, e);
+        }
+        matrix
+    }
+
+    /// Adds the expressions testing the given capability (including the expressions loading the registers it uses)
+    /// to the given rule.
+    fn add_probe_expressions(rule: &mut Rule, capability: Capability, nflog_group: u16) {
+        match capability {
+            Capability::NetAdmin | Capability::InetTables => {},
+            Capability::Meta => rule.add_expr(&nft_expr!(meta nfproto)),
+            Capability::Payload => rule.add_expr(&nft_expr!(payload ipv4 saddr)),
+            Capability::Cmp => {
+                rule.add_expr(&nft_expr!(meta nfproto));
+                rule.add_expr(&nft_expr!(cmp == libc::NFPROTO_IPV4 as u8));
+            },
+            Capability::Verdict => rule.add_expr(&nft_expr!(verdict accept)),
+            Capability::Counter => rule.add_expr(&nft_expr!(counter)),
+            Capability::Reject => rule.add_expr(&VerdictExpr::Reject(RejectionType::Icmp(IcmpCode::AdminProhibited))),
+            Capability::ConnTrack => rule.add_expr(&nft_expr!(ct state)),
+            Capability::Bitwise => {
+                rule.add_expr(&nft_expr!(meta nfproto));
+                rule.add_expr(&nft_expr!(bitwise mask 0x03u8, xor 0u8));
+            },
+            Capability::Limit => rule.add_expr(&Limit {
+                rate: 1,
+                burst: 1,
+                limit_type: LimitType::Packets,
+                over: false,
+            }),
+            Capability::Quota => rule.add_expr(&Quota {
+                bytes: 1,
+                consumed: 0,
+                over: true,
+            }),
+            Capability::ConnLimit => {
+                rule.add_expr(&nft_expr!(ct state));
+                rule.add_expr(&nft_expr!(bitwise mask States::NEW.bits(), xor 0u32));
+                rule.add_expr(&ConnLimit { count: 1, over: true });
+            },
+            Capability::Log => rule.add_expr(&NfLog {
+                group: nflog_group,
+                prefix: CString::new("namib self-test").unwrap(),
+                snaplen: 0,
+            }),
+            Capability::Lookup => {
+                rule.add_expr(&nft_expr!(payload ipv4 saddr));
+                rule.add_expr(&Lookup {
+                    set: CString::new(PROBE_SET_NAME).unwrap(),
+                });
+            },
+            Capability::Time => rule.add_expr(&MetaTime::Day),
+            Capability::ByteOrder => {
+                rule.add_expr(&MetaTime::Hour);
+                rule.add_expr(&HostToNetwork { size: 4 });
+            },
+            Capability::Mark => {
+                rule.add_expr(&Immediate32 { value: 1 });
+                rule.add_expr(&SetMark);
+            },
+            Capability::PayloadWrite => {
+                rule.add_expr(&NetworkHeaderLoad { offset: 1, len: 1 });
+                rule.add_expr(&NetworkHeaderWrite {
+                    offset: 1,
+                    len: 1,
+                    checksum_offset: Some(10),
+                });
+            },
+            Capability::FlowOffload => rule.add_expr(&FlowOffload {
+                flowtable: CString::new(PROBE_FLOWTABLE_NAME).unwrap(),
+            }),
+            Capability::Redirect => {
+                rule.add_expr(&ImmediatePort { port: 53 });
+                rule.add_expr(&Redirect);
+            },
+        }
+    }
+}
+
+/// Tests the capabilities of the kernel and the enforcer's privileges.
+#[cfg(feature = "nftables")]
+pub fn run(settings: &Settings) -> CapabilityMatrix {
+    nftables::run(settings.nflog_group)
+}
+
+/// Without nftables support, no capabilities are tested.
+#[cfg(not(feature = "nftables"))]
+pub fn run(_settings: &Settings) -> CapabilityMatrix {
+    CapabilityMatrix::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::TrafficLimits;
+
+    #[test]
+    fn test_required_capabilities() {
+        let mut matrix = CapabilityMatrix::default();
+        for capability in &Capability::ALL {
+            matrix.record(*capability, ProbeResult::Supported);
+        }
+        matrix.record(Capability::Quota, ProbeResult::Unsupported("errno 2".to_string()));
+        matrix.record(
+            Capability::FlowOffload,
+            ProbeResult::Unsupported("errno 95".to_string()),
+        );
+        matrix.record(Capability::Log, ProbeResult::Unsupported("errno 2".to_string()));
+
+        let mut settings = Settings::default();
+        settings.anomaly_detection.enabled = true;
+        settings.flow_offload.enabled = true;
+        assert!(matrix.missing_required(&settings).is_empty());
+        matrix.clone().apply_to(&mut settings).unwrap();
+        assert!(!settings.anomaly_detection.enabled);
+        assert!(!settings.flow_offload.enabled);
+        assert!(!settings.capabilities.supports(Capability::Log));
+        assert!(settings.capabilities.supports(Capability::Reject));
+
+        let mut settings = Settings::default();
+        settings.limits.push(TrafficLimits {
+            device_id: 1,
+            quota_bytes: Some(1000),
+            ..TrafficLimits::default()
+        });
+        assert_eq!(matrix.missing_required(&settings), vec![Capability::Quota]);
+        assert!(matrix.clone().apply_to(&mut settings).is_err());
+
+        // Sets are required for rules with hostnames, which may be part of any config.
+        matrix.record(Capability::Lookup, ProbeResult::Unsupported("errno 2".to_string()));
+        assert_eq!(matrix.missing_required(&Settings::default()), vec![Capability::Lookup]);
+    }
+
+    #[test]
+    fn test_untested_capabilities_are_supported() {
+        let mut matrix = CapabilityMatrix::default();
+        assert!(matrix.supports(Capability::NetAdmin));
+        matrix.record(Capability::NetAdmin, ProbeResult::Unsupported("errno 1".to_string()));
+        matrix.record(Capability::InetTables, ProbeResult::Untested);
+        assert!(!matrix.supports(Capability::NetAdmin));
+        assert!(matrix.supports(Capability::InetTables));
+        assert_eq!(
+            matrix.to_string(),
+            "CAP_NET_ADMIN    UNSUPPORTED: errno 1\ninet tables      untested (nf_tables_inet)\n"
+        );
+        assert!(matrix.check(&Settings::default()).is_err());
+    }
+}
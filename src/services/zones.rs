@@ -0,0 +1,174 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::collections::HashMap;
+
+use crate::{
+    error::Result,
+    services::{
+        controller_name::{CONFIG_DIR, SAVE_DIR},
+        is_system_mode,
+    },
+    uci::Uci,
+};
+
+/// Upper bound for the number of zone sections that are read from the firewall configuration.
+const MAX_ZONES: usize = 256;
+
+/// Opens the UCI configuration of the system (or the local `config` directory if the enforcer is not running in
+/// system mode).
+pub fn open_uci() -> Result<Uci> {
+    let mut uci = Uci::new()?;
+    if !is_system_mode() {
+        uci.set_config_dir(CONFIG_DIR)?;
+        uci.set_save_dir(SAVE_DIR)?;
+    }
+    Ok(uci)
+}
+
+/// Splits a UCI value containing a whitespace-separated list of names.
+fn split_names(value: &str) -> impl Iterator<Item=String>+'_ {
+    value.split_whitespace().map(String::from)
+}
+
+/// Mapping of OpenWrt firewall zones to the names of the network devices (as used by `iifname` and `oifname`) they
+/// consist of, read from the UCI `firewall` and `network` configuration.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ZoneMap {
+    zones: HashMap<String, Vec<String>>,
+    /// Logical networks (`config interface` sections of the network configuration) of each zone.
+    networks: HashMap<String, Vec<String>>,
+}
+
+impl ZoneMap {
+    /// Reads the zones from the UCI configuration of the system (or from the local `config` directory if the enforcer
+    /// is not running in system mode). Returns an empty mapping if the configuration can not be read.
+    pub fn load() -> ZoneMap {
+        match open_uci() {
+            Ok(mut uci) => ZoneMap::from_uci(&mut uci),
+            Err(e) => {
+                warn!("Unable to read firewall zones from UCI: {:?}", e);
+                ZoneMap::default()
+            },
+        }
+    }
+
+    /// Reads all zone sections of the firewall configuration and resolves their networks to network devices.
+    pub fn from_uci(uci: &mut Uci) -> ZoneMap {
+        let mut zones = HashMap::new();
+        let mut zone_networks = HashMap::new();
+        for index in 0..MAX_ZONES {
+            let section = format!("firewall.@zone[{}]", index);
+            if uci.get(&section).is_err() {
+                break;
+            }
+            let name = match uci.get(&format!("{}.name", section)) {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let mut devices: Vec<String> = Vec::new();
+            let networks: Vec<String> = match uci.get(&format!("{}.network", section)) {
+                Ok(networks) => split_names(&networks).collect(),
+                Err(e) => {
+                    debug!("Zone {} has no readable networks: {:?}", name, e);
+                    Vec::new()
+                },
+            };
+            for network in &networks {
+                devices.extend(network_devices(uci, network));
+            }
+            // Zones may also contain network devices directly.
+            if let Ok(zone_devices) = uci.get(&format!("{}.device", section)) {
+                devices.extend(split_names(&zone_devices));
+            }
+            devices.dedup();
+            if devices.is_empty() {
+                warn!("Unable to determine the network devices of firewall zone {}", name);
+            }
+            zone_networks.insert(name.clone(), networks);
+            zones.insert(name, devices);
+        }
+        ZoneMap {
+            zones,
+            networks: zone_networks,
+        }
+    }
+
+    /// Returns the network devices of the given zone, or `None` if the zone does not exist.
+    pub fn devices(&self, zone: &str) -> Option<&[String]> {
+        self.zones.get(zone).map(Vec::as_slice)
+    }
+
+    /// Returns the logical networks of the given zone, or `None` if the zone does not exist.
+    pub fn networks(&self, zone: &str) -> Option<&[String]> {
+        self.networks.get(zone).map(Vec::as_slice)
+    }
+
+    /// Returns the given interfaces together with the network devices of the given zones. Unknown zones are ignored.
+    pub fn resolve(&self, interfaces: &[String], zones: &[String]) -> Vec<String> {
+        let mut resolved = interfaces.to_vec();
+        for zone in zones {
+            match self.devices(zone) {
+                Some(devices) => resolved.extend(devices.iter().cloned()),
+                None => warn!("Ignoring unknown firewall zone {}", zone),
+            }
+        }
+        resolved.sort();
+        resolved.dedup();
+        resolved
+    }
+}
+
+/// Returns the network devices of the given logical network (`config interface` section) of the UCI network
+/// configuration.
+fn network_devices(uci: &mut Uci, network: &str) -> Vec<String> {
+    // Since OpenWrt 21.02, the network device (e.g. `br-lan`) is set directly.
+    if let Ok(device) = uci.get(&format!("network.{}.device", network)) {
+        return split_names(&device).collect();
+    }
+    // Older configurations create a bridge named after the network.
+    if matches!(uci.get(&format!("network.{}.type", network)).as_deref(), Ok("bridge")) {
+        return vec![format!("br-{}", network)];
+    }
+    match uci.get(&format!("network.{}.ifname", network)) {
+        Ok(ifname) => split_names(&ifname).collect(),
+        Err(_) => {
+            debug!("Network {} has no network device", network);
+            Vec::new()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+
+    fn init() -> Result<Uci> {
+        let mut uci = Uci::new()?;
+        uci.set_config_dir("tests/config")?;
+        uci.set_save_dir("/tmp/.uci_tests")?;
+        Ok(uci)
+    }
+
+    #[test]
+    fn test_zones_from_uci() -> Result<()> {
+        let zones = ZoneMap::from_uci(&mut init()?);
+        assert_eq!(zones.devices("lan"), Some(&["eth0".to_string()][..]));
+        // The wan6 network does not exist in the test configuration.
+        assert_eq!(
+            zones.devices("wan"),
+            Some(&["eth1".to_string(), "ppp0".to_string()][..])
+        );
+        assert_eq!(zones.devices("guest"), None);
+        assert_eq!(
+            zones.networks("wan"),
+            Some(&["wan".to_string(), "wan6".to_string()][..])
+        );
+        assert_eq!(
+            zones.resolve(&["wlan1".to_string()], &["lan".to_string(), "guest".to_string()]),
+            vec!["eth0".to_string(), "wlan1".to_string()]
+        );
+        Ok(())
+    }
+}
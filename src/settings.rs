@@ -0,0 +1,680 @@
+// Copyright 2020-2021, Benjamin Ludewig, Florian Bonetti, Jeffrey Munstermann, Luca Nittscher, Hugo Damer, Michael Bach
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use std::{collections::HashMap, env, fs::File, io, net::IpAddr, path::PathBuf};
+
+use chrono::{NaiveTime, Weekday};
+use serde::Deserialize;
+use snafu::ResultExt;
+
+use crate::{
+    error::{Result, SettingsError},
+    services::self_test::CapabilityMatrix,
+};
+
+/// Default location for the file containing enforcer-local settings.
+const DEFAULT_SETTINGS_FILE: &str = "/etc/namib/settings.json";
+
+/// Settings which are specific to this enforcer instance and are not part of the configuration provided by the
+/// NAMIB controller.
+///
+/// The settings are read once at startup from the JSON file at the location specified by the `NAMIB_SETTINGS_FILE`
+/// environment variable (or `DEFAULT_SETTINGS_FILE` if the environment variable is not set).
+/// Missing fields (or a missing file) result in default values being used, while a file that cannot be parsed prevents
+/// the enforcer from starting.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Time windows in which the rules of specific devices are active.
+    pub schedules: Vec<DeviceSchedule>,
+    /// Bandwidth and connection limits for specific devices or rules.
+    pub limits: Vec<TrafficLimits>,
+    /// NFLOG group used by the firewall to notify the enforcer about packets (e.g. packets exceeding a limit).
+    pub nflog_group: u16,
+    /// Detection of port scans, connection floods and rejected connection attempts.
+    pub anomaly_detection: AnomalyDetectionSettings,
+    /// Annotate the device chains with comments (in addition to the rules).
+    /// Requires libnftnl 1.1.9 and Linux 5.10 or newer, older versions of libnftnl abort the enforcer.
+    pub annotate_chains: bool,
+    /// Priority of the enforcer's chain on the forward hook. Base chains of other tables (e.g. the `fw4` table of
+    /// OpenWrt) with a lower priority are evaluated before the enforcer's chain.
+    pub hook_priority: i32,
+    /// Action taken if the rules of other tables would bypass the enforcer's rules.
+    pub on_bypass: BypassAction,
+    /// Offloading of flows accepted by the device rules to a flowtable.
+    pub flow_offload: FlowOffloadSettings,
+    /// DSCP values and packet marks set for the traffic of specific devices or rules.
+    pub markings: Vec<TrafficMarking>,
+    /// Network segments (interfaces or OpenWrt firewall zones) of specific devices.
+    pub segments: Vec<DeviceSegment>,
+    /// Interfaces (or OpenWrt firewall zones) specific rules are restricted to.
+    pub rule_interfaces: Vec<RuleInterfaces>,
+    /// MUD abstractions (e.g. `local-networks`) used as source or destination of specific rules.
+    pub abstractions: AbstractionSettings,
+    /// Verdict for the traffic of devices that is not matched by any of their rules.
+    pub fallback: FallbackSettings,
+    /// Resolution of the hostnames used by rules.
+    pub dns: DnsSettings,
+    /// Capabilities of the kernel as determined by the startup self-test, not read from the settings file.
+    #[serde(skip)]
+    pub capabilities: CapabilityMatrix,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            schedules: Vec::new(),
+            limits: Vec::new(),
+            nflog_group: 100,
+            anomaly_detection: AnomalyDetectionSettings::default(),
+            annotate_chains: false,
+            hook_priority: 0,
+            on_bypass: BypassAction::Warn,
+            flow_offload: FlowOffloadSettings::default(),
+            markings: Vec::new(),
+            segments: Vec::new(),
+            rule_interfaces: Vec::new(),
+            abstractions: AbstractionSettings::default(),
+            fallback: FallbackSettings::default(),
+            dns: DnsSettings::default(),
+            capabilities: CapabilityMatrix::default(),
+        }
+    }
+}
+
+/// Action taken if the enforcer's rules would be bypassed by the rules of other tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BypassAction {
+    /// Log a warning and report the issue to the controller.
+    Warn,
+    /// Additionally refuse to start the enforcer.
+    Refuse,
+}
+
+/// Restricts the rules of a device (or a single rule of a device) to a set of time windows.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceSchedule {
+    /// ID of the device (as assigned by the NAMIB controller) this schedule applies to.
+    pub device_id: i64,
+    /// Name of the rule (the MUD ACE name) this schedule applies to.
+    /// If not set, the schedule applies to all rules of the device.
+    #[serde(default)]
+    pub rule_name: Option<String>,
+    /// Offset of the time zone the time windows are specified in from UTC in minutes.
+    /// If not set, the current offset of the system's local time zone is used.
+    #[serde(default)]
+    pub utc_offset_minutes: Option<i32>,
+    /// Time windows in which the rules are active. Outside of these windows, the rules are not applied.
+    pub windows: Vec<TimeWindow>,
+}
+
+/// A recurring time window, e.g. Monday to Friday from 07:00:00 to 21:00:00.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimeWindow {
+    /// Days of the week on which the window starts. If empty, the window starts on every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// Start of the time window (local time, format `HH:MM:SS`).
+    pub start: NaiveTime,
+    /// End of the time window (local time, format `HH:MM:SS`).
+    /// If the end is before the start, the window ends on the following day.
+    pub end: NaiveTime,
+}
+
+/// Settings for offloading established flows to an nftables flowtable, which reduces the CPU load of forwarding.
+///
+/// Only flows accepted by a device rule are offloaded, and only once they are established, so new connections are
+/// always evaluated by the rules. Packets of offloaded flows are not evaluated by the rules anymore, which is why
+/// flows of rules with a schedule or a limit (and of devices with limits) are never offloaded.
+/// All offloaded flows are removed whenever the firewall configuration is updated.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FlowOffloadSettings {
+    pub enabled: bool,
+    /// Names of the interfaces of the flowtable, which have to include the LAN and WAN interfaces (e.g. `br-lan` and
+    /// `eth0`).
+    pub interfaces: Vec<String>,
+    /// Offload flows to the network hardware instead of the software fast path.
+    /// Requires support by the drivers of all interfaces.
+    pub hardware: bool,
+}
+
+impl FlowOffloadSettings {
+    /// Returns true if flows should be offloaded.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled && !self.interfaces.is_empty()
+    }
+}
+
+/// Final verdict of the device chains for traffic that is not matched by any rule of the device.
+///
+/// As the chains of both devices are evaluated for traffic between two devices, traffic not matched by the rules of
+/// one of them is dropped or rejected if this device's verdict says so, even if the rules of the other device accept
+/// it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FallbackSettings {
+    /// Verdict for devices without a specific verdict.
+    pub verdict: FallbackVerdict,
+    /// Verdicts for specific devices.
+    pub devices: Vec<DeviceFallback>,
+    /// Maximum number of rejections (ICMP errors or TCP resets) sent per second, further rejected packets are dropped
+    /// silently. Applies to all rejections of the firewall, not only to the fallback verdicts.
+    pub reject_rate_per_second: u32,
+    /// Number of rejections that may exceed `reject_rate_per_second` at once.
+    pub reject_burst: u32,
+}
+
+impl Default for FallbackSettings {
+    fn default() -> Self {
+        FallbackSettings {
+            verdict: FallbackVerdict::Accept,
+            devices: Vec::new(),
+            reject_rate_per_second: 10,
+            reject_burst: 20,
+        }
+    }
+}
+
+impl FallbackSettings {
+    /// Returns the verdict for the unmatched traffic of the given device.
+    pub fn verdict_for(&self, device_id: i64) -> FallbackVerdict {
+        self.devices
+            .iter()
+            .find(|d| d.device_id == device_id)
+            .map_or(self.verdict, |d| d.verdict)
+    }
+}
+
+/// Verdict for traffic of a device that is not matched by any of its rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FallbackVerdict {
+    /// Return to the base chain, whose policy accepts the traffic (unless the chain of the other device rejects it).
+    Accept,
+    /// Like `accept`, but report the unmatched traffic to the controller.
+    AcceptAndLog,
+    /// Drop the traffic silently.
+    Drop,
+    /// Reject the traffic with ICMP (or ICMPv6) administratively prohibited.
+    Reject,
+    /// Reject TCP connections with a TCP reset and all other traffic with ICMP administratively prohibited.
+    RejectWithTcpReset,
+}
+
+/// Final verdict for a specific device.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceFallback {
+    /// ID of the device (as assigned by the NAMIB controller) this verdict applies to.
+    pub device_id: i64,
+    pub verdict: FallbackVerdict,
+}
+
+/// Settings for the resolution of the hostnames used by rules.
+///
+/// The addresses a hostname resolves to are matched using an nftables set whose elements expire. Addresses that are no
+/// longer returned for a hostname (e.g. because of DNS load balancing) remain in the set until their grace period (or
+/// their TTL, if it is longer) has passed since they were last resolved, so established connections to them are not
+/// cut off.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DnsSettings {
+    /// Number of seconds an address remains allowed after it was last resolved.
+    pub grace_period_seconds: u64,
+    /// Upstream name servers used to resolve hostnames, tried in the given order.
+    /// If empty, the name servers of the system configuration (`/etc/resolv.conf`) are used, which on OpenWrt is the
+    /// local dnsmasq instance that is also used (and possibly manipulated) by the devices.
+    pub servers: Vec<DnsServer>,
+    /// Number of seconds after which a query to a name server times out.
+    pub timeout_seconds: u64,
+    /// Number of attempts to resolve a hostname before the resolution fails.
+    pub attempts: usize,
+    /// IP versions of the addresses that are resolved for a hostname.
+    pub ip_strategy: DnsIpStrategy,
+    /// Validate the responses using DNSSEC, responses that cannot be validated are treated as failures.
+    pub dnssec: bool,
+    /// Allow a device to reach a hostname only at the addresses it was told by dnsmasq (as learned from the dnsmasq
+    /// query log) instead of the addresses resolved by the enforcer.
+    pub device_view: bool,
+    /// Number of seconds an address remains allowed for a device after dnsmasq last told it the address.
+    pub device_view_lifetime_seconds: u64,
+    /// Blocking of the domains a device is not allowed to reach at dnsmasq.
+    pub blocking: DnsBlockingSettings,
+}
+
+impl Default for DnsSettings {
+    fn default() -> Self {
+        DnsSettings {
+            grace_period_seconds: 300,
+            servers: Vec::new(),
+            timeout_seconds: 5,
+            attempts: 2,
+            ip_strategy: DnsIpStrategy::Ipv4AndIpv6,
+            dnssec: false,
+            device_view: false,
+            device_view_lifetime_seconds: 3600,
+            blocking: DnsBlockingSettings::default(),
+        }
+    }
+}
+
+/// Blocking of domains per device, which answers the queries of a device for domains it is not allowed to reach with
+/// `NXDOMAIN`. dnsmasq cannot answer queries depending on the client, so the DNS queries of the devices are redirected
+/// to additional dnsmasq instances (one for each set of blocked domains), which forward all other queries to the local
+/// dnsmasq instance.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DnsBlockingSettings {
+    pub enabled: bool,
+    /// Domains (including their subdomains) that are blocked for a device unless its rules allow one of their names.
+    pub domains: Vec<String>,
+    /// Path of the dnsmasq executable.
+    pub dnsmasq_path: PathBuf,
+    /// Directory the configuration files of the additional dnsmasq instances are written to.
+    pub conf_dir: PathBuf,
+    /// Port of the first additional dnsmasq instance, the other instances use the following ports.
+    pub first_port: u16,
+    /// Name server the additional dnsmasq instances forward the queries for other domains to (dnsmasq `server`
+    /// syntax), usually the local dnsmasq instance.
+    pub upstream_server: String,
+    /// User the additional dnsmasq instances run as, which has to be able to write to the query log of the local
+    /// dnsmasq instance.
+    pub user: String,
+}
+
+impl Default for DnsBlockingSettings {
+    fn default() -> Self {
+        DnsBlockingSettings {
+            enabled: false,
+            domains: Vec::new(),
+            dnsmasq_path: PathBuf::from("/usr/sbin/dnsmasq"),
+            conf_dir: PathBuf::from("/tmp/namib/dnsmasq"),
+            first_port: 5400,
+            upstream_server: String::from("127.0.0.1#53"),
+            user: String::from("dnsmasq"),
+        }
+    }
+}
+
+/// Upstream name server used to resolve hostnames.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnsServer {
+    pub address: IpAddr,
+    /// Port of the name server, defaults to the standard port of the protocol (53, 853 for TLS, 443 for HTTPS).
+    #[serde(default)]
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    /// Name the TLS certificate of the name server is verified against, required for TLS and HTTPS.
+    #[serde(default)]
+    pub tls_name: Option<String>,
+}
+
+/// Protocol used to query a name server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsProtocol {
+    Udp,
+    Tcp,
+    /// DNS-over-TLS.
+    Tls,
+    /// DNS-over-HTTPS.
+    Https,
+}
+
+impl Default for DnsProtocol {
+    fn default() -> Self {
+        DnsProtocol::Udp
+    }
+}
+
+/// IP versions of the addresses resolved for a hostname.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DnsIpStrategy {
+    Ipv4Only,
+    Ipv6Only,
+    /// Both IPv4 and IPv6 addresses.
+    Ipv4AndIpv6,
+    /// IPv6 addresses only if there are no IPv4 addresses.
+    Ipv4ThenIpv6,
+    /// IPv4 addresses only if there are no IPv6 addresses.
+    Ipv6ThenIpv4,
+}
+
+/// Bandwidth and connection limits for a device (or a single rule of a device).
+/// Unset limits are not enforced.
+///
+/// Limits for a device are enforced before any of its rules are evaluated: Rate and quota limits apply separately to
+/// the traffic sent and received by the device, connection limits apply to connections initiated by the device.
+/// Limits for a single rule only apply to packets accepted by this rule.
+/// Limits are tracked separately for the IPv4 and IPv6 address of a device.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TrafficLimits {
+    /// ID of the device (as assigned by the NAMIB controller) these limits apply to.
+    pub device_id: i64,
+    /// Name of the rule (the MUD ACE name) these limits apply to.
+    /// If not set, the limits apply to all traffic of the device.
+    pub rule_name: Option<String>,
+    /// Maximum number of bytes per second.
+    pub bytes_per_second: Option<u64>,
+    /// Number of bytes that may exceed `bytes_per_second` at once. Defaults to one second worth of traffic.
+    pub burst_bytes: Option<u32>,
+    /// Maximum number of new connections per second.
+    pub new_connections_per_second: Option<u64>,
+    /// Maximum number of bytes in total. The consumed bytes are kept when the firewall configuration is updated, the
+    /// quota only starts over when the router is restarted.
+    pub quota_bytes: Option<u64>,
+    /// Maximum number of concurrent connections.
+    pub max_connections: Option<u32>,
+}
+
+impl TrafficLimits {
+    /// Returns true if at least one limit is set.
+    pub fn is_limited(&self) -> bool {
+        self.bytes_per_second.is_some()
+            || self.new_connections_per_second.is_some()
+            || self.quota_bytes.is_some()
+            || self.max_connections.is_some()
+    }
+}
+
+/// DSCP value and packet mark set for packets accepted by the rules of a group of devices (e.g. all devices of a
+/// class) or by a single rule of these devices, e.g. to let SQM or upstream QoS deprioritise bulk traffic.
+/// Unset values are not changed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct TrafficMarking {
+    /// IDs of the devices (as assigned by the NAMIB controller) this marking applies to.
+    pub device_ids: Vec<i64>,
+    /// Name of the rule (the MUD ACE name) this marking applies to.
+    /// If not set, the marking applies to all rules of the devices.
+    pub rule_name: Option<String>,
+    /// DSCP value (0-63) set in the IP header, e.g. 8 (CS1) for bulk traffic or 46 (EF) for alarm systems.
+    pub dscp: Option<u8>,
+    /// Packet mark (`meta mark`) set for the packets, e.g. for tc filters or policy routing.
+    pub mark: Option<u32>,
+}
+
+/// Network segment of a device, i.e. the interfaces through which the traffic of the device is routed.
+/// Only packets received from or sent to these interfaces are considered to belong to the device, which allows
+/// devices with the same address in different segments (e.g. VLANs or guest networks).
+/// Interfaces may be given directly or as OpenWrt firewall zones, which are resolved using the UCI configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DeviceSegment {
+    /// ID of the device (as assigned by the NAMIB controller) this segment applies to.
+    pub device_id: i64,
+    /// Names of the network devices (e.g. `br-lan` or `eth0.10`).
+    pub interfaces: Vec<String>,
+    /// Names of OpenWrt firewall zones (e.g. `lan` or `guest`).
+    pub zones: Vec<String>,
+}
+
+/// Restricts a rule of a device to packets received from (`iifname`) and/or sent to (`oifname`) specific interfaces,
+/// e.g. to restrict a rule to local networks by only allowing the LAN zones as output.
+/// Interfaces may be given directly or as OpenWrt firewall zones. Empty lists match any interface.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RuleInterfaces {
+    /// ID of the device (as assigned by the NAMIB controller) the rule belongs to.
+    pub device_id: i64,
+    /// Name of the rule (the MUD ACE name).
+    pub rule_name: String,
+    /// Names of the network devices the packets have to be received from.
+    pub input_interfaces: Vec<String>,
+    /// Names of the OpenWrt firewall zones the packets have to be received from.
+    pub input_zones: Vec<String>,
+    /// Names of the network devices the packets have to be sent to.
+    pub output_interfaces: Vec<String>,
+    /// Names of the OpenWrt firewall zones the packets have to be sent to.
+    pub output_zones: Vec<String>,
+}
+
+/// Settings for enforcing the abstract classes of hosts MUD access control entries may refer to instead of IP
+/// addresses or hostnames. The abstractions are resolved using local knowledge of the enforcer: the LAN prefixes
+/// from the UCI configuration, the MUD URLs sent by devices in their DHCP requests and the controllers announced via
+/// DNS-SD.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AbstractionSettings {
+    /// Rules whose source or destination is an abstraction.
+    pub rules: Vec<RuleAbstraction>,
+    /// OpenWrt firewall zones whose networks are the local networks.
+    pub local_zones: Vec<String>,
+    /// DNS-SD service types (e.g. `_mqtt._tcp`) under which the controllers of devices (`my-controller`) are
+    /// announced, keyed by the MUD URL of the devices.
+    pub my_controllers: HashMap<String, String>,
+}
+
+impl Default for AbstractionSettings {
+    fn default() -> Self {
+        AbstractionSettings {
+            rules: Vec::new(),
+            local_zones: vec!["lan".to_string()],
+            my_controllers: HashMap::new(),
+        }
+    }
+}
+
+impl AbstractionSettings {
+    /// Returns the abstractions used by the rule with the given name of the given device, if any.
+    pub fn abstraction_for(&self, device_id: i64, rule_name: &str) -> Option<&RuleAbstraction> {
+        self.rules
+            .iter()
+            .find(|r| r.device_id == device_id && r.rule_name == rule_name)
+    }
+
+    /// Returns the DNS-SD service types of all controllers referred to by `controller` and `my-controller`
+    /// abstractions.
+    pub fn controller_service_types(&self) -> Vec<String> {
+        let mut service_types: Vec<String> = self
+            .rules
+            .iter()
+            .flat_map(|r| r.src.iter().chain(r.dst.iter()))
+            .filter_map(|abstraction| match abstraction {
+                MudAbstraction::Controller { service_type } => Some(service_type.clone()),
+                _ => None,
+            })
+            .chain(self.my_controllers.values().cloned())
+            .collect();
+        service_types.sort();
+        service_types.dedup();
+        service_types
+    }
+}
+
+/// Replaces the source and/or destination host of a rule of a device with a MUD abstraction.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RuleAbstraction {
+    /// ID of the device (as assigned by the NAMIB controller) the rule belongs to.
+    pub device_id: i64,
+    /// Name of the rule (the MUD ACE name).
+    pub rule_name: String,
+    /// Abstraction used as the source of the rule's packets.
+    pub src: Option<MudAbstraction>,
+    /// Abstraction used as the destination of the rule's packets.
+    pub dst: Option<MudAbstraction>,
+}
+
+/// Abstract class of hosts as defined by the MUD specification (RFC 8520, section 2.1).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum MudAbstraction {
+    /// Hosts in the local networks.
+    LocalNetworks,
+    /// Devices whose MUD URL has the same authority as the MUD URL of the device.
+    SameManufacturer,
+    /// Devices whose MUD URL has the given authority (e.g. `example.com`).
+    Manufacturer { authority: String },
+    /// Controllers of a class, which are announced via DNS-SD under the given service type.
+    Controller { service_type: String },
+    /// Controllers for the MUD URL of the device, see `AbstractionSettings::my_controllers`.
+    MyController,
+}
+
+/// Settings for the detection of anomalous connection attempts of devices.
+///
+/// New connections initiated by a device and rejected connection attempts are counted in fixed time windows.
+/// If a device exceeds one of the thresholds within a window, an anomaly is reported to the controller (and the device
+/// is optionally quarantined, i.e. all of its traffic is rejected).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AnomalyDetectionSettings {
+    pub enabled: bool,
+    /// IDs of the devices to monitor. If not set, all devices are monitored.
+    pub device_ids: Option<Vec<i64>>,
+    /// Length of a time window in seconds.
+    pub window_seconds: u64,
+    /// Number of distinct ports on a single destination host which indicates a port scan.
+    pub port_scan_threshold: usize,
+    /// Number of distinct destination hosts which indicates a fan-out (e.g. a network scan or participation in a
+    /// DDoS attack).
+    pub fan_out_threshold: usize,
+    /// Number of rejected connection attempts which indicates a spike of rejected attempts.
+    pub rejected_threshold: usize,
+    /// Quarantine devices for which an anomaly was detected.
+    pub quarantine: bool,
+    /// Duration of a quarantine in seconds. If not set, devices stay quarantined until the enforcer is restarted.
+    pub quarantine_seconds: Option<u64>,
+}
+
+impl Default for AnomalyDetectionSettings {
+    fn default() -> Self {
+        AnomalyDetectionSettings {
+            enabled: false,
+            device_ids: None,
+            window_seconds: 60,
+            port_scan_threshold: 30,
+            fan_out_threshold: 50,
+            rejected_threshold: 20,
+            quarantine: false,
+            quarantine_seconds: None,
+        }
+    }
+}
+
+impl AnomalyDetectionSettings {
+    /// Returns true if the device with the given ID should be monitored.
+    pub fn is_enabled_for(&self, device_id: i64) -> bool {
+        self.enabled && self.device_ids.as_ref().map_or(true, |ids| ids.contains(&device_id))
+    }
+}
+
+impl Settings {
+    /// Reads the enforcer settings from the settings file, falling back to default settings if the file does not
+    /// exist. Fails if the file exists but can not be read or parsed.
+    pub fn load() -> Result<Settings> {
+        let settings_path = env::var("NAMIB_SETTINGS_FILE").unwrap_or_else(|_| DEFAULT_SETTINGS_FILE.to_string());
+        match File::open(&settings_path) {
+            Ok(file) => {
+                let settings = serde_json::from_reader(file).context(SettingsError { path: &settings_path })?;
+                debug!("Loaded enforcer settings from \"{}\"", settings_path);
+                Ok(settings)
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("No enforcer settings found at \"{}\", using defaults", settings_path);
+                Ok(Settings::default())
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the marking that applies to the rule with the given name of the given device, if any.
+    /// Markings for a specific rule take precedence over markings for all rules of a device.
+    pub fn marking_for(&self, device_id: i64, rule_name: Option<&str>) -> Option<&TrafficMarking> {
+        let markings = self.markings.iter().filter(|m| m.device_ids.contains(&device_id));
+        markings
+            .clone()
+            .find(|m| rule_name.is_some() && m.rule_name.as_deref() == rule_name)
+            .or_else(|| markings.clone().find(|m| m.rule_name.is_none()))
+            .filter(|m| m.dscp.is_some() || m.mark.is_some())
+    }
+
+    /// Returns the network segment of the given device, if any.
+    pub fn segment_for(&self, device_id: i64) -> Option<&DeviceSegment> {
+        self.segments.iter().find(|s| s.device_id == device_id)
+    }
+
+    /// Returns the interfaces the rule with the given name of the given device is restricted to, if any.
+    pub fn rule_interfaces_for(&self, device_id: i64, rule_name: &str) -> Option<&RuleInterfaces> {
+        self.rule_interfaces
+            .iter()
+            .find(|r| r.device_id == device_id && r.rule_name == rule_name)
+    }
+
+    /// Returns true if any segment or rule is restricted to OpenWrt firewall zones.
+    pub fn uses_zones(&self) -> bool {
+        self.segments.iter().any(|s| !s.zones.is_empty())
+            || self
+                .rule_interfaces
+                .iter()
+                .any(|r| !r.input_zones.is_empty() || !r.output_zones.is_empty())
+    }
+
+    /// Returns the schedule that applies to the rule with the given name of the given device, if any.
+    /// Schedules for a specific rule take precedence over schedules for the whole device.
+    pub fn schedule_for(&self, device_id: i64, rule_name: Option<&str>) -> Option<&DeviceSchedule> {
+        self.schedules
+            .iter()
+            .filter(|s| s.device_id == device_id)
+            .find(|s| rule_name.is_some() && s.rule_name.as_deref() == rule_name)
+            .or_else(|| {
+                self.schedules
+                    .iter()
+                    .find(|s| s.device_id == device_id && s.rule_name.is_none())
+            })
+    }
+
+    /// Returns the rule names the per-rule settings of the given device refer to which are not among the given rule
+    /// names of the device, together with the kind of setting.
+    pub fn unmatched_rule_names<'a>(&'a self, device_id: i64, rule_names: &[String]) -> Vec<(&'static str, &'a str)> {
+        let schedules = self
+            .schedules
+            .iter()
+            .filter(|s| s.device_id == device_id)
+            .filter_map(|s| s.rule_name.as_deref())
+            .map(|name| ("schedule", name));
+        let limits = self
+            .limits
+            .iter()
+            .filter(|l| l.device_id == device_id)
+            .filter_map(|l| l.rule_name.as_deref())
+            .map(|name| ("limits", name));
+        let markings = self
+            .markings
+            .iter()
+            .filter(|m| m.device_ids.contains(&device_id))
+            .filter_map(|m| m.rule_name.as_deref())
+            .map(|name| ("marking", name));
+        let rule_interfaces = self
+            .rule_interfaces
+            .iter()
+            .filter(|r| r.device_id == device_id)
+            .map(|r| ("interfaces", r.rule_name.as_str()));
+        let abstractions = self
+            .abstractions
+            .rules
+            .iter()
+            .filter(|r| r.device_id == device_id)
+            .map(|r| ("abstraction", r.rule_name.as_str()));
+        schedules
+            .chain(limits)
+            .chain(markings)
+            .chain(rule_interfaces)
+            .chain(abstractions)
+            .filter(|(_, name)| !rule_names.iter().any(|rule_name| rule_name == name))
+            .collect()
+    }
+
+    /// Returns the limits for the given device (if `rule_name` is `None`) or for the rule with the given name of the
+    /// given device.
+    pub fn limits_for(&self, device_id: i64, rule_name: Option<&str>) -> Option<&TrafficLimits> {
+        self.limits
+            .iter()
+            .find(|l| l.device_id == device_id && l.rule_name.as_deref() == rule_name && l.is_limited())
+    }
+}